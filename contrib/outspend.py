@@ -0,0 +1,18 @@
+#!/usr/bin/env python3
+import argparse
+import client
+import json
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument('--host', default='localhost')
+    parser.add_argument("txid")
+    parser.add_argument("vout", type=int)
+    args = parser.parse_args()
+
+    conn = client.Client((args.host, 50001))
+    spender, = conn.call([client.request("blockchain.outpoint.get_spender", args.txid, args.vout)])
+    print(json.dumps(spender))
+
+if __name__ == "__main__":
+    main()
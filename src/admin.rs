@@ -0,0 +1,82 @@
+use anyhow::Result;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use serde_json::Value;
+
+use std::net::SocketAddr;
+
+use crate::config::AuthToken;
+
+/// Access to the admin API, which is served on the monitoring port (under `/admin/`).
+#[derive(Clone, Debug)]
+pub struct AdminAccess {
+    token: Option<AuthToken>,
+}
+
+impl AdminAccess {
+    /// Without a token, only loopback clients are allowed.
+    pub(crate) fn new(token: Option<AuthToken>) -> Self {
+        Self { token }
+    }
+
+    /// `authorization` is the value of the HTTP "Authorization" header (if sent).
+    pub(crate) fn is_allowed(
+        &self,
+        remote: Option<&SocketAddr>,
+        authorization: Option<&str>,
+    ) -> bool {
+        match &self.token {
+            Some(token) => authorization
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map_or(false, |value| token.matches(value.trim())),
+            None => remote.map_or(false, |addr| addr.ip().is_loopback()),
+        }
+    }
+}
+
+/// An operational command (e.g. "clients"), executed by the server loop.
+pub(crate) struct AdminRequest {
+    pub(crate) command: String,
+    pub(crate) params: Value,
+    reply: Sender<Result<Value, String>>,
+}
+
+impl AdminRequest {
+    pub(crate) fn new(command: String, params: Value) -> (Self, Receiver<Result<Value, String>>) {
+        let (reply, reply_rx) = bounded(1);
+        let request = Self {
+            command,
+            params,
+            reply,
+        };
+        (request, reply_rx)
+    }
+
+    pub(crate) fn reply(self, result: Result<Value>) {
+        // the HTTP handler may have timed out meanwhile
+        let _ = self.reply.send(result.map_err(|e| format!("{:#}", e)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AdminAccess;
+    use crate::config::ListenerConfig;
+
+    #[test]
+    fn test_access() {
+        let local = "127.0.0.1:4224".parse().unwrap();
+        let remote = "192.168.1.2:4224".parse().unwrap();
+
+        let access = AdminAccess::new(None);
+        assert!(access.is_allowed(Some(&local), None));
+        assert!(!access.is_allowed(Some(&remote), None));
+        assert!(!access.is_allowed(None, None));
+
+        let listener: ListenerConfig = "127.0.0.1:50001;token=secret".parse().unwrap();
+        let access = AdminAccess::new(listener.auth_token);
+        assert!(access.is_allowed(Some(&remote), Some("Bearer secret")));
+        assert!(!access.is_allowed(Some(&local), None));
+        assert!(!access.is_allowed(Some(&local), Some("Bearer wrong")));
+        assert!(!access.is_allowed(Some(&local), Some("secret")));
+    }
+}
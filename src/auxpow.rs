@@ -0,0 +1,120 @@
+use anyhow::{Context, Result};
+use bitcoin::{
+    blockdata::block::Header as BlockHeader,
+    consensus::{encode, Decodable},
+    BlockHash, Transaction, TxMerkleNode,
+};
+
+use std::io;
+
+use crate::types::SerBlock;
+
+const HEADER_SIZE: usize = 80;
+
+/// Merged-mined headers (e.g. Namecoin's) are followed by their AuxPoW, signaled by this version bit.
+const VERSION_AUXPOW: i32 = 1 << 8;
+
+fn has_auxpow(header: &BlockHeader) -> bool {
+    cfg!(feature = "auxpow") && header.version.to_consensus() & VERSION_AUXPOW != 0
+}
+
+/// The AuxPoW proves the work done on the parent chain's block, so it is not needed for
+/// tracking the chain (the block hash covers only the 80-byte header).
+fn skip_auxpow<R: io::Read + ?Sized>(r: &mut R) -> Result<(), encode::Error> {
+    let _coinbase_tx = Transaction::consensus_decode(r)?;
+    let _parent_hash = BlockHash::consensus_decode(r)?;
+    let _coinbase_branch = Vec::<TxMerkleNode>::consensus_decode(r)?;
+    let _coinbase_index = u32::consensus_decode(r)?;
+    let _chain_branch = Vec::<TxMerkleNode>::consensus_decode(r)?;
+    let _chain_index = u32::consensus_decode(r)?;
+    let _parent_header = BlockHeader::consensus_decode(r)?;
+    Ok(())
+}
+
+/// Decode a (possibly AuxPoW-extended) header, as sent in P2P `headers` messages.
+pub(crate) fn decode_header<R: io::Read + ?Sized>(r: &mut R) -> Result<BlockHeader, encode::Error> {
+    let header = BlockHeader::consensus_decode(r)?;
+    if has_auxpow(&header) {
+        skip_auxpow(r)?;
+    }
+    Ok(header)
+}
+
+/// Remove the block header's AuxPoW (if any), so the block can be parsed and indexed
+/// like a Bitcoin block.
+pub(crate) fn strip_block(block: SerBlock) -> Result<SerBlock> {
+    let auxpow_end = {
+        let mut rest: &[u8] = &block;
+        let header = BlockHeader::consensus_decode(&mut rest).context("invalid block header")?;
+        if !has_auxpow(&header) {
+            None
+        } else {
+            skip_auxpow(&mut rest).context("invalid AuxPoW")?;
+            Some(block.len() - rest.len())
+        }
+    };
+    Ok(match auxpow_end {
+        None => block,
+        Some(end) => {
+            let mut stripped = Vec::with_capacity(HEADER_SIZE + block.len() - end);
+            stripped.extend_from_slice(&block[..HEADER_SIZE]);
+            stripped.extend_from_slice(&block[end..]);
+            stripped
+        }
+    })
+}
+
+#[cfg(all(test, feature = "auxpow"))]
+mod tests {
+    use super::{decode_header, strip_block, VERSION_AUXPOW};
+    use bitcoin::{
+        block::{Header as BlockHeader, Version},
+        consensus::{deserialize, serialize, Encodable},
+        hashes::Hash,
+        BlockHash, CompactTarget, Transaction, TxMerkleNode,
+    };
+    use hex_lit::hex;
+
+    #[test]
+    fn test_strip_auxpow() {
+        let header = BlockHeader {
+            version: Version::from_consensus(VERSION_AUXPOW | 4),
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 1,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 2,
+        };
+        let parent_header = BlockHeader {
+            version: Version::from_consensus(4),
+            ..header
+        };
+        let coinbase_tx: Transaction = deserialize(&hex!("01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0100ffffffff0100f2052a01000000015100000000")).unwrap();
+
+        let mut auxpow = vec![];
+        coinbase_tx.consensus_encode(&mut auxpow).unwrap();
+        BlockHash::all_zeros()
+            .consensus_encode(&mut auxpow)
+            .unwrap();
+        vec![TxMerkleNode::all_zeros()]
+            .consensus_encode(&mut auxpow)
+            .unwrap();
+        0u32.consensus_encode(&mut auxpow).unwrap();
+        Vec::<TxMerkleNode>::new()
+            .consensus_encode(&mut auxpow)
+            .unwrap();
+        0u32.consensus_encode(&mut auxpow).unwrap();
+        parent_header.consensus_encode(&mut auxpow).unwrap();
+
+        let txs = serialize(&vec![coinbase_tx]);
+        let block = [serialize(&header), auxpow.clone(), txs.clone()].concat();
+        let expected = [serialize(&header), txs].concat();
+        assert_eq!(strip_block(block).unwrap(), expected);
+        let plain = [serialize(&parent_header), vec![0]].concat();
+        assert_eq!(strip_block(plain.clone()).unwrap(), plain); // no AuxPoW
+
+        let mut headers_msg: &[u8] = &[serialize(&header), auxpow, vec![0]].concat();
+        assert_eq!(decode_header(&mut headers_msg).unwrap(), header);
+        assert_eq!(headers_msg, [0]); // followed by the (empty) transactions' count
+    }
+}
@@ -0,0 +1,126 @@
+use bitcoin::Txid;
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+
+/// A transaction broadcast through the server, persisted in the DB log (for investigating
+/// abuse, or transactions which were not propagated).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct BroadcastRecord {
+    pub(crate) txid: Txid,
+    pub(crate) time: u64,            // UNIX timestamp
+    pub(crate) peer: Option<IpAddr>, // the broadcasting client's address
+    pub(crate) accepted: bool,
+    pub(crate) error: Option<String>, // the daemon's rejection reason
+}
+
+impl BroadcastRecord {
+    pub(crate) fn new(txid: Txid, peer: Option<IpAddr>, error: Option<String>) -> Self {
+        Self {
+            txid,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            peer,
+            accepted: error.is_none(),
+            error,
+        }
+    }
+}
+
+/// Filters the logged broadcasts (via the admin API).
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BroadcastQuery {
+    txid: Option<Txid>,
+    peer: Option<IpAddr>,
+    limit: Option<usize>,
+}
+
+impl BroadcastQuery {
+    /// The newest `limit` matching records (newest first).
+    pub(crate) fn filter(
+        &self,
+        records: impl DoubleEndedIterator<Item = BroadcastRecord>,
+    ) -> Vec<BroadcastRecord> {
+        records
+            .rev()
+            .filter(|record| self.txid.map_or(true, |txid| record.txid == txid))
+            .filter(|record| self.peer.map_or(true, |peer| record.peer == Some(peer)))
+            .take(self.limit.unwrap_or(DEFAULT_QUERY_LIMIT))
+            .collect()
+    }
+}
+
+/// Assigns the logged broadcasts' sequence numbers, keeping (at most) the last `max_records`.
+pub(crate) struct BroadcastLog {
+    max_records: u64, // 0 - disable the log
+    next_seq: AtomicU64,
+}
+
+impl BroadcastLog {
+    pub(crate) fn new(max_records: usize, last_seq: Option<u64>) -> Self {
+        Self {
+            max_records: max_records as u64,
+            next_seq: AtomicU64::new(last_seq.map_or(0, |seq| seq + 1)),
+        }
+    }
+
+    /// Returns the next record's sequence number, and the first one to keep (the older ones
+    /// are deleted) - or `None` if the log is disabled.
+    pub(crate) fn next(&self) -> Option<(u64, u64)> {
+        if self.max_records == 0 {
+            return None;
+        }
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        Some((seq, (seq + 1).saturating_sub(self.max_records)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BroadcastLog, BroadcastQuery, BroadcastRecord};
+    use bitcoin::{hashes::Hash, Txid};
+    use serde_json::json;
+
+    #[test]
+    fn test_broadcast_log() {
+        let disabled = BroadcastLog::new(0, None);
+        assert_eq!(disabled.next(), None);
+
+        let log = BroadcastLog::new(2, Some(6));
+        assert_eq!(log.next(), Some((7, 6)));
+        assert_eq!(log.next(), Some((8, 7)));
+
+        let log = BroadcastLog::new(10, None);
+        assert_eq!(log.next(), Some((0, 0)));
+    }
+
+    #[test]
+    fn test_broadcast_query() {
+        let txid = |i: u8| Txid::from_byte_array([i; 32]);
+        let local = "127.0.0.1".parse().unwrap();
+        let records = vec![
+            BroadcastRecord::new(txid(1), Some(local), None),
+            BroadcastRecord::new(txid(2), None, Some("insufficient fee".to_owned())),
+            BroadcastRecord::new(txid(1), None, None),
+        ];
+        let query = |value| -> BroadcastQuery { serde_json::from_value(value).unwrap() };
+
+        let all = BroadcastQuery::default().filter(records.clone().into_iter());
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0], records[2]); // newest first
+        assert!(!all[1].accepted);
+
+        let by_txid = query(json!({"txid": txid(1)})).filter(records.clone().into_iter());
+        assert_eq!(by_txid, [records[2].clone(), records[0].clone()]);
+        let by_peer = query(json!({"peer": "127.0.0.1"})).filter(records.clone().into_iter());
+        assert_eq!(by_peer, [records[0].clone()]);
+        let limited = query(json!({"limit": 1})).filter(records.clone().into_iter());
+        assert_eq!(limited, [records[2].clone()]);
+        assert!(serde_json::from_value::<BroadcastQuery>(json!({"ip": "127.0.0.1"})).is_err());
+    }
+}
@@ -1,22 +1,35 @@
 use bitcoin::{Transaction, Txid};
 use parking_lot::RwLock;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 
-use crate::metrics::{self, Histogram, Metrics};
+use crate::{
+    memory::tx_memory,
+    metrics::{self, Histogram, Metrics},
+};
+
+#[derive(Default)]
+struct TxCache {
+    txs: HashMap<Txid, Transaction>,
+    order: VecDeque<Txid>, // by insertion, for eviction
+    bytes: usize,          // estimated memory usage
+}
 
 pub(crate) struct Cache {
-    txs: Arc<RwLock<HashMap<Txid, Transaction>>>,
+    txs: Arc<RwLock<TxCache>>,
+    max_bytes: Option<usize>,
 
     // stats
     txs_size: Histogram,
 }
 
 impl Cache {
-    pub fn new(metrics: &Metrics) -> Self {
+    /// The oldest transactions are evicted when the cache exceeds `max_bytes` (if set).
+    pub fn new(metrics: &Metrics, max_bytes: Option<usize>) -> Self {
         Cache {
             txs: Default::default(),
+            max_bytes,
             txs_size: metrics.histogram_vec(
                 "cache_txs_size",
                 "Cached transactions' size (in bytes)",
@@ -27,17 +40,69 @@ impl Cache {
     }
 
     pub fn add_tx(&self, txid: Txid, f: impl FnOnce() -> Transaction) {
-        self.txs.write().entry(txid).or_insert_with(|| {
-            let tx = f();
-            self.txs_size.observe("serialized", tx.total_size() as f64);
-            tx
-        });
+        let mut cache = self.txs.write();
+        if cache.txs.contains_key(&txid) {
+            return;
+        }
+        let tx = f();
+        self.txs_size.observe("serialized", tx.total_size() as f64);
+        cache.bytes += tx_memory(&tx);
+        cache.txs.insert(txid, tx);
+        cache.order.push_back(txid);
+        if let Some(max_bytes) = self.max_bytes {
+            while cache.bytes > max_bytes {
+                let oldest = match cache.order.pop_front() {
+                    Some(txid) => txid,
+                    None => break,
+                };
+                let tx = cache.txs.remove(&oldest).expect("missing cached tx");
+                cache.bytes -= tx_memory(&tx);
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        *self.txs.write() = TxCache::default();
     }
 
     pub fn get_tx<F, T>(&self, txid: &Txid, f: F) -> Option<T>
     where
         F: FnOnce(&Transaction) -> T,
     {
-        self.txs.read().get(txid).map(f)
+        self.txs.read().txs.get(txid).map(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cache;
+    use crate::{memory::tx_memory, metrics::Metrics};
+    use bitcoin::{absolute::LockTime, transaction::Version, Transaction, Txid};
+
+    fn tx(lock_time: u32) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::from_consensus(lock_time),
+            input: vec![],
+            output: vec![],
+        }
+    }
+
+    #[test]
+    fn test_eviction() {
+        let txs: Vec<(Txid, Transaction)> = (0..3)
+            .map(|i| {
+                let tx = tx(i);
+                (tx.txid(), tx)
+            })
+            .collect();
+        let cache = Cache::new(&Metrics::unserved(), Some(2 * tx_memory(&txs[0].1)));
+        for (txid, tx) in &txs {
+            cache.add_tx(*txid, || tx.clone());
+        }
+        // the oldest transaction is evicted first
+        assert_eq!(cache.get_tx(&txs[0].0, |_| ()), None);
+        assert_eq!(cache.get_tx(&txs[1].0, |_| ()), Some(()));
+        assert_eq!(cache.get_tx(&txs[2].0, |_| ()), Some(()));
     }
 }
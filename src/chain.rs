@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 
 use bitcoin::blockdata::block::Header as BlockHeader;
-use bitcoin::{BlockHash, Network};
+use bitcoin::consensus::{deserialize, Encodable};
+use bitcoin::{consensus::Params, BlockHash, Network, Work};
 
 /// A new header found, to be added to the chain at specific height
 pub(crate) struct NewHeader {
@@ -28,20 +29,49 @@ impl NewHeader {
     }
 }
 
+const MEDIAN_TIME_SPAN: usize = 11; // see BIP-113
+const HEADER_SIZE: usize = 80;
+
+/// Headers which were replaced by a chain update.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Reorg {
+    pub(crate) height: usize, // first replaced height
+    pub(crate) depth: usize,  // # of replaced headers
+    pub(crate) old_tip: BlockHash,
+}
+
+/// Next difficulty adjustment, estimated from the current period's average block interval
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Retarget {
+    height: usize,
+    blocks_remaining: usize,
+    estimated_time: u64,   // UNIX timestamp
+    estimated_change: f64, // difficulty ratio (after/before)
+}
+
 /// Current blockchain headers' list
 pub struct Chain {
     headers: Vec<(BlockHash, BlockHeader)>,
     heights: HashMap<BlockHash, usize>,
+    chainwork: Work, // cumulative proof-of-work, up to (and including) the tip
+    params: Params,
 }
 
 impl Chain {
     // create an empty chain
     pub fn new(network: Network) -> Self {
         let genesis = bitcoin::blockdata::constants::genesis_block(network);
+        Self::with_genesis(network, genesis.header)
+    }
+
+    // create an empty chain, starting from a custom genesis block (see `chain_params_file`)
+    pub(crate) fn with_genesis(network: Network, genesis: BlockHeader) -> Self {
         let genesis_hash = genesis.block_hash();
         Self {
-            headers: vec![(genesis_hash, genesis.header)],
+            headers: vec![(genesis_hash, genesis)],
             heights: std::iter::once((genesis_hash, 0)).collect(), // genesis header @ zero height
+            chainwork: genesis.work(),
+            params: Params::new(network),
         }
     }
 
@@ -53,7 +83,7 @@ impl Chain {
         self.update(vec![NewHeader::from((
             self.headers[new_height].1,
             new_height,
-        ))])
+        ))]);
     }
 
     /// Load the chain from a collection of headers, up to the given tip
@@ -74,7 +104,71 @@ impl Chain {
         }
         info!("loading {} headers, tip={}", new_headers.len(), tip);
         let new_headers = new_headers.into_iter().rev().copied(); // order by height
-        self.update(new_headers.zip(1..).map(NewHeader::from).collect())
+        self.update(new_headers.zip(1..).map(NewHeader::from).collect());
+    }
+
+    /// Serialize the headers (excluding genesis) ordered by height, so they can be bulk-loaded
+    /// on restart (see `load_snapshot`)
+    pub(crate) fn snapshot(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.height() * HEADER_SIZE);
+        for (_hash, header) in &self.headers[1..] {
+            header
+                .consensus_encode(&mut data)
+                .expect("in-memory writers don't error");
+        }
+        data
+    }
+
+    /// Load the chain from a headers' snapshot, up to the given tip.
+    /// Each block hash is taken from the next header's `prev_blockhash`, so only the last
+    /// `verify` headers are hashed (to make sure the snapshot ends at `tip`).
+    /// Returns false (without changing the chain) if the snapshot is invalid or stale.
+    pub(crate) fn load_snapshot(&mut self, snapshot: &[u8], tip: BlockHash, verify: usize) -> bool {
+        if snapshot.is_empty() || snapshot.len() % HEADER_SIZE != 0 {
+            warn!("invalid headers snapshot: {} bytes", snapshot.len());
+            return false;
+        }
+        let headers: Vec<BlockHeader> =
+            match snapshot.chunks(HEADER_SIZE).map(deserialize).collect() {
+                Ok(headers) => headers,
+                Err(e) => {
+                    warn!("invalid headers snapshot: {}", e);
+                    return false;
+                }
+            };
+        if headers[0].prev_blockhash != self.headers[0].0 {
+            warn!("headers snapshot doesn't start at genesis");
+            return false;
+        }
+        let hashes: Vec<BlockHash> = headers[1..]
+            .iter()
+            .map(|header| header.prev_blockhash)
+            .chain(std::iter::once(tip))
+            .collect();
+        let start = headers.len().saturating_sub(verify.max(1));
+        let verified = headers[start..]
+            .iter()
+            .zip(&hashes[start..])
+            .all(|(header, hash)| header.block_hash() == *hash);
+        if !verified {
+            info!("headers snapshot is stale (not ending at tip={})", tip);
+            return false;
+        }
+        self.headers.truncate(1);
+        self.heights.retain(|_hash, height| *height == 0);
+        self.heights.reserve(headers.len());
+        self.headers.reserve(headers.len());
+        for (height, (hash, header)) in (1..).zip(hashes.into_iter().zip(headers)) {
+            self.heights.insert(hash, height);
+            self.headers.push((hash, header));
+        }
+        self.chainwork = self.total_work();
+        info!(
+            "loaded {} headers from snapshot, tip={}",
+            self.height(),
+            tip
+        );
+        true
     }
 
     /// Get the block hash at specified height (if exists)
@@ -92,16 +186,34 @@ impl Chain {
         self.heights.get(blockhash).copied()
     }
 
-    /// Update the chain with a list of new headers (possibly a reorg)
-    pub(crate) fn update(&mut self, headers: Vec<NewHeader>) {
+    /// Update the chain with a list of new headers (possibly a reorg, which is returned).
+    /// Dropping the last headers (without replacing them) is not considered a reorg.
+    pub(crate) fn update(&mut self, headers: Vec<NewHeader>) -> Option<Reorg> {
+        let mut reorg = None;
         if let Some(first_height) = headers.first().map(|h| h.height) {
-            for (hash, _header) in self.headers.drain(first_height..) {
+            let old_tip = self.tip();
+            let old_height = self.height();
+            let dropped = first_height <= old_height;
+            for (i, (hash, _header)) in self.headers.drain(first_height..).enumerate() {
                 assert!(self.heights.remove(&hash).is_some());
+                let replaced = headers.get(i).map_or(false, |h| h.hash != hash);
+                if replaced && reorg.is_none() {
+                    let height = first_height + i;
+                    reorg = Some(Reorg {
+                        height,
+                        depth: old_height + 1 - height,
+                        old_tip,
+                    });
+                }
+            }
+            if dropped {
+                self.chainwork = self.total_work(); // rare (e.g. during a reorg)
             }
             for (h, height) in headers.into_iter().zip(first_height..) {
                 assert_eq!(h.height, height);
                 assert_eq!(h.hash, h.header.block_hash());
                 assert!(self.heights.insert(h.hash, h.height).is_none());
+                self.chainwork = self.chainwork + h.header.work();
                 self.headers.push((h.hash, h.header));
             }
             info!(
@@ -110,6 +222,7 @@ impl Chain {
                 self.headers.len() - 1
             );
         }
+        reorg
     }
 
     /// Best block hash
@@ -122,6 +235,84 @@ impl Chain {
         self.headers.len() - 1
     }
 
+    /// Median timestamp of the last 11 blocks, up to (and including) `height` (if exists)
+    pub(crate) fn median_time_past(&self, height: usize) -> Option<u32> {
+        let start = (height + 1).saturating_sub(MEDIAN_TIME_SPAN);
+        let headers = self.headers.get(start..=height)?;
+        let mut times: Vec<u32> = headers.iter().map(|(_hash, header)| header.time).collect();
+        times.sort_unstable();
+        Some(times[times.len() / 2])
+    }
+
+    /// The first block whose median-time-past is not before `timestamp` (if exists).
+    /// Since the median-time-past is monotonic, it can be found using a binary search.
+    pub(crate) fn height_at_time(&self, timestamp: u32) -> Option<usize> {
+        let mtp = |height| self.median_time_past(height).expect("missing header");
+        let (mut low, mut high) = (0, self.headers.len()); // result is in [low, high]
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if mtp(mid) < timestamp {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        if low < self.headers.len() {
+            Some(low)
+        } else {
+            None
+        }
+    }
+
+    /// Cumulative proof-of-work, up to (and including) the tip
+    pub(crate) fn chainwork(&self) -> Work {
+        self.chainwork
+    }
+
+    fn total_work(&self) -> Work {
+        let mut headers = self.headers.iter().map(|(_hash, header)| header.work());
+        let genesis = headers.next().expect("empty chain");
+        headers.fold(genesis, |total, work| total + work)
+    }
+
+    /// Current tip's difficulty (relative to the minimal one)
+    pub(crate) fn difficulty(&self) -> f64 {
+        self.headers
+            .last()
+            .expect("empty chain")
+            .1
+            .difficulty_float()
+    }
+
+    /// `None` if the network doesn't retarget (e.g. regtest)
+    pub(crate) fn next_retarget(&self) -> Option<Retarget> {
+        if self.params.no_pow_retargeting {
+            return None;
+        }
+        let spacing = self.params.pow_target_spacing;
+        let interval = (self.params.pow_target_timespan / spacing) as usize;
+        let height = self.height();
+        let start_height = height - height % interval;
+        let mined = (height - start_height) as u64;
+        let tip_time = u64::from(self.headers[height].1.time);
+        let start_time = u64::from(self.headers[start_height].1.time);
+        // assume the target spacing, until the first block of the period is mined
+        let average = match mined {
+            0 => spacing as f64,
+            _ => tip_time.saturating_sub(start_time) as f64 / mined as f64,
+        };
+        let next_height = start_height + interval;
+        let blocks_remaining = next_height - height;
+        // the adjustment is limited to a factor of 4 (in each direction)
+        let estimated_change = (spacing as f64 / average.max(1.0)).max(0.25).min(4.0);
+        Some(Retarget {
+            height: next_height,
+            blocks_remaining,
+            estimated_time: tip_time + (average * blocks_remaining as f64) as u64,
+            estimated_change,
+        })
+    }
+
     /// List of block hashes for efficient fork detection and block/header sync
     /// see https://en.bitcoin.it/wiki/Protocol_documentation#getblocks
     pub(crate) fn locator(&self) -> Vec<BlockHash> {
@@ -144,10 +335,10 @@ impl Chain {
 
 #[cfg(test)]
 mod tests {
-    use super::{Chain, NewHeader};
+    use super::{Chain, NewHeader, Reorg, Retarget};
     use bitcoin::blockdata::block::Header as BlockHeader;
     use bitcoin::consensus::deserialize;
-    use bitcoin::Network::Regtest;
+    use bitcoin::Network::{Bitcoin, Regtest};
     use hex_lit::hex;
 
     #[test]
@@ -162,6 +353,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_chainwork_and_retarget() {
+        let regtest = Chain::new(Regtest);
+        assert_eq!(regtest.chainwork().to_be_bytes()[31], 2);
+        assert_eq!(regtest.next_retarget(), None);
+
+        let mainnet = Chain::new(Bitcoin);
+        assert!(regtest.difficulty() < mainnet.difficulty());
+        assert_eq!(
+            mainnet.next_retarget(),
+            Some(Retarget {
+                height: 2016,
+                blocks_remaining: 2016,
+                estimated_time: 1231006505 + 2016 * 600,
+                estimated_change: 1.0,
+            })
+        );
+    }
+
     #[test]
     fn test_updates() {
         let byte_headers = [
@@ -204,6 +414,32 @@ hex!("000000200030d7f9c11ef35b89a0eefb9a5e449909339b5e7854d99804ea8d6a49bf900a03
         let mut regtest = Chain::new(Regtest);
         regtest.load(headers.clone(), headers.last().unwrap().block_hash());
         assert_eq!(regtest.height(), headers.len());
+        assert_eq!(
+            regtest.chainwork().to_be_bytes()[31],
+            2 * (headers.len() as u8 + 1)
+        );
+
+        // test loading from a snapshot
+        let snapshot = regtest.snapshot();
+        assert_eq!(snapshot.len(), headers.len() * 80);
+        let mut loaded = Chain::new(Regtest);
+        assert!(loaded.load_snapshot(&snapshot, regtest.tip(), 2));
+        assert_eq!(loaded.height(), regtest.height());
+        assert_eq!(
+            loaded.chainwork().to_be_bytes(),
+            regtest.chainwork().to_be_bytes()
+        );
+        for height in 0..=regtest.height() {
+            let hash = regtest.get_block_hash(height).unwrap();
+            assert_eq!(loaded.get_block_hash(height), Some(hash));
+            assert_eq!(loaded.get_block_height(&hash), Some(height));
+        }
+        let stale_tip = headers[headers.len() - 2].block_hash();
+        let mut loaded = Chain::new(Regtest);
+        assert!(!loaded.load_snapshot(&snapshot, stale_tip, 2));
+        assert!(!loaded.load_snapshot(&snapshot[1..], regtest.tip(), 2));
+        assert!(!loaded.load_snapshot(&snapshot[80..], regtest.tip(), 2)); // missing first header
+        assert_eq!(loaded.height(), 0);
 
         // test getters
         for (header, height) in headers.iter().zip(1usize..) {
@@ -212,12 +448,30 @@ hex!("000000200030d7f9c11ef35b89a0eefb9a5e449909339b5e7854d99804ea8d6a49bf900a03
             assert_eq!(regtest.get_block_height(&header.block_hash()), Some(height));
         }
 
+        // test median-time-past (regtest headers' timestamps are non-decreasing)
+        for height in 0..=regtest.height() {
+            let start = height.saturating_sub(10);
+            let mut times: Vec<u32> = (start..=height)
+                .map(|h| regtest.get_block_header(h).unwrap().time)
+                .collect();
+            times.sort_unstable();
+            let mtp = regtest.median_time_past(height).unwrap();
+            assert_eq!(mtp, times[times.len() / 2]);
+            let first = regtest.height_at_time(mtp).unwrap();
+            assert!(first <= height);
+            assert_eq!(regtest.median_time_past(first), Some(mtp));
+        }
+        assert_eq!(regtest.median_time_past(regtest.height() + 1), None);
+        assert_eq!(regtest.height_at_time(0), Some(0));
+        assert_eq!(regtest.height_at_time(u32::MAX), None);
+
         // test chain shortening
         for i in (0..=headers.len()).rev() {
             let hash = regtest.get_block_hash(i).unwrap();
             assert_eq!(regtest.get_block_height(&hash), Some(i));
             assert_eq!(regtest.height(), i);
             assert_eq!(regtest.tip(), hash);
+            assert_eq!(regtest.chainwork().to_be_bytes()[31], 2 * (i as u8 + 1));
             regtest.drop_last_headers(1);
         }
         assert_eq!(regtest.height(), 0);
@@ -243,7 +497,16 @@ hex!("000000200030d7f9c11ef35b89a0eefb9a5e449909339b5e7854d99804ea8d6a49bf900a03
         let height = regtest.height();
 
         let new_header: BlockHeader = deserialize(&hex!("000000200030d7f9c11ef35b89a0eefb9a5e449909339b5e7854d99804ea8d6a49bf900a0304d2e55fe0b6415949cff9bca0f88c0717884a5e5797509f89f856af93624a7a6bcc60ffff7f2000000000")).unwrap();
-        regtest.update(vec![NewHeader::from((new_header, height))]);
+        let old_tip = regtest.tip();
+        let reorg = regtest.update(vec![NewHeader::from((new_header, height))]);
+        assert_eq!(
+            reorg,
+            Some(Reorg {
+                height,
+                depth: 1,
+                old_tip
+            })
+        );
         assert_eq!(regtest.height(), height);
         assert_eq!(
             regtest.tip(),
@@ -1,20 +1,28 @@
+use bitcoin::blockdata::block::Header as BlockHeader;
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::{hex::FromHex, sha256, sha256d, Hash, HashEngine};
 use bitcoin::p2p::Magic;
 use bitcoin::Network;
 use bitcoincore_rpc::Auth;
 use dirs_next::home_dir;
 
+use std::collections::BTreeMap;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use std::env::consts::{ARCH, OS};
 use std::time::Duration;
 
+use crate::{admin::AdminAccess, memory::MemoryBudget, status_page::ErrorLog, types::ScriptType};
+
 pub const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
 const DEFAULT_SERVER_ADDRESS: [u8; 4] = [127, 0, 0, 1]; // by default, serve on IPv4 localhost
+const REGTEST_TESTING_WAIT_DURATION: Duration = Duration::from_millis(100);
+const LOW_POWER_INDEX_THROTTLE: u32 = 20; // blocks per second
 
 mod internal {
     #![allow(clippy::enum_variant_names)]
@@ -91,6 +99,236 @@ impl ResolvAddr {
     }
 }
 
+/// Electrum RPC listener configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerConfig {
+    pub addr: SocketAddr,
+    pub max_clients: Option<usize>,
+    pub proxy_protocol: bool,
+    pub allow: Vec<IpCidr>,
+    pub deny: Vec<IpCidr>,
+    pub auth_token: Option<AuthToken>,
+    pub private: bool, // allows RPCs for trusted clients (e.g. serving raw blocks)
+    pub padding: Option<usize>, // responses are padded to power-of-two sizes (at least this one)
+    pub delay: Option<(Duration, Duration)>, // responses are sent after a random delay in this range
+}
+
+impl ListenerConfig {
+    fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            max_clients: None,
+            proxy_protocol: false,
+            allow: vec![],
+            deny: vec![],
+            auth_token: None,
+            private: false,
+            padding: None,
+            delay: None,
+        }
+    }
+
+    /// Denied addresses take precedence over allowed ones.
+    /// An empty allow list allows all (non-denied) addresses.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    fn set_option(&mut self, key: &str, value: Option<&str>) -> Result<(), String> {
+        match (key, value) {
+            ("max_clients", Some(value)) => {
+                let max_clients = value
+                    .parse()
+                    .map_err(|e| format!("invalid max_clients {:?}: {}", value, e))?;
+                self.max_clients = Some(max_clients);
+            }
+            ("proxy_protocol", None) => self.proxy_protocol = true,
+            ("private", None) => self.private = true,
+            ("allow", Some(value)) => self.allow.push(value.parse()?),
+            ("deny", Some(value)) => self.deny.push(value.parse()?),
+            ("pad", Some(value)) => {
+                let padding = value
+                    .parse()
+                    .map_err(|e| format!("invalid pad {:?}: {}", value, e))?;
+                self.padding = Some(padding);
+            }
+            ("delay_ms", Some(value)) => self.delay = Some(parse_delay(value)?),
+            ("token", Some(value)) => {
+                if value.is_empty() {
+                    return Err("empty listener token".to_owned());
+                }
+                self.auth_token = Some(AuthToken(value.to_owned()));
+            }
+            _ => return Err(format!("unsupported listener option {:?}", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Parse 'MAX' or 'MIN-MAX' delay range (in milliseconds).
+fn parse_delay(value: &str) -> Result<(Duration, Duration), String> {
+    let (min, max) = value.split_once('-').unwrap_or(("0", value));
+    let parse = |ms: &str| {
+        ms.trim()
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|e| format!("invalid delay_ms {:?}: {}", value, e))
+    };
+    let (min, max) = (parse(min)?, parse(max)?);
+    if min > max {
+        return Err(format!("invalid delay_ms {:?}: empty range", value));
+    }
+    Ok((min, max))
+}
+
+impl FromStr for ListenerConfig {
+    type Err = String;
+
+    /// Parse 'addr:port[;key[=value]...]' listener specification
+    fn from_str(spec: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = spec.split(';').map(str::trim);
+        let addr = parts.next().unwrap_or_default();
+        let addr = ResolvAddr(addr.to_owned())
+            .resolve()
+            .map_err(|e| e.to_string())?;
+        let mut listener = ListenerConfig::new(addr);
+        for option in parts.filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some((key, value)) => listener.set_option(key.trim(), Some(value.trim()))?,
+                None => listener.set_option(option, None)?,
+            }
+        }
+        Ok(listener)
+    }
+}
+
+/// IP address range in CIDR notation (e.g. '10.0.0.0/8' or 'fd00::/8').
+/// A plain IP address matches only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let bytes = usize::from(prefix_len / 8);
+    if net[..bytes] != ip[..bytes] {
+        return false;
+    }
+    let bits = prefix_len % 8;
+    if bits == 0 {
+        return true;
+    }
+    let mask = 0xFFu8 << (8 - bits);
+    (net[bytes] & mask) == (ip[bytes] & mask)
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|e| format!("invalid CIDR {:?}: {}", s, e))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("invalid CIDR {:?}: bad prefix length", s))?,
+            None => max_len,
+        };
+        Ok(IpCidr { addr, prefix_len })
+    }
+}
+
+/// Shared secret, which clients must present (using `server.authenticate`)
+/// before accessing the features restricted to authenticated clients.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthToken(String);
+
+impl AuthToken {
+    /// Compare in constant time (for a given token length).
+    pub(crate) fn matches(&self, token: &str) -> bool {
+        let (expected, actual) = (self.0.as_bytes(), token.as_bytes());
+        expected.len() == actual.len()
+            && expected
+                .iter()
+                .zip(actual)
+                .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+                == 0
+    }
+
+    /// Identifies the token's holders (e.g. for storing their labels), without revealing it.
+    pub(crate) fn identity(&self) -> sha256::Hash {
+        let mut engine = sha256::Hash::engine();
+        engine.input(b"electrs/auth-identity/");
+        engine.input(self.0.as_bytes());
+        sha256::Hash::from_engine(engine)
+    }
+}
+
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<sensitive>")
+    }
+}
+
+/// How blocks are fetched from bitcoind.
+/// Note that the index false positives can't be resolved from the DB, since only transactions'
+/// prefixes are indexed (there is no exact transaction index).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockFetch {
+    #[default]
+    P2p,
+    Rest,
+    Rpc,
+}
+
+impl FromStr for BlockFetch {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "p2p" => Ok(BlockFetch::P2p),
+            "rest" => Ok(BlockFetch::Rest),
+            "rpc" => Ok(BlockFetch::Rpc),
+            _ => Err(format!("unknown block fetch method: {:?}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for BlockFetch {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'p2p', 'rest' or 'rpc'")
+    }
+}
+
 /// This newtype implements `ParseArg` for `Network`.
 #[derive(Deserialize)]
 pub struct BitcoinNetwork(Network);
@@ -121,6 +359,87 @@ impl From<BitcoinNetwork> for Network {
     }
 }
 
+/// Custom chain parameters (see `chain_params_file`), e.g. for private test networks.
+/// The consensus rules and address formats are those of the configured `network`.
+/// There's no silent payments activation height, since silent payments aren't indexed here.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChainParams {
+    genesis_header: String, // hex-encoded
+    magic: Option<String>,  // hex-encoded
+    daemon_rpc_port: Option<u16>,
+    daemon_p2p_port: Option<u16>,
+    electrum_port: Option<u16>,
+    monitoring_port: Option<u16>,
+}
+
+impl ChainParams {
+    fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| e.to_string())?;
+        serde_json::from_slice(&data).map_err(|e| e.to_string())
+    }
+
+    fn genesis(&self) -> Result<BlockHeader, String> {
+        let bytes = Vec::from_hex(&self.genesis_header).map_err(|e| e.to_string())?;
+        deserialize(&bytes).map_err(|e| e.to_string())
+    }
+}
+
+/// The fields returned by `server.features` RPC (including the ones added by this server,
+/// possibly only in some configurations), which can't be replaced by custom ones.
+pub const STANDARD_FEATURES: &[&str] = &[
+    "genesis_hash",
+    "hosts",
+    "protocol_max",
+    "protocol_min",
+    "pruning",
+    "server_version",
+    "hash_function",
+    "limits",
+    "index_script_types",
+    "index_from_height",
+    "draining",
+];
+
+/// Additional `server.features` metadata (see `server_features_file`), so clients can discover
+/// the server's addresses and capabilities.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerFeatures {
+    #[serde(default)]
+    pub hosts: Option<BTreeMap<String, ServerHost>>,
+    #[serde(default)]
+    pub extras: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerHost {
+    #[serde(default)]
+    pub tcp_port: Option<u16>,
+    #[serde(default)]
+    pub ssl_port: Option<u16>,
+}
+
+impl ServerFeatures {
+    fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| e.to_string())?;
+        let features: Self = serde_json::from_slice(&data).map_err(|e| e.to_string())?;
+        features.check()?;
+        Ok(features)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if let Some(name) = STANDARD_FEATURES
+            .iter()
+            .find(|name| self.extras.contains_key(**name))
+        {
+            return Err(format!("'{}' is a standard field", name));
+        }
+        Ok(())
+    }
+}
+
 /// Parsed and post-processed configuration
 #[derive(Debug)]
 pub struct Config {
@@ -128,25 +447,71 @@ pub struct Config {
     pub network: Network,
     pub db_path: PathBuf,
     pub db_log_dir: Option<PathBuf>,
+    pub db_bloom_bits_per_key: u8,
     pub daemon_dir: PathBuf,
     pub daemon_auth: SensitiveAuth,
     pub daemon_rpc_addr: SocketAddr,
     pub daemon_p2p_addr: SocketAddr,
+    pub block_fetch: BlockFetch,
     pub electrum_rpc_addr: SocketAddr,
+    pub electrum_listeners: Vec<ListenerConfig>,
     pub monitoring_addr: SocketAddr,
+    pub admin_access: Option<AdminAccess>,
+    pub http_addr: Option<SocketAddr>,
+    pub metrics_push_url: Option<String>,
+    pub metrics_push_interval: Duration,
     pub wait_duration: Duration,
     pub jsonrpc_timeout: Duration,
+    pub drain_timeout: Duration,
+    pub notify_coalescing: Duration,
     pub index_batch_size: usize,
+    pub index_batch_bytes: Option<usize>,
+    pub index_throttle: Option<u32>,
+    pub profile_sync: bool,
     pub index_lookup_limit: Option<usize>,
+    pub request_timeout: Option<Duration>,
+    pub broadcast_log_size: usize,
+    pub resolve_prevouts: bool,
+    pub index_script_types: Option<Vec<ScriptType>>,
+    pub index_script_prefixes: bool,
+    pub index_coinbase: bool,
+    pub index_feerates: bool,
+    pub index_from_height: usize,
+    pub index_manifest_path: Option<PathBuf>,
+    pub db_min_free_space: Option<u64>,
     pub reindex_last_blocks: usize,
     pub auto_reindex: bool,
+    pub ingest_sst_files: bool,
     pub ignore_mempool: bool,
+    pub mempool_sync_interval: Duration,
+    pub mempool_max_txs: Option<usize>,
+    pub mempool_max_tx_vsize: Option<u64>,
+    pub memory_budget: MemoryBudget,
+    pub labels_max_bytes: Option<usize>,
+    pub scan_cache_bytes: usize,
+    pub warmup_scripthashes: usize,
+    pub warmup_blocks: usize,
+    pub notify_shards: usize,
     pub sync_once: bool,
     pub skip_block_download_wait: bool,
+    pub regtest_testing: bool,
     pub disable_electrum_rpc: bool,
     pub server_banner: String,
+    pub server_donation_address: Option<String>,
+    pub server_features: ServerFeatures,
     pub signet_magic: Magic,
+    pub custom_genesis: Option<BlockHeader>,
     pub args: Vec<String>,
+    pub chains: Vec<Config>, // additional chains, served by this process
+}
+
+/// Custom signets' magic is derived from their challenge script (see BIP-325).
+fn signet_challenge_magic(challenge: &str) -> Result<Magic, String> {
+    let challenge = Vec::<u8>::from_hex(challenge).map_err(|e| e.to_string())?;
+    let hash = sha256d::Hash::hash(&serialize(&challenge));
+    let mut magic = [0u8; 4];
+    magic.copy_from_slice(&hash[..4]);
+    Ok(Magic::from_bytes(magic))
 }
 
 pub struct SensitiveAuth(pub Auth);
@@ -198,7 +563,75 @@ impl Config {
         let (mut config, args) =
             internal::Config::including_optional_config_files(default_config_files())
                 .unwrap_or_exit();
+        if config.verbose > 0 {
+            eprintln!("Error: please use `log_filters` to set logging verbosity",);
+            std::process::exit(1);
+        }
+        let log_filters = config.log_filters.take();
+        let chain_config_files = config.chain_config_files.take();
 
+        let mut config = Self::post_process(config, args.map(|a| a.into_string().unwrap()));
+        config.chains = chain_config_files
+            .iter()
+            .flat_map(|files| files.split(','))
+            .map(str::trim)
+            .filter(|file| !file.is_empty())
+            .map(Self::for_chain)
+            .collect();
+        for (i, chain) in config.chains.iter().enumerate() {
+            let others = std::iter::once(&config).chain(&config.chains[..i]);
+            if others
+                .map(|other| other.network)
+                .any(|n| n == chain.network)
+            {
+                eprintln!(
+                    "Error: network {} is served by more than one chain",
+                    chain.network
+                );
+                std::process::exit(1);
+            }
+        }
+
+        eprintln!(
+            "Starting electrs {} on {} {} with {:?}",
+            ELECTRS_VERSION, ARCH, OS, config
+        );
+        let mut builder = env_logger::Builder::from_default_env();
+        builder.default_format().format_timestamp_millis();
+        if let Some(log_filters) = &log_filters {
+            builder.parse_filters(log_filters);
+        }
+        let logger = builder.build();
+        let max_level = logger.filter();
+        log::set_boxed_logger(Box::new(ErrorLog::new(logger))).expect("failed to set logger");
+        log::set_max_level(max_level);
+
+        config
+    }
+
+    /// Parses an additional chain's config file (see `chain_config_files`).
+    fn for_chain(path: &str) -> Config {
+        use internal::ResultExt;
+
+        if !Path::new(path).is_file() {
+            eprintln!("Error: chain config file {:?} not found", path);
+            std::process::exit(1);
+        }
+        let program = std::iter::once(OsString::from("electrs"));
+        let (config, args) =
+            internal::Config::custom_args_and_optional_files(program, std::iter::once(path))
+                .unwrap_or_exit();
+        if config.chain_config_files.is_some() {
+            eprintln!(
+                "Error: chain config file {:?} can't specify chain_config_files",
+                path
+            );
+            std::process::exit(1);
+        }
+        Self::post_process(config, args.map(|a| a.into_string().unwrap()))
+    }
+
+    fn post_process(mut config: internal::Config, args: impl Iterator<Item = String>) -> Config {
         fn unsupported_network(network: Network) -> ! {
             eprintln!("Error: unsupported network: {}", network);
             std::process::exit(1);
@@ -243,20 +676,68 @@ impl Config {
             unsupported => unsupported_network(unsupported),
         };
 
-        let magic = match (config.network, config.signet_magic) {
-            (Network::Signet, Some(magic)) => magic.parse().unwrap_or_else(|error| {
+        let chain_params = config.chain_params_file.as_ref().map(|path| {
+            ChainParams::load(path).unwrap_or_else(|error| {
+                eprintln!("Error: invalid chain_params_file {:?}: {}", path, error);
+                std::process::exit(1);
+            })
+        });
+        let custom_genesis = chain_params.as_ref().map(|params| {
+            params.genesis().unwrap_or_else(|error| {
+                eprintln!("Error: invalid custom genesis header: {}", error);
+                std::process::exit(1);
+            })
+        });
+        let custom_port = |port: fn(&ChainParams) -> Option<u16>, default: u16| {
+            chain_params.as_ref().and_then(port).unwrap_or(default)
+        };
+        let default_daemon_rpc_port = custom_port(|p| p.daemon_rpc_port, default_daemon_rpc_port);
+        let default_daemon_p2p_port = custom_port(|p| p.daemon_p2p_port, default_daemon_p2p_port);
+        let default_electrum_port = custom_port(|p| p.electrum_port, default_electrum_port);
+        let default_monitoring_port = custom_port(|p| p.monitoring_port, default_monitoring_port);
+
+        let custom_magic = chain_params
+            .as_ref()
+            .and_then(|params| params.magic.as_deref());
+        if custom_magic.is_some()
+            && (config.signet_magic.is_some() || config.signet_challenge.is_some())
+        {
+            eprintln!("Error: custom chain magic can't be combined with signet magic or challenge");
+            std::process::exit(1);
+        }
+        let network_magic = match (config.network, config.signet_magic, config.signet_challenge) {
+            (Network::Signet, Some(magic), None) => magic.parse().unwrap_or_else(|error| {
                 eprintln!(
                     "Error: signet magic '{}' is not a valid hex string: {}",
                     magic, error
                 );
                 std::process::exit(1);
             }),
-            (network, None) => network.magic(),
-            (_, Some(_)) => {
-                eprintln!("Error: signet magic only available on signet");
+            (Network::Signet, None, Some(challenge)) => signet_challenge_magic(&challenge)
+                .unwrap_or_else(|error| {
+                    eprintln!("Error: invalid signet challenge '{}': {}", challenge, error);
+                    std::process::exit(1);
+                }),
+            (Network::Signet, Some(_), Some(_)) => {
+                eprintln!("Error: please specify either signet magic or signet challenge");
+                std::process::exit(1);
+            }
+            (network, None, None) => network.magic(),
+            (_, _, _) => {
+                eprintln!("Error: signet magic and challenge are only available on signet");
                 std::process::exit(1);
             }
         };
+        let magic = match custom_magic {
+            Some(magic) => magic.parse().unwrap_or_else(|error| {
+                eprintln!(
+                    "Error: custom chain magic '{}' is not a valid hex string: {}",
+                    magic, error
+                );
+                std::process::exit(1);
+            }),
+            None => network_magic,
+        };
 
         let daemon_rpc_addr: SocketAddr = config.daemon_rpc_addr.map_or(
             (DEFAULT_SERVER_ADDRESS, default_daemon_rpc_port).into(),
@@ -270,13 +751,61 @@ impl Config {
             (DEFAULT_SERVER_ADDRESS, default_electrum_port).into(),
             ResolvAddr::resolve_or_exit,
         );
+        let electrum_listeners: Vec<ListenerConfig> =
+            std::iter::once(ListenerConfig::new(electrum_rpc_addr))
+                .chain(
+                    config
+                        .electrum_listeners
+                        .iter()
+                        .flat_map(|specs| specs.split(','))
+                        .filter(|spec| !spec.trim().is_empty())
+                        .map(|spec| {
+                            spec.parse().unwrap_or_else(|err| {
+                                eprintln!("Error: invalid Electrum listener {:?}: {}", spec, err);
+                                std::process::exit(1)
+                            })
+                        }),
+                )
+                .collect();
         #[cfg(not(feature = "metrics"))]
         {
             if config.monitoring_addr.is_some() {
                 eprintln!("Error: enable \"metrics\" feature to specify monitoring_addr");
                 std::process::exit(1);
             }
+            if config.metrics_push_url.is_some() {
+                eprintln!("Error: enable \"metrics\" feature to specify metrics_push_url");
+                std::process::exit(1);
+            }
+            if config.admin_api {
+                eprintln!("Error: enable \"metrics\" feature to serve admin_api");
+                std::process::exit(1);
+            }
+        }
+        #[cfg(not(feature = "rest"))]
+        {
+            if config.http_addr.is_some() {
+                eprintln!("Error: enable \"rest\" feature to specify http_addr");
+                std::process::exit(1);
+            }
+        }
+        let http_addr = config.http_addr.map(ResolvAddr::resolve_or_exit);
+        if config.admin_token.is_some() && !config.admin_api {
+            eprintln!("Error: admin_token requires admin_api to be enabled");
+            std::process::exit(1);
         }
+        let admin_access = if config.admin_api {
+            let token = config.admin_token.map(|token| {
+                if token.is_empty() {
+                    eprintln!("Error: empty admin_token");
+                    std::process::exit(1);
+                }
+                AuthToken(token)
+            });
+            Some(AdminAccess::new(token))
+        } else {
+            None
+        };
         let monitoring_addr: SocketAddr = config.monitoring_addr.map_or(
             (DEFAULT_SERVER_ADDRESS, default_monitoring_port).into(),
             ResolvAddr::resolve_or_exit,
@@ -308,17 +837,94 @@ impl Config {
             }
         });
 
-        if config.verbose > 0 {
-            eprintln!("Error: please use `log_filters` to set logging verbosity",);
-            std::process::exit(1);
-        }
-        let log_filters = config.log_filters;
-
         let index_lookup_limit = match config.index_lookup_limit {
             0 => None,
             _ => Some(config.index_lookup_limit),
         };
 
+        let request_timeout = match config.request_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+
+        let index_throttle = match (config.index_throttle, config.low_power) {
+            (0, false) => None,
+            (0, true) => Some(LOW_POWER_INDEX_THROTTLE),
+            (blocks_per_second, _) => Some(blocks_per_second),
+        };
+
+        let mempool_max_txs = match config.mempool_max_txs {
+            0 => None,
+            _ => Some(config.mempool_max_txs),
+        };
+        let mempool_max_tx_vsize = match config.mempool_max_tx_vsize {
+            0 => None,
+            _ => Some(config.mempool_max_tx_vsize),
+        };
+        let memory_budget = MemoryBudget::new(match config.memory_limit {
+            0 => None,
+            mb => Some(mb << 20),
+        });
+        let labels_max_bytes = match config.labels_max_kb {
+            0 => None,
+            kb => Some(kb << 10),
+        };
+
+        let index_script_types = config.index_script_types.as_deref().map(|types| {
+            let mut script_types: Vec<ScriptType> = types
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| match name.parse() {
+                    Ok(ScriptType::OpReturn) => {
+                        eprintln!("Error: unspendable {} outputs are never indexed", name);
+                        std::process::exit(1)
+                    }
+                    Ok(script_type) => script_type,
+                    Err(err) => {
+                        eprintln!("Error: invalid index_script_types: {}", err);
+                        std::process::exit(1)
+                    }
+                })
+                .collect();
+            script_types.sort_unstable();
+            script_types.dedup();
+            if script_types.is_empty() {
+                eprintln!("Error: index_script_types must not be empty");
+                std::process::exit(1);
+            }
+            script_types
+        });
+
+        let server_donation_address = config.server_donation_address.map(|address| {
+            let parsed = address
+                .parse::<bitcoin::Address<bitcoin::address::NetworkUnchecked>>()
+                .map_err(|e| e.to_string())
+                .and_then(|parsed| {
+                    parsed
+                        .require_network(config.network)
+                        .map_err(|e| e.to_string())
+                });
+            if let Err(error) = parsed {
+                eprintln!(
+                    "Error: invalid server_donation_address {:?}: {}",
+                    address, error
+                );
+                std::process::exit(1);
+            }
+            address
+        });
+        let server_features =
+            config
+                .server_features_file
+                .as_ref()
+                .map_or_else(ServerFeatures::default, |path| {
+                    ServerFeatures::load(path).unwrap_or_else(|error| {
+                        eprintln!("Error: invalid server_features_file {:?}: {}", path, error);
+                        std::process::exit(1);
+                    })
+                });
+
         if config.jsonrpc_timeout_secs <= config.wait_duration_secs {
             eprintln!(
                 "Error: jsonrpc_timeout_secs ({}) must be higher than wait_duration_secs ({})",
@@ -327,54 +933,130 @@ impl Config {
             std::process::exit(1);
         }
 
+        if config.regtest_testing && config.network != Network::Regtest {
+            eprintln!("Error: regtest_testing is only available on regtest");
+            std::process::exit(1);
+        }
+        let wait_duration = if config.regtest_testing {
+            REGTEST_TESTING_WAIT_DURATION // notice new blocks and transactions without delay
+        } else {
+            Duration::from_secs(config.wait_duration_secs)
+        };
+
+        let index_batch_bytes = match config.index_batch_mb {
+            0 => None,
+            mb => Some(mb << 20),
+        };
+        let db_min_free_space = match config.db_min_free_space_mb {
+            0 => None,
+            mb => Some(mb << 20),
+        };
+
+        let mempool_sync_interval = if config.regtest_testing {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(config.mempool_sync_interval_secs)
+        };
+
+        if config.metrics_push_interval_secs == 0 {
+            eprintln!("Error: metrics_push_interval_secs must be positive");
+            std::process::exit(1);
+        }
+
         if config.version {
             println!("v{}", ELECTRS_VERSION);
             std::process::exit(0);
         }
 
-        let config = Config {
+        Config {
             network: config.network,
             db_path: config.db_dir,
             db_log_dir: config.db_log_dir,
+            db_bloom_bits_per_key: config.db_bloom_bits_per_key,
             daemon_dir: config.daemon_dir,
             daemon_auth,
             daemon_rpc_addr,
             daemon_p2p_addr,
+            block_fetch: config.block_fetch,
             electrum_rpc_addr,
+            electrum_listeners,
             monitoring_addr,
-            wait_duration: Duration::from_secs(config.wait_duration_secs),
+            admin_access,
+            http_addr,
+            metrics_push_url: config.metrics_push_url,
+            metrics_push_interval: Duration::from_secs(config.metrics_push_interval_secs),
+            wait_duration,
             jsonrpc_timeout: Duration::from_secs(config.jsonrpc_timeout_secs),
+            drain_timeout: Duration::from_secs(config.drain_timeout_secs),
+            notify_coalescing: Duration::from_millis(config.notify_coalescing_ms),
             index_batch_size: config.index_batch_size,
+            index_batch_bytes,
+            index_throttle,
+            profile_sync: config.profile_sync,
             index_lookup_limit,
+            request_timeout,
+            broadcast_log_size: config.broadcast_log_size,
+            resolve_prevouts: config.resolve_prevouts,
+            index_script_types,
+            index_script_prefixes: config.index_script_prefixes,
+            index_coinbase: config.index_coinbase,
+            index_feerates: config.index_feerates,
+            index_from_height: config.index_from_height,
+            index_manifest_path: config.index_manifest_path,
+            db_min_free_space,
             reindex_last_blocks: config.reindex_last_blocks,
             auto_reindex: config.auto_reindex,
+            ingest_sst_files: config.ingest_sst_files,
             ignore_mempool: config.ignore_mempool,
+            mempool_sync_interval,
+            mempool_max_txs,
+            mempool_max_tx_vsize,
+            memory_budget,
+            labels_max_bytes,
+            scan_cache_bytes: config.scan_cache_mb << 20,
+            warmup_scripthashes: config.warmup_scripthashes,
+            warmup_blocks: config.warmup_blocks,
+            notify_shards: config.notify_shards,
             sync_once: config.sync_once,
             skip_block_download_wait: config.skip_block_download_wait,
+            regtest_testing: config.regtest_testing,
             disable_electrum_rpc: config.disable_electrum_rpc,
             server_banner: config.server_banner,
+            server_donation_address,
+            server_features,
             signet_magic: magic,
-            args: args.map(|a| a.into_string().unwrap()).collect(),
-        };
-        eprintln!(
-            "Starting electrs {} on {} {} with {:?}",
-            ELECTRS_VERSION, ARCH, OS, config
-        );
-        let mut builder = env_logger::Builder::from_default_env();
-        builder.default_format().format_timestamp_millis();
-        if let Some(log_filters) = &log_filters {
-            builder.parse_filters(log_filters);
+            custom_genesis,
+            args: args.collect(),
+            chains: vec![],
         }
-        builder.init();
-
-        config
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Auth, SensitiveAuth};
+    use super::{
+        signet_challenge_magic, Auth, BlockFetch, ChainParams, IpCidr, ListenerConfig,
+        SensitiveAuth, ServerFeatures,
+    };
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::Network;
     use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn test_signet_challenge_magic() {
+        // default signet challenge
+        let challenge = "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae";
+        assert_eq!(
+            signet_challenge_magic(challenge).unwrap(),
+            Network::Signet.magic()
+        );
+        assert_ne!(
+            signet_challenge_magic("51").unwrap(), // OP_TRUE
+            Network::Signet.magic()
+        );
+        assert!(signet_challenge_magic("5").is_err());
+    }
 
     #[test]
     fn test_auth_debug() {
@@ -393,4 +1075,159 @@ mod tests {
             "UserPass(\"user\", \"<sensitive>\")"
         );
     }
+
+    #[test]
+    fn test_listener_config() {
+        let listener: ListenerConfig = "127.0.0.1:50001".parse().unwrap();
+        assert_eq!(listener.addr, "127.0.0.1:50001".parse().unwrap());
+        assert_eq!(listener.max_clients, None);
+
+        let listener: ListenerConfig = "0.0.0.0:50011; max_clients=100".parse().unwrap();
+        assert_eq!(listener.addr, "0.0.0.0:50011".parse().unwrap());
+        assert_eq!(listener.max_clients, Some(100));
+        assert!(!listener.proxy_protocol);
+
+        let listener: ListenerConfig = "0.0.0.0:50012;proxy_protocol".parse().unwrap();
+        assert!(listener.proxy_protocol);
+        assert!(!listener.private);
+
+        let listener: ListenerConfig = "127.0.0.1:50013;private;token=secret".parse().unwrap();
+        assert!(listener.private);
+        assert_eq!(listener.padding, None);
+        assert_eq!(listener.delay, None);
+
+        let listener: ListenerConfig = "127.0.0.1:50014;pad=1024;delay_ms=500".parse().unwrap();
+        assert_eq!(listener.padding, Some(1024));
+        let max = Duration::from_millis(500);
+        assert_eq!(listener.delay, Some((Duration::ZERO, max)));
+        let listener: ListenerConfig = "127.0.0.1:50014;delay_ms=100-500".parse().unwrap();
+        assert_eq!(listener.delay, Some((Duration::from_millis(100), max)));
+        assert!("127.0.0.1:50014;delay_ms=500-100"
+            .parse::<ListenerConfig>()
+            .is_err());
+        assert!("127.0.0.1:50014;pad".parse::<ListenerConfig>().is_err());
+
+        assert!("127.0.0.1:50001;max_clients"
+            .parse::<ListenerConfig>()
+            .is_err());
+        assert!("127.0.0.1:50001;max_clients=x"
+            .parse::<ListenerConfig>()
+            .is_err());
+        assert!("127.0.0.1:50001;proxy_protocol=1"
+            .parse::<ListenerConfig>()
+            .is_err());
+        assert!("127.0.0.1:50001;unknown=1"
+            .parse::<ListenerConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_block_fetch() {
+        assert_eq!("p2p".parse(), Ok(BlockFetch::P2p));
+        assert_eq!("rest".parse(), Ok(BlockFetch::Rest));
+        assert_eq!("rpc".parse(), Ok(BlockFetch::Rpc));
+        assert!("REST".parse::<BlockFetch>().is_err());
+        assert_eq!(BlockFetch::default(), BlockFetch::P2p);
+    }
+
+    #[test]
+    fn test_chain_params() {
+        // regtest genesis block header
+        let params: ChainParams = serde_json::from_str(r#"{
+            "genesis_header": "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000",
+            "magic": "0a0b0c0d",
+            "electrum_port": 50123
+        }"#).unwrap();
+        assert_eq!(
+            params.genesis().unwrap(),
+            genesis_block(Network::Regtest).header
+        );
+        assert_eq!(params.magic.as_deref(), Some("0a0b0c0d"));
+        assert_eq!(params.electrum_port, Some(50123));
+        assert_eq!(params.daemon_rpc_port, None);
+
+        let truncated = r#"{"genesis_header": "0100"}"#;
+        let params: ChainParams = serde_json::from_str(truncated).unwrap();
+        assert!(params.genesis().is_err());
+        assert!(serde_json::from_str::<ChainParams>(r#"{"genesis": "00"}"#).is_err());
+    }
+
+    #[test]
+    fn test_server_features() {
+        let features: ServerFeatures = serde_json::from_str(
+            r#"{
+            "hosts": {"example.com": {"ssl_port": 50002}},
+            "extras": {"sp_version": "0.1"}
+        }"#,
+        )
+        .unwrap();
+        assert!(features.check().is_ok());
+        let host = &features.hosts.as_ref().unwrap()["example.com"];
+        assert_eq!((host.tcp_port, host.ssl_port), (None, Some(50002)));
+        assert_eq!(features.extras["sp_version"], "0.1");
+
+        let features: ServerFeatures =
+            serde_json::from_str(r#"{"extras": {"pruning": 1000}}"#).unwrap();
+        assert_eq!(
+            features.check().unwrap_err(),
+            "'pruning' is a standard field"
+        );
+        let features: ServerFeatures =
+            serde_json::from_str(r#"{"extras": {"draining": false}}"#).unwrap();
+        assert_eq!(
+            features.check().unwrap_err(),
+            "'draining' is a standard field"
+        );
+        assert!(serde_json::from_str::<ServerFeatures>(r#"{"banner": "hi"}"#).is_err());
+    }
+
+    #[test]
+    fn test_ip_cidr() {
+        let cidr: IpCidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains("10.1.2.3".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains("10.2.0.1".parse().unwrap()));
+        assert!(!cidr.contains("fd00::1".parse().unwrap()));
+
+        let cidr: IpCidr = "192.168.1.128/25".parse().unwrap();
+        assert!(cidr.contains("192.168.1.200".parse().unwrap()));
+        assert!(!cidr.contains("192.168.1.100".parse().unwrap()));
+
+        let cidr: IpCidr = "fd00::/8".parse().unwrap();
+        assert!(cidr.contains("fd12:3456::1".parse().unwrap()));
+        assert!(!cidr.contains("fe80::1".parse().unwrap()));
+
+        let cidr: IpCidr = "127.0.0.1".parse().unwrap();
+        assert!(cidr.contains("127.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("127.0.0.2".parse().unwrap()));
+
+        assert!("0.0.0.0/0".parse::<IpCidr>().is_ok());
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("localhost/8".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn test_listener_acl() {
+        let listener: ListenerConfig =
+            "0.0.0.0:50001;allow=10.0.0.0/8;allow=192.168.0.0/16;deny=10.0.0.1;token=secret"
+                .parse()
+                .unwrap();
+        assert!(listener.is_allowed("10.1.2.3".parse().unwrap()));
+        assert!(listener.is_allowed("192.168.1.1".parse().unwrap()));
+        assert!(!listener.is_allowed("10.0.0.1".parse().unwrap()));
+        assert!(!listener.is_allowed("8.8.8.8".parse().unwrap()));
+        assert_eq!(format!("{:?}", listener.auth_token), "Some(<sensitive>)");
+
+        let token = listener.auth_token.unwrap();
+        assert!(token.matches("secret"));
+        assert!(!token.matches("secreT"));
+        assert!(!token.matches("secret2"));
+
+        let listener: ListenerConfig = "0.0.0.0:50001;deny=fd00::/8".parse().unwrap();
+        assert!(listener.is_allowed("8.8.8.8".parse().unwrap()));
+        assert!(!listener.is_allowed("fd00::1".parse().unwrap()));
+
+        assert!("0.0.0.0:50001;allow".parse::<ListenerConfig>().is_err());
+        assert!("0.0.0.0:50001;token=".parse::<ListenerConfig>().is_err());
+    }
 }
@@ -0,0 +1,125 @@
+use std::time::Instant;
+
+// Relative weights of the resources consumed by a client (in cost units)
+const UNITS_PER_BLOCK: f64 = 100.0; // fetched via p2p and parsed
+const UNITS_PER_ROW: f64 = 1.0; // scanned from the index
+const UNITS_PER_KB: f64 = 1.0; // returned to the client
+
+// Clients are deprioritized while their usage exceeds the budget
+const BUDGET_UNITS: f64 = 100_000.0;
+const DRAIN_UNITS_PER_SEC: f64 = 10_000.0;
+
+/// Resources consumed while handling client requests.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cost {
+    pub blocks: u64,
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+impl Cost {
+    /// Resources consumed since `start` was taken (both are cumulative counters).
+    pub(crate) fn since(&self, start: &Cost) -> Cost {
+        Cost {
+            blocks: self.blocks.saturating_sub(start.blocks),
+            rows: self.rows.saturating_sub(start.rows),
+            bytes: self.bytes.saturating_sub(start.bytes),
+        }
+    }
+
+    fn units(&self) -> f64 {
+        self.blocks as f64 * UNITS_PER_BLOCK
+            + self.rows as f64 * UNITS_PER_ROW
+            + self.bytes as f64 / 1024.0 * UNITS_PER_KB
+    }
+}
+
+/// Per-client "leaky bucket": each request adds its cost, which drains over time.
+#[derive(Debug)]
+pub(crate) struct Usage {
+    level: f64,
+    updated: Instant,
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self {
+            level: 0.0,
+            updated: Instant::now(),
+        }
+    }
+}
+
+impl Usage {
+    pub(crate) fn charge(&mut self, cost: Cost, now: Instant) {
+        self.level = self.level(now) + cost.units();
+        self.updated = now;
+    }
+
+    /// Current usage (in cost units), used to prioritize lighter clients.
+    pub(crate) fn level(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.level - elapsed * DRAIN_UNITS_PER_SEC).max(0.0)
+    }
+
+    pub(crate) fn is_over_budget(&self, now: Instant) -> bool {
+        self.level(now) > BUDGET_UNITS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cost, Usage, BUDGET_UNITS, DRAIN_UNITS_PER_SEC};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_cost_since() {
+        let start = Cost {
+            blocks: 1,
+            rows: 10,
+            bytes: 100,
+        };
+        let end = Cost {
+            blocks: 3,
+            rows: 15,
+            bytes: 2148,
+        };
+        let cost = end.since(&start);
+        assert_eq!(
+            cost,
+            Cost {
+                blocks: 2,
+                rows: 5,
+                bytes: 2048
+            }
+        );
+        assert_eq!(cost.units(), 207.0);
+    }
+
+    #[test]
+    fn test_usage_drain() {
+        let t0 = Instant::now();
+        let mut usage = Usage {
+            level: 0.0,
+            updated: t0,
+        };
+        let heavy = Cost {
+            blocks: 2000,
+            rows: 0,
+            bytes: 0,
+        };
+        usage.charge(heavy, t0);
+        assert_eq!(usage.level(t0), 200_000.0);
+        assert!(usage.is_over_budget(t0));
+
+        let t1 = t0 + Duration::from_secs(5);
+        assert_eq!(usage.level(t1), 200_000.0 - 5.0 * DRAIN_UNITS_PER_SEC);
+        assert!(usage.level(t1) <= BUDGET_UNITS);
+        assert!(!usage.is_over_budget(t1));
+
+        let t2 = t0 + Duration::from_secs(60);
+        assert_eq!(usage.level(t2), 0.0);
+        usage.charge(Cost::default(), t2);
+        assert_eq!(usage.level(t2), 0.0);
+    }
+}
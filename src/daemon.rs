@@ -1,25 +1,38 @@
 use anyhow::{Context, Result};
 
+use bitcoin::{
+    block::Header as BlockHeader, blockdata::constants::genesis_block, Amount, BlockHash,
+    Transaction, Txid,
+};
 use bitcoin::{consensus::deserialize, hashes::hex::FromHex};
-use bitcoin::{Amount, BlockHash, Transaction, Txid};
 use bitcoincore_rpc::{json, jsonrpc, Auth, Client, RpcApi};
-use crossbeam_channel::Receiver;
+use crossbeam_channel::{bounded, Receiver};
 use parking_lot::Mutex;
+use rayon::prelude::*;
 use serde_json::{json, Value};
 
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use crate::{
+    auxpow,
     chain::{Chain, NewHeader},
-    config::Config,
-    metrics::Metrics,
+    config::{BlockFetch, Config},
+    http::HttpTarget,
+    metrics::{self, Counter, Histogram, Metrics},
     p2p::Connection,
     signals::ExitFlag,
+    singleflight::{Join, SingleFlight},
+    trace::{Deadline, RequestId},
     types::SerBlock,
 };
 
+const PREFETCH_BLOCKS: usize = 10;
+const SLOW_DAEMON_CALL: Duration = Duration::from_secs(1);
+
 enum PollResult {
     Done(Result<()>),
     Retry,
@@ -100,6 +113,14 @@ fn rpc_connect(config: &Config) -> Result<Client> {
 pub struct Daemon {
     p2p: Mutex<Connection>,
     rpc: Client,
+    rest: HttpTarget,
+    rest_timeout: Duration,
+    block_fetch: BlockFetch,
+    blocks_fetched: AtomicU64,
+    block_flights: SingleFlight<BlockHash, SerBlock>,
+    tx_flights: SingleFlight<(Txid, Option<BlockHash>), Transaction>,
+    dedup_hits: Counter,
+    rpc_duration: Histogram,
 }
 
 impl Daemon {
@@ -136,6 +157,18 @@ impl Daemon {
         if info.pruned {
             bail!("electrs requires non-pruned bitcoind node");
         }
+        let genesis_hash = match &config.custom_genesis {
+            Some(genesis) => genesis.block_hash(),
+            None => genesis_block(config.network).block_hash(),
+        };
+        let daemon_genesis_hash = rpc.get_block_hash(0)?;
+        if daemon_genesis_hash != genesis_hash {
+            bail!(
+                "bitcoind genesis block {} doesn't match the configured chain ({})",
+                daemon_genesis_hash,
+                genesis_hash
+            );
+        }
 
         let p2p = Mutex::new(Connection::connect(
             config.network,
@@ -143,43 +176,85 @@ impl Daemon {
             metrics,
             config.signet_magic,
         )?);
-        Ok(Self { p2p, rpc })
+        let dedup_hits = metrics.counter(
+            "daemon_dedup_hits",
+            "# of daemon requests served by an identical in-flight request",
+            "request",
+        );
+        let rpc_duration = metrics.histogram_vec(
+            "daemon_rpc_duration",
+            "Time spent on daemon calls (in seconds)",
+            "method",
+            metrics::default_duration_buckets(),
+        );
+        let rest = HttpTarget::parse(&format!("http://{}", config.daemon_rpc_addr))?;
+        Ok(Self {
+            p2p,
+            rpc,
+            rest,
+            rest_timeout: config.jsonrpc_timeout,
+            block_fetch: config.block_fetch,
+            blocks_fetched: AtomicU64::new(0),
+            block_flights: SingleFlight::new(),
+            tx_flights: SingleFlight::new(),
+            dedup_hits,
+            rpc_duration,
+        })
+    }
+
+    /// Measure a daemon call, and log it with the client request that caused it (if any).
+    fn observe<T>(&self, method: &str, func: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = func();
+        self.observe_elapsed(method, start.elapsed());
+        result
+    }
+
+    fn observe_elapsed(&self, method: &str, elapsed: Duration) {
+        self.rpc_duration.observe(method, elapsed.as_secs_f64());
+        if let Some(request) = RequestId::current() {
+            if elapsed >= SLOW_DAEMON_CALL {
+                info!("{}: slow {} took {:?}", request, method, elapsed);
+            } else {
+                debug!("{}: {} took {:?}", request, method, elapsed);
+            }
+        }
     }
 
     pub(crate) fn estimate_fee(&self, nblocks: u16) -> Result<Option<Amount>> {
         Ok(self
-            .rpc
-            .estimate_smart_fee(nblocks, None)
+            .observe("estimatesmartfee", || {
+                self.rpc.estimate_smart_fee(nblocks, None)
+            })
             .context("failed to estimate fee")?
             .fee_rate)
     }
 
     pub(crate) fn get_relay_fee(&self) -> Result<Amount> {
         Ok(self
-            .rpc
-            .get_network_info()
+            .observe("getnetworkinfo", || self.rpc.get_network_info())
             .context("failed to get relay fee")?
             .relay_fee)
     }
 
-    pub(crate) fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
-        self.rpc
-            .send_raw_transaction(tx)
-            .context("failed to broadcast transaction")
-    }
-
+    /// With `prevouts`, a confirmed transaction's spent outputs are also returned (bitcoind
+    /// resolves them from its undo data, if it is available - since 25.0).
     pub(crate) fn get_transaction_info(
         &self,
         txid: &Txid,
         blockhash: Option<BlockHash>,
+        prevouts: bool,
     ) -> Result<Value> {
+        let verbosity = if prevouts { 2 } else { 1 }; // older versions treat 2 as 1
+
         // No need to parse the resulting JSON, just return it as-is to the client.
-        self.rpc
-            .call(
+        self.observe("getrawtransaction", || {
+            self.rpc.call(
                 "getrawtransaction",
-                &[json!(txid), json!(true), json!(blockhash)],
+                &[json!(txid), json!(verbosity), json!(blockhash)],
             )
-            .context("failed to get transaction info")
+        })
+        .context("failed to get transaction info")
     }
 
     pub(crate) fn get_transaction_hex(
@@ -201,26 +276,229 @@ impl Daemon {
         txid: &Txid,
         blockhash: Option<BlockHash>,
     ) -> Result<Transaction> {
-        self.rpc
-            .get_raw_transaction(txid, blockhash.as_ref())
-            .context("failed to get transaction")
+        let leader = match self.tx_flights.join((*txid, blockhash)) {
+            Join::Leader(leader) => leader,
+            Join::Follower(follower) => {
+                self.dedup_hits.inc("getrawtransaction");
+                return follower.wait().context("failed to get transaction");
+            }
+        };
+        let result = self
+            .observe("getrawtransaction", || {
+                self.rpc.get_raw_transaction(txid, blockhash.as_ref())
+            })
+            .context("failed to get transaction");
+        leader.complete(&result);
+        result
+    }
+
+    /// Fetch a single block via REST or RPC (P2P fetches are batched, see `for_blocks`).
+    fn get_block(&self, blockhash: BlockHash) -> Result<SerBlock> {
+        let block = match self.block_fetch {
+            BlockFetch::Rest => self
+                .observe("rest:block", || {
+                    self.rest
+                        .with_path(&format!("/rest/block/{}.bin", blockhash))
+                        .get(self.rest_timeout)
+                })
+                .with_context(|| format!("failed to get block {} via REST", blockhash))?,
+            BlockFetch::Rpc | BlockFetch::P2p => {
+                let hex = self
+                    .observe("getblock", || self.rpc.get_block_hex(&blockhash))
+                    .with_context(|| format!("failed to get block {}", blockhash))?;
+                Vec::from_hex(&hex).context("non-hex block")?
+            }
+        };
+        let block = auxpow::strip_block(block)?;
+        let header: BlockHeader = deserialize(block.get(..80).context("truncated block")?)
+            .context("invalid block header")?;
+        ensure!(header.block_hash() == blockhash, "got unexpected block");
+        Ok(block)
+    }
+
+    pub(crate) fn get_headers_count(&self) -> Result<usize> {
+        let info = self
+            .rpc
+            .get_blockchain_info()
+            .context("failed to get blockchain info")?;
+        Ok(info.headers as usize)
     }
 
     pub(crate) fn get_block_txids(&self, blockhash: BlockHash) -> Result<Vec<Txid>> {
         Ok(self
-            .rpc
-            .get_block_info(&blockhash)
+            .observe("getblock", || self.rpc.get_block_info(&blockhash))
             .context("failed to get block txids")?
             .tx)
     }
 
-    pub(crate) fn get_mempool_txids(&self) -> Result<Vec<Txid>> {
-        self.rpc
-            .get_raw_mempool()
+    /// Total number of blocks fetched via p2p (used for query cost accounting).
+    pub(crate) fn blocks_fetched(&self) -> u64 {
+        self.blocks_fetched.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn new_block_notification(&self) -> Receiver<()> {
+        self.p2p.lock().new_block_notification()
+    }
+}
+
+/// A source of new headers and their blocks, used for indexing the chain (and for fetching
+/// the blocks relevant to client queries).
+pub(crate) trait BlockSource: Sync {
+    fn get_new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>>;
+
+    /// Blocks may be passed to `func` in a different order than `blockhashes`.
+    fn for_blocks<B, F>(&self, blockhashes: B, func: F) -> Result<()>
+    where
+        B: IntoIterator<Item = BlockHash>,
+        F: FnMut(BlockHash, SerBlock);
+
+    /// Feerate percentiles of the block's transactions (see `FEERATE_PERCENTILES`),
+    /// which require the block's undo data (for the spent outputs' values).
+    fn get_feerate_percentiles(&self, blockhash: BlockHash) -> Result<[u64; 5]>;
+
+    /// Fetch blocks in a background thread (keeping up to `PREFETCH_BLOCKS` of them in memory),
+    /// while the already fetched blocks are processed in parallel by `func`.
+    /// The results are returned in arbitrary order.
+    fn map_blocks<B, F, T>(&self, blockhashes: B, func: F) -> Result<Vec<T>>
+    where
+        B: IntoIterator<Item = BlockHash> + Send,
+        F: Fn(BlockHash, SerBlock) -> T + Sync,
+        T: Send,
+    {
+        let (block_send, block_recv) = bounded::<(BlockHash, SerBlock)>(PREFETCH_BLOCKS);
+        let request = RequestId::current(); // blocks are fetched on behalf of this request
+        let deadline = Deadline::current();
+        std::thread::scope(|scope| {
+            let fetcher = scope.spawn(move || {
+                RequestId::scope(request, || {
+                    Deadline::scope(deadline, || {
+                        self.for_blocks(blockhashes, |blockhash, block| {
+                            // may fail only if the receiver has panicked
+                            let _ = block_send.send((blockhash, block));
+                        })
+                    })
+                })
+            });
+            let results = block_recv
+                .into_iter()
+                .par_bridge()
+                .map(|(blockhash, block)| func(blockhash, block))
+                .collect();
+            fetcher.join().expect("block fetcher panicked")?;
+            Ok(results)
+        })
+    }
+}
+
+/// A source of unconfirmed transactions.
+pub(crate) trait TxSource {
+    fn get_mempool_txids(&self) -> Result<Vec<Txid>>;
+
+    fn get_mempool_entries(
+        &self,
+        txids: &[Txid],
+    ) -> Result<Vec<Result<json::GetMempoolEntryResult>>>;
+
+    fn get_mempool_transactions(&self, txids: &[Txid]) -> Result<Vec<Result<Transaction>>>;
+}
+
+/// Relaying new transactions to the network.
+pub(crate) trait Broadcast {
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid>;
+}
+
+impl BlockSource for Daemon {
+    fn get_new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>> {
+        self.p2p.lock().get_new_headers(chain)
+    }
+
+    /// Blocks which are already being fetched by another thread are waited for (instead of being
+    /// fetched again), and are passed to `func` after the rest of the blocks.
+    fn for_blocks<B, F>(&self, blockhashes: B, mut func: F) -> Result<()>
+    where
+        B: IntoIterator<Item = BlockHash>,
+        F: FnMut(BlockHash, SerBlock),
+    {
+        // the blockhashes may be an index scan, stopped early due to the deadline - so it is
+        // checked after the scan is done (p2p fetches can't be stopped midway anyway)
+        let blockhashes: Vec<BlockHash> = blockhashes.into_iter().collect();
+        Deadline::check()?;
+        let mut leaders = vec![];
+        let mut followers = vec![];
+        for blockhash in blockhashes {
+            match self.block_flights.join(blockhash) {
+                Join::Leader(leader) => leaders.push(leader),
+                Join::Follower(follower) => followers.push((blockhash, follower)),
+            }
+        }
+        let blockhashes: Vec<BlockHash> = leaders.iter().map(|leader| *leader.key()).collect();
+        let mut leaders = leaders.into_iter();
+        {
+            let mut on_block = |blockhash: BlockHash, block: SerBlock| {
+                self.blocks_fetched.fetch_add(1, Ordering::Relaxed);
+                let leader = leaders.next().expect("unexpected block");
+                let result = Ok(block);
+                leader.complete(&result);
+                func(blockhash, result.unwrap())
+            };
+            match self.block_fetch {
+                BlockFetch::P2p => {
+                    let mut p2p = self.p2p.lock();
+                    // only the p2p receive is timed (not the lock wait, nor the callbacks)
+                    let start = Instant::now();
+                    let mut callbacks = Duration::ZERO;
+                    let result = p2p.for_blocks(blockhashes, |blockhash, block| {
+                        let callback_start = Instant::now();
+                        on_block(blockhash, block);
+                        callbacks += callback_start.elapsed();
+                    });
+                    self.observe_elapsed("getdata", start.elapsed().saturating_sub(callbacks));
+                    result?
+                }
+                BlockFetch::Rest | BlockFetch::Rpc => {
+                    for blockhash in blockhashes {
+                        Deadline::check()?;
+                        let block = self.get_block(blockhash)?;
+                        on_block(blockhash, block);
+                    }
+                }
+            }
+        }
+        // must be done after releasing the p2p connection, to allow other leaders to make progress
+        for (blockhash, follower) in followers {
+            self.dedup_hits.inc("getdata");
+            let block = follower
+                .wait()
+                .with_context(|| format!("failed to get block {}", blockhash))?;
+            func(blockhash, block)
+        }
+        Ok(())
+    }
+
+    fn get_feerate_percentiles(&self, blockhash: BlockHash) -> Result<[u64; 5]> {
+        #[derive(Deserialize)]
+        struct BlockStats {
+            feerate_percentiles: [u64; 5],
+        }
+        let stats: BlockStats = self
+            .observe("getblockstats", || {
+                self.rpc.call(
+                    "getblockstats",
+                    &[json!(blockhash), json!(["feerate_percentiles"])],
+                )
+            })
+            .with_context(|| format!("failed to get block {} feerates", blockhash))?;
+        Ok(stats.feerate_percentiles)
+    }
+}
+
+impl TxSource for Daemon {
+    fn get_mempool_txids(&self) -> Result<Vec<Txid>> {
+        self.observe("getrawmempool", || self.rpc.get_raw_mempool())
             .context("failed to get mempool txids")
     }
 
-    pub(crate) fn get_mempool_entries(
+    fn get_mempool_entries(
         &self,
         txids: &[Txid],
     ) -> Result<Vec<Result<json::GetMempoolEntryResult>>> {
@@ -234,7 +512,9 @@ impl Daemon {
             .iter()
             .map(|a| client.build_request("getmempoolentry", a))
             .collect();
-        let res = client.send_batch(&reqs).context("batch request failed")?;
+        let res = self
+            .observe("getmempoolentry:batch", || client.send_batch(&reqs))
+            .context("batch request failed")?;
         debug!("got {} mempool entries", res.len());
         Ok(res
             .into_iter()
@@ -246,10 +526,7 @@ impl Daemon {
             .collect())
     }
 
-    pub(crate) fn get_mempool_transactions(
-        &self,
-        txids: &[Txid],
-    ) -> Result<Vec<Result<Transaction>>> {
+    fn get_mempool_transactions(&self, txids: &[Txid]) -> Result<Vec<Result<Transaction>>> {
         let client = self.rpc.get_jsonrpc_client();
         debug!("getting {} transactions", txids.len());
         let args: Vec<_> = txids
@@ -260,7 +537,9 @@ impl Daemon {
             .iter()
             .map(|a| client.build_request("getrawtransaction", a))
             .collect();
-        let res = client.send_batch(&reqs).context("batch request failed")?;
+        let res = self
+            .observe("getrawtransaction:batch", || client.send_batch(&reqs))
+            .context("batch request failed")?;
         debug!("got {} mempool transactions", res.len());
         Ok(res
             .into_iter()
@@ -274,21 +553,12 @@ impl Daemon {
             })
             .collect())
     }
+}
 
-    pub(crate) fn get_new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>> {
-        self.p2p.lock().get_new_headers(chain)
-    }
-
-    pub(crate) fn for_blocks<B, F>(&self, blockhashes: B, func: F) -> Result<()>
-    where
-        B: IntoIterator<Item = BlockHash>,
-        F: FnMut(BlockHash, SerBlock),
-    {
-        self.p2p.lock().for_blocks(blockhashes, func)
-    }
-
-    pub(crate) fn new_block_notification(&self) -> Receiver<()> {
-        self.p2p.lock().new_block_notification()
+impl Broadcast for Daemon {
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        self.observe("sendrawtransaction", || self.rpc.send_raw_transaction(tx))
+            .context("failed to broadcast transaction")
     }
 }
 
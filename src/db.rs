@@ -1,8 +1,10 @@
 use anyhow::{Context, Result};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
 use electrs_rocksdb as rocksdb;
 
+use std::collections::BTreeMap;
 use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 pub(crate) type Row = Box<[u8]>;
 
@@ -13,6 +15,12 @@ pub(crate) struct WriteBatch {
     pub(crate) funding_rows: Vec<Row>,
     pub(crate) spending_rows: Vec<Row>,
     pub(crate) txid_rows: Vec<Row>,
+    pub(crate) script_prefix_rows: Vec<Row>, // empty, unless prefix index is enabled
+    pub(crate) coinbase_rows: Vec<Row>,      // empty, unless coinbase index is enabled
+    pub(crate) feerates_rows: Vec<Row>,      // empty, unless feerates index is enabled
+    pub(crate) script_stats_row: Row,        // skipped if empty
+    pub(crate) touched_rows: Vec<(Row, Row)>, // keys and values, written after the initial sync
+    pub(crate) touched_end: Row, // older touched rows (below this key) are deleted, if non-empty
 }
 
 impl WriteBatch {
@@ -21,6 +29,9 @@ impl WriteBatch {
         self.funding_rows.sort_unstable();
         self.spending_rows.sort_unstable();
         self.txid_rows.sort_unstable();
+        self.script_prefix_rows.sort_unstable();
+        self.coinbase_rows.sort_unstable();
+        self.feerates_rows.sort_unstable();
     }
 }
 
@@ -28,6 +39,8 @@ impl WriteBatch {
 pub struct DBStore {
     db: rocksdb::DB,
     bulk_import: AtomicBool,
+    ingest_sst_files: bool, // used for indexed rows during the initial bulk import
+    ingested_files: AtomicU64,
 }
 
 const CONFIG_CF: &str = "config";
@@ -35,11 +48,53 @@ const HEADERS_CF: &str = "headers";
 const TXID_CF: &str = "txid";
 const FUNDING_CF: &str = "funding";
 const SPENDING_CF: &str = "spending";
+const SCRIPT_PREFIX_CF: &str = "script_prefix";
+const COINBASE_CF: &str = "coinbase";
+const FEERATES_CF: &str = "feerates";
+const REORGS_CF: &str = "reorgs";
+const TOUCHED_CF: &str = "touched";
+const BROADCASTS_CF: &str = "broadcasts";
 
-const COLUMN_FAMILIES: &[&str] = &[CONFIG_CF, HEADERS_CF, TXID_CF, FUNDING_CF, SPENDING_CF];
+const COLUMN_FAMILIES: &[&str] = &[
+    CONFIG_CF,
+    HEADERS_CF,
+    TXID_CF,
+    FUNDING_CF,
+    SPENDING_CF,
+    SCRIPT_PREFIX_CF,
+    COINBASE_CF,
+    FEERATES_CF,
+    REORGS_CF,
+    TOUCHED_CF,
+    BROADCASTS_CF,
+];
+// scanned by their 8-byte prefix (see `iter_prefix_cf`), so prefix bloom filters can skip most SST files
+const PREFIX_FILTERED_COLUMN_FAMILIES: &[&str] = &[TXID_CF, FUNDING_CF, SPENDING_CF];
+// config, reorgs, touched and broadcasts CFs are skipped, since they contain local state (e.g. the
+// persisted mempool, or the blocks indexed after the initial sync)
+const DIGEST_COLUMN_FAMILIES: &[&str] = &[
+    HEADERS_CF,
+    TXID_CF,
+    FUNDING_CF,
+    SPENDING_CF,
+    SCRIPT_PREFIX_CF,
+    COINBASE_CF,
+    FEERATES_CF,
+];
+
+const INGEST_DIR: &str = "ingest"; // temporary SST files (moved into the DB when ingested)
 
 const CONFIG_KEY: &str = "C";
 const TIP_KEY: &[u8] = b"T";
+const SCRIPT_STATS_KEY: &[u8] = b"S";
+const MEMPOOL_KEY: &[u8] = b"M";
+const TUNING_KEY: &[u8] = b"P";
+const BANS_KEY: &[u8] = b"B";
+const WEBHOOKS_KEY: &[u8] = b"W";
+const WARMUP_KEY: &[u8] = b"A";
+const HEADERS_SNAPSHOT_KEY: &[u8] = b"H";
+const WATCHES_KEY: &[u8] = b"O";
+const LABELS_PREFIX: &[u8] = b"L"; // followed by the authentication identity
 
 // Taken from https://github.com/facebook/rocksdb/blob/master/include/rocksdb/db.h#L654-L689
 const DB_PROPERIES: &[&str] = &[
@@ -78,10 +133,37 @@ const DB_PROPERIES: &[&str] = &[
     "rocksdb.block-cache-pinned-usage",
 ];
 
-#[derive(Debug, Deserialize, Serialize)]
+// RocksDB default `num_levels`
+const LEVELS: usize = 7;
+
+/// Content summary of a column family, allowing to compare DBs built on different machines.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Digest {
+    rows: u64,
+    sha256: sha256::Hash, // over all (length-prefixed) keys and values, in DB order
+}
+
+/// Resources used by a column family (as estimated by RocksDB).
+#[derive(Debug, Serialize)]
+pub(crate) struct Usage {
+    pub(crate) disk_bytes: u64, // live SST files
+    pub(crate) memtable_bytes: u64,
+    pub(crate) keys: u64,
+    pub(crate) bytes_per_block: u64, // average disk growth rate (over the indexed chain)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct Config {
     compacted: bool,
     format: u64,
+    #[serde(default)]
+    script_types: Option<Vec<String>>, // indexed funding script types (`None` means all)
+    #[serde(flatten)]
+    optional: OptionalIndexes,
+    #[serde(default)]
+    network: Option<String>, // recorded since custom signets share the same genesis block
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    compacted_cfs: Vec<String>, // the initial full compaction's progress (if interrupted)
 }
 
 const CURRENT_FORMAT: u64 = 0;
@@ -91,13 +173,90 @@ impl Default for Config {
         Config {
             compacted: false,
             format: CURRENT_FORMAT,
+            script_types: None,
+            optional: OptionalIndexes::default(),
+            network: None,
+            compacted_cfs: vec![],
+        }
+    }
+}
+
+/// Indexes which are maintained only if enabled by configuration (due to their size).
+/// The funding and spending indexes may also be limited to the recent blocks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct OptionalIndexes {
+    #[serde(default)]
+    pub(crate) script_prefixes: bool,
+    #[serde(default)]
+    pub(crate) coinbase: bool,
+    #[serde(default)]
+    pub(crate) feerates: bool,
+    #[serde(default, rename = "index_from_height")]
+    pub(crate) from_height: usize, // funding and spending rows are indexed only from this height
+}
+
+impl OptionalIndexes {
+    fn describe(&self) -> String {
+        let mut names: Vec<String> = [
+            ("script_prefixes", self.script_prefixes),
+            ("coinbase", self.coinbase),
+            ("feerates", self.feerates),
+        ]
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| name.to_string())
+        .collect();
+        if self.from_height > 0 {
+            names.push(format!("from_height={}", self.from_height));
+        }
+        if names.is_empty() {
+            "none".to_owned()
+        } else {
+            format!("[{}]", names.join(","))
         }
     }
 }
 
-fn default_opts() -> rocksdb::Options {
+fn describe_script_types(script_types: &Option<Vec<String>>) -> String {
+    match script_types {
+        Some(script_types) => format!("[{}]", script_types.join(",")),
+        None => "all".to_owned(),
+    }
+}
+
+/// RocksDB settings which don't affect the DB contents (so they can be changed without re-indexing).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DBTuning {
+    pub block_cache_size: Option<usize>, // in bytes (if set)
+    pub bloom_bits_per_key: u8,          // 0 - disable the prefix bloom filters
+}
+
+pub const DEFAULT_BLOOM_BITS_PER_KEY: u8 = 10; // ~1% false positive rate
+
+impl Default for DBTuning {
+    fn default() -> Self {
+        Self {
+            block_cache_size: None,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+        }
+    }
+}
+
+fn default_opts(block_cache: Option<&rocksdb::Cache>) -> rocksdb::Options {
+    cf_opts(block_cache, 0)
+}
+
+fn cf_opts(block_cache: Option<&rocksdb::Cache>, bloom_bits_per_key: u8) -> rocksdb::Options {
     let mut block_opts = rocksdb::BlockBasedOptions::default();
     block_opts.set_checksum_type(rocksdb::ChecksumType::CRC32c);
+    if let Some(cache) = block_cache {
+        block_opts.set_block_cache(cache); // shared by all column families
+    }
+    if bloom_bits_per_key > 0 {
+        // lookups are prefix scans, so there is no need to filter by the whole key
+        block_opts.set_bloom_filter(bloom_bits_per_key.into(), false);
+        block_opts.set_whole_key_filtering(false);
+    }
 
     let mut opts = rocksdb::Options::default();
     opts.set_keep_log_file_num(10);
@@ -113,23 +272,58 @@ fn default_opts() -> rocksdb::Options {
     opts
 }
 
+/// The smallest key following all the keys starting with `prefix` (if any).
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let end = prefix.iter().rposition(|&b| b != 0xff)?;
+    let mut bound = prefix[..=end].to_vec();
+    bound[end] += 1;
+    Some(bound)
+}
+
+/// Stop the scan at the end of the `prefix` range, without reading the following keys.
+/// Without such bound, `prefix` is made of 0xFF bytes (so all the following keys start with it).
+fn prefix_read_opts(prefix: &[u8]) -> rocksdb::ReadOptions {
+    let mut opts = rocksdb::ReadOptions::default();
+    if let Some(bound) = prefix_upper_bound(prefix) {
+        opts.set_iterate_upper_bound(bound);
+    }
+    opts
+}
+
 impl DBStore {
-    fn create_cf_descriptors() -> Vec<rocksdb::ColumnFamilyDescriptor> {
+    fn create_cf_descriptors(
+        block_cache: Option<&rocksdb::Cache>,
+        bloom_bits_per_key: u8,
+    ) -> Vec<rocksdb::ColumnFamilyDescriptor> {
         COLUMN_FAMILIES
             .iter()
-            .map(|&name| rocksdb::ColumnFamilyDescriptor::new(name, default_opts()))
+            .map(|&name| {
+                let opts = if PREFIX_FILTERED_COLUMN_FAMILIES.contains(&name) {
+                    cf_opts(block_cache, bloom_bits_per_key)
+                } else {
+                    default_opts(block_cache)
+                };
+                rocksdb::ColumnFamilyDescriptor::new(name, opts)
+            })
             .collect()
     }
 
-    fn open_internal(path: &Path, log_dir: Option<&Path>) -> Result<Self> {
-        let mut db_opts = default_opts();
+    fn open_internal(path: &Path, log_dir: Option<&Path>, tuning: DBTuning) -> Result<Self> {
+        let block_cache = tuning
+            .block_cache_size
+            .map(rocksdb::Cache::new_lru_cache)
+            .transpose()
+            .context("failed to create DB block cache")?;
+        let mut db_opts = default_opts(block_cache.as_ref());
         db_opts.create_if_missing(true);
         db_opts.create_missing_column_families(true);
         if let Some(d) = log_dir {
             db_opts.set_db_log_dir(d);
         }
 
-        let db = rocksdb::DB::open_cf_descriptors(&db_opts, path, Self::create_cf_descriptors())
+        let cf_descriptors =
+            Self::create_cf_descriptors(block_cache.as_ref(), tuning.bloom_bits_per_key);
+        let db = rocksdb::DB::open_cf_descriptors(&db_opts, path, cf_descriptors)
             .with_context(|| format!("failed to open DB: {}", path.display()))?;
         let live_files = db.live_files()?;
         info!(
@@ -142,6 +336,8 @@ impl DBStore {
         let store = DBStore {
             db,
             bulk_import: AtomicBool::new(true),
+            ingest_sst_files: false,
+            ingested_files: AtomicU64::new(0),
         };
         Ok(store)
     }
@@ -155,11 +351,39 @@ impl DBStore {
     }
 
     /// Opens a new RocksDB at the specified location.
-    pub fn open(path: &Path, log_dir: Option<&Path>, auto_reindex: bool) -> Result<Self> {
-        let mut store = Self::open_internal(path, log_dir)?;
+    /// The funding index may be restricted to specific `script_types` (by their names).
+    /// The `optional` indexes are maintained only if enabled.
+    /// A DB created for a different `network` is never re-indexed automatically.
+    /// RocksDB block cache and bloom filters are configured by `tuning`.
+    pub fn open(
+        path: &Path,
+        log_dir: Option<&Path>,
+        auto_reindex: bool,
+        script_types: Option<Vec<String>>,
+        optional: OptionalIndexes,
+        network: &str,
+        tuning: DBTuning,
+    ) -> Result<Self> {
+        let mut store = Self::open_internal(path, log_dir, tuning)?;
         let config = store.get_config();
         debug!("DB {:?}", config);
-        let mut config = config.unwrap_or_default(); // use default config when DB is empty
+        let new_config = || Config {
+            script_types: script_types.clone(),
+            optional,
+            network: Some(network.to_owned()),
+            ..Default::default()
+        };
+        let mut config = config.unwrap_or_else(new_config); // use default config when DB is empty
+        match &config.network {
+            Some(db_network) => ensure!(
+                db_network == network,
+                "DB {} was created for {} network (instead of {})",
+                path.display(),
+                db_network,
+                network
+            ),
+            None => config.network = Some(network.to_owned()), // created by an older version
+        }
 
         let reindex_cause = if store.is_legacy_format() {
             Some("legacy format".to_owned())
@@ -168,6 +392,18 @@ impl DBStore {
                 "unsupported format {} != {}",
                 config.format, CURRENT_FORMAT
             ))
+        } else if config.script_types != script_types {
+            Some(format!(
+                "different indexed script types {} != {}",
+                describe_script_types(&config.script_types),
+                describe_script_types(&script_types)
+            ))
+        } else if config.optional != optional {
+            Some(format!(
+                "different optional indexes {} != {}",
+                config.optional.describe(),
+                optional.describe()
+            ))
         } else {
             None
         };
@@ -182,14 +418,14 @@ impl DBStore {
             );
             // close DB before deletion
             drop(store);
-            rocksdb::DB::destroy(&default_opts(), path).with_context(|| {
+            rocksdb::DB::destroy(&default_opts(None), path).with_context(|| {
                 format!(
                     "re-index required but the old database ({}) can not be deleted",
                     path.display()
                 )
             })?;
-            store = Self::open_internal(path, log_dir)?;
-            config = Config::default(); // re-init config after dropping DB
+            store = Self::open_internal(path, log_dir, tuning)?;
+            config = new_config(); // re-init config after dropping DB
         }
         if config.compacted {
             store.start_compactions();
@@ -198,6 +434,15 @@ impl DBStore {
         Ok(store)
     }
 
+    pub(crate) fn path(&self) -> &Path {
+        self.db.path()
+    }
+
+    /// Whether the initial sync (and its full compaction) is not over yet.
+    pub(crate) fn is_bulk_import(&self) -> bool {
+        self.bulk_import.load(Ordering::Relaxed)
+    }
+
     fn config_cf(&self) -> &rocksdb::ColumnFamily {
         self.db.cf_handle(CONFIG_CF).expect("missing CONFIG_CF")
     }
@@ -218,6 +463,12 @@ impl DBStore {
         self.db.cf_handle(HEADERS_CF).expect("missing HEADERS_CF")
     }
 
+    fn broadcasts_cf(&self) -> &rocksdb::ColumnFamily {
+        self.db
+            .cf_handle(BROADCASTS_CF)
+            .expect("missing BROADCASTS_CF")
+    }
+
     pub(crate) fn iter_funding(&self, prefix: Row) -> impl Iterator<Item = Row> + '_ {
         self.iter_prefix_cf(self.funding_cf(), prefix)
     }
@@ -230,19 +481,121 @@ impl DBStore {
         self.iter_prefix_cf(self.txid_cf(), prefix)
     }
 
+    /// Unlike the other scans, `prefix` may be shorter than the CF prefix extractor's length
+    /// (e.g. a single witness version byte), so the scan is done in total order.
+    pub(crate) fn iter_script_prefix(&self, prefix: Row) -> impl Iterator<Item = Row> + '_ {
+        let cf = self
+            .db
+            .cf_handle(SCRIPT_PREFIX_CF)
+            .expect("missing SCRIPT_PREFIX_CF");
+        let mode = rocksdb::IteratorMode::From(&prefix, rocksdb::Direction::Forward);
+        let mut opts = prefix_read_opts(&prefix);
+        opts.set_total_order_seek(true);
+        self.db
+            .iterator_cf_opt(cf, opts, mode)
+            .map(|row| row.expect("script prefix iterator failed").0)
+    }
+
     fn iter_prefix_cf(
         &self,
         cf: &rocksdb::ColumnFamily,
         prefix: Row,
     ) -> impl Iterator<Item = Row> + '_ {
         let mode = rocksdb::IteratorMode::From(&prefix, rocksdb::Direction::Forward);
-        let mut opts = rocksdb::ReadOptions::default();
-        opts.set_prefix_same_as_start(true); // requires .set_prefix_extractor() above.
+        let mut opts = prefix_read_opts(&prefix);
+        opts.set_prefix_same_as_start(true); // uses the bloom filters (see `cf_opts()`)
         self.db
             .iterator_cf_opt(cf, opts, mode)
             .map(|row| row.expect("prefix iterator failed").0) // values are empty in prefix-scanned CFs
     }
 
+    pub(crate) fn iter_coinbase(&self, start: Row) -> impl Iterator<Item = Row> + '_ {
+        self.iter_by_height_cf(COINBASE_CF, start)
+    }
+
+    pub(crate) fn iter_feerates(&self, start: Row) -> impl Iterator<Item = Row> + '_ {
+        self.iter_by_height_cf(FEERATES_CF, start)
+    }
+
+    /// Per-block rows are ordered by height, starting from `start` (an encoded height).
+    fn iter_by_height_cf(&self, name: &str, start: Row) -> impl Iterator<Item = Row> + '_ {
+        let cf = self.db.cf_handle(name).expect("missing CF");
+        let mode = rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward);
+        let mut opts = rocksdb::ReadOptions::default();
+        opts.set_total_order_seek(true);
+        self.db
+            .iterator_cf_opt(cf, opts, mode)
+            .map(|row| row.expect("height iterator failed").0)
+    }
+
+    /// The touched rows' keys and values, ordered by height (starting from `start`).
+    pub(crate) fn iter_touched(&self, start: Row) -> impl Iterator<Item = (Row, Row)> + '_ {
+        let cf = self.db.cf_handle(TOUCHED_CF).expect("missing TOUCHED_CF");
+        let mode = rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward);
+        let mut opts = rocksdb::ReadOptions::default();
+        opts.set_total_order_seek(true);
+        self.db
+            .iterator_cf_opt(cf, opts, mode)
+            .map(|row| row.expect("touched iterator failed"))
+    }
+
+    /// The values of the reorgs' log, ordered by their (sequence number) keys.
+    pub(crate) fn read_reorgs(&self) -> Vec<Box<[u8]>> {
+        let cf = self.db.cf_handle(REORGS_CF).expect("missing REORGS_CF");
+        let mut opts = rocksdb::ReadOptions::default();
+        opts.set_total_order_seek(true);
+        self.db
+            .iterator_cf_opt(cf, opts, rocksdb::IteratorMode::Start)
+            .map(|row| row.expect("reorgs iterator failed").1)
+            .collect()
+    }
+
+    pub(crate) fn add_reorg(&self, seq: u64, value: &[u8]) {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        opts.disable_wal(false);
+        let cf = self.db.cf_handle(REORGS_CF).expect("missing REORGS_CF");
+        self.db
+            .put_cf_opt(cf, seq.to_be_bytes(), value, &opts)
+            .expect("add_reorg failed");
+    }
+
+    /// The broadcasts' log entries, ordered by their sequence numbers.
+    pub(crate) fn read_broadcasts(&self) -> Vec<(u64, Box<[u8]>)> {
+        let cf = self.broadcasts_cf();
+        let mut opts = rocksdb::ReadOptions::default();
+        opts.set_total_order_seek(true);
+        self.db
+            .iterator_cf_opt(cf, opts, rocksdb::IteratorMode::Start)
+            .map(|row| {
+                let (key, value) = row.expect("broadcasts iterator failed");
+                let seq = u64::from_be_bytes(key[..].try_into().expect("invalid broadcast key"));
+                (seq, value)
+            })
+            .collect()
+    }
+
+    pub(crate) fn last_broadcast_seq(&self) -> Option<u64> {
+        let mut opts = rocksdb::ReadOptions::default();
+        opts.set_total_order_seek(true);
+        self.db
+            .iterator_cf_opt(self.broadcasts_cf(), opts, rocksdb::IteratorMode::End)
+            .next()
+            .map(|row| {
+                let (key, _value) = row.expect("broadcasts iterator failed");
+                u64::from_be_bytes(key[..].try_into().expect("invalid broadcast key"))
+            })
+    }
+
+    /// Older entries (before `first_kept`) are deleted, to bound the log's size.
+    pub(crate) fn add_broadcast(&self, seq: u64, value: &[u8], first_kept: u64) {
+        let cf = self.broadcasts_cf();
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.delete_range_cf(cf, &[][..], &first_kept.to_be_bytes()[..]);
+        batch.put_cf(cf, seq.to_be_bytes(), value);
+        self.db.write(batch).expect("add_broadcast failed");
+    }
+
     pub(crate) fn read_headers(&self) -> Vec<Row> {
         let mut opts = rocksdb::ReadOptions::default();
         opts.fill_cache(false);
@@ -259,30 +612,239 @@ impl DBStore {
             .expect("get_tip failed")
     }
 
+    pub(crate) fn get_script_stats(&self) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.config_cf(), SCRIPT_STATS_KEY)
+            .expect("get_script_stats failed")
+    }
+
+    pub(crate) fn get_mempool(&self) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.config_cf(), MEMPOOL_KEY)
+            .expect("get_mempool failed")
+    }
+
+    pub(crate) fn set_mempool(&self, value: &[u8]) {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        opts.disable_wal(false);
+        self.db
+            .put_cf_opt(self.config_cf(), MEMPOOL_KEY, value, &opts)
+            .expect("set_mempool failed");
+    }
+
+    pub(crate) fn get_headers_snapshot(&self) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.config_cf(), HEADERS_SNAPSHOT_KEY)
+            .expect("get_headers_snapshot failed")
+    }
+
+    pub(crate) fn set_headers_snapshot(&self, value: &[u8]) {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        opts.disable_wal(false);
+        self.db
+            .put_cf_opt(self.config_cf(), HEADERS_SNAPSHOT_KEY, value, &opts)
+            .expect("set_headers_snapshot failed");
+    }
+
+    pub(crate) fn get_tuning(&self) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.config_cf(), TUNING_KEY)
+            .expect("get_tuning failed")
+    }
+
+    pub(crate) fn set_tuning(&self, value: &[u8]) {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        opts.disable_wal(false);
+        self.db
+            .put_cf_opt(self.config_cf(), TUNING_KEY, value, &opts)
+            .expect("set_tuning failed");
+    }
+
+    pub(crate) fn get_bans(&self) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.config_cf(), BANS_KEY)
+            .expect("get_bans failed")
+    }
+
+    pub(crate) fn set_bans(&self, value: &[u8]) {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        opts.disable_wal(false);
+        self.db
+            .put_cf_opt(self.config_cf(), BANS_KEY, value, &opts)
+            .expect("set_bans failed");
+    }
+
+    pub(crate) fn get_watches(&self) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.config_cf(), WATCHES_KEY)
+            .expect("get_watches failed")
+    }
+
+    pub(crate) fn set_watches(&self, value: &[u8]) {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        opts.disable_wal(false);
+        self.db
+            .put_cf_opt(self.config_cf(), WATCHES_KEY, value, &opts)
+            .expect("set_watches failed");
+    }
+
+    pub(crate) fn get_webhooks(&self) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.config_cf(), WEBHOOKS_KEY)
+            .expect("get_webhooks failed")
+    }
+
+    pub(crate) fn set_webhooks(&self, value: &[u8]) {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        opts.disable_wal(false);
+        self.db
+            .put_cf_opt(self.config_cf(), WEBHOOKS_KEY, value, &opts)
+            .expect("set_webhooks failed");
+    }
+
+    pub(crate) fn get_warmup(&self) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.config_cf(), WARMUP_KEY)
+            .expect("get_warmup failed")
+    }
+
+    pub(crate) fn set_warmup(&self, value: &[u8]) {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        opts.disable_wal(false);
+        self.db
+            .put_cf_opt(self.config_cf(), WARMUP_KEY, value, &opts)
+            .expect("set_warmup failed");
+    }
+
+    pub(crate) fn get_labels(&self, identity: &[u8]) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.config_cf(), [LABELS_PREFIX, identity].concat())
+            .expect("get_labels failed")
+    }
+
+    /// Remove the labels, if `value` is `None`.
+    pub(crate) fn set_labels(&self, identity: &[u8], value: Option<&[u8]>) {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        opts.disable_wal(false);
+        let key = [LABELS_PREFIX, identity].concat();
+        let result = match value {
+            Some(value) => self.db.put_cf_opt(self.config_cf(), key, value, &opts),
+            None => self.db.delete_cf_opt(self.config_cf(), key, &opts),
+        };
+        result.expect("set_labels failed");
+    }
+
     pub(crate) fn write(&self, batch: &WriteBatch) {
+        self.write_opt(batch, true)
+    }
+
+    /// Skip syncing the WAL, so a group of batches can be committed by syncing only the last one
+    /// (since syncing the WAL also makes all the preceding writes durable).
+    pub(crate) fn write_unsynced(&self, batch: &WriteBatch) {
+        self.write_opt(batch, false)
+    }
+
+    /// Write the indexed rows into SST files during the initial bulk import, and ingest them
+    /// directly into the DB (skipping the memtables).
+    pub(crate) fn set_ingest_sst_files(&mut self, ingest_sst_files: bool) -> Result<()> {
+        let dir = self.db.path().join(INGEST_DIR);
+        if dir.exists() {
+            // may be left over after a crash
+            std::fs::remove_dir_all(&dir)
+                .with_context(|| format!("failed to remove {}", dir.display()))?;
+        }
+        self.ingest_sst_files = ingest_sst_files;
+        Ok(())
+    }
+
+    fn write_opt(&self, batch: &WriteBatch, sync: bool) {
+        // during the initial bulk import, the WAL is disabled (since the DB is flushed when done)
+        let bulk_import = self.bulk_import.load(Ordering::Relaxed);
         let mut db_batch = rocksdb::WriteBatch::default();
-        for key in &batch.funding_rows {
-            db_batch.put_cf(self.funding_cf(), key, b"");
+        let rows = [
+            (FUNDING_CF, &batch.funding_rows),
+            (SPENDING_CF, &batch.spending_rows),
+            (TXID_CF, &batch.txid_rows),
+            (SCRIPT_PREFIX_CF, &batch.script_prefix_rows),
+            (COINBASE_CF, &batch.coinbase_rows),
+            (FEERATES_CF, &batch.feerates_rows),
+        ];
+        for (name, keys) in rows {
+            let cf = self.db.cf_handle(name).expect("missing CF");
+            if bulk_import && self.ingest_sst_files {
+                self.ingest(cf, name, keys)
+                    .unwrap_or_else(|e| panic!("failed to ingest {} rows: {:#}", name, e));
+                continue;
+            }
+            for key in keys {
+                db_batch.put_cf(cf, key, b"");
+            }
         }
-        for key in &batch.spending_rows {
-            db_batch.put_cf(self.spending_cf(), key, b"");
+        let touched_cf = self.db.cf_handle(TOUCHED_CF).expect("missing TOUCHED_CF");
+        if !batch.touched_end.is_empty() {
+            db_batch.delete_range_cf(touched_cf, &[][..], &batch.touched_end[..]);
         }
-        for key in &batch.txid_rows {
-            db_batch.put_cf(self.txid_cf(), key, b"");
+        for (key, value) in &batch.touched_rows {
+            db_batch.put_cf(touched_cf, key, value);
         }
         for key in &batch.header_rows {
             db_batch.put_cf(self.headers_cf(), key, b"");
         }
         db_batch.put_cf(self.headers_cf(), TIP_KEY, &batch.tip_row);
+        if !batch.script_stats_row.is_empty() {
+            db_batch.put_cf(self.config_cf(), SCRIPT_STATS_KEY, &batch.script_stats_row);
+        }
 
         let mut opts = rocksdb::WriteOptions::new();
-        let bulk_import = self.bulk_import.load(Ordering::Relaxed);
-        opts.set_sync(!bulk_import);
+        opts.set_sync(sync && !bulk_import);
         opts.disable_wal(bulk_import);
         self.db.write_opt(db_batch, &opts).unwrap();
     }
 
+    // `keys` must be sorted (duplicates are skipped)
+    fn ingest(&self, cf: &rocksdb::ColumnFamily, name: &str, keys: &[Row]) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(()); // empty SST files can't be created
+        }
+        let dir = self.db.path().join(INGEST_DIR);
+        std::fs::create_dir_all(&dir)?;
+        let id = self.ingested_files.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("{}-{}.sst", name, id));
+
+        let opts = default_opts(None);
+        let mut writer = rocksdb::SstFileWriter::create(&opts);
+        writer.open(&path)?;
+        let mut prev: Option<&Row> = None;
+        for key in keys {
+            if prev != Some(key) {
+                writer.put(key, b"")?;
+            }
+            prev = Some(key);
+        }
+        writer.finish()?;
+
+        let mut ingest_opts = rocksdb::IngestExternalFileOptions::default();
+        ingest_opts.set_move_files(true);
+        self.db
+            .ingest_external_file_cf_opts(cf, &ingest_opts, vec![&path])?;
+        Ok(())
+    }
+
     pub(crate) fn flush(&self) {
+        self.flush_observed(|_done, _total| ())
+    }
+
+    /// `on_compacted` is called with the initial full compaction's progress
+    /// (# of compacted column families, out of their total count).
+    pub(crate) fn flush_observed(&self, on_compacted: impl FnMut(usize, usize)) {
         debug!("flushing DB column families");
         let mut config = self.get_config().unwrap_or_default();
         for name in COLUMN_FAMILIES {
@@ -290,14 +852,9 @@ impl DBStore {
             self.db.flush_cf(cf).expect("CF flush failed");
         }
         if !config.compacted {
-            for name in COLUMN_FAMILIES {
-                info!("starting {} compaction", name);
-                let cf = self.db.cf_handle(name).expect("missing CF");
-                self.db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
-            }
+            self.full_compaction(&mut config, on_compacted);
             config.compacted = true;
             self.set_config(config);
-            info!("finished full compaction");
             self.start_compactions();
         }
         if log_enabled!(log::Level::Trace) {
@@ -310,6 +867,123 @@ impl DBStore {
         }
     }
 
+    /// Compact all column families (may take a while for large DBs).
+    /// `on_compacted` is called with its progress, as in `flush_observed()`.
+    pub(crate) fn compact(&self, mut on_compacted: impl FnMut(usize, usize)) {
+        let total = COLUMN_FAMILIES.len();
+        for (i, name) in COLUMN_FAMILIES.iter().enumerate() {
+            self.compact_cf(name);
+            on_compacted(i + 1, total);
+        }
+        info!("finished full compaction");
+    }
+
+    fn compact_cf(&self, name: &str) {
+        info!("starting {} compaction", name);
+        let cf = self.db.cf_handle(name).expect("missing CF");
+        self.db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
+    }
+
+    /// The initial compaction may take a few hours, so its progress is persisted after each
+    /// column family - allowing a restart to skip the already compacted ones. Rows written
+    /// since (e.g. new blocks) are handled later by the automatic compactions.
+    fn full_compaction(&self, config: &mut Config, mut on_compacted: impl FnMut(usize, usize)) {
+        let total = COLUMN_FAMILIES.len();
+        for name in COLUMN_FAMILIES {
+            if config.compacted_cfs.iter().any(|cf| cf == name) {
+                info!("skipping {} compaction (done before restart)", name);
+            } else {
+                self.compact_cf(name);
+                config.compacted_cfs.push(name.to_string());
+                self.set_config(config.clone());
+            }
+            on_compacted(config.compacted_cfs.len(), total);
+        }
+        config.compacted_cfs.clear();
+        info!("finished full compaction");
+    }
+
+    /// Writes are delayed (or stopped) by RocksDB when compactions can't keep up with them.
+    pub(crate) fn is_write_stalled(&self) -> bool {
+        let property = |name| {
+            self.db
+                .property_int_value(name)
+                .expect("failed to get property")
+                .unwrap_or(0)
+        };
+        property("rocksdb.is-write-stopped") > 0
+            || property("rocksdb.actual-delayed-write-rate") > 0
+    }
+
+    /// The number of SST files at each level of each column family.
+    pub(crate) fn get_level_files(&self) -> impl Iterator<Item = (&'static str, usize, u64)> + '_ {
+        COLUMN_FAMILIES.iter().flat_map(move |cf_name| {
+            let cf = self.db.cf_handle(cf_name).expect("missing CF");
+            (0..LEVELS).filter_map(move |level| {
+                let name = format!("rocksdb.num-files-at-level{}", level);
+                let value = self
+                    .db
+                    .property_value_cf(cf, name.as_str())
+                    .expect("failed to get property")?;
+                Some((*cf_name, level, value.trim().parse().ok()?))
+            })
+        })
+    }
+
+    /// Scans all the rows, so it may take a while for large DBs.
+    pub(crate) fn digests(&self) -> BTreeMap<&'static str, Digest> {
+        DIGEST_COLUMN_FAMILIES
+            .iter()
+            .map(|name| {
+                let cf = self.db.cf_handle(name).expect("missing CF");
+                let mut opts = rocksdb::ReadOptions::default();
+                opts.fill_cache(false);
+                opts.set_total_order_seek(true); // scan across all prefixes
+                let mut engine = sha256::Hash::engine();
+                let mut rows = 0;
+                for row in self
+                    .db
+                    .iterator_cf_opt(cf, opts, rocksdb::IteratorMode::Start)
+                {
+                    let (key, value) = row.expect("digest iterator failed");
+                    for data in [&key, &value] {
+                        engine.input(&(data.len() as u32).to_le_bytes());
+                        engine.input(data);
+                    }
+                    rows += 1;
+                }
+                let digest = Digest {
+                    rows,
+                    sha256: sha256::Hash::from_engine(engine),
+                };
+                (*name, digest)
+            })
+            .collect()
+    }
+
+    pub(crate) fn usage(&self, height: usize) -> BTreeMap<&'static str, Usage> {
+        COLUMN_FAMILIES
+            .iter()
+            .map(|name| {
+                let cf = self.db.cf_handle(name).expect("missing CF");
+                let property = |property_name: &str| {
+                    self.db
+                        .property_int_value_cf(cf, property_name)
+                        .expect("failed to get property")
+                        .unwrap_or_default()
+                };
+                let disk_bytes = property("rocksdb.live-sst-files-size");
+                let usage = Usage {
+                    disk_bytes,
+                    memtable_bytes: property("rocksdb.size-all-mem-tables"),
+                    keys: property("rocksdb.estimate-num-keys"),
+                    bytes_per_block: disk_bytes / (height.max(1) as u64),
+                };
+                (*name, usage)
+            })
+            .collect()
+    }
+
     pub(crate) fn get_properties(
         &self,
     ) -> impl Iterator<Item = (&'static str, &'static str, u64)> + '_ {
@@ -362,7 +1036,10 @@ impl Drop for DBStore {
 
 #[cfg(test)]
 mod tests {
-    use super::{rocksdb, DBStore, WriteBatch, CURRENT_FORMAT};
+    use super::{
+        prefix_upper_bound, rocksdb, DBStore, DBTuning, OptionalIndexes, WriteBatch,
+        COLUMN_FAMILIES, CONFIG_CF, CURRENT_FORMAT, HEADERS_CF,
+    };
     use std::ffi::{OsStr, OsString};
     use std::path::Path;
 
@@ -370,16 +1047,33 @@ mod tests {
     fn test_reindex_new_format() {
         let dir = tempfile::tempdir().unwrap();
         {
-            let store = DBStore::open(dir.path(), None, false).unwrap();
+            let store = DBStore::open(
+                dir.path(),
+                None,
+                false,
+                None,
+                OptionalIndexes::default(),
+                "regtest",
+                DBTuning::default(),
+            )
+            .unwrap();
             let mut config = store.get_config().unwrap();
             config.format += 1;
             store.set_config(config);
         };
         assert_eq!(
-            DBStore::open(dir.path(), None, false)
-                .err()
-                .unwrap()
-                .to_string(),
+            DBStore::open(
+                dir.path(),
+                None,
+                false,
+                None,
+                OptionalIndexes::default(),
+                "regtest",
+                DBTuning::default()
+            )
+            .err()
+            .unwrap()
+            .to_string(),
             format!(
                 "re-index required due to unsupported format {} != {}",
                 CURRENT_FORMAT + 1,
@@ -387,7 +1081,16 @@ mod tests {
             )
         );
         {
-            let store = DBStore::open(dir.path(), None, true).unwrap();
+            let store = DBStore::open(
+                dir.path(),
+                None,
+                true,
+                None,
+                OptionalIndexes::default(),
+                "regtest",
+                DBTuning::default(),
+            )
+            .unwrap();
             store.flush();
             let config = store.get_config().unwrap();
             assert_eq!(config.format, CURRENT_FORMAT);
@@ -395,6 +1098,180 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_reindex_script_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let script_types = Some(vec!["p2wpkh".to_owned(), "p2tr".to_owned()]);
+        {
+            let store = DBStore::open(
+                dir.path(),
+                None,
+                false,
+                script_types.clone(),
+                OptionalIndexes::default(),
+                "regtest",
+                DBTuning::default(),
+            )
+            .unwrap();
+            let config = store.get_config().unwrap();
+            assert_eq!(config.script_types, script_types);
+        }
+        assert!(DBStore::open(
+            dir.path(),
+            None,
+            false,
+            script_types.clone(),
+            OptionalIndexes::default(),
+            "regtest",
+            DBTuning::default()
+        )
+        .is_ok());
+        assert_eq!(
+            DBStore::open(
+                dir.path(),
+                None,
+                false,
+                None,
+                OptionalIndexes::default(),
+                "regtest",
+                DBTuning::default()
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            "re-index required due to different indexed script types [p2wpkh,p2tr] != all"
+        );
+        {
+            let store = DBStore::open(
+                dir.path(),
+                None,
+                true,
+                None,
+                OptionalIndexes::default(),
+                "regtest",
+                DBTuning::default(),
+            )
+            .unwrap();
+            let config = store.get_config().unwrap();
+            assert_eq!(config.script_types, None);
+        }
+    }
+
+    #[test]
+    fn test_reindex_optional_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let optional = OptionalIndexes {
+            coinbase: true,
+            ..Default::default()
+        };
+        {
+            let store = DBStore::open(
+                dir.path(),
+                None,
+                false,
+                None,
+                optional,
+                "regtest",
+                DBTuning::default(),
+            );
+            assert_eq!(store.unwrap().get_config().unwrap().optional, optional);
+        }
+        assert_eq!(
+            DBStore::open(
+                dir.path(),
+                None,
+                false,
+                None,
+                Default::default(),
+                "regtest",
+                DBTuning::default()
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            "re-index required due to different optional indexes [coinbase] != none"
+        );
+        {
+            let store = DBStore::open(
+                dir.path(),
+                None,
+                true,
+                None,
+                Default::default(),
+                "regtest",
+                DBTuning::default(),
+            );
+            let config = store.unwrap().get_config().unwrap();
+            assert_eq!(config.optional, OptionalIndexes::default());
+        }
+        let optional = OptionalIndexes {
+            from_height: 1000,
+            ..Default::default()
+        };
+        assert_eq!(
+            DBStore::open(
+                dir.path(),
+                None,
+                false,
+                None,
+                optional,
+                "regtest",
+                DBTuning::default()
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            "re-index required due to different optional indexes none != [from_height=1000]"
+        );
+    }
+
+    #[test]
+    fn test_network_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = DBStore::open(
+                dir.path(),
+                None,
+                false,
+                None,
+                OptionalIndexes::default(),
+                "signet/0a03cf40",
+                DBTuning::default(),
+            )
+            .unwrap();
+            let config = store.get_config().unwrap();
+            assert_eq!(config.network.as_deref(), Some("signet/0a03cf40"));
+        }
+        assert!(DBStore::open(
+            dir.path(),
+            None,
+            false,
+            None,
+            OptionalIndexes::default(),
+            "signet/0a03cf40",
+            DBTuning::default()
+        )
+        .is_ok());
+        assert_eq!(
+            DBStore::open(
+                dir.path(),
+                None,
+                true,
+                None,
+                OptionalIndexes::default(),
+                "signet/4a6bd4a9",
+                DBTuning::default()
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            format!(
+                "DB {} was created for signet/0a03cf40 network (instead of signet/4a6bd4a9)",
+                dir.path().display()
+            )
+        );
+    }
+
     #[test]
     fn test_reindex_legacy_format() {
         let dir = tempfile::tempdir().unwrap();
@@ -405,14 +1282,31 @@ mod tests {
             db.put(b"F", b"").unwrap(); // insert legacy DB compaction marker (in 'default' column family)
         };
         assert_eq!(
-            DBStore::open(dir.path(), None, false)
-                .err()
-                .unwrap()
-                .to_string(),
+            DBStore::open(
+                dir.path(),
+                None,
+                false,
+                None,
+                OptionalIndexes::default(),
+                "regtest",
+                DBTuning::default()
+            )
+            .err()
+            .unwrap()
+            .to_string(),
             format!("re-index required due to legacy format",)
         );
         {
-            let store = DBStore::open(dir.path(), None, true).unwrap();
+            let store = DBStore::open(
+                dir.path(),
+                None,
+                true,
+                None,
+                OptionalIndexes::default(),
+                "regtest",
+                DBTuning::default(),
+            )
+            .unwrap();
             store.flush();
             let config = store.get_config().unwrap();
             assert_eq!(config.format, CURRENT_FORMAT);
@@ -422,7 +1316,16 @@ mod tests {
     #[test]
     fn test_db_prefix_scan() {
         let dir = tempfile::tempdir().unwrap();
-        let store = DBStore::open(dir.path(), None, true).unwrap();
+        let store = DBStore::open(
+            dir.path(),
+            None,
+            true,
+            None,
+            OptionalIndexes::default(),
+            "regtest",
+            DBTuning::default(),
+        )
+        .unwrap();
 
         let items: &[&[u8]] = &[
             b"ab",
@@ -444,6 +1347,137 @@ mod tests {
         assert_eq!(rows.collect::<Vec<_>>(), to_rows(&items[1..5]));
     }
 
+    #[test]
+    fn test_bloom_filter_tuning() {
+        let dir = tempfile::tempdir().unwrap();
+        let items: &[&[u8]] = &[b"abcdefgh1", b"abcdefgh2", b"abcdefgi1", b"bcdefghi1"];
+        for bloom_bits_per_key in [0, 4, DBTuning::default().bloom_bits_per_key] {
+            // changing the filters doesn't require re-indexing
+            let store = DBStore::open(
+                dir.path(),
+                None,
+                false,
+                None,
+                OptionalIndexes::default(),
+                "regtest",
+                DBTuning {
+                    block_cache_size: None,
+                    bloom_bits_per_key,
+                },
+            )
+            .unwrap();
+            store.write(&WriteBatch {
+                funding_rows: to_rows(items),
+                ..Default::default()
+            });
+            store.flush();
+
+            let rows = store.iter_funding(b"abcdefgh".to_vec().into_boxed_slice());
+            assert_eq!(rows.collect::<Vec<_>>(), to_rows(&items[..2]));
+            let rows = store.iter_funding(b"cdefghij".to_vec().into_boxed_slice());
+            assert_eq!(rows.count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_touched_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(
+            dir.path(),
+            None,
+            true,
+            None,
+            OptionalIndexes::default(),
+            "regtest",
+            DBTuning::default(),
+        )
+        .unwrap();
+        let entry = |key: &[u8], value: &[u8]| (key.into(), value.into());
+        store.write(&WriteBatch {
+            touched_rows: vec![entry(b"\x00\x01", b"a"), entry(b"\x00\x02", b"b")],
+            ..Default::default()
+        });
+        store.write(&WriteBatch {
+            touched_rows: vec![entry(b"\x00\x03", b"c")],
+            touched_end: b"\x00\x02".to_vec().into_boxed_slice(), // deletes the first row
+            ..Default::default()
+        });
+        let rows: Vec<_> = store.iter_touched(b"\x00".to_vec().into()).collect();
+        assert_eq!(
+            rows,
+            vec![entry(b"\x00\x02", b"b"), entry(b"\x00\x03", b"c")]
+        );
+    }
+
+    #[test]
+    fn test_broadcasts_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(
+            dir.path(),
+            None,
+            true,
+            None,
+            OptionalIndexes::default(),
+            "regtest",
+            DBTuning::default(),
+        )
+        .unwrap();
+        assert_eq!(store.last_broadcast_seq(), None);
+        for seq in 0..5 {
+            store.add_broadcast(seq, &[seq as u8], seq.saturating_sub(2)); // keep 3 entries
+        }
+        assert_eq!(store.last_broadcast_seq(), Some(4));
+        let entries: Vec<(u64, Vec<u8>)> = store
+            .read_broadcasts()
+            .into_iter()
+            .map(|(seq, value)| (seq, value.into_vec()))
+            .collect();
+        assert_eq!(entries, vec![(2, vec![2]), (3, vec![3]), (4, vec![4])]);
+    }
+
+    #[test]
+    fn test_prefix_upper_bound() {
+        assert_eq!(prefix_upper_bound(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_upper_bound(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_upper_bound(b"\xff\xff"), None);
+        assert_eq!(prefix_upper_bound(b""), None);
+    }
+
+    #[test]
+    fn test_db_script_prefix_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let optional = OptionalIndexes {
+            script_prefixes: true,
+            ..Default::default()
+        };
+        let store = DBStore::open(
+            dir.path(),
+            None,
+            true,
+            None,
+            optional,
+            "regtest",
+            DBTuning::default(),
+        )
+        .unwrap();
+
+        let items: &[&[u8]] = &[
+            b"\x00\x14abcdefgh",
+            b"\x00\x20",
+            b"\x51\x20xyz",
+            b"\x51\x21",
+        ];
+        store.write(&WriteBatch {
+            script_prefix_rows: to_rows(items),
+            ..Default::default()
+        });
+
+        let rows = store.iter_script_prefix(b"\x00".to_vec().into_boxed_slice());
+        assert_eq!(rows.collect::<Vec<_>>(), to_rows(&items[..2]));
+        let rows = store.iter_script_prefix(b"\x51\x20".to_vec().into_boxed_slice());
+        assert_eq!(rows.collect::<Vec<_>>(), to_rows(&items[2..3]));
+    }
+
     fn to_rows(values: &[&[u8]]) -> Vec<Box<[u8]>> {
         values
             .iter()
@@ -451,10 +1485,128 @@ mod tests {
             .collect()
     }
 
+    #[test]
+    fn test_digests() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let stores: Vec<DBStore> = dirs
+            .iter()
+            .map(|dir| {
+                DBStore::open(
+                    dir.path(),
+                    None,
+                    true,
+                    None,
+                    OptionalIndexes::default(),
+                    "regtest",
+                    DBTuning::default(),
+                )
+                .unwrap()
+            })
+            .collect();
+        let items: &[&[u8]] = &[b"a", b"bc", b"d"];
+        // rows written in different batches result in the same digest
+        stores[0].write(&WriteBatch {
+            funding_rows: to_rows(items),
+            ..Default::default()
+        });
+        for item in items.iter().rev() {
+            stores[1].write(&WriteBatch {
+                funding_rows: to_rows(&[*item]),
+                ..Default::default()
+            });
+        }
+        stores[1].set_mempool(b"local state"); // config CF is not digested
+        let digests = stores[0].digests();
+        assert_eq!(digests, stores[1].digests());
+        assert_eq!(digests["funding"].rows, 3);
+        assert_eq!(digests["spending"].rows, 0);
+        assert_eq!(digests["headers"].rows, 1); // tip row
+
+        stores[1].write(&WriteBatch {
+            funding_rows: to_rows(&[&b"e"[..]]),
+            ..Default::default()
+        });
+        assert_ne!(digests["funding"], stores[1].digests()["funding"]);
+        assert_eq!(digests["txid"], stores[1].digests()["txid"]);
+    }
+
+    #[test]
+    fn test_ingest_sst_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = DBStore::open(
+            dir.path(),
+            None,
+            true,
+            None,
+            OptionalIndexes::default(),
+            "regtest",
+            DBTuning::default(),
+        )
+        .unwrap();
+        store.set_ingest_sst_files(true).unwrap();
+
+        let items: &[&[u8]] = &[b"abcdefgh1", b"abcdefgh2", b"abcdefgh2", b"abcdefgi"];
+        store.write(&WriteBatch {
+            funding_rows: to_rows(items),
+            ..Default::default()
+        });
+        store.write(&WriteBatch {
+            funding_rows: to_rows(&[&b"abcdefgh0"[..]]),
+            ..Default::default()
+        });
+        let rows = store.iter_funding(b"abcdefgh".to_vec().into_boxed_slice());
+        let expected: &[&[u8]] = &[b"abcdefgh0", b"abcdefgh1", b"abcdefgh2"];
+        assert_eq!(rows.collect::<Vec<_>>(), to_rows(expected));
+        // ingested files are moved into the DB
+        let ingest_dir = dir.path().join(super::INGEST_DIR);
+        assert_eq!(std::fs::read_dir(ingest_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_resume_full_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(
+            dir.path(),
+            None,
+            true,
+            None,
+            OptionalIndexes::default(),
+            "regtest",
+            DBTuning::default(),
+        )
+        .unwrap();
+        // simulate a restart after compacting the first 2 column families
+        let mut config = store.get_config().unwrap();
+        config.compacted_cfs = vec![CONFIG_CF.to_owned(), HEADERS_CF.to_owned()];
+        store.set_config(config);
+
+        let mut progress = vec![];
+        store.flush_observed(|done, total| progress.push((done, total)));
+        let total = COLUMN_FAMILIES.len();
+        assert_eq!(
+            progress,
+            (1..=total).map(|done| (done, total)).collect::<Vec<_>>()
+        );
+        let config = store.get_config().unwrap();
+        assert!(config.compacted);
+        assert!(config.compacted_cfs.is_empty());
+        assert!(!store.is_bulk_import());
+        assert!(!store.is_write_stalled());
+    }
+
     #[test]
     fn test_db_log_in_same_dir() {
         let dir1 = tempfile::tempdir().unwrap();
-        let _store = DBStore::open(dir1.path(), None, true).unwrap();
+        let _store = DBStore::open(
+            dir1.path(),
+            None,
+            true,
+            None,
+            OptionalIndexes::default(),
+            "regtest",
+            DBTuning::default(),
+        )
+        .unwrap();
 
         // LOG file is created in dir1
         let dir_files = list_log_files(dir1.path());
@@ -462,7 +1614,16 @@ mod tests {
 
         let dir2 = tempfile::tempdir().unwrap();
         let dir3 = tempfile::tempdir().unwrap();
-        let _store = DBStore::open(dir2.path(), Some(dir3.path()), true).unwrap();
+        let _store = DBStore::open(
+            dir2.path(),
+            Some(dir3.path()),
+            true,
+            None,
+            OptionalIndexes::default(),
+            "regtest",
+            DBTuning::default(),
+        )
+        .unwrap();
 
         // *_LOG file is not created in dir2, but in dir3
         let dir_files = list_log_files(dir2.path());
@@ -0,0 +1,292 @@
+use anyhow::{Context, Result};
+use bitcoin::{
+    bip32::{ChildNumber, Xpub},
+    secp256k1::{Secp256k1, Verification},
+    PublicKey, ScriptBuf,
+};
+
+use std::str::FromStr;
+
+// See https://github.com/bitcoin/bips/blob/master/bip-0380.mediawiki#checksum for details.
+const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// A single-key output descriptor (e.g. `wpkh([d34db33f/84h/0h/0h]xpub.../0/*)`),
+/// supporting `pkh`, `wpkh`, `sh(wpkh)` and `tr` (key path only).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Descriptor {
+    kind: Kind,
+    key: Key,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Pkh,
+    Wpkh,
+    ShWpkh,
+    Tr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Key {
+    Single(PublicKey),
+    Extended {
+        xpub: Xpub,
+        path: Vec<ChildNumber>, // unhardened steps
+        wildcard: bool,         // whether the path ends with '/*'
+    },
+}
+
+impl Descriptor {
+    /// Derive the output script at `index` (ignored by descriptors without a wildcard).
+    pub(crate) fn script_pubkey<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        index: u32,
+    ) -> Result<ScriptBuf> {
+        let pubkey = match &self.key {
+            Key::Single(pubkey) => *pubkey,
+            Key::Extended {
+                xpub,
+                path,
+                wildcard,
+            } => {
+                let mut path = path.clone();
+                if *wildcard {
+                    path.push(ChildNumber::from_normal_idx(index)?);
+                }
+                PublicKey::new(xpub.derive_pub(secp, &path)?.public_key)
+            }
+        };
+        let wpkh = || {
+            pubkey
+                .wpubkey_hash()
+                .context("uncompressed key in segwit descriptor")
+        };
+        Ok(match self.kind {
+            Kind::Pkh => ScriptBuf::new_p2pkh(&pubkey.pubkey_hash()),
+            Kind::Wpkh => ScriptBuf::new_p2wpkh(&wpkh()?),
+            Kind::ShWpkh => ScriptBuf::new_p2sh(&ScriptBuf::new_p2wpkh(&wpkh()?).script_hash()),
+            Kind::Tr => ScriptBuf::new_p2tr(secp, pubkey.inner.x_only_public_key().0, None),
+        })
+    }
+
+    /// Whether the descriptor derives a different script for each index.
+    pub(crate) fn is_ranged(&self) -> bool {
+        matches!(self.key, Key::Extended { wildcard: true, .. })
+    }
+}
+
+impl FromStr for Descriptor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let desc = match s.split_once('#') {
+            Some((desc, expected)) => {
+                let actual = checksum(desc)?;
+                ensure!(
+                    actual == expected,
+                    "invalid descriptor checksum {} != {}",
+                    expected,
+                    actual
+                );
+                desc
+            }
+            None => s,
+        };
+        let (kind, key) = if let Some(inner) = unwrap("sh(wpkh(", desc, "))") {
+            (Kind::ShWpkh, inner)
+        } else if let Some(inner) = unwrap("wpkh(", desc, ")") {
+            (Kind::Wpkh, inner)
+        } else if let Some(inner) = unwrap("pkh(", desc, ")") {
+            (Kind::Pkh, inner)
+        } else if let Some(inner) = unwrap("tr(", desc, ")") {
+            ensure!(
+                !inner.contains(','),
+                "taproot script paths are not supported"
+            );
+            (Kind::Tr, inner)
+        } else {
+            bail!("unsupported descriptor: {}", desc);
+        };
+        let key = parse_key(key).with_context(|| format!("invalid descriptor key: {}", key))?;
+        Ok(Descriptor { kind, key })
+    }
+}
+
+fn unwrap<'a>(prefix: &str, s: &'a str, suffix: &str) -> Option<&'a str> {
+    s.strip_prefix(prefix)?.strip_suffix(suffix)
+}
+
+fn parse_key(key: &str) -> Result<Key> {
+    // key origin information is not needed for derivation
+    let key = match key.strip_prefix('[') {
+        Some(rest) => rest.split_once(']').context("unterminated key origin")?.1,
+        None => key,
+    };
+    let mut parts = key.split('/');
+    let key = parts.next().unwrap_or_default();
+    if key.len() == 66 || key.len() == 130 {
+        ensure!(parts.next().is_none(), "derivation from a non-extended key");
+        return Ok(Key::Single(PublicKey::from_str(key)?));
+    }
+    let xpub = Xpub::from_str(key)?;
+    let mut path = vec![];
+    let mut wildcard = false;
+    for step in parts {
+        ensure!(!wildcard, "wildcard must be the last derivation step");
+        match step {
+            "*" => wildcard = true,
+            _ if step.ends_with(&['\'', 'h', 'H'][..]) || step.starts_with('*') => {
+                bail!("hardened derivation requires a private key")
+            }
+            _ => path.push(ChildNumber::from_normal_idx(step.parse()?)?),
+        }
+    }
+    Ok(Key::Extended {
+        xpub,
+        path,
+        wildcard,
+    })
+}
+
+fn polymod(c: u64, value: u64) -> u64 {
+    const GENERATORS: [u64; 5] = [
+        0xf5dee51989,
+        0xa9fdca3312,
+        0x1bab10e32d,
+        0x3706b1677a,
+        0x644d626ffd,
+    ];
+    let c0 = c >> 35;
+    let mut c = ((c & 0x7ffffffff) << 5) ^ value;
+    for (i, generator) in GENERATORS.iter().enumerate() {
+        if c0 & (1 << i) != 0 {
+            c ^= generator;
+        }
+    }
+    c
+}
+
+fn checksum(desc: &str) -> Result<String> {
+    let mut c = 1;
+    let mut class = 0;
+    let mut class_count = 0;
+    for ch in desc.chars() {
+        let pos = INPUT_CHARSET
+            .find(ch)
+            .with_context(|| format!("invalid descriptor character {:?}", ch))?
+            as u64;
+        c = polymod(c, pos & 31);
+        class = class * 3 + (pos >> 5);
+        class_count += 1;
+        if class_count == 3 {
+            c = polymod(c, class);
+            class = 0;
+            class_count = 0;
+        }
+    }
+    if class_count > 0 {
+        c = polymod(c, class);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+    Ok((0..8)
+        .map(|j| char::from(CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, Descriptor};
+    use crate::types::ScriptHash;
+    use bitcoin::secp256k1::Secp256k1;
+
+    // BIP-32 test vector 1 (m/0H/1)
+    const XPUB: &str = "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ";
+
+    fn scripthashes(desc: &str) -> Vec<String> {
+        let secp = Secp256k1::verification_only();
+        let desc: Descriptor = desc.parse().unwrap();
+        (0..2)
+            .map(|index| {
+                let script = desc.script_pubkey(&secp, index).unwrap();
+                ScriptHash::new(&script).to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        assert_eq!(
+            checksum("pkh(02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5)")
+                .unwrap(),
+            "8fhd9pwu"
+        );
+        assert!(checksum("pkh(\u{e9})").is_err());
+    }
+
+    #[test]
+    fn test_derivation() {
+        assert_eq!(
+            scripthashes(&format!("wpkh([d34db33f/84h/0h/0h]{}/0/*)#m708q8zw", XPUB)),
+            [
+                "bab464e12207cbd0f16fd6a972e5a071e68699986abb31671ca80652c54dc3ae",
+                "bd1fa4162d9cdf2ddb9f954e2444e5ae42ba308720b46ac06440da38c3197512"
+            ]
+        );
+        assert_eq!(
+            scripthashes(&format!("pkh({}/0/*)", XPUB)),
+            [
+                "c292f626bdadc918f86e02adb00437585c6c29c158ecf61ef37b00cf792f73c8",
+                "3ef8b18cc2846c0bba71cdc7dc47e6a09c760bbfaf395b4a732e9c243955d6a4"
+            ]
+        );
+        assert_eq!(
+            scripthashes(&format!("sh(wpkh({}/0/*))", XPUB)),
+            [
+                "8a64fd332c79df308bb617435161ae00bfb3207d804774a568a04c396404ce61",
+                "0589f35e61bffd78c52bb5f30a4edb8309b1066025b1d88abb24d8d32a279737"
+            ]
+        );
+        assert_eq!(
+            scripthashes(&format!("tr({}/0/*)", XPUB)),
+            [
+                "1f7060b5bc876176ebbd35cff0a92806af2480ef3b479392be1953e6f29e7ceb",
+                "b9c07ab783e3a8e52667fd7ee3dd03b4d2366e6030b6cb528807299f9a7eaf9a"
+            ]
+        );
+        // non-ranged descriptors derive the same script for all indices
+        let single = scripthashes(&format!("wpkh({}/0/1)", XPUB));
+        assert_eq!(single[0], single[1]);
+        assert_eq!(
+            single[0],
+            "bd1fa4162d9cdf2ddb9f954e2444e5ae42ba308720b46ac06440da38c3197512"
+        );
+        assert_eq!(
+            scripthashes(
+                "wpkh(034b6c8648fd6e08f9cd1e663fe6f0e1fb65b06b18748b2c26bd23583d0bc9e01c)"
+            )[0],
+            "bab464e12207cbd0f16fd6a972e5a071e68699986abb31671ca80652c54dc3ae"
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = [
+            format!("wpkh({}/0/*)#m708q8zw", XPUB), // checksum of a different descriptor
+            format!("wpkh({}/0h/*)", XPUB),
+            format!("wpkh({}/0/*h)", XPUB),
+            format!("wpkh({}/*/0)", XPUB),
+            format!("wsh({}/0/*)", XPUB),
+            format!("tr({}/0/*,pk({}))", XPUB, XPUB),
+            "wpkh(xpub)".to_owned(),
+        ];
+        for desc in &invalid {
+            assert!(desc.parse::<Descriptor>().is_err(), "{}", desc);
+        }
+    }
+}
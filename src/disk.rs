@@ -0,0 +1,39 @@
+use anyhow::{Context, Result};
+
+use std::path::Path;
+
+/// Space available to unprivileged users, on the filesystem containing `path` (in bytes).
+#[cfg(unix)]
+pub(crate) fn available_space(path: &Path) -> Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).context("invalid path")?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("failed to get filesystem stats of {}", path.display()));
+    }
+    #[allow(clippy::unnecessary_cast)] // the fields' types are platform-dependent
+    let available = stat.f_bavail as u64 * stat.f_frsize as u64;
+    Ok(available)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn available_space(path: &Path) -> Result<u64> {
+    bail!(
+        "checking available space of {} is not supported",
+        path.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    #[cfg(unix)]
+    fn test_available_space() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(super::available_space(dir.path()).unwrap() > 0);
+        assert!(super::available_space(&dir.path().join("missing")).is_err());
+    }
+}
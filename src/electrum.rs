@@ -1,41 +1,141 @@
 use anyhow::{bail, Context, Result};
 use bitcoin::{
     consensus::{deserialize, encode::serialize_hex},
-    hashes::hex::FromHex,
-    BlockHash, Txid,
+    hashes::{
+        hex::{DisplayHex, FromHex},
+        sha256, Hash,
+    },
+    secp256k1::Secp256k1,
+    Amount, BlockHash, OutPoint, ScriptBuf, Transaction, TxOut, Txid,
 };
 use crossbeam_channel::Receiver;
+use parking_lot::Mutex;
 use rayon::prelude::*;
 use serde_derive::Deserialize;
 use serde_json::{self, json, Value};
 
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 use std::fmt;
 use std::iter::FromIterator;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use crate::{
+    broadcasts::{BroadcastLog, BroadcastQuery, BroadcastRecord},
     cache::Cache,
-    config::{Config, ELECTRS_VERSION},
-    daemon::{self, extract_bitcoind_error, Daemon},
+    chain::Chain,
+    config::{AuthToken, Config, ListenerConfig, ServerFeatures, ELECTRS_VERSION},
+    cost::{Cost, Usage},
+    daemon::{self, extract_bitcoind_error, BlockSource, Broadcast, Daemon},
+    descriptor::Descriptor,
+    errors::{CodedError, ErrorCode},
+    index::{SyncProgress, Tuning},
     merkle::Proof,
-    metrics::{self, Histogram, Metrics},
-    signals::Signal,
-    status::ScriptHashStatus,
-    tracker::Tracker,
-    types::ScriptHash,
+    metrics::{self, Counter, Histogram, Metrics},
+    preview::{self, Candidate},
+    server::{MAX_LINE_SIZE, MAX_PENDING_SIZE},
+    signals::{ExitFlag, Signal},
+    status::{HistoryEntry, ScriptHashStatus, UnspentEntry},
+    trace::{Deadline, RequestId},
+    tracker::{Spender, Tracker},
+    types::{ScriptHash, ScriptType, ShortChannelId, StatusHash, FEERATE_PERCENTILES},
+    warmup::Warmup,
+    watchtower::Watch,
+    webhook::Registrations,
 };
 
 const PROTOCOL_VERSION: &str = "1.4";
 const UNKNOWN_FEE: isize = -1; // (allowed by Electrum protocol)
+const MAX_BULK_SCRIPTHASHES: usize = 1000;
+const MAX_RAW_BLOCKS: usize = 10;
+const MAX_PREFIX_MATCHES: usize = 1000;
+const MAX_RESCAN_SCRIPTHASHES: usize = 1000;
+const MAX_COINBASE_BLOCKS: usize = 2016; // a difficulty adjustment period
+const MAX_FEERATES_BLOCKS: usize = 2016;
+const MAX_REORGS_LISTED: usize = 100;
+const MAX_OUTPOINT_SUBSCRIPTIONS: usize = 1000;
+const MAX_TX_SUBSCRIPTIONS: usize = 1000;
+const MAX_MERKLE_PROOFS: usize = 1000;
+const MAX_MERKLE_BLOCKS: usize = 50; // distinct blocks' txids fetched per batch
+const MAX_PREVOUT_INPUTS: usize = 500; // larger transactions are returned without their prevouts
+pub(crate) const MAX_PREVOUT_FETCHES: usize = 25; // spent transactions' lookups per request
+const MAX_NOTIFY_DEPTH: usize = 100;
+const DEFAULT_NOTIFY_DEPTHS: [usize; 3] = [1, 3, 6];
+
+const MAX_JSON_DEPTH: usize = 32; // Electrum requests' params are shallow
+const MAX_BATCH_REQUESTS: usize = 10_000;
+const MAX_LOGGED_REQUEST_LEN: usize = 200;
+
+const MAX_USER_AGENT_LEN: usize = 64;
+const MAX_USER_AGENT_LABELS: usize = 32; // bound the metrics' cardinality
 
 const UNSUBSCRIBED_QUERY_MESSAGE: &str = "your wallet uses less efficient method of querying electrs, consider contacting the developer of your wallet. Reason:";
 
+// Subscribed statuses are partitioned by their scripthashes' prefix
+fn shard_of(scripthash: &ScriptHash, shards_count: usize) -> usize {
+    let prefix = u64::from_be_bytes(scripthash.as_byte_array()[..8].try_into().unwrap());
+    (prefix % shards_count as u64) as usize
+}
+
 /// Per-client Electrum protocol state
 #[derive(Default)]
 pub struct Client {
     tip: Option<BlockHash>,
+    reorgs_seen: Option<usize>, // # of logged reorgs, when subscribed to them
     scripthashes: HashMap<ScriptHash, ScriptHashStatus>,
+    outpoints: HashMap<OutPoint, OutPointWatch>,
+    outpoints_synced: Option<(BlockHash, u64)>, // chain tip and mempool generation
+    transactions: HashMap<Txid, TxWatch>,
+    auth_token: Option<AuthToken>,
+    authenticated: bool,
+    private: bool, // connected via a private listener
+    usage: Usage,
+    user_agent: Option<String>, // sent via `server.version`
+    addr: Option<SocketAddr>,
+}
+
+/// A subscribed outpoint's last known spender.
+struct OutPointWatch {
+    spender: Option<Spender>,
+    block: Option<BlockHash>, // the confirmed spender's block
+}
+
+/// A subscribed transaction's confirmation state.
+struct TxWatch {
+    depths: Vec<usize>, // sorted, not yet notified
+    block: Option<BlockHash>,
+    tip: Option<BlockHash>, // when the transaction was looked up
+}
+
+impl Client {
+    /// Clients of a listener without a token are implicitly authenticated.
+    pub(crate) fn new(listener: &ListenerConfig, addr: SocketAddr) -> Self {
+        Self {
+            auth_token: listener.auth_token.clone(),
+            authenticated: listener.auth_token.is_none(),
+            private: listener.private,
+            addr: Some(addr),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn scripthash_subscriptions(&self) -> usize {
+        self.scripthashes.len()
+    }
+
+    pub(crate) fn headers_subscribed(&self) -> bool {
+        self.tip.is_some()
+    }
+
+    pub(crate) fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+
+    pub(crate) fn usage(&self) -> &Usage {
+        &self.usage
+    }
 }
 
 #[derive(Deserialize)]
@@ -77,6 +177,146 @@ impl From<&TxGetArgs> for (Txid, bool) {
     }
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TxSubscribeArgs {
+    Txid((Txid,)),
+    TxidDepths(Txid, Vec<usize>),
+}
+
+impl From<&TxSubscribeArgs> for (Txid, Vec<usize>) {
+    fn from(args: &TxSubscribeArgs) -> Self {
+        match args {
+            TxSubscribeArgs::Txid((txid,)) => (*txid, DEFAULT_NOTIFY_DEPTHS.to_vec()),
+            TxSubscribeArgs::TxidDepths(txid, depths) => (*txid, depths.clone()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PrefixSearchArgs {
+    Prefix((String,)),
+    PrefixLimit(String, usize),
+}
+
+impl From<&PrefixSearchArgs> for (String, usize) {
+    fn from(args: &PrefixSearchArgs) -> Self {
+        match args {
+            PrefixSearchArgs::Prefix((prefix,)) => (prefix.clone(), MAX_PREFIX_MATCHES),
+            PrefixSearchArgs::PrefixLimit(prefix, limit) => (prefix.clone(), *limit),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HistoryArgs {
+    ScriptHash((ScriptHash,)),
+    ScriptHashFrom(ScriptHash, usize),
+    ScriptHashRange(ScriptHash, usize, usize),
+}
+
+impl From<&HistoryArgs> for (ScriptHash, Option<RangeInclusive<usize>>) {
+    fn from(args: &HistoryArgs) -> Self {
+        match args {
+            HistoryArgs::ScriptHash((scripthash,)) => (*scripthash, None),
+            HistoryArgs::ScriptHashFrom(scripthash, start_height) => {
+                (*scripthash, Some(*start_height..=usize::MAX))
+            }
+            HistoryArgs::ScriptHashRange(scripthash, start_height, end_height) => {
+                (*scripthash, Some(*start_height..=*end_height))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScidArgs {
+    Number((u64,)),
+    Text((String,)),
+}
+
+impl TryFrom<&ScidArgs> for ShortChannelId {
+    type Error = anyhow::Error;
+
+    fn try_from(args: &ScidArgs) -> Result<Self> {
+        match args {
+            ScidArgs::Number((value,)) => Ok(ShortChannelId::from(*value)),
+            ScidArgs::Text((text,)) => text.parse(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RecentHistoryArgs {
+    ScriptHash((ScriptHash,)),
+    ScriptHashBefore(ScriptHash, usize),
+}
+
+impl From<&RecentHistoryArgs> for (ScriptHash, Option<usize>) {
+    fn from(args: &RecentHistoryArgs) -> Self {
+        match args {
+            RecentHistoryArgs::ScriptHash((scripthash,)) => (*scripthash, None),
+            RecentHistoryArgs::ScriptHashBefore(scripthash, before_height) => {
+                (*scripthash, Some(*before_height))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DescriptorArgs {
+    Range(String, u32, u32),
+    RangeSubscribe(String, u32, u32, bool),
+}
+
+impl<'a> From<&'a DescriptorArgs> for (&'a str, u32, u32, bool) {
+    fn from(args: &'a DescriptorArgs) -> Self {
+        match args {
+            DescriptorArgs::Range(desc, start, count) => (desc.as_str(), *start, *count, false),
+            DescriptorArgs::RangeSubscribe(desc, start, count, subscribe) => {
+                (desc.as_str(), *start, *count, *subscribe)
+            }
+        }
+    }
+}
+
+/// The last state synced by the client (see `blockchain.scripthashes.sync`).
+#[derive(Deserialize)]
+struct SyncCheckpoint {
+    height: usize,
+    block_hash: BlockHash,
+    #[serde(default)]
+    statuses: HashMap<ScriptHash, Option<StatusHash>>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SyncArgs {
+    Full((Vec<ScriptHash>,)),
+    Since(Vec<ScriptHash>, Option<SyncCheckpoint>),
+}
+
+impl<'a> From<&'a SyncArgs> for (&'a [ScriptHash], Option<&'a SyncCheckpoint>) {
+    fn from(args: &'a SyncArgs) -> Self {
+        match args {
+            SyncArgs::Full((scripthashes,)) => (scripthashes, None),
+            SyncArgs::Since(scripthashes, checkpoint) => (scripthashes, checkpoint.as_ref()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BlockGetRawArgs {
+    Height((usize,)),
+    Range(usize, usize),
+}
+
 enum StandardError {
     ParseError,
     InvalidRequest,
@@ -90,30 +330,66 @@ enum RpcError {
     // Electrum-specific errors
     BadRequest(anyhow::Error),
     DaemonError(daemon::RpcError),
-    UnavailableIndex,
+    UnavailableIndex(SyncProgress),
+    Unauthenticated,
+    PrivateMethod,
 }
 
 impl RpcError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            RpcError::Standard(err) => match err {
+                StandardError::ParseError | StandardError::InvalidRequest => {
+                    ErrorCode::InvalidRequest
+                }
+                StandardError::MethodNotFound => ErrorCode::MethodNotFound,
+                StandardError::InvalidParams => ErrorCode::InvalidParams,
+            },
+            RpcError::BadRequest(err) => ErrorCode::of(err),
+            RpcError::DaemonError(err) => ErrorCode::of_daemon_error(err),
+            RpcError::UnavailableIndex(_) => ErrorCode::IndexSyncing,
+            RpcError::Unauthenticated => ErrorCode::Unauthenticated,
+            RpcError::PrivateMethod => ErrorCode::PrivateMethod,
+        }
+    }
+
     fn to_value(&self) -> Value {
+        let data = json!({"code": self.code().as_str()});
         match self {
             RpcError::Standard(err) => match err {
-                StandardError::ParseError => json!({"code": -32700, "message": "parse error"}),
+                StandardError::ParseError => {
+                    json!({"code": -32700, "message": "parse error", "data": data})
+                }
                 StandardError::InvalidRequest => {
-                    json!({"code": -32600, "message": "invalid request"})
+                    json!({"code": -32600, "message": "invalid request", "data": data})
                 }
                 StandardError::MethodNotFound => {
-                    json!({"code": -32601, "message": "method not found"})
+                    json!({"code": -32601, "message": "method not found", "data": data})
                 }
                 StandardError::InvalidParams => {
-                    json!({"code": -32602, "message": "invalid params"})
+                    json!({"code": -32602, "message": "invalid params", "data": data})
                 }
             },
-            RpcError::BadRequest(err) => json!({"code": 1, "message": err.to_string()}),
-            RpcError::DaemonError(err) => json!({"code": 2, "message": err.message}),
-            RpcError::UnavailableIndex => {
+            RpcError::BadRequest(err) => {
+                json!({"code": 1, "message": err.to_string(), "data": data})
+            }
+            RpcError::DaemonError(err) => json!({"code": 2, "message": err.message, "data": data}),
+            RpcError::UnavailableIndex(progress) => {
                 // Internal JSON-RPC error (https://www.jsonrpc.org/specification#error_object)
-                json!({"code": -32603, "message": "unavailable index"})
+                json!({
+                    "code": -32603,
+                    "message": format!("server busy, syncing ({})", progress),
+                    "data": {"code": self.code().as_str(), "progress": progress},
+                })
+            }
+            RpcError::Unauthenticated => {
+                json!({"code": 3, "message": "authentication required", "data": data})
             }
+            RpcError::PrivateMethod => json!({
+                "code": 4,
+                "message": "method is available only on private listeners",
+                "data": data,
+            }),
         }
     }
 }
@@ -126,23 +402,46 @@ pub struct Rpc {
     daemon: Daemon,
     signal: Signal,
     banner: String,
+    donation_address: Option<String>,
+    server_features: ServerFeatures,
     port: u16,
+    index_script_types: Option<Vec<ScriptType>>,
+    index_from_height: usize,
+    regtest_testing: bool,
+    client_versions: Counter,
+    user_agent_labels: Mutex<HashSet<String>>,
+    labels_max_bytes: Option<usize>,
+    warmup: Warmup,
+    warmup_blocks: usize,
+    notify_shards: usize, // 0 - # of CPUs
+    request_timeout: Option<Duration>,
+    broadcasts: BroadcastLog,
+    resolve_prevouts: bool,
 }
 
 impl Rpc {
     /// Perform initial index sync (may take a while on first run).
-    pub fn new(config: &Config, metrics: Metrics) -> Result<Self> {
+    pub fn new(config: &Config, metrics: Metrics, exit_flag: ExitFlag) -> Result<Self> {
         let rpc_duration = metrics.histogram_vec(
             "rpc_duration",
             "RPC duration (in seconds)",
             "method",
             metrics::default_duration_buckets(),
         );
+        let client_versions = metrics.counter(
+            "electrum_client_versions",
+            "# of server.version requests, by the client's software",
+            "user_agent",
+        );
 
-        let tracker = Tracker::new(config, metrics)?;
-        let signal = Signal::new();
+        let mut tracker = Tracker::new(config, metrics)?;
+        let signal = Signal::new(tracker.throttle().clone(), exit_flag);
         let daemon = Daemon::connect(config, signal.exit_flag(), tracker.metrics())?;
-        let cache = Cache::new(tracker.metrics());
+        let headers_count = daemon.get_headers_count()?;
+        tracker.preflight_disk_space(headers_count);
+        tracker.set_sync_target(headers_count);
+        let cache = Cache::new(tracker.metrics(), config.memory_budget.tx_cache());
+        let broadcasts = BroadcastLog::new(config.broadcast_log_size, tracker.last_broadcast_seq());
         Ok(Self {
             tracker,
             cache,
@@ -150,7 +449,21 @@ impl Rpc {
             daemon,
             signal,
             banner: config.server_banner.clone(),
+            donation_address: config.server_donation_address.clone(),
+            server_features: config.server_features.clone(),
             port: config.electrum_rpc_addr.port(),
+            index_script_types: config.index_script_types.clone(),
+            index_from_height: config.index_from_height,
+            regtest_testing: config.regtest_testing,
+            client_versions,
+            user_agent_labels: Default::default(),
+            labels_max_bytes: config.labels_max_bytes,
+            warmup: Warmup::new(config.warmup_scripthashes),
+            warmup_blocks: config.warmup_blocks,
+            notify_shards: config.notify_shards,
+            request_timeout: config.request_timeout,
+            broadcasts,
+            resolve_prevouts: config.resolve_prevouts,
         })
     }
 
@@ -158,6 +471,260 @@ impl Rpc {
         &self.signal
     }
 
+    pub(crate) fn drop_caches(&self) {
+        self.cache.clear();
+    }
+
+    pub(crate) fn compact_db(&self) -> Result<bool> {
+        self.tracker.compact_db()
+    }
+
+    /// The logged broadcasts, matching `query` (newest first).
+    pub(crate) fn broadcasts(&self, query: &BroadcastQuery) -> Vec<BroadcastRecord> {
+        query.filter(self.tracker.load_broadcasts().into_iter())
+    }
+
+    pub(crate) fn chain(&self) -> &Chain {
+        self.tracker.chain()
+    }
+
+    /// Find a mempool or a confirmed transaction (with its confirming block).
+    pub(crate) fn find_transaction(
+        &self,
+        txid: Txid,
+    ) -> Result<Option<(Transaction, Option<BlockHash>)>> {
+        if let Some(tx) = self.tracker.mempool_transaction(&txid) {
+            return Ok(Some((tx.clone(), None)));
+        }
+        Ok(self
+            .tracker
+            .lookup_transaction(&self.daemon, txid)?
+            .map(|(blockhash, tx)| (tx, Some(blockhash))))
+    }
+
+    /// A mempool or a cached transaction (without looking up the index).
+    pub(crate) fn known_transaction(&self, txid: &Txid) -> Option<Transaction> {
+        match self.tracker.mempool_transaction(txid) {
+            Some(tx) => Some(tx.clone()),
+            None => self.cache.get_tx(txid, Transaction::clone),
+        }
+    }
+
+    pub(crate) fn in_mempool(&self, txid: &Txid) -> bool {
+        self.tracker.mempool_transaction(txid).is_some()
+    }
+
+    /// Find the output spent by `outpoint` - in the mempool, in the transactions' cache, or else
+    /// by looking up its confirmed transaction (which fetches its block). The lookups are
+    /// bounded by `fetches` (shared by the request's transactions), and cached.
+    pub(crate) fn find_prevout(
+        &self,
+        outpoint: OutPoint,
+        fetches: &mut usize,
+    ) -> Result<Option<TxOut>> {
+        let vout = outpoint.vout as usize;
+        if let Some(tx) = self.known_transaction(&outpoint.txid) {
+            return Ok(tx.output.get(vout).cloned());
+        }
+        if *fetches == 0 {
+            let message = format!(
+                "too many spent transactions to look up (more than {})",
+                MAX_PREVOUT_FETCHES
+            );
+            return Err(CodedError::new(ErrorCode::LookupLimitExceeded, message));
+        }
+        *fetches -= 1;
+        let found = self
+            .tracker
+            .lookup_transaction(&self.daemon, outpoint.txid)?;
+        let tx = match found {
+            Some((_blockhash, tx)) => tx,
+            None => return Ok(None),
+        };
+        let prevout = tx.output.get(vout).cloned();
+        self.cache.add_tx(outpoint.txid, move || tx);
+        Ok(prevout)
+    }
+
+    /// The outputs spent by `tx` (`None` for coinbase and missing inputs). A confirmed
+    /// transaction's prevouts are resolved by bitcoind (from its undo data), and the rest by
+    /// `find_prevout()`.
+    pub(crate) fn tx_prevouts(
+        &self,
+        tx: &Transaction,
+        blockhash: Option<BlockHash>,
+        fetches: &mut usize,
+    ) -> Result<Vec<Option<TxOut>>> {
+        let mut prevouts = match blockhash {
+            Some(blockhash) if !tx.is_coin_base() => {
+                let info = self
+                    .daemon
+                    .get_transaction_info(&tx.txid(), Some(blockhash), true)?;
+                info.get("vin")
+                    .and_then(Value::as_array)
+                    .map_or_else(Vec::new, |inputs| {
+                        inputs.iter().map(parse_prevout).collect()
+                    })
+            }
+            _ => vec![],
+        };
+        prevouts.resize(tx.input.len(), None);
+        for (prevout, txin) in prevouts.iter_mut().zip(&tx.input) {
+            if prevout.is_none() && !txin.previous_output.is_null() {
+                *prevout = self.find_prevout(txin.previous_output, fetches)?;
+            }
+        }
+        Ok(prevouts)
+    }
+
+    /// Fail for scripts whose funding rows are not indexed (see `Config::index_script_types`),
+    /// instead of returning their (empty) history. Scripthash-only queries can't be checked.
+    pub(crate) fn check_script_type(&self, script_type: ScriptType) -> Result<()> {
+        match &self.index_script_types {
+            Some(script_types) if !script_types.contains(&script_type) => {
+                let message = format!("{} scripts are not indexed", script_type.as_str());
+                Err(CodedError::new(ErrorCode::NotIndexed, message))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the sync progress if the index is not ready (e.g. during the initial sync),
+    /// so its queries would return partial results.
+    pub(crate) fn unavailable_index(&self) -> Option<SyncProgress> {
+        match self.tracker.status() {
+            Ok(()) => None,
+            Err(_) => Some(self.tracker.sync_progress()),
+        }
+    }
+
+    pub(crate) fn scripthash_status(&self, scripthash: ScriptHash) -> Result<ScriptHashStatus> {
+        self.new_status(scripthash)
+    }
+
+    pub(crate) fn unspent(&self, status: &ScriptHashStatus) -> Vec<UnspentEntry> {
+        self.tracker.get_unspent(status)
+    }
+
+    pub(crate) fn load_bans(&self) -> HashSet<IpAddr> {
+        self.tracker.load_bans()
+    }
+
+    pub(crate) fn persist_bans(&self, bans: &HashSet<IpAddr>) {
+        self.tracker.persist_bans(bans)
+    }
+
+    pub(crate) fn load_webhooks(&self) -> Registrations {
+        self.tracker.load_webhooks()
+    }
+
+    pub(crate) fn persist_webhooks(&self, registrations: &Registrations) {
+        self.tracker.persist_webhooks(registrations)
+    }
+
+    pub(crate) fn load_watches(&self) -> Vec<Watch> {
+        self.tracker.load_watches()
+    }
+
+    pub(crate) fn persist_watches(&self, watches: &[Watch]) {
+        self.tracker.persist_watches(watches)
+    }
+
+    pub(crate) fn find_spender(&self, outpoint: OutPoint) -> Result<Option<Spender>> {
+        self.tracker.lookup_spender(&self.daemon, outpoint)
+    }
+
+    pub(crate) fn find_mempool_spender(&self, outpoint: OutPoint) -> Option<Spender> {
+        self.tracker.mempool_spender(outpoint)
+    }
+
+    /// Sync multiple statuses together (see `Tracker::sync_statuses`).
+    pub(crate) fn sync_statuses(&self, statuses: &mut [ScriptHashStatus]) -> Result<()> {
+        self.tracker
+            .sync_statuses(statuses, &self.daemon, &self.cache)
+    }
+
+    pub(crate) fn mempool_generation(&self) -> u64 {
+        self.tracker.mempool_generation()
+    }
+
+    /// Broadcast `tx` via the daemon, logging the result (see `BroadcastLog`).
+    /// `peer` is the broadcasting client's address (`None` for the server's own broadcasts).
+    pub(crate) fn broadcast(&self, tx: &Transaction, peer: Option<IpAddr>) -> Result<Txid> {
+        let result = self.daemon.broadcast(tx);
+        if let Some((seq, first_kept)) = self.broadcasts.next() {
+            let error = result.as_ref().err().map(|e| format!("{:#}", e));
+            let record = BroadcastRecord::new(tx.txid(), peer, error);
+            self.tracker.log_broadcast(seq, first_kept, &record);
+        }
+        result
+    }
+
+    /// Persist the state that should survive a restart.
+    pub fn shutdown(&self) {
+        self.tracker.persist_mempool();
+        self.tracker.persist_headers();
+        if self.warmup.is_enabled() {
+            self.tracker.persist_warmup_hints(&self.warmup.hints());
+        }
+    }
+
+    /// Sync the statuses of the scripthashes queried before the last shutdown, and cache the
+    /// transactions of the last blocks (should be called once, after the initial sync).
+    pub(crate) fn warm_up(&self) -> Result<()> {
+        let start = Instant::now();
+        let mut statuses: Vec<ScriptHashStatus> = self
+            .tracker
+            .load_warmup_hints()
+            .into_iter()
+            .take(self.warmup.max_scripthashes())
+            .map(ScriptHashStatus::new)
+            .collect();
+        if let Err(e) = self
+            .tracker
+            .sync_statuses(&mut statuses, &self.daemon, &self.cache)
+        {
+            // the failing scripthashes (e.g. due to the lookup limit) are skipped
+            warn!("retrying warm-up one scripthash at a time: {:#}", e);
+            statuses.retain_mut(|status| {
+                let status = std::slice::from_mut(status);
+                match self
+                    .tracker
+                    .sync_statuses(status, &self.daemon, &self.cache)
+                {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!("failed to warm up {}: {:#}", status[0].scripthash(), e);
+                        false
+                    }
+                }
+            });
+        }
+        let warm_scripthashes = statuses.len();
+        self.warmup.add_statuses(statuses);
+
+        let chain = self.tracker.chain();
+        let start_height = (chain.height() + 1).saturating_sub(self.warmup_blocks);
+        let blockhashes = (start_height..=chain.height()).filter_map(|h| chain.get_block_hash(h));
+        let mut warm_blocks = 0;
+        self.daemon.for_blocks(blockhashes, |_blockhash, block| {
+            let block: bitcoin::Block = deserialize(&block).expect("core returned invalid block");
+            for tx in block.txdata {
+                self.cache.add_tx(tx.txid(), move || tx);
+            }
+            warm_blocks += 1;
+        })?;
+        if warm_scripthashes > 0 || warm_blocks > 0 {
+            info!(
+                "warmed up {} scripthashes and {} blocks in {:?}",
+                warm_scripthashes,
+                warm_blocks,
+                start.elapsed()
+            );
+        }
+        Ok(())
+    }
+
     pub fn new_block_notification(&self) -> Receiver<()> {
         self.daemon.new_block_notification()
     }
@@ -166,26 +733,202 @@ impl Rpc {
         self.tracker.sync(&self.daemon, self.signal.exit_flag())
     }
 
-    pub fn update_client(&self, client: &mut Client) -> Result<Vec<String>> {
-        let chain = self.tracker.chain();
-        let mut notifications = client
-            .scripthashes
+    /// Sent to the connected clients when the server starts draining, so they can switch
+    /// to another server before being disconnected.
+    pub fn draining_notification(&self) -> String {
+        notification("server.draining", &[]).to_string()
+    }
+
+    /// Returns the notifications of each client (or the failure of its subscriptions' update).
+    pub fn update_clients(&self, clients: &mut [&mut Client]) -> Vec<Result<Vec<String>>> {
+        let changed = self.sync_subscriptions(clients);
+        clients
             .par_iter_mut()
-            .filter_map(|(scripthash, status)| -> Option<Result<Value>> {
-                match self
-                    .tracker
-                    .update_scripthash_status(status, &self.daemon, &self.cache)
-                {
-                    Ok(true) => Some(Ok(notification(
-                        "blockchain.scripthash.subscribe",
-                        &[json!(scripthash), json!(status.statushash())],
-                    ))),
-                    Ok(false) => None, // statushash is the same
-                    Err(e) => Some(Err(e)),
+            .zip(changed)
+            .map(|(client, changed)| {
+                let changed = changed.context("failed to update status")?;
+                self.update_client(client, changed)
+            })
+            .collect()
+    }
+
+    /// Sync the statuses subscribed by all the clients, partitioned by their scripthashes into
+    /// shards - which are synced in parallel, sharing the fetched blocks between their statuses.
+    /// Returns the scripthashes whose statushash has changed (for each client).
+    fn sync_subscriptions(&self, clients: &mut [&mut Client]) -> Vec<Result<HashSet<ScriptHash>>> {
+        let shards_count = match self.notify_shards {
+            0 => rayon::current_num_threads(),
+            n => n,
+        };
+        // statuses are moved into their shards, and back into their clients after the sync
+        let mut shards: Vec<Vec<(usize, ScriptHashStatus)>> =
+            (0..shards_count).map(|_| vec![]).collect();
+        for (i, client) in clients.iter_mut().enumerate() {
+            for (scripthash, status) in client.scripthashes.iter_mut() {
+                let status = std::mem::replace(status, ScriptHashStatus::new(*scripthash));
+                shards[shard_of(scripthash, shards_count)].push((i, status));
+            }
+        }
+        let synced: Vec<Vec<(usize, ScriptHashStatus, Result<bool>)>> = shards
+            .into_par_iter()
+            .map(|shard| self.sync_shard(shard))
+            .collect();
+
+        let mut results: Vec<Result<HashSet<ScriptHash>>> =
+            clients.iter().map(|_| Ok(HashSet::new())).collect();
+        for (i, status, result) in synced.into_iter().flatten() {
+            let scripthash = status.scripthash();
+            match (result, &mut results[i]) {
+                (Ok(true), Ok(changed)) => {
+                    changed.insert(scripthash);
                 }
+                (Ok(_), _) | (Err(_), Err(_)) => (), // the first failure is reported
+                (Err(e), result) => *result = Err(e),
+            }
+            clients[i].scripthashes.insert(scripthash, status);
+        }
+        results
+    }
+
+    /// If the shard fails to sync, its statuses are synced one by one (so a failure affects
+    /// only the clients subscribed to the failing statuses).
+    fn sync_shard(
+        &self,
+        shard: Vec<(usize, ScriptHashStatus)>,
+    ) -> Vec<(usize, ScriptHashStatus, Result<bool>)> {
+        let (positions, mut statuses): (Vec<usize>, Vec<ScriptHashStatus>) =
+            shard.into_iter().unzip();
+        let prev: Vec<Option<StatusHash>> = statuses.iter().map(|s| s.statushash()).collect();
+        let result = self
+            .tracker
+            .sync_statuses(&mut statuses, &self.daemon, &self.cache);
+        if let Err(e) = result {
+            debug!(
+                "failed to sync {} statuses together: {:#}",
+                statuses.len(),
+                e
+            );
+            return positions
+                .into_iter()
+                .zip(statuses)
+                .zip(prev)
+                .map(|((i, mut status), prev)| {
+                    let result = self
+                        .tracker
+                        .update_scripthash_status(&mut status, &self.daemon, &self.cache)
+                        .map(|_| status.statushash() != prev);
+                    (i, status, result)
+                })
+                .collect();
+        }
+        positions
+            .into_iter()
+            .zip(statuses)
+            .zip(prev)
+            .map(|((i, status), prev)| {
+                let changed = status.statushash() != prev;
+                (i, status, Ok(changed))
+            })
+            .collect()
+    }
+
+    /// Notify the client about its changed scripthashes (whose statuses are already synced),
+    /// and about its other subscriptions.
+    fn update_client(
+        &self,
+        client: &mut Client,
+        changed: HashSet<ScriptHash>,
+    ) -> Result<Vec<String>> {
+        let chain = self.tracker.chain();
+        let mut notifications: Vec<Value> = changed
+            .into_iter()
+            .map(|scripthash| {
+                let status = &client.scripthashes[&scripthash];
+                notification(
+                    "blockchain.scripthash.subscribe",
+                    &[json!(scripthash), json!(status.statushash())],
+                )
             })
-            .collect::<Result<Vec<Value>>>()
-            .context("failed to update status")?;
+            .collect();
+
+        // the spenders may change only after a new block or a mempool update
+        let outpoints_synced = Some((chain.tip(), self.tracker.mempool_generation()));
+        if client.outpoints_synced != outpoints_synced {
+            // the new blocks' touched prefixes (unknown after a reorg, or for the first update)
+            let touched = client
+                .outpoints_synced
+                .and_then(|(tip, _)| self.tracker.touched_since(&tip));
+            let mut failed = false;
+            for (outpoint, watch) in client.outpoints.iter_mut() {
+                // a confirmed spender may change only if its block is reorged out
+                if let Some(blockhash) = watch.block {
+                    if chain.get_block_height(&blockhash).is_some() {
+                        continue;
+                    }
+                }
+                let spender = match &touched {
+                    // not spent by the new blocks, so only its mempool spender may have changed
+                    Some(blocks) if !blocks.iter().any(|block| block.spends(*outpoint)) => {
+                        self.tracker.mempool_spender(*outpoint)
+                    }
+                    _ => match self.tracker.lookup_spender(&self.daemon, *outpoint) {
+                        Ok(spender) => spender,
+                        Err(e) => {
+                            // retried on the next update (instead of disconnecting the client)
+                            warn!("failed to look up the spender of {}: {:#}", outpoint, e);
+                            failed = true;
+                            continue;
+                        }
+                    },
+                };
+                watch.block = spender_block(chain, spender.as_ref());
+                if spender != watch.spender {
+                    notifications.push(notification(
+                        "blockchain.outpoint.subscribe",
+                        &[
+                            json!([outpoint.txid, outpoint.vout]),
+                            outpoint_status(watch.spender.as_ref(), spender.as_ref()),
+                        ],
+                    ));
+                    watch.spender = spender;
+                }
+            }
+            if !failed {
+                client.outpoints_synced = outpoints_synced;
+            }
+        }
+
+        let mut confirmed = vec![]; // notified at all the requested depths
+        for (txid, watch) in client.transactions.iter_mut() {
+            let height = self.tx_confirmed_height(txid, watch)?;
+            let confirmations = height.map_or(0, |height| chain.height() + 1 - height);
+            let reached = watch
+                .depths
+                .iter()
+                .take_while(|d| **d <= confirmations)
+                .count();
+            if reached > 0 {
+                watch.depths.drain(..reached);
+                notifications.push(notification(
+                    "blockchain.transaction.subscribe",
+                    &[json!(txid), self.tx_status(txid, height)],
+                ));
+            }
+            if watch.depths.is_empty() {
+                confirmed.push(*txid);
+            }
+        }
+        for txid in confirmed {
+            client.transactions.remove(&txid);
+        }
+
+        if let Some(seen) = client.reorgs_seen {
+            let reorgs = self.tracker.reorgs();
+            for event in reorgs.get(seen..).unwrap_or_default() {
+                notifications.push(notification("blockchain.reorgs.subscribe", &[json!(event)]));
+            }
+            client.reorgs_seen = Some(reorgs.len());
+        }
 
         if let Some(old_tip) = client.tip {
             let new_tip = self.tracker.chain().tip();
@@ -210,10 +953,64 @@ impl Rpc {
         Ok(json!({"hex": serialize_hex(header), "height": height}))
     }
 
+    /// The most recent reorgs (newest first).
+    fn reorgs_list(&self, (count,): (usize,)) -> Result<Value> {
+        let reorgs = self.tracker.reorgs();
+        let count = std::cmp::min(count, MAX_REORGS_LISTED);
+        Ok(json!(reorgs.iter().rev().take(count).collect::<Vec<_>>()))
+    }
+
+    /// Returns the most recent reorg (if any), and notifies about new ones.
+    fn reorgs_subscribe(&self, client: &mut Client) -> Result<Value> {
+        let reorgs = self.tracker.reorgs();
+        client.reorgs_seen = Some(reorgs.len());
+        Ok(json!(reorgs.last()))
+    }
+
+    fn blockchain_info(&self) -> Result<Value> {
+        let chain = self.tracker.chain();
+        Ok(json!({
+            "height": chain.height(),
+            "tip": chain.tip(),
+            "chainwork": chain.chainwork().to_be_bytes().to_lower_hex_string(),
+            "difficulty": chain.difficulty(),
+            "next_retarget": chain.next_retarget(), // null if the network doesn't retarget
+        }))
+    }
+
+    fn block_median_time_past(&self, (height,): (usize,)) -> Result<Value> {
+        let chain = self.tracker.chain();
+        let mtp = match chain.median_time_past(height) {
+            Some(mtp) => mtp,
+            None => {
+                let message = format!("no block at height {}", height);
+                return Err(CodedError::new(ErrorCode::NotFound, message));
+            }
+        };
+        let header = chain.get_block_header(height).expect("missing header");
+        Ok(json!({"height": height, "median_time_past": mtp, "time": header.time}))
+    }
+
+    /// Returns `null` if no block has reached `timestamp` yet.
+    fn block_height_at_time(&self, (timestamp,): (u32,)) -> Result<Value> {
+        let chain = self.tracker.chain();
+        Ok(match chain.height_at_time(timestamp) {
+            Some(height) => json!({
+                "height": height,
+                "block_hash": chain.get_block_hash(height),
+                "median_time_past": chain.median_time_past(height),
+            }),
+            None => Value::Null,
+        })
+    }
+
     fn block_header(&self, (height,): (usize,)) -> Result<Value> {
         let chain = self.tracker.chain();
         let header = match chain.get_block_header(height) {
-            None => bail!("no header at {}", height),
+            None => {
+                let message = format!("no header at {}", height);
+                return Err(CodedError::new(ErrorCode::NotFound, message));
+            }
             Some(header) => header,
         };
         Ok(json!(serialize_hex(header)))
@@ -235,6 +1032,73 @@ impl Rpc {
         Ok(json!({"count": count, "hex": String::from_iter(hex_headers), "max": max_count}))
     }
 
+    fn block_get_raw(&self, args: &BlockGetRawArgs) -> Result<Value> {
+        let chain = self.tracker.chain();
+        match *args {
+            BlockGetRawArgs::Height((height,)) => {
+                let blockhash = match chain.get_block_hash(height) {
+                    Some(blockhash) => blockhash,
+                    None => {
+                        let message = format!("no block at height {}", height);
+                        return Err(CodedError::new(ErrorCode::NotFound, message));
+                    }
+                };
+                let mut blocks = self.raw_blocks(&[blockhash])?;
+                Ok(json!(blocks.remove(0)))
+            }
+            BlockGetRawArgs::Range(start_height, count) => {
+                // return only the available blocks
+                let end_height = std::cmp::min(
+                    chain.height() + 1,
+                    start_height.saturating_add(std::cmp::min(count, MAX_RAW_BLOCKS)),
+                );
+                let blockhashes: Vec<BlockHash> = (start_height..end_height)
+                    .filter_map(|height| chain.get_block_hash(height))
+                    .collect();
+                let blocks = self.raw_blocks(&blockhashes)?;
+                Ok(json!({"count": blocks.len(), "hex": blocks, "max": MAX_RAW_BLOCKS}))
+            }
+        }
+    }
+
+    fn block_coinbase_search(
+        &self,
+        (pattern, start_height, count): &(String, usize, usize),
+    ) -> Result<Value> {
+        let count = std::cmp::min(*count, MAX_COINBASE_BLOCKS);
+        let blocks = self
+            .tracker
+            .search_coinbase(pattern, *start_height, count)?;
+        Ok(json!({"blocks": blocks, "max": MAX_COINBASE_BLOCKS}))
+    }
+
+    fn block_feerates(&self, (start_height, count): (usize, usize)) -> Result<Value> {
+        let count = std::cmp::min(count, MAX_FEERATES_BLOCKS);
+        let blocks = self.tracker.block_feerates(start_height, count)?;
+        Ok(json!({
+            "percentiles": FEERATE_PERCENTILES,
+            "blocks": blocks,
+            "max": MAX_FEERATES_BLOCKS,
+        }))
+    }
+
+    // Blocks may be fetched out of order, so they are sorted according to `blockhashes`
+    fn raw_blocks(&self, blockhashes: &[BlockHash]) -> Result<Vec<String>> {
+        let mut blocks = HashMap::with_capacity(blockhashes.len());
+        self.daemon
+            .for_blocks(blockhashes.iter().copied(), |blockhash, block| {
+                blocks.insert(blockhash, block.as_slice().to_lower_hex_string());
+            })?;
+        blockhashes
+            .iter()
+            .map(|blockhash| {
+                blocks
+                    .remove(blockhash)
+                    .with_context(|| format!("missing block {}", blockhash))
+            })
+            .collect()
+    }
+
     fn estimate_fee(&self, (nblocks,): (u16,)) -> Result<Value> {
         Ok(self
             .daemon
@@ -265,24 +1129,69 @@ impl Rpc {
         Ok(json!(balance))
     }
 
-    fn scripthash_get_history(
-        &self,
-        client: &Client,
-        (scripthash,): &(ScriptHash,),
-    ) -> Result<Value> {
-        let history_entries = match client.scripthashes.get(scripthash) {
+    fn scripthash_get_history(&self, client: &Client, args: &HistoryArgs) -> Result<Value> {
+        let (scripthash, heights) = args.into();
+        if let Some(heights) = heights {
+            return self.scripthash_get_history_range(client, scripthash, heights);
+        }
+        let history_entries = match client.scripthashes.get(&scripthash) {
             Some(status) => json!(status.get_history()),
             None => {
                 info!(
                     "{} blockchain.scripthash.get_history called for unsubscribed scripthash",
                     UNSUBSCRIBED_QUERY_MESSAGE
                 );
-                json!(self.new_status(*scripthash)?.get_history())
+                json!(self.new_status(scripthash)?.get_history())
             }
         };
         Ok(history_entries)
     }
 
+    /// The confirmed history within the (inclusive) height range - filtered from the subscribed
+    /// status, or fetched without syncing the whole history. Mempool entries are not included.
+    fn scripthash_get_history_range(
+        &self,
+        client: &Client,
+        scripthash: ScriptHash,
+        heights: RangeInclusive<usize>,
+    ) -> Result<Value> {
+        ensure!(
+            heights.start() <= heights.end(),
+            "invalid height range: {}-{}",
+            heights.start(),
+            heights.end()
+        );
+        let history_entries = match client.scripthashes.get(&scripthash) {
+            Some(status) => {
+                let history: Vec<&HistoryEntry> = status
+                    .get_history()
+                    .iter()
+                    .filter(|entry| {
+                        entry
+                            .confirmed_height()
+                            .map_or(false, |height| heights.contains(&height))
+                    })
+                    .collect();
+                json!(history)
+            }
+            None => {
+                json!(self
+                    .tracker
+                    .history_range(scripthash, heights, &self.daemon, &self.cache)?)
+            }
+        };
+        Ok(history_entries)
+    }
+
+    /// Unlike `get_history`, this doesn't fail for scripthashes having too many index entries.
+    fn scripthash_get_history_recent(&self, args: &RecentHistoryArgs) -> Result<Value> {
+        let (scripthash, before_height) = args.into();
+        let page =
+            self.tracker
+                .recent_history(scripthash, before_height, &self.daemon, &self.cache)?;
+        Ok(json!(page))
+    }
+
     fn scripthash_list_unspent(
         &self,
         client: &Client,
@@ -301,6 +1210,97 @@ impl Rpc {
         Ok(json!(unspent_entries))
     }
 
+    fn scripthashes_get_status(
+        &self,
+        client: &Client,
+        (scripthashes,): &(Vec<ScriptHash>,),
+    ) -> Result<Value> {
+        let new_statuses = self.sync_unsubscribed(client, scripthashes)?;
+        let statushashes: Vec<Option<StatusHash>> = scripthashes
+            .iter()
+            .map(|scripthash| match client.scripthashes.get(scripthash) {
+                Some(status) => status.statushash(),
+                None => new_statuses[scripthash].statushash(),
+            })
+            .collect();
+        Ok(json!(statushashes))
+    }
+
+    /// Return the history changes since the client's checkpoint, so a wallet can catch up
+    /// using a single request (instead of polling each of its scripthashes).
+    /// Only the scripthashes whose status differs from the checkpoint are returned, with their
+    /// confirmed entries above the checkpoint height and all their mempool entries.
+    /// If the checkpoint block is not in the active chain (e.g. due to a reorg), "reset" is set
+    /// and the full history of the changed scripthashes is returned.
+    fn scripthashes_sync(&self, client: &Client, args: &SyncArgs) -> Result<Value> {
+        let (scripthashes, checkpoint) = args.into();
+        let new_statuses = self.sync_unsubscribed(client, scripthashes)?;
+        let chain = self.tracker.chain();
+        let since_height = checkpoint
+            .filter(|c| chain.get_block_hash(c.height) == Some(c.block_hash))
+            .map(|c| c.height);
+        let mut seen = HashSet::new();
+        let changes: Vec<Value> = scripthashes
+            .iter()
+            .filter(|scripthash| seen.insert(**scripthash))
+            .filter_map(|scripthash| {
+                let status = client
+                    .scripthashes
+                    .get(scripthash)
+                    .unwrap_or_else(|| &new_statuses[scripthash]);
+                let statushash = status.statushash();
+                let known = checkpoint.and_then(|c| c.statuses.get(scripthash));
+                if since_height.is_some() && known == Some(&statushash) {
+                    return None; // unchanged
+                }
+                // scripthashes missing from the checkpoint (e.g. new addresses) get their full history
+                let since_height = known.and(since_height);
+                let history: Vec<&HistoryEntry> = status
+                    .get_history()
+                    .iter()
+                    .filter(|entry| match (entry.confirmed_height(), since_height) {
+                        (Some(height), Some(since_height)) => height > since_height,
+                        _ => true,
+                    })
+                    .collect();
+                Some(json!({"scripthash": scripthash, "status": statushash, "history": history}))
+            })
+            .collect();
+        Ok(json!({
+            "checkpoint": {"height": chain.height(), "block_hash": chain.tip()},
+            "reset": checkpoint.is_some() && since_height.is_none(),
+            "changes": changes,
+        }))
+    }
+
+    /// Sync the statuses of the scripthashes the client is not subscribed to.
+    fn sync_unsubscribed(
+        &self,
+        client: &Client,
+        scripthashes: &[ScriptHash],
+    ) -> Result<HashMap<ScriptHash, ScriptHashStatus>> {
+        ensure!(
+            scripthashes.len() <= MAX_BULK_SCRIPTHASHES,
+            "too many scripthashes: {} > {}",
+            scripthashes.len(),
+            MAX_BULK_SCRIPTHASHES
+        );
+        let mut seen = HashSet::new();
+        let mut new_statuses: Vec<ScriptHashStatus> = scripthashes
+            .iter()
+            .copied()
+            .filter(|scripthash| !client.scripthashes.contains_key(scripthash))
+            .filter(|scripthash| seen.insert(*scripthash))
+            .map(ScriptHashStatus::new)
+            .collect();
+        self.tracker
+            .sync_statuses(&mut new_statuses, &self.daemon, &self.cache)?;
+        Ok(new_statuses
+            .into_iter()
+            .map(|status| (status.scripthash(), status))
+            .collect())
+    }
+
     fn scripthash_subscribe(
         &self,
         client: &mut Client,
@@ -331,9 +1331,17 @@ impl Rpc {
             .filter(|scripthash| !client.scripthashes.contains_key(scripthash))
             .collect();
 
+        // the statuses are synced on behalf of the current request
+        let request = RequestId::current();
+        let deadline = Deadline::current();
         let mut results: HashMap<ScriptHash, Result<ScriptHashStatus>> = new_scripthashes
             .into_par_iter()
-            .map(|scripthash| (scripthash, self.new_status(scripthash)))
+            .map(|scripthash| {
+                let status = RequestId::scope(request, || {
+                    Deadline::scope(deadline, || self.new_status(scripthash))
+                });
+                (scripthash, status)
+            })
             .collect();
 
         scripthashes.iter().map(move |scripthash| {
@@ -350,20 +1358,232 @@ impl Rpc {
         })
     }
 
+    fn descriptor_get_scripthashes(
+        &self,
+        client: &mut Client,
+        args: &DescriptorArgs,
+    ) -> Result<Value> {
+        let (desc, start, count, subscribe) = args.into();
+        let desc: Descriptor = desc.parse()?;
+        ensure!(
+            count as usize <= MAX_BULK_SCRIPTHASHES,
+            "too many scripthashes: {} > {}",
+            count,
+            MAX_BULK_SCRIPTHASHES
+        );
+        let count = if desc.is_ranged() {
+            count
+        } else {
+            count.min(1)
+        };
+        let end = start.checked_add(count).context("too large index range")?;
+        let secp = Secp256k1::verification_only();
+        let scripts = (start..end)
+            .map(|index| desc.script_pubkey(&secp, index))
+            .collect::<Result<Vec<ScriptBuf>>>()?;
+        if let Some(script) = scripts.first() {
+            // all of the descriptor's scripts have the same type
+            self.check_script_type(ScriptType::classify(script))?;
+        }
+        let scripthashes: Vec<ScriptHash> = scripts.iter().map(|s| ScriptHash::new(s)).collect();
+
+        let mut statushashes = HashMap::new();
+        if subscribe {
+            // subscribe to all scripthashes, or to none of them (if any status sync fails)
+            let mut seen = HashSet::new();
+            let mut new_statuses: Vec<ScriptHashStatus> = scripthashes
+                .iter()
+                .copied()
+                .filter(|scripthash| !client.scripthashes.contains_key(scripthash))
+                .filter(|scripthash| seen.insert(*scripthash))
+                .map(ScriptHashStatus::new)
+                .collect();
+            self.tracker
+                .sync_statuses(&mut new_statuses, &self.daemon, &self.cache)?;
+            for status in new_statuses {
+                client.scripthashes.insert(status.scripthash(), status);
+            }
+            statushashes = scripthashes
+                .iter()
+                .map(|scripthash| (*scripthash, client.scripthashes[scripthash].statushash()))
+                .collect();
+        }
+        let entries: Vec<Value> = (start..)
+            .zip(scripthashes)
+            .map(|(index, scripthash)| match statushashes.get(&scripthash) {
+                Some(statushash) => {
+                    json!({"index": index, "scripthash": scripthash, "status": statushash})
+                }
+                None => json!({"index": index, "scripthash": scripthash}),
+            })
+            .collect();
+        Ok(json!(entries))
+    }
+
     fn new_status(&self, scripthash: ScriptHash) -> Result<ScriptHashStatus> {
-        let mut status = ScriptHashStatus::new(scripthash);
+        self.warmup.touch(scripthash);
+        let mut status = self
+            .warmup
+            .take_status(&scripthash)
+            .unwrap_or_else(|| ScriptHashStatus::new(scripthash));
         self.tracker
             .update_scripthash_status(&mut status, &self.daemon, &self.cache)?;
         Ok(status)
     }
 
-    fn transaction_broadcast(&self, (tx_hex,): &(String,)) -> Result<Value> {
+    fn outpoint_get_spender(&self, (txid, vout): &(Txid, u32)) -> Result<Value> {
+        let outpoint = OutPoint::new(*txid, *vout);
+        Ok(json!(self
+            .tracker
+            .lookup_spender(&self.daemon, outpoint)?))
+    }
+
+    fn script_prefix_search(&self, args: &PrefixSearchArgs) -> Result<Value> {
+        let (prefix, limit) = args.into();
+        let prefix = Vec::from_hex(&prefix).context("non-hex script prefix")?;
+        let matches = self.tracker.search_script_prefix(
+            &self.daemon,
+            &prefix,
+            limit.min(MAX_PREFIX_MATCHES),
+        )?;
+        Ok(json!(matches))
+    }
+
+    fn scripthashes_find_funding(
+        &self,
+        (scripthashes, start_height, end_height): &(Vec<ScriptHash>, usize, usize),
+    ) -> Result<Value> {
+        ensure!(
+            scripthashes.len() <= MAX_RESCAN_SCRIPTHASHES,
+            "too many scripthashes: {} > {}",
+            scripthashes.len(),
+            MAX_RESCAN_SCRIPTHASHES
+        );
+        let matches =
+            self.tracker
+                .find_funding(&self.daemon, scripthashes, *start_height, *end_height)?;
+        Ok(json!(matches))
+    }
+
+    fn outpoint_subscribe(&self, client: &mut Client, (txid, vout): &(Txid, u32)) -> Result<Value> {
+        let outpoint = OutPoint::new(*txid, *vout);
+        ensure!(
+            client.outpoints.contains_key(&outpoint)
+                || client.outpoints.len() < MAX_OUTPOINT_SUBSCRIPTIONS,
+            "too many outpoint subscriptions: {}",
+            client.outpoints.len()
+        );
+        let spender = self.tracker.lookup_spender(&self.daemon, outpoint)?;
+        let status = outpoint_status(None, spender.as_ref());
+        let watch = OutPointWatch {
+            block: spender_block(self.tracker.chain(), spender.as_ref()),
+            spender,
+        };
+        client.outpoints.insert(outpoint, watch);
+        Ok(status)
+    }
+
+    fn outpoint_unsubscribe(
+        &self,
+        client: &mut Client,
+        (txid, vout): &(Txid, u32),
+    ) -> Result<Value> {
+        let removed = client
+            .outpoints
+            .remove(&OutPoint::new(*txid, *vout))
+            .is_some();
+        Ok(json!(removed))
+    }
+
+    /// Notifications are sent when the transaction reaches each one of the requested depths
+    /// (and the subscription is removed after the last one).
+    fn transaction_subscribe(&self, client: &mut Client, args: &TxSubscribeArgs) -> Result<Value> {
+        let (txid, mut depths) = args.into();
+        depths.sort_unstable();
+        depths.dedup();
+        ensure!(
+            !depths.is_empty() && depths.iter().all(|d| (1..=MAX_NOTIFY_DEPTH).contains(d)),
+            "notification depths must be in [1, {}]",
+            MAX_NOTIFY_DEPTH
+        );
+        ensure!(
+            client.transactions.contains_key(&txid)
+                || client.transactions.len() < MAX_TX_SUBSCRIPTIONS,
+            "too many transaction subscriptions: {}",
+            client.transactions.len()
+        );
+        let chain = self.tracker.chain();
+        let mut watch = TxWatch {
+            depths,
+            block: None,
+            tip: None,
+        };
+        let height = self.tx_confirmed_height(&txid, &mut watch)?;
+        // skip the depths which were already reached
+        let confirmations = height.map_or(0, |height| chain.height() + 1 - height);
+        watch.depths.retain(|d| *d > confirmations);
+        if !watch.depths.is_empty() {
+            client.transactions.insert(txid, watch);
+        }
+        Ok(self.tx_status(&txid, height))
+    }
+
+    fn transaction_unsubscribe(&self, client: &mut Client, (txid,): &(Txid,)) -> Result<Value> {
+        Ok(json!(client.transactions.remove(txid).is_some()))
+    }
+
+    /// Confirmed transactions are looked up again only after a reorg (or a new block, if they
+    /// are still unconfirmed).
+    fn tx_confirmed_height(&self, txid: &Txid, watch: &mut TxWatch) -> Result<Option<usize>> {
+        let chain = self.tracker.chain();
+        if let Some(height) = watch.block.and_then(|b| chain.get_block_height(&b)) {
+            return Ok(Some(height));
+        }
+        if watch.block.is_none() && watch.tip == Some(chain.tip()) {
+            return Ok(None);
+        }
+        watch.tip = Some(chain.tip());
+        watch.block = self
+            .tracker
+            .lookup_transaction(&self.daemon, *txid)?
+            .map(|(blockhash, _tx)| blockhash);
+        Ok(watch.block.and_then(|b| chain.get_block_height(&b)))
+    }
+
+    /// Height is 0 for mempool transactions, and `null` for unknown ones.
+    fn tx_status(&self, txid: &Txid, height: Option<usize>) -> Value {
+        let chain = self.tracker.chain();
+        match height {
+            Some(height) => json!({"height": height, "confirmations": chain.height() + 1 - height}),
+            None => {
+                let height = self.tracker.mempool_transaction(txid).map(|_| 0);
+                json!({"height": height, "confirmations": 0})
+            }
+        }
+    }
+
+    fn transaction_get_mempool_ancestors(&self, (txid,): &(Txid,)) -> Result<Value> {
+        match self.tracker.mempool_ancestors(txid) {
+            Some(ancestors) => Ok(json!(ancestors)),
+            None => {
+                let message = format!("{} is not in mempool", txid);
+                Err(CodedError::new(ErrorCode::NotFound, message))
+            }
+        }
+    }
+
+    fn transaction_broadcast(&self, client: &Client, (tx_hex,): &(String,)) -> Result<Value> {
         let tx_bytes = Vec::from_hex(tx_hex).context("non-hex transaction")?;
-        let tx = deserialize(&tx_bytes).context("invalid transaction")?;
-        let txid = self.daemon.broadcast(&tx)?;
+        let tx: Transaction = deserialize(&tx_bytes).context("invalid transaction")?;
+        let txid = self.broadcast(&tx, client.addr.map(|addr| addr.ip()))?;
         Ok(json!(txid))
     }
 
+    fn transaction_preview(&self, (tx_hex,): &(String,)) -> Result<Value> {
+        let candidate = Candidate::parse(tx_hex)?;
+        preview::preview(self, &candidate)
+    }
+
     fn transaction_get(&self, args: &TxGetArgs) -> Result<Value> {
         let (txid, verbose) = args.into();
         if verbose {
@@ -371,7 +1591,15 @@ impl Rpc {
                 .tracker
                 .lookup_transaction(&self.daemon, txid)?
                 .map(|(blockhash, _tx)| blockhash);
-            return self.daemon.get_transaction_info(&txid, blockhash);
+            // bitcoind resolves the confirmed transactions' prevouts (from its undo data)
+            let with_prevouts = self.resolve_prevouts && blockhash.is_some();
+            let mut info = self
+                .daemon
+                .get_transaction_info(&txid, blockhash, with_prevouts)?;
+            if self.resolve_prevouts {
+                self.add_prevouts(&mut info)?;
+            }
+            return Ok(info);
         }
         if let Some(tx) = self.cache.get_tx(&txid, serialize_hex) {
             return Ok(json!(tx));
@@ -389,15 +1617,75 @@ impl Rpc {
         Ok(json!(self.daemon.get_transaction_hex(&txid, None)?))
     }
 
+    /// Add the spent outputs to a verbose transaction's inputs (and its fee, if all of them
+    /// are found) - similar to bitcoind's `getrawtransaction` with verbosity 2 (whose prevouts
+    /// are used, if returned). The rest are resolved up to `MAX_PREVOUT_FETCHES` lookups.
+    fn add_prevouts(&self, info: &mut Value) -> Result<()> {
+        let tx: Transaction = match info.get("hex").and_then(Value::as_str) {
+            Some(hex) => deserialize(&Vec::from_hex(hex)?)?,
+            None => return Ok(()),
+        };
+        if tx.is_coin_base() || tx.input.len() > MAX_PREVOUT_INPUTS {
+            return Ok(());
+        }
+        let inputs = match info.get_mut("vin").and_then(Value::as_array_mut) {
+            Some(inputs) if inputs.len() == tx.input.len() => inputs,
+            _ => return Ok(()),
+        };
+        let mut input_value = Some(Amount::ZERO);
+        let mut fetches = MAX_PREVOUT_FETCHES;
+        for (input, txin) in inputs.iter_mut().zip(&tx.input) {
+            let prevout = match parse_prevout(input) {
+                Some(prevout) => Some(prevout),
+                None => match self.find_prevout(txin.previous_output, &mut fetches) {
+                    Ok(prevout) => prevout,
+                    // the remaining prevouts are omitted (like the missing ones)
+                    Err(e) if ErrorCode::of(&e) == ErrorCode::LookupLimitExceeded => None,
+                    Err(e) => return Err(e),
+                },
+            };
+            let prevout = match prevout {
+                Some(prevout) => prevout,
+                None => {
+                    input_value = None;
+                    continue;
+                }
+            };
+            input_value = input_value.and_then(|total| total.checked_add(prevout.value));
+            let script_type = ScriptType::classify(&prevout.script_pubkey).as_str();
+            match input.get_mut("prevout") {
+                Some(returned) => returned["script_type"] = json!(script_type),
+                None => {
+                    input["prevout"] = json!({
+                        "value": prevout.value.to_btc(),
+                        "scriptPubKey": {"hex": prevout.script_pubkey.as_bytes().to_lower_hex_string()},
+                        "script_type": script_type,
+                    })
+                }
+            }
+        }
+        let output_value: Amount = tx.output.iter().map(|txout| txout.value).sum();
+        if let Some(fee) = input_value.and_then(|total| total.checked_sub(output_value)) {
+            info["fee"] = json!(fee.to_btc());
+        }
+        Ok(())
+    }
+
     fn transaction_get_merkle(&self, (txid, height): &(Txid, usize)) -> Result<Value> {
         let chain = self.tracker.chain();
         let blockhash = match chain.get_block_hash(*height) {
-            None => bail!("missing block at {}", height),
+            None => {
+                let message = format!("missing block at {}", height);
+                return Err(CodedError::new(ErrorCode::NotFound, message));
+            }
             Some(blockhash) => blockhash,
         };
         let txids = self.daemon.get_block_txids(blockhash)?;
         match txids.iter().position(|current_txid| *current_txid == *txid) {
-            None => bail!("missing txid {} in block {}", txid, blockhash),
+            None => {
+                let message = format!("missing txid {} in block {}", txid, blockhash);
+                Err(CodedError::new(ErrorCode::NotFound, message))
+            }
             Some(position) => {
                 let proof = Proof::create(&txids, position);
                 Ok(json!({
@@ -409,13 +1697,89 @@ impl Rpc {
         }
     }
 
+    /// Merkle proofs of several confirmed transactions, fetching each block's txids only once.
+    /// Failures are reported per transaction, so a single missing one doesn't fail the batch.
+    fn transaction_get_merkle_batch(&self, (items,): &(Vec<(Txid, usize)>,)) -> Result<Value> {
+        ensure!(
+            items.len() <= MAX_MERKLE_PROOFS,
+            "too many transactions: {} > {}",
+            items.len(),
+            MAX_MERKLE_PROOFS
+        );
+        let mut by_height: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (index, (_txid, height)) in items.iter().enumerate() {
+            by_height.entry(*height).or_default().push(index);
+        }
+        // each block's txids are fetched from bitcoind
+        ensure!(
+            by_height.len() <= MAX_MERKLE_BLOCKS,
+            "too many blocks: {} > {}",
+            by_height.len(),
+            MAX_MERKLE_BLOCKS
+        );
+        let mut results = vec![Value::Null; items.len()];
+        let chain = self.tracker.chain();
+        for (height, indices) in by_height {
+            let blockhash = match chain.get_block_hash(height) {
+                Some(blockhash) => blockhash,
+                None => {
+                    for index in indices {
+                        let error = format!("missing block at {}", height);
+                        results[index] = json!({"tx_hash": items[index].0, "error": error});
+                    }
+                    continue;
+                }
+            };
+            let txids = match self.daemon.get_block_txids(blockhash) {
+                Ok(txids) => txids,
+                Err(e) => {
+                    let error = format!("failed to get block {} txids: {:#}", blockhash, e);
+                    for index in indices {
+                        results[index] = json!({"tx_hash": items[index].0, "error": error});
+                    }
+                    continue;
+                }
+            };
+            let positions: HashMap<Txid, usize> = txids
+                .iter()
+                .enumerate()
+                .map(|(position, txid)| (*txid, position))
+                .collect();
+            let (found, missing): (Vec<usize>, Vec<usize>) = indices
+                .into_iter()
+                .partition(|index| positions.contains_key(&items[*index].0));
+            for index in missing {
+                let txid = items[index].0;
+                let error = format!("missing txid {} in block {}", txid, blockhash);
+                results[index] = json!({"tx_hash": txid, "error": error});
+            }
+            let found_positions: Vec<usize> = found
+                .iter()
+                .map(|index| positions[&items[*index].0])
+                .collect();
+            let proofs = Proof::create_many(&txids, &found_positions);
+            for (index, proof) in found.into_iter().zip(proofs) {
+                results[index] = json!({
+                    "tx_hash": items[index].0,
+                    "block_height": height,
+                    "pos": proof.position(),
+                    "merkle": proof.to_hex(),
+                });
+            }
+        }
+        Ok(json!(results))
+    }
+
     fn transaction_from_pos(
         &self,
         (height, tx_pos, merkle): (usize, usize, bool),
     ) -> Result<Value> {
         let chain = self.tracker.chain();
         let blockhash = match chain.get_block_hash(height) {
-            None => bail!("missing block at {}", height),
+            None => {
+                let message = format!("missing block at {}", height);
+                return Err(CodedError::new(ErrorCode::NotFound, message));
+            }
             Some(blockhash) => blockhash,
         };
         let txids = self.daemon.get_block_txids(blockhash)?;
@@ -431,6 +1795,68 @@ impl Rpc {
         }
     }
 
+    /// Resolve a short channel ID into its funding outpoint (and the output's value and script).
+    fn scid_resolve(&self, args: &ScidArgs) -> Result<Value> {
+        let scid = ShortChannelId::try_from(args)?;
+        let not_found = |message: String| Err(CodedError::new(ErrorCode::NotFound, message));
+        let blockhash = match self.tracker.chain().get_block_hash(scid.height()) {
+            Some(blockhash) => blockhash,
+            None => return not_found(format!("missing block at {}", scid.height())),
+        };
+        let txids = self.daemon.get_block_txids(blockhash)?;
+        let txid = match txids.get(scid.tx_pos()) {
+            Some(txid) => *txid,
+            None => return not_found(format!("no transaction at {}", scid)),
+        };
+        let tx = match self.tracker.lookup_transaction(&self.daemon, txid)? {
+            Some((_blockhash, tx)) => tx,
+            None => return not_found(format!("transaction {} is not indexed", txid)),
+        };
+        let output = match tx.output.get(scid.vout() as usize) {
+            Some(output) => output,
+            None => return not_found(format!("no output at {}", scid)),
+        };
+        Ok(json!({
+            "scid": scid.to_string(),
+            "txid": txid,
+            "vout": scid.vout(),
+            "height": scid.height(),
+            "tx_pos": scid.tx_pos(),
+            "value": output.value.to_sat(),
+            "scriptpubkey": output.script_pubkey.as_bytes().to_lower_hex_string(),
+            "script_type": ScriptType::classify(&output.script_pubkey).as_str(),
+        }))
+    }
+
+    /// The short channel ID of a confirmed outpoint (e.g. a channel's funding output).
+    fn outpoint_get_scid(&self, (txid, vout): &(Txid, u32)) -> Result<Value> {
+        let not_found = |message: String| Err(CodedError::new(ErrorCode::NotFound, message));
+        let (blockhash, tx) = match self.tracker.lookup_transaction(&self.daemon, *txid)? {
+            Some(found) => found,
+            None => return not_found(format!("transaction {} is not confirmed", txid)),
+        };
+        if *vout as usize >= tx.output.len() {
+            return not_found(format!("no output {}:{}", txid, vout));
+        }
+        let height = self
+            .tracker
+            .chain()
+            .get_block_height(&blockhash)
+            .context("missing confirming block")?;
+        let txids = self.daemon.get_block_txids(blockhash)?;
+        let tx_pos = txids
+            .iter()
+            .position(|current_txid| current_txid == txid)
+            .with_context(|| format!("missing txid {} in block {}", txid, blockhash))?;
+        let scid = ShortChannelId::new(height, tx_pos, *vout)?;
+        Ok(json!({
+            "scid": scid.to_string(),
+            "scid_u64": scid.to_u64(),
+            "height": height,
+            "tx_pos": tx_pos,
+        }))
+    }
+
     fn get_fee_histogram(&self) -> Result<Value> {
         Ok(json!(self.tracker.fees_histogram()))
     }
@@ -439,7 +1865,18 @@ impl Rpc {
         format!("electrs/{}", ELECTRS_VERSION)
     }
 
-    fn version(&self, (client_id, client_version): &(String, VersionRequest)) -> Result<Value> {
+    fn sync_status(&self) -> Result<Value> {
+        let progress = self.tracker.sync_progress();
+        Ok(json!({"ready": self.tracker.status().is_ok(), "progress": progress}))
+    }
+
+    fn version(
+        &self,
+        client: &mut Client,
+        (client_id, client_version): &(String, VersionRequest),
+    ) -> Result<Value> {
+        client.user_agent = Some(client_id.chars().take(MAX_USER_AGENT_LEN).collect());
+        self.client_versions.inc(&self.user_agent_label(client_id));
         match client_version {
             VersionRequest::Single(exact) => check_between(PROTOCOL_VERSION, exact, exact),
             VersionRequest::MinMax(min, max) => check_between(PROTOCOL_VERSION, min, max),
@@ -448,20 +1885,152 @@ impl Rpc {
         Ok(json!([self.server_id(), PROTOCOL_VERSION]))
     }
 
-    fn features(&self) -> Result<Value> {
+    fn user_agent_label(&self, user_agent: &str) -> String {
+        let family = user_agent_family(user_agent);
+        let mut labels = self.user_agent_labels.lock();
+        if labels.contains(&family) || labels.len() < MAX_USER_AGENT_LABELS {
+            labels.insert(family.clone());
+            family
+        } else {
+            "other".to_owned()
+        }
+    }
+
+    fn db_usage(&self) -> Result<Value> {
+        Ok(json!(self.tracker.db_usage()))
+    }
+
+    fn script_type_stats(&self) -> Result<Value> {
+        Ok(self.tracker.script_type_stats().summary())
+    }
+
+    fn authenticate(&self, client: &mut Client, (token,): &(String,)) -> Result<Value> {
+        if let Some(expected) = &client.auth_token {
+            ensure!(expected.matches(token), "invalid authentication token");
+        }
+        client.authenticated = true;
+        Ok(json!(true))
+    }
+
+    /// Labels are stored per authentication token (so they are shared by its clients).
+    fn labels_identity(&self, client: &Client) -> Result<(sha256::Hash, usize)> {
+        let max_bytes = match self.labels_max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => bail!("labels' storage is disabled"),
+        };
+        match &client.auth_token {
+            Some(token) => Ok((token.identity(), max_bytes)),
+            None => bail!("labels' storage requires a token-authenticated listener"),
+        }
+    }
+
+    fn labels_get(&self, client: &Client, (key,): &(String,)) -> Result<Value> {
+        let (identity, _max_bytes) = self.labels_identity(client)?;
+        Ok(json!(self.tracker.load_labels(&identity).get(key)))
+    }
+
+    fn labels_list(&self, client: &Client) -> Result<Value> {
+        let (identity, max_bytes) = self.labels_identity(client)?;
+        let labels = self.tracker.load_labels(&identity);
         Ok(json!({
+            "keys": labels.keys().collect::<Vec<&str>>(),
+            "bytes": labels.size(),
+            "max_bytes": max_bytes,
+        }))
+    }
+
+    fn labels_set(
+        &self,
+        client: &Client,
+        (key, value): &(String, Option<String>),
+    ) -> Result<Value> {
+        let (identity, max_bytes) = self.labels_identity(client)?;
+        let mut labels = self.tracker.load_labels(&identity);
+        labels.set(key, value.as_deref(), max_bytes)?;
+        self.tracker.persist_labels(&identity, &labels);
+        Ok(json!(true))
+    }
+
+    fn drain(&self) -> Result<Value> {
+        info!("draining requested via RPC");
+        self.signal.drain_flag().set(); // handled by the server loop
+        Ok(json!(true))
+    }
+
+    fn index_tune(&self, update: Tuning) -> Result<Value> {
+        Ok(json!(self.tracker.tune_index(update)?))
+    }
+
+    fn set_index_throttle(&self, (blocks_per_second,): (u32,)) -> Result<Value> {
+        let throttle = self.tracker.throttle();
+        throttle.set_limit(Some(blocks_per_second).filter(|&limit| limit > 0));
+        Ok(json!(throttle.limit()))
+    }
+
+    fn reindex_last_blocks(&self, (n,): (usize,)) -> Result<Value> {
+        ensure!(self.regtest_testing, "regtest testing mode is disabled");
+        self.tracker.request_reindex(n); // applied on the next sync
+        Ok(json!(true))
+    }
+
+    fn features(&self) -> Result<Value> {
+        let hosts = match &self.server_features.hosts {
+            Some(hosts) => json!(hosts),
+            None => json!({ "tcp_port": self.port }),
+        };
+        let mut features = json!({
             "genesis_hash": self.tracker.chain().get_block_hash(0),
-            "hosts": { "tcp_port": self.port },
+            "hosts": hosts,
             "protocol_max": PROTOCOL_VERSION,
             "protocol_min": PROTOCOL_VERSION,
             "pruning": null,
             "server_version": self.server_id(),
-            "hash_function": "sha256"
-        }))
+            "hash_function": "sha256",
+            "limits": {
+                "max_request_size": MAX_LINE_SIZE,
+                "max_pending_size": MAX_PENDING_SIZE,
+                "max_batch_requests": MAX_BATCH_REQUESTS,
+                "max_bulk_scripthashes": MAX_BULK_SCRIPTHASHES,
+                "max_outpoint_subscriptions": MAX_OUTPOINT_SUBSCRIPTIONS,
+                "max_tx_subscriptions": MAX_TX_SUBSCRIPTIONS,
+                "max_merkle_proofs": MAX_MERKLE_PROOFS,
+                "max_merkle_blocks": MAX_MERKLE_BLOCKS,
+            },
+        });
+        // confirmed history of other script types is not available
+        if let Some(script_types) = &self.index_script_types {
+            features["index_script_types"] = json!(script_types);
+        }
+        // confirmed history of older blocks is not available
+        if self.index_from_height > 0 {
+            features["index_from_height"] = json!(self.index_from_height);
+        }
+        if self.signal.drain_flag().is_set() {
+            features["draining"] = json!(true);
+        }
+        // custom fields can't replace the server's own ones
+        let fields = features
+            .as_object_mut()
+            .expect("features must be an object");
+        for (name, value) in &self.server_features.extras {
+            fields.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+        Ok(features)
+    }
+
+    // Cumulative counters, so the cost of a request is the difference before and after handling it
+    // (requests from different clients are handled sequentially).
+    fn consumed(&self) -> Cost {
+        Cost {
+            blocks: self.daemon.blocks_fetched(),
+            rows: self.tracker.rows_scanned(),
+            bytes: 0,
+        }
     }
 
     pub fn handle_requests(&self, client: &mut Client, lines: &[String]) -> Vec<String> {
-        lines
+        let start = self.consumed();
+        let responses: Vec<String> = lines
             .iter()
             .map(|line| {
                 parse_requests(line)
@@ -469,7 +2038,13 @@ impl Rpc {
                     .map_err(error_msg_no_id)
             })
             .map(|calls| self.handle_calls(client, calls).to_string())
-            .collect()
+            .collect();
+        let cost = Cost {
+            bytes: responses.iter().map(|response| response.len() as u64).sum(),
+            ..self.consumed().since(&start)
+        };
+        client.usage.charge(cost, Instant::now());
+        responses
     }
 
     fn handle_calls(&self, client: &mut Client, calls: Result<Calls, Value>) -> Value {
@@ -497,6 +2072,9 @@ impl Rpc {
         client: &mut Client,
         calls: &[Result<Call, Value>],
     ) -> Option<Vec<Value>> {
+        if !client.authenticated {
+            return None; // unauthenticated calls are rejected by `single_call()`
+        }
         // exit if any call failed to parse
         let valid_calls = calls
             .iter()
@@ -512,15 +2090,23 @@ impl Rpc {
             })
             .collect::<Option<Vec<ScriptHash>>>()?;
 
-        Some(
-            self.rpc_duration
-                .observe_duration("blockchain.scripthash.subscribe:multi", || {
-                    self.scripthashes_subscribe(client, &scripthashes)
-                        .zip(valid_calls)
-                        .map(|(result, call)| call.response(result))
-                        .collect::<Vec<Value>>()
-                }),
-        )
+        let request = RequestId::next();
+        debug!(
+            "{}: {} scripthash subscriptions",
+            request,
+            scripthashes.len()
+        );
+        Some(RequestId::scope(Some(request), || {
+            Deadline::scope(self.deadline(), || {
+                self.rpc_duration
+                    .observe_duration("blockchain.scripthash.subscribe:multi", || {
+                        self.scripthashes_subscribe(client, &scripthashes)
+                            .zip(valid_calls)
+                            .map(|(result, call)| call.response(result))
+                            .collect::<Vec<Value>>()
+                    })
+            })
+        }))
     }
 
     fn single_call(&self, client: &mut Client, call: Result<Call, Value>) -> Value {
@@ -528,6 +2114,18 @@ impl Rpc {
             Ok(call) => call,
             Err(response) => return response, // params parsing may fail - the response contains request id
         };
+        let request = RequestId::next();
+        debug!("{}: {}", request, call.method);
+        RequestId::scope(Some(request), || {
+            Deadline::scope(self.deadline(), || self.traced_call(client, call))
+        })
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.request_timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    fn traced_call(&self, client: &mut Client, call: Call) -> Value {
         self.rpc_duration.observe_duration(&call.method, || {
             if self.tracker.status().is_err() {
                 // Allow only a few RPC (for sync status notification) not requiring index DB being compacted.
@@ -535,88 +2133,263 @@ impl Rpc {
                     Params::BlockHeader(_)
                     | Params::BlockHeaders(_)
                     | Params::HeadersSubscribe
+                    | Params::SyncStatus
+                    | Params::Version(_) => (),
+                    _ => {
+                        let progress = self.tracker.sync_progress();
+                        return error_msg(&call.id, RpcError::UnavailableIndex(progress));
+                    }
+                };
+            }
+            if !client.authenticated {
+                // Allow only the RPCs required for connection handshake and authentication.
+                match &call.params {
+                    Params::Authenticate(_)
+                    | Params::Banner
+                    | Params::Features
+                    | Params::Ping
                     | Params::Version(_) => (),
-                    _ => return error_msg(&call.id, RpcError::UnavailableIndex),
+                    _ => return error_msg(&call.id, RpcError::Unauthenticated),
                 };
             }
+            if !client.private && call.params.is_private() {
+                return error_msg(&call.id, RpcError::PrivateMethod);
+            }
             let result = match &call.params {
+                Params::Authenticate(args) => self.authenticate(client, args),
                 Params::Banner => Ok(json!(self.banner)),
+                Params::BlockCoinbaseSearch(args) => self.block_coinbase_search(args),
+                Params::BlockFeerates(args) => self.block_feerates(*args),
+                Params::BlockchainInfo => self.blockchain_info(),
                 Params::BlockHeader(args) => self.block_header(*args),
                 Params::BlockHeaders(args) => self.block_headers(*args),
-                Params::Donation => Ok(Value::Null),
+                Params::BlockHeightAtTime(args) => self.block_height_at_time(*args),
+                Params::BlockMedianTimePast(args) => self.block_median_time_past(*args),
+                Params::BlockGetRaw(args) => self.block_get_raw(args),
+                Params::DbUsage => self.db_usage(),
+                Params::DescriptorGetScripthashes(args) => {
+                    self.descriptor_get_scripthashes(client, args)
+                }
+                Params::Donation => Ok(json!(self.donation_address)),
+                Params::Drain => self.drain(),
                 Params::EstimateFee(args) => self.estimate_fee(*args),
                 Params::Features => self.features(),
                 Params::HeadersSubscribe => self.headers_subscribe(client),
+                Params::IndexTune(args) => self.index_tune(*args),
+                Params::LabelsGet(args) => self.labels_get(client, args),
+                Params::LabelsList => self.labels_list(client),
+                Params::LabelsSet(args) => self.labels_set(client, args),
                 Params::MempoolFeeHistogram => self.get_fee_histogram(),
+                Params::OutpointGetScid(args) => self.outpoint_get_scid(args),
+                Params::OutpointGetSpender(args) => self.outpoint_get_spender(args),
+                Params::OutpointSubscribe(args) => self.outpoint_subscribe(client, args),
+                Params::OutpointUnsubscribe(args) => self.outpoint_unsubscribe(client, args),
                 Params::PeersSubscribe => Ok(json!([])),
                 Params::Ping => Ok(Value::Null),
+                Params::ReindexLastBlocks(args) => self.reindex_last_blocks(*args),
                 Params::RelayFee => self.relayfee(),
+                Params::ReorgsList(args) => self.reorgs_list(*args),
+                Params::ReorgsSubscribe => self.reorgs_subscribe(client),
+                Params::SetIndexThrottle(args) => self.set_index_throttle(*args),
+                Params::ScidResolve(args) => self.scid_resolve(args),
+                Params::ScriptPrefixSearch(args) => self.script_prefix_search(args),
                 Params::ScriptHashGetBalance(args) => self.scripthash_get_balance(client, args),
                 Params::ScriptHashGetHistory(args) => self.scripthash_get_history(client, args),
+                Params::ScriptHashGetHistoryRecent(args) => {
+                    self.scripthash_get_history_recent(args)
+                }
                 Params::ScriptHashListUnspent(args) => self.scripthash_list_unspent(client, args),
                 Params::ScriptHashSubscribe(args) => self.scripthash_subscribe(client, args),
                 Params::ScriptHashUnsubscribe(args) => self.scripthash_unsubscribe(client, args),
-                Params::TransactionBroadcast(args) => self.transaction_broadcast(args),
+                Params::ScriptHashesFindFunding(args) => self.scripthashes_find_funding(args),
+                Params::ScriptHashesGetStatus(args) => self.scripthashes_get_status(client, args),
+                Params::ScriptHashesSync(args) => self.scripthashes_sync(client, args),
+                Params::ScriptTypesGetStats => self.script_type_stats(),
+                Params::SyncStatus => self.sync_status(),
+                Params::TransactionBroadcast(args) => self.transaction_broadcast(client, args),
                 Params::TransactionGet(args) => self.transaction_get(args),
                 Params::TransactionGetMerkle(args) => self.transaction_get_merkle(args),
+                Params::TransactionGetMerkleBatch(args) => self.transaction_get_merkle_batch(args),
+                Params::TransactionPreview(args) => self.transaction_preview(args),
+                Params::TransactionGetMempoolAncestors(args) => {
+                    self.transaction_get_mempool_ancestors(args)
+                }
                 Params::TransactionFromPosition(args) => self.transaction_from_pos(*args),
-                Params::Version(args) => self.version(args),
+                Params::TransactionSubscribe(args) => self.transaction_subscribe(client, args),
+                Params::TransactionUnsubscribe(args) => self.transaction_unsubscribe(client, args),
+                Params::Version(args) => self.version(client, args),
             };
-            call.response(result)
+            let mut response = call.response(result);
+            // older blocks' outputs (and their spends) are missing from these results
+            if self.index_from_height > 0
+                && call.params.uses_full_history()
+                && response.get("result").is_some()
+            {
+                response["index_from_height"] = json!(self.index_from_height);
+            }
+            response
         })
     }
 }
 
 #[derive(Deserialize)]
 enum Params {
+    Authenticate((String,)),
     Banner,
+    BlockCoinbaseSearch((String, usize, usize)),
+    BlockFeerates((usize, usize)),
+    BlockchainInfo,
     BlockHeader((usize,)),
     BlockHeaders((usize, usize)),
+    BlockHeightAtTime((u32,)),
+    BlockMedianTimePast((usize,)),
+    BlockGetRaw(BlockGetRawArgs),
+    DbUsage,
+    DescriptorGetScripthashes(DescriptorArgs),
     TransactionBroadcast((String,)),
     Donation,
+    Drain,
     EstimateFee((u16,)),
     Features,
     HeadersSubscribe,
+    IndexTune(Tuning),
+    LabelsGet((String,)),
+    LabelsList,
+    LabelsSet((String, Option<String>)),
     MempoolFeeHistogram,
+    OutpointGetScid((Txid, u32)),
+    OutpointGetSpender((Txid, u32)),
+    OutpointSubscribe((Txid, u32)),
+    OutpointUnsubscribe((Txid, u32)),
     PeersSubscribe,
     Ping,
+    ReindexLastBlocks((usize,)),
     RelayFee,
+    ReorgsList((usize,)),
+    ReorgsSubscribe,
+    SetIndexThrottle((u32,)),
+    ScidResolve(ScidArgs),
+    ScriptPrefixSearch(PrefixSearchArgs),
     ScriptHashGetBalance((ScriptHash,)),
-    ScriptHashGetHistory((ScriptHash,)),
+    ScriptHashGetHistory(HistoryArgs),
+    ScriptHashGetHistoryRecent(RecentHistoryArgs),
     ScriptHashListUnspent((ScriptHash,)),
     ScriptHashSubscribe((ScriptHash,)),
     ScriptHashUnsubscribe((ScriptHash,)),
+    ScriptHashesFindFunding((Vec<ScriptHash>, usize, usize)),
+    ScriptHashesGetStatus((Vec<ScriptHash>,)),
+    ScriptHashesSync(SyncArgs),
+    ScriptTypesGetStats,
+    SyncStatus,
     TransactionGet(TxGetArgs),
     TransactionGetMerkle((Txid, usize)),
+    TransactionGetMerkleBatch((Vec<(Txid, usize)>,)),
+    TransactionGetMempoolAncestors((Txid,)),
+    TransactionPreview((String,)),
+    TransactionSubscribe(TxSubscribeArgs),
+    TransactionUnsubscribe((Txid,)),
     TransactionFromPosition((usize, usize, bool)),
     Version((String, VersionRequest)),
 }
 
 impl Params {
+    /// Methods that are allowed only for trusted clients (see `ListenerConfig::private`).
+    fn is_private(&self) -> bool {
+        matches!(
+            self,
+            Params::BlockGetRaw(_)
+                | Params::DbUsage
+                | Params::DescriptorGetScripthashes(_)
+                | Params::Drain
+                | Params::IndexTune(_)
+                | Params::ReindexLastBlocks(_)
+                | Params::ScriptPrefixSearch(_)
+                | Params::SetIndexThrottle(_)
+        )
+    }
+
+    /// Methods whose results may be truncated when the index starts at a later height
+    /// (see `Config::index_from_height`).
+    fn uses_full_history(&self) -> bool {
+        matches!(
+            self,
+            Params::ScriptHashGetBalance(_)
+                | Params::ScriptHashGetHistory(_)
+                | Params::ScriptHashListUnspent(_)
+        )
+    }
+
     fn parse(method: &str, params: Value) -> std::result::Result<Params, StandardError> {
         Ok(match method {
+            "blockchain.block.coinbase_search" => Params::BlockCoinbaseSearch(convert(params)?),
+            "blockchain.block.feerates" => Params::BlockFeerates(convert(params)?),
             "blockchain.block.header" => Params::BlockHeader(convert(params)?),
             "blockchain.block.headers" => Params::BlockHeaders(convert(params)?),
+            "blockchain.block.height_at_time" => Params::BlockHeightAtTime(convert(params)?),
+            "blockchain.block.median_time_past" => Params::BlockMedianTimePast(convert(params)?),
+            "blockchain.block.get_raw" => Params::BlockGetRaw(convert(params)?),
+            "blockchain.descriptor.get_scripthashes" => {
+                Params::DescriptorGetScripthashes(convert(params)?)
+            }
             "blockchain.estimatefee" => Params::EstimateFee(convert(params)?),
             "blockchain.headers.subscribe" => Params::HeadersSubscribe,
+            "blockchain.info" => Params::BlockchainInfo,
+            "blockchain.outpoint.get_scid" => Params::OutpointGetScid(convert(params)?),
+            "blockchain.outpoint.get_spender" => Params::OutpointGetSpender(convert(params)?),
+            "blockchain.outpoint.subscribe" => Params::OutpointSubscribe(convert(params)?),
+            "blockchain.outpoint.unsubscribe" => Params::OutpointUnsubscribe(convert(params)?),
             "blockchain.relayfee" => Params::RelayFee,
+            "blockchain.reorgs.list" => Params::ReorgsList(convert(params)?),
+            "blockchain.reorgs.subscribe" => Params::ReorgsSubscribe,
+            "blockchain.scid.resolve" => Params::ScidResolve(convert(params)?),
+            "blockchain.scriptpubkey.search_prefix" => Params::ScriptPrefixSearch(convert(params)?),
             "blockchain.scripthash.get_balance" => Params::ScriptHashGetBalance(convert(params)?),
             "blockchain.scripthash.get_history" => Params::ScriptHashGetHistory(convert(params)?),
+            "blockchain.scripthash.get_history_recent" => {
+                Params::ScriptHashGetHistoryRecent(convert(params)?)
+            }
             "blockchain.scripthash.listunspent" => Params::ScriptHashListUnspent(convert(params)?),
             "blockchain.scripthash.subscribe" => Params::ScriptHashSubscribe(convert(params)?),
             "blockchain.scripthash.unsubscribe" => Params::ScriptHashUnsubscribe(convert(params)?),
+            "blockchain.scripthashes.find_funding" => {
+                Params::ScriptHashesFindFunding(convert(params)?)
+            }
+            "blockchain.scripthashes.get_status" => Params::ScriptHashesGetStatus(convert(params)?),
+            "blockchain.scripthashes.sync" => Params::ScriptHashesSync(convert(params)?),
+            "blockchain.scripttypes.get_stats" => Params::ScriptTypesGetStats,
             "blockchain.transaction.broadcast" => Params::TransactionBroadcast(convert(params)?),
             "blockchain.transaction.get" => Params::TransactionGet(convert(params)?),
             "blockchain.transaction.get_merkle" => Params::TransactionGetMerkle(convert(params)?),
+            "blockchain.transaction.get_merkle_batch" => {
+                Params::TransactionGetMerkleBatch(convert(params)?)
+            }
+            "blockchain.transaction.get_mempool_ancestors" => {
+                Params::TransactionGetMempoolAncestors(convert(params)?)
+            }
+            "blockchain.transaction.preview" => Params::TransactionPreview(convert(params)?),
+            "blockchain.transaction.subscribe" => Params::TransactionSubscribe(convert(params)?),
+            "blockchain.transaction.unsubscribe" => {
+                Params::TransactionUnsubscribe(convert(params)?)
+            }
             "blockchain.transaction.id_from_pos" => {
                 Params::TransactionFromPosition(convert(params)?)
             }
             "mempool.get_fee_histogram" => Params::MempoolFeeHistogram,
+            "regtest.reindex_last_blocks" => Params::ReindexLastBlocks(convert(params)?),
+            "server.authenticate" => Params::Authenticate(convert(params)?),
             "server.banner" => Params::Banner,
+            "server.db.get_usage" => Params::DbUsage,
             "server.donation_address" => Params::Donation,
+            "server.drain" => Params::Drain,
             "server.features" => Params::Features,
+            "server.index.set_throttle" => Params::SetIndexThrottle(convert(params)?),
+            "server.index.tune" => Params::IndexTune(convert(params)?),
+            "server.labels.get" => Params::LabelsGet(convert(params)?),
+            "server.labels.list" => Params::LabelsList,
+            "server.labels.set" => Params::LabelsSet(convert(params)?),
             "server.peers.subscribe" => Params::PeersSubscribe,
             "server.ping" => Params::Ping,
+            "server.sync_status" => Params::SyncStatus,
             "server.version" => Params::Version(convert(params)?),
             _ => {
                 warn!("unknown method {}", method);
@@ -648,7 +2421,10 @@ impl Call {
         match result {
             Ok(value) => result_msg(&self.id, value),
             Err(err) => {
-                warn!("RPC {} failed: {:#}", self.method, err);
+                match RequestId::current() {
+                    Some(request) => warn!("{}: RPC {} failed: {:#}", request, self.method, err),
+                    None => warn!("RPC {} failed: {:#}", self.method, err),
+                }
                 match err
                     .downcast_ref::<bitcoincore_rpc::Error>()
                     .and_then(extract_bitcoind_error)
@@ -688,6 +2464,47 @@ where
     })
 }
 
+/// The software name, without its version (e.g. "electrum/4.5.4" -> "electrum").
+fn user_agent_family(user_agent: &str) -> String {
+    let family: String = user_agent
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .take(16)
+        .collect();
+    if family.is_empty() {
+        "unknown".to_owned()
+    } else {
+        family.to_ascii_lowercase()
+    }
+}
+
+/// `replaced` is set when the previous spender was double-spent (e.g. replaced via RBF),
+/// or dropped (e.g. evicted from the mempool).
+fn outpoint_status(old: Option<&Spender>, new: Option<&Spender>) -> Value {
+    let replaced = old
+        .filter(|old| new.map_or(true, |new| new.tx_hash() != old.tx_hash()))
+        .map(Spender::tx_hash);
+    json!({"spender": new, "replaced": replaced})
+}
+
+/// The spent output of a verbose transaction's input (as returned by bitcoind's
+/// `getrawtransaction` with verbosity 2).
+fn parse_prevout(input: &Value) -> Option<TxOut> {
+    let prevout = input.get("prevout")?;
+    let value = Amount::from_btc(prevout.get("value")?.as_f64()?).ok()?;
+    let script = Vec::from_hex(prevout.get("scriptPubKey")?.get("hex")?.as_str()?).ok()?;
+    Some(TxOut {
+        value,
+        script_pubkey: ScriptBuf::from(script),
+    })
+}
+
+fn spender_block(chain: &Chain, spender: Option<&Spender>) -> Option<BlockHash> {
+    spender
+        .filter(|spender| spender.is_confirmed())
+        .and_then(|spender| chain.get_block_hash(spender.height()))
+}
+
 fn notification(method: &str, params: &[Value]) -> Value {
     json!({"jsonrpc": "2.0", "method": method, "params": params})
 }
@@ -705,23 +2522,73 @@ fn error_msg_no_id(err: StandardError) -> Value {
 }
 
 fn parse_requests(line: &str) -> Result<Requests, StandardError> {
+    if json_depth(line) > MAX_JSON_DEPTH {
+        warn!("too deep JSON ({:?})", truncated(line));
+        return Err(StandardError::InvalidRequest);
+    }
     match serde_json::from_str(line) {
         // parse JSON from str
         Ok(value) => match serde_json::from_value(value) {
             // parse RPC from JSON
+            Ok(Requests::Batch(batch)) if batch.len() > MAX_BATCH_REQUESTS => {
+                warn!("too many batched requests: {}", batch.len());
+                Err(StandardError::InvalidRequest)
+            }
             Ok(requests) => Ok(requests),
             Err(err) => {
-                warn!("invalid RPC request ({:?}): {}", line, err);
+                warn!("invalid RPC request ({:?}): {}", truncated(line), err);
                 Err(StandardError::InvalidRequest)
             }
         },
         Err(err) => {
-            warn!("invalid JSON ({:?}): {}", line, err);
+            warn!("invalid JSON ({:?}): {}", truncated(line), err);
             Err(StandardError::ParseError)
         }
     }
 }
 
+/// Maximal nesting of JSON arrays and objects (ignoring brackets within strings),
+/// checked before parsing, since untrusted input may be nested arbitrarily deep.
+fn json_depth(line: &str) -> usize {
+    let (mut depth, mut max_depth) = (0usize, 0usize);
+    let (mut in_string, mut escaped) = (false, false);
+    for byte in line.bytes() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+    max_depth
+}
+
+/// Avoid logging huge (possibly malicious) requests.
+fn truncated(line: &str) -> &str {
+    match line.char_indices().nth(MAX_LOGGED_REQUEST_LEN) {
+        Some((end, _)) => &line[..end],
+        None => line,
+    }
+}
+
+/// Used by the fuzz targets (see `fuzz/`): requests' parsing must never panic.
+#[cfg(feature = "fuzzing")]
+pub(crate) fn fuzz_parse_requests(line: &str) {
+    let _ = parse_requests(line).map(Calls::parse);
+}
+
 fn parse_version(version: &str) -> Result<Version> {
     let result = version
         .split('.')
@@ -760,7 +2627,73 @@ fn check_between(version_str: &str, min_str: &str, max_str: &str) -> Result<()>
 
 #[cfg(test)]
 mod tests {
-    use super::{check_between, parse_version, Version};
+    use super::{
+        check_between, json_depth, parse_requests, parse_version, shard_of, truncated,
+        user_agent_family, HistoryArgs, Requests, Version,
+    };
+    use crate::types::ScriptHash;
+    use bitcoin::hashes::Hash;
+    use serde_json::json;
+    use std::ops::RangeInclusive;
+
+    #[test]
+    fn test_shard_of() {
+        let mut bytes = [0xff; 32];
+        bytes[..8].copy_from_slice(&10u64.to_be_bytes());
+        let scripthash = ScriptHash::from_byte_array(bytes);
+        assert_eq!(shard_of(&scripthash, 1), 0);
+        assert_eq!(shard_of(&scripthash, 4), 2);
+        assert_eq!(shard_of(&scripthash, 16), 10);
+    }
+
+    #[test]
+    fn test_history_args() {
+        let scripthash = ScriptHash::from_byte_array([1; 32]);
+        let parse = |params| -> (ScriptHash, Option<RangeInclusive<usize>>) {
+            let args: HistoryArgs = serde_json::from_value(params).unwrap();
+            (&args).into()
+        };
+        assert_eq!(parse(json!([scripthash])), (scripthash, None));
+        assert_eq!(
+            parse(json!([scripthash, 100])),
+            (scripthash, Some(100..=usize::MAX))
+        );
+        assert_eq!(
+            parse(json!([scripthash, 100, 200])),
+            (scripthash, Some(100..=200))
+        );
+        assert!(serde_json::from_value::<HistoryArgs>(json!([scripthash, "100"])).is_err());
+    }
+
+    #[test]
+    fn test_user_agent_family() {
+        assert_eq!(user_agent_family("electrum/4.5.4"), "electrum");
+        assert_eq!(user_agent_family("Sparrow 1.8.1"), "sparrow");
+        assert_eq!(user_agent_family("bdk_electrum"), "bdk_electrum");
+        assert_eq!(user_agent_family(""), "unknown");
+        assert_eq!(user_agent_family("\"x\""), "unknown");
+        assert_eq!(user_agent_family(&"a".repeat(100)), "a".repeat(16));
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        assert_eq!(json_depth(r#"{"id": 1, "params": []}"#), 2);
+        assert_eq!(json_depth(r#"{"params": ["[[[{{{", "\\\"]]]"]}"#), 2); // brackets in strings
+        assert_eq!(json_depth("]]]["), 1);
+
+        let deep = format!("{}{}", "[".repeat(1000), "]".repeat(1000));
+        assert!(parse_requests(&deep).is_err());
+
+        let request = r#"{"id": 1, "method": "server.ping", "params": []}"#;
+        assert!(matches!(parse_requests(request), Ok(Requests::Single(_))));
+        let batch = format!("[{}]", vec![request; 10_001].join(","));
+        assert!(parse_requests(&batch).is_err());
+        let batch = format!("[{}]", vec![request; 10].join(","));
+        assert!(matches!(parse_requests(&batch), Ok(Requests::Batch(b)) if b.len() == 10));
+
+        assert_eq!(truncated(&"€".repeat(300)), "€".repeat(200));
+        assert_eq!(truncated("short"), "short");
+    }
 
     #[test]
     fn test_version() {
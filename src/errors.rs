@@ -0,0 +1,128 @@
+use std::fmt;
+
+use crate::daemon::{self, extract_bitcoind_error};
+
+// bitcoind's RPC_INVALID_ADDRESS_OR_KEY (e.g. "No such mempool or blockchain transaction")
+const BITCOIND_NOT_FOUND: i32 = -5;
+
+/// Stable error codes, so clients can handle specific failures without parsing the messages.
+/// Returned by the Electrum RPC (as the error's `data.code`), and by the REST API
+/// (as the `X-Error-Code` header).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorCode {
+    InvalidRequest,
+    MethodNotFound,
+    InvalidParams,
+    NotFound,
+    NotIndexed,
+    LookupLimitExceeded,
+    IndexSyncing,
+    DaemonUnreachable,
+    DaemonError,
+    Unauthenticated,
+    PrivateMethod,
+    Unavailable,
+    Timeout,
+    Internal, // any other failure
+}
+
+impl ErrorCode {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::MethodNotFound => "method_not_found",
+            ErrorCode::InvalidParams => "invalid_params",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::NotIndexed => "not_indexed",
+            ErrorCode::LookupLimitExceeded => "lookup_limit_exceeded",
+            ErrorCode::IndexSyncing => "index_syncing",
+            ErrorCode::DaemonUnreachable => "daemon_unreachable",
+            ErrorCode::DaemonError => "daemon_error",
+            ErrorCode::Unauthenticated => "unauthenticated",
+            ErrorCode::PrivateMethod => "private_method",
+            ErrorCode::Unavailable => "unavailable",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::Internal => "internal_error",
+        }
+    }
+
+    #[cfg(feature = "rest")]
+    pub(crate) fn http_status(&self) -> u16 {
+        match self {
+            ErrorCode::InvalidRequest => 405, // only GET requests are supported
+            ErrorCode::MethodNotFound | ErrorCode::NotFound | ErrorCode::NotIndexed => 404,
+            ErrorCode::InvalidParams | ErrorCode::LookupLimitExceeded => 400,
+            ErrorCode::IndexSyncing | ErrorCode::Unavailable => 503,
+            ErrorCode::DaemonUnreachable | ErrorCode::DaemonError => 502,
+            ErrorCode::Unauthenticated => 401,
+            ErrorCode::PrivateMethod => 403,
+            ErrorCode::Timeout => 504,
+            ErrorCode::Internal => 500,
+        }
+    }
+
+    pub(crate) fn of_daemon_error(err: &daemon::RpcError) -> Self {
+        if err.code == BITCOIND_NOT_FOUND {
+            ErrorCode::NotFound
+        } else {
+            ErrorCode::DaemonError
+        }
+    }
+
+    /// Classify an error by its coded cause (or by its bitcoind RPC failure, if any).
+    pub(crate) fn of(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if let Some(coded) = cause.downcast_ref::<CodedError>() {
+                return coded.code;
+            }
+            if let Some(rpc_err) = cause.downcast_ref::<bitcoincore_rpc::Error>() {
+                return match extract_bitcoind_error(rpc_err) {
+                    Some(e) => Self::of_daemon_error(e),
+                    None => ErrorCode::DaemonUnreachable,
+                };
+            }
+        }
+        ErrorCode::Internal
+    }
+}
+
+/// A failure having a specific error code (may be wrapped by additional context).
+#[derive(Debug)]
+pub(crate) struct CodedError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl CodedError {
+    pub(crate) fn new(code: ErrorCode, message: String) -> anyhow::Error {
+        anyhow::Error::new(Self { code, message })
+    }
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{CodedError, ErrorCode};
+    use anyhow::Context;
+
+    #[test]
+    fn test_error_code() {
+        let err = CodedError::new(ErrorCode::NotFound, "missing block at 10".to_owned());
+        assert_eq!(ErrorCode::of(&err), ErrorCode::NotFound);
+        assert_eq!(err.to_string(), "missing block at 10");
+
+        let err = Err::<(), _>(err)
+            .context("failed to get block")
+            .unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::NotFound); // context is ignored
+
+        assert_eq!(ErrorCode::of(&anyhow!("oops")), ErrorCode::Internal);
+    }
+}
@@ -0,0 +1,17 @@
+//! Entry points for the fuzz targets (see `fuzz/`), exercising the parsers of untrusted
+//! client input. They must never panic, regardless of the input.
+
+use crate::{electrum, proxy, server};
+
+/// Parse newline-separated Electrum requests, as received from a client connection.
+pub fn electrum_requests(data: &[u8]) {
+    let mut reader = data;
+    while let Ok(Some(line)) = server::read_line(&mut reader, server::MAX_LINE_SIZE) {
+        electrum::fuzz_parse_requests(&line);
+    }
+}
+
+/// Parse a PROXY protocol (v1 or v2) header.
+pub fn proxy_header(data: &[u8]) {
+    let _ = proxy::read_header(&mut &data[..]);
+}
@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// A plain HTTP endpoint (TLS is not supported), used for pushing metrics and webhooks
+/// (and for fetching blocks via bitcoind REST API).
+pub(crate) struct HttpTarget {
+    addr: String,
+    path: String,
+}
+
+impl HttpTarget {
+    pub(crate) fn parse(url: &str) -> Result<Self> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => bail!("unsupported URL {:?} (must be 'http://...')", url),
+        };
+        let (authority, path) = match rest.split_once('/') {
+            Some((authority, path)) => (authority, format!("/{}", path)),
+            None => (rest, "/".to_owned()),
+        };
+        ensure!(!authority.is_empty(), "missing host in URL {:?}", url);
+        let addr = if authority.ends_with(']') || !authority.contains(':') {
+            format!("{}:80", authority) // no explicit port
+        } else {
+            authority.to_owned()
+        };
+        Ok(Self { addr, path })
+    }
+
+    pub(crate) fn with_path(&self, path: &str) -> Self {
+        Self {
+            addr: self.addr.clone(),
+            path: path.to_owned(),
+        }
+    }
+
+    /// Send a request (e.g. "PUT"), and check that its response status is successful.
+    pub(crate) fn send(
+        &self,
+        method: &str,
+        content_type: &str,
+        body: &[u8],
+        timeout: Duration,
+    ) -> Result<()> {
+        self.request(method, content_type, body, timeout)?;
+        Ok(())
+    }
+
+    /// Send a "GET" request, and return its response body (read until the connection is closed).
+    pub(crate) fn get(&self, timeout: Duration) -> Result<Vec<u8>> {
+        let mut reader = self.request("GET", "text/plain", &[], timeout)?;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader
+                .read_line(&mut line)
+                .context("failed to read response headers")?;
+            if line.trim_end().is_empty() {
+                break; // end of headers (or of the response)
+            }
+        }
+        let mut body = vec![];
+        reader
+            .read_to_end(&mut body)
+            .context("failed to read response body")?;
+        Ok(body)
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        content_type: &str,
+        body: &[u8],
+        timeout: Duration,
+    ) -> Result<BufReader<TcpStream>> {
+        let mut stream = TcpStream::connect(&self.addr).context("failed to connect")?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            self.path,
+            self.addr,
+            content_type,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader
+            .read_line(&mut status_line)
+            .context("failed to read response")?;
+        let status_line = status_line.trim_end();
+        match status_line.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(reader),
+            _ => bail!("unexpected response: {:?}", status_line),
+        }
+    }
+}
+
+impl std::fmt::Display for HttpTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}{}", self.addr, self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HttpTarget;
+
+    #[test]
+    fn test_parse() {
+        let target = HttpTarget::parse("http://localhost:9091/metrics/job/electrs").unwrap();
+        assert_eq!(target.addr, "localhost:9091");
+        assert_eq!(target.path, "/metrics/job/electrs");
+
+        let target = HttpTarget::parse("http://[::1]").unwrap();
+        assert_eq!(target.addr, "[::1]:80");
+        assert_eq!(target.path, "/");
+
+        assert!(HttpTarget::parse("https://localhost:9091/").is_err());
+        assert!(HttpTarget::parse("http:///metrics").is_err());
+    }
+}
@@ -2,26 +2,113 @@ use anyhow::{Context, Result};
 use bitcoin::consensus::{deserialize, serialize, Decodable};
 use bitcoin::{BlockHash, OutPoint, Txid};
 use bitcoin_slices::{bsl, Visit, Visitor};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use parking_lot::Mutex;
+use rayon::prelude::*;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::{
-    chain::{Chain, NewHeader},
-    daemon::Daemon,
-    db::{DBStore, Row, WriteBatch},
-    metrics::{self, Gauge, Histogram, Metrics},
+    chain::{Chain, NewHeader, Reorg},
+    daemon::BlockSource,
+    db::{DBStore, OptionalIndexes, Row, Usage, WriteBatch},
+    disk,
+    errors::{CodedError, ErrorCode},
+    metrics::{self, Counter, Gauge, Histogram, Metrics},
+    scan_cache::{ScanCache, ScanKind},
     signals::ExitFlag,
+    thread::spawn,
+    throttle::Throttle,
+    trace::Deadline,
     types::{
-        bsl_txid, HashPrefixRow, HeaderRow, ScriptHash, ScriptHashRow, SerBlock, SpendingPrefixRow,
-        TxidRow,
+        bsl_txid, CoinbaseRow, FeeratesRow, HashPrefixRow, HeaderRow, ScriptHash, ScriptHashRow,
+        ScriptPrefixRow, ScriptType, SerBlock, SpendingPrefixRow, TouchedRow, TxidRow,
     },
 };
 
+// Used for estimating the required disk space (before enough blocks are indexed)
+const DEFAULT_BYTES_PER_BLOCK: u64 = 64 << 10; // rough average over mainnet history
+const MIN_ESTIMATE_HEIGHT: usize = 10_000;
+const HEADER_ROW_BYTES: u64 = 80;
+
+// Bounds the number of blocks per batch, when it is adapted to their sizes
+const MAX_ADAPTIVE_BATCH_SIZE: usize = 10_000;
+
+// The recent blocks' touched prefixes are stored (after the initial sync), for skipping
+// the re-sync of statuses which are not affected by the new blocks
+const MAX_TOUCHED_BLOCKS: usize = 100;
+
+// The last headers of the persisted snapshot are re-hashed on startup (the rest are linked by
+// their `prev_blockhash`), and the new tip is then re-synced against the daemon
+const SNAPSHOT_VERIFIED_HEADERS: usize = 2016;
+
+// Indexing phases (by their duration label), and what they are usually bound by
+const PROFILE_PHASES: &[(&str, &str, &str)] = &[
+    ("headers", "headers", "bitcoind"),
+    ("fetch", "block fetch", "bitcoind"),
+    ("feerates", "feerates", "bitcoind"),
+    ("block", "parse", "CPU"),
+    ("sort", "sort", "CPU"),
+    ("write", "write", "disk"),
+    ("flush", "compaction", "disk"),
+];
+
+/// Total time spent in each indexing phase (see `profile_sync`).
+#[derive(Default)]
+struct SyncProfile {
+    phases: Mutex<HashMap<&'static str, (Duration, usize)>>, // label -> (total, count)
+}
+
+impl SyncProfile {
+    fn add(&self, label: &'static str, duration: Duration) {
+        let mut phases = self.phases.lock();
+        let (total, count) = phases.entry(label).or_default();
+        *total += duration;
+        *count += 1;
+    }
+
+    /// Fetching, parsing and writing are concurrent, so their sum may exceed the elapsed time.
+    fn log_summary(&self, blocks: usize, elapsed: Duration) {
+        info!(
+            "sync profile: {} blocks indexed in {:.1}s",
+            blocks,
+            elapsed.as_secs_f64()
+        );
+        info!(
+            "{:<12} {:>12} {:>8} {:>14}  {}",
+            "phase", "total [s]", "share", "average [ms]", "bound by"
+        );
+        let phases = self.phases.lock();
+        for (label, name, bound_by) in PROFILE_PHASES {
+            let (total, count) = phases.get(label).copied().unwrap_or_default();
+            info!(
+                "{:<12} {:>12.1} {:>7.1}% {:>14.1}  {}",
+                name,
+                total.as_secs_f64(),
+                100.0 * total.as_secs_f64() / elapsed.as_secs_f64().max(1e-9),
+                1e3 * total.as_secs_f64() / count.max(1) as f64,
+                bound_by
+            );
+        }
+    }
+}
+
 #[derive(Clone)]
 struct Stats {
     update_duration: Histogram,
     update_size: Histogram,
     height: Gauge,
     db_properties: Gauge,
+    script_types: Gauge,
+    lookup_blocks: Counter,
+    skipped_rows: Counter,
+    full_compaction: Gauge,
+    profile: Option<Arc<SyncProfile>>,
 }
 
 impl Stats {
@@ -41,11 +128,47 @@ impl Stats {
             ),
             height: metrics.gauge("index_height", "Indexed block height", "type"),
             db_properties: metrics.gauge("index_db_properties", "Index DB properties", "name"),
+            script_types: metrics.gauge(
+                "index_script_types",
+                "# of indexed outputs by script type",
+                "type",
+            ),
+            lookup_blocks: metrics.counter(
+                "index_lookup_blocks",
+                "# of candidate blocks fetched by index lookups (and how many were false positives)",
+                "type",
+            ),
+            skipped_rows: metrics.counter(
+                "index_skipped_rows",
+                "# of blocks whose optional rows were skipped (e.g. due to missing undo data)",
+                "type",
+            ),
+            full_compaction: metrics.gauge(
+                "index_full_compaction",
+                "# of column families compacted by the last full compaction (and their total)",
+                "type",
+            ),
+            profile: None,
         }
     }
 
-    fn observe_duration<T>(&self, label: &str, f: impl FnOnce() -> T) -> T {
-        self.update_duration.observe_duration(label, f)
+    fn observe_script_types(&self, script_stats: &ScriptTypeStats) {
+        for script_type in ScriptType::ALL {
+            let count = script_stats.counts.get(&script_type).copied().unwrap_or(0);
+            self.script_types.set(script_type.as_str(), count as f64);
+        }
+    }
+
+    fn observe_duration<T>(&self, label: &'static str, f: impl FnOnce() -> T) -> T {
+        match &self.profile {
+            None => self.update_duration.observe_duration(label, f),
+            Some(profile) => {
+                let start = Instant::now();
+                let result = self.update_duration.observe_duration(label, f);
+                profile.add(label, start.elapsed());
+                result
+            }
+        }
     }
 
     fn observe_size(&self, label: &str, rows: &[Row]) {
@@ -56,6 +179,9 @@ impl Stats {
         self.observe_size("write_funding_rows", &batch.funding_rows);
         self.observe_size("write_spending_rows", &batch.spending_rows);
         self.observe_size("write_txid_rows", &batch.txid_rows);
+        self.observe_size("write_script_prefix_rows", &batch.script_prefix_rows);
+        self.observe_size("write_coinbase_rows", &batch.coinbase_rows);
+        self.observe_size("write_feerates_rows", &batch.feerates_rows);
         self.observe_size("write_header_rows", &batch.header_rows);
         debug!(
             "writing {} funding and {} spending rows from {} transactions, {} blocks",
@@ -75,18 +201,143 @@ impl Stats {
             self.db_properties
                 .set(&format!("{}:{}", name, cf), value as f64);
         }
+        for (cf, level, files) in store.get_level_files() {
+            self.db_properties.set(
+                &format!("rocksdb.num-files-at-level{}:{}", level, cf),
+                files as f64,
+            );
+        }
+    }
+}
+
+/// Index parameters which may be tuned at runtime (see `Index::tune`).
+/// Tuned values are persisted in the DB, and override the command-line ones after a restart.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Tuning {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    batch_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lookup_limit: Option<usize>, // 0 means no limit
+}
+
+/// A detected reorg, persisted in the DB log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ReorgEvent {
+    height: usize, // first replaced height
+    depth: usize,  // # of replaced blocks
+    old_tip: BlockHash,
+    new_tip: BlockHash,
+    new_height: usize,
+    detected_at: u64, // UNIX timestamp
+}
+
+/// Indexing progress (reported to clients until the index is ready)
+#[derive(Debug, Serialize)]
+pub(crate) struct SyncProgress {
+    height: usize,
+    target_height: usize,
+    eta: Option<u64>, // estimated seconds until the target height is indexed
+}
+
+impl std::fmt::Display for SyncProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "height {} of {}", self.height, self.target_height)?;
+        if let Some(eta) = self.eta {
+            write!(f, ", ETA {}s", eta)?;
+        }
+        Ok(())
+    }
+}
+
+/// Cumulative counts of indexed outputs by their script type.
+/// The recent blocks' counts are kept, so they are not counted twice when these blocks are
+/// re-indexed (e.g. after a reorg).
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ScriptTypeStats {
+    since_height: Option<usize>, // when counting has started (may be after the DB was created)
+    counts: BTreeMap<ScriptType, u64>,
+    #[serde(default)]
+    recent: BTreeMap<usize, BTreeMap<ScriptType, u64>>, // the last blocks' counts, by height
+}
+
+impl ScriptTypeStats {
+    fn add(&mut self, height: usize, counts: BTreeMap<ScriptType, u64>) {
+        self.since_height.get_or_insert(height);
+        // the block at this height was indexed before (and may be reorged out)
+        for (script_type, count) in self.recent.remove(&height).unwrap_or_default() {
+            let total = self.counts.entry(script_type).or_default();
+            *total = total.saturating_sub(count);
+        }
+        for (script_type, count) in &counts {
+            *self.counts.entry(*script_type).or_default() += count;
+        }
+        self.recent.insert(height, counts);
+        while self.recent.len() > MAX_TOUCHED_BLOCKS {
+            let oldest = *self.recent.keys().next().expect("empty recent blocks");
+            self.recent.remove(&oldest);
+        }
+    }
+
+    /// Returned by `blockchain.scripttypes.get_stats` RPC (without the per-block counts).
+    pub(crate) fn summary(&self) -> Value {
+        json!({"since_height": self.since_height, "counts": self.counts})
+    }
+}
+
+/// The funded scripthashes' and the spent outpoints' prefixes of a block (as indexed).
+/// May have false positives (due to prefix collisions), but no false negatives.
+#[derive(Debug, Default)]
+pub(crate) struct TouchedPrefixes {
+    funding: HashSet<Row>,
+    spending: HashSet<Row>,
+}
+
+impl TouchedPrefixes {
+    fn new(row: &TouchedRow) -> Self {
+        Self {
+            funding: row.funding().map(Row::from).collect(),
+            spending: row.spending().map(Row::from).collect(),
+        }
+    }
+
+    pub(crate) fn funds(&self, scripthash: ScriptHash) -> bool {
+        self.funding
+            .contains(&ScriptHashRow::scan_prefix(scripthash))
+    }
+
+    pub(crate) fn spends(&self, outpoint: OutPoint) -> bool {
+        self.spending
+            .contains(&SpendingPrefixRow::scan_prefix(outpoint))
     }
 }
 
 /// Confirmed transactions' address index
 pub struct Index {
-    store: DBStore,
-    batch_size: usize,
-    lookup_limit: Option<usize>,
+    store: Arc<DBStore>,         // shared with the batch writer thread (during sync)
+    compacting: Arc<AtomicBool>, // a manual compaction is running in the background
+    batch_size: AtomicUsize,
+    batch_bytes: Option<usize>, // if set, the batch size is adapted to the blocks' sizes
+    avg_block_bytes: Option<f64>, // moving average of the recently indexed blocks' sizes
+    lookup_limit: AtomicUsize,  // 0 means no limit
+    tuning: Mutex<Tuning>,      // persisted overrides
     chain: Chain,
     stats: Stats,
     is_ready: bool,
     flush_needed: bool,
+    flush_eagerly: bool, // flush after each sync, instead of only when it is done
+    manifest_path: Option<PathBuf>, // written after each flush
+    min_free_space: Option<u64>, // sync is stopped before the disk gets full
+    throttle: Throttle,
+    rows_scanned: AtomicU64,
+    scan_cache: ScanCache,
+    script_stats: ScriptTypeStats,
+    script_types: Option<Vec<ScriptType>>, // funding rows are indexed only for these types
+    optional: OptionalIndexes,
+    reorgs: Vec<ReorgEvent>,              // ordered by detection
+    target_height: usize,                 // the highest known header
+    sync_start: Option<(Instant, usize)>, // when (and from which height) indexing has started
+    write_stalled: bool,                  // RocksDB compactions are falling behind writes
+    touched: BTreeMap<usize, (BlockHash, TouchedPrefixes)>, // the recent blocks, by height
 }
 
 impl Index {
@@ -97,81 +348,442 @@ impl Index {
         batch_size: usize,
         lookup_limit: Option<usize>,
         reindex_last_blocks: usize,
+        script_types: Option<Vec<ScriptType>>,
     ) -> Result<Self> {
         if let Some(row) = store.get_tip() {
             let tip = deserialize(&row).expect("invalid tip");
-            let headers = store
-                .read_headers()
-                .into_iter()
-                .map(|row| HeaderRow::from_db_row(&row).header)
-                .collect();
-            chain.load(headers, tip);
+            let loaded = store.get_headers_snapshot().map_or(false, |snapshot| {
+                chain.load_snapshot(&snapshot, tip, SNAPSHOT_VERIFIED_HEADERS)
+            });
+            if !loaded {
+                let headers = store
+                    .read_headers()
+                    .into_iter()
+                    .map(|row| HeaderRow::from_db_row(&row).header)
+                    .collect();
+                chain.load(headers, tip);
+            }
             chain.drop_last_headers(reindex_last_blocks);
         };
+        let touched = load_touched(&store, &chain);
+        let script_stats: ScriptTypeStats = store
+            .get_script_stats()
+            .map(|row| serde_json::from_slice(&row).expect("invalid script stats"))
+            .unwrap_or_default();
         let stats = Stats::new(metrics);
         stats.observe_chain(&chain);
         stats.observe_db(&store);
+        stats.observe_script_types(&script_stats);
+        log_usage(&store.usage(chain.height()));
+        let tuning: Tuning = store
+            .get_tuning()
+            .map(|row| serde_json::from_slice(&row).expect("invalid index tuning"))
+            .unwrap_or_default();
+        if tuning != Tuning::default() {
+            info!("using index parameters tuned at runtime: {:?}", tuning);
+        }
+        let reorgs: Vec<ReorgEvent> = store
+            .read_reorgs()
+            .iter()
+            .map(|value| serde_json::from_slice(value).expect("invalid reorg event"))
+            .collect();
         Ok(Index {
-            store,
-            batch_size,
-            lookup_limit,
+            store: Arc::new(store),
+            compacting: Arc::new(AtomicBool::new(false)),
+            batch_size: AtomicUsize::new(tuning.batch_size.unwrap_or(batch_size)),
+            batch_bytes: None,
+            avg_block_bytes: None,
+            lookup_limit: AtomicUsize::new(tuning.lookup_limit.or(lookup_limit).unwrap_or(0)),
+            tuning: Mutex::new(tuning),
             chain,
             stats,
             is_ready: false,
             flush_needed: false,
+            flush_eagerly: false,
+            manifest_path: None,
+            min_free_space: None,
+            throttle: Throttle::new(None),
+            rows_scanned: AtomicU64::new(0),
+            scan_cache: ScanCache::new(metrics, 0),
+            script_stats,
+            script_types,
+            optional: OptionalIndexes::default(),
+            reorgs,
+            target_height: 0,
+            sync_start: None,
+            write_stalled: false,
+            touched,
         })
     }
 
+    /// Make the DB state deterministic after each sync (used for integration testing).
+    pub(crate) fn set_flush_eagerly(&mut self, flush_eagerly: bool) {
+        self.flush_eagerly = flush_eagerly;
+    }
+
+    pub(crate) fn set_manifest_path(&mut self, manifest_path: Option<PathBuf>) {
+        self.manifest_path = manifest_path;
+    }
+
+    /// Must match the DB configuration (see `DBStore::open`).
+    pub(crate) fn set_optional_indexes(&mut self, optional: OptionalIndexes) {
+        self.optional = optional;
+    }
+
+    pub(crate) fn optional_indexes(&self) -> OptionalIndexes {
+        self.optional
+    }
+
+    /// Fail (instead of returning partial results) if the funding and spending rows
+    /// are not indexed at `height`.
+    pub(crate) fn check_indexed(&self, height: usize) -> Result<()> {
+        if height < self.optional.from_height {
+            return Err(CodedError::new(
+                ErrorCode::NotIndexed,
+                format!(
+                    "height {} is not indexed (index starts at height {})",
+                    height, self.optional.from_height
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The touched prefixes of the blocks following `blockhash` (in the current chain).
+    /// Returns `None` if `blockhash` is stale, or if some of these blocks are not tracked.
+    pub(crate) fn touched_since(&self, blockhash: &BlockHash) -> Option<Vec<&TouchedPrefixes>> {
+        let height = self.chain.get_block_height(blockhash)?;
+        (height + 1..=self.chain.height())
+            .map(|height| {
+                let (blockhash, touched) = self.touched.get(&height)?;
+                if Some(*blockhash) == self.chain.get_block_hash(height) {
+                    Some(touched)
+                } else {
+                    None // a reorged block
+                }
+            })
+            .collect()
+    }
+
+    /// Update the height to be reached by the initial sync (e.g. the daemon's headers count).
+    pub(crate) fn set_target_height(&mut self, target_height: usize) {
+        self.target_height = self.target_height.max(target_height);
+    }
+
+    pub(crate) fn set_min_free_space(&mut self, min_free_space: Option<u64>) {
+        self.min_free_space = min_free_space;
+    }
+
+    /// Update the given parameters (persisting them for the next restarts),
+    /// and return the current values of all of them.
+    pub(crate) fn tune(&self, update: Tuning) -> Result<Tuning> {
+        ensure!(update.batch_size != Some(0), "batch size must be positive");
+        let mut tuning = self.tuning.lock();
+        tuning.batch_size = update.batch_size.or(tuning.batch_size);
+        tuning.lookup_limit = update.lookup_limit.or(tuning.lookup_limit);
+        self.store
+            .set_tuning(&serde_json::to_vec(&*tuning).expect("failed to serialize index tuning"));
+        if let Some(batch_size) = update.batch_size {
+            self.batch_size.store(batch_size, Ordering::Relaxed);
+        }
+        if let Some(lookup_limit) = update.lookup_limit {
+            self.lookup_limit.store(lookup_limit, Ordering::Relaxed);
+        }
+        let current = self.tuning();
+        info!("index parameters tuned to {:?}", current);
+        Ok(current)
+    }
+
+    pub(crate) fn tuning(&self) -> Tuning {
+        Tuning {
+            batch_size: Some(self.batch_size.load(Ordering::Relaxed)),
+            lookup_limit: Some(self.lookup_limit.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub(crate) fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = throttle;
+    }
+
+    pub(crate) fn set_batch_bytes(&mut self, batch_bytes: Option<usize>) {
+        self.batch_bytes = batch_bytes;
+    }
+
+    // The first batch (before any block size is known) uses the configured number of blocks.
+    fn next_batch_size(&self) -> usize {
+        match (self.batch_bytes, self.avg_block_bytes) {
+            (Some(batch_bytes), Some(avg_block_bytes)) => {
+                adaptive_batch_size(batch_bytes, avg_block_bytes)
+            }
+            _ => self.batch_size.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Cache the index scans' heights, using up to `max_bytes` of memory (0 - disabled).
+    pub(crate) fn set_scan_cache_size(&mut self, max_bytes: usize) {
+        self.scan_cache.set_max_bytes(max_bytes);
+    }
+
+    pub(crate) fn set_profile_sync(&mut self, enabled: bool) {
+        self.stats.profile = if enabled { Some(Arc::default()) } else { None };
+    }
+
+    pub(crate) fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+
+    /// Estimate the disk space needed for indexing up to `target_height` (per phase),
+    /// and warn if it's not available.
+    pub(crate) fn preflight_disk_space(&self, target_height: usize) {
+        let height = self.chain.height();
+        let new_blocks = target_height.saturating_sub(height) as u64;
+        let db_bytes: u64 = self.db_usage().values().map(|usage| usage.disk_bytes).sum();
+        let bytes_per_block = if height >= MIN_ESTIMATE_HEIGHT {
+            db_bytes / height as u64
+        } else {
+            DEFAULT_BYTES_PER_BLOCK
+        };
+        let headers = new_blocks * HEADER_ROW_BYTES;
+        let index = new_blocks * bytes_per_block;
+        // full compaction (done after initial sync) may temporarily need as much as the whole DB
+        let compaction = if self.store.is_bulk_import() {
+            db_bytes + index
+        } else {
+            0
+        };
+        let required = headers + index + compaction + self.min_free_space.unwrap_or(0);
+        info!(
+            "indexing {} new blocks may require {} MB (headers: {} MB, index: {} MB, compaction: {} MB)",
+            new_blocks,
+            required >> 20,
+            headers >> 20,
+            index >> 20,
+            compaction >> 20
+        );
+        match disk::available_space(self.store.path()) {
+            Ok(available) if available < required => warn!(
+                "only {} MB are available for DB at {}, indexing may run out of disk space",
+                available >> 20,
+                self.store.path().display()
+            ),
+            Ok(_) => (),
+            Err(e) => warn!("failed to check available disk space: {:#}", e),
+        }
+    }
+
+    /// Re-index the last `n` blocks on the next sync.
+    pub(crate) fn reindex_last_blocks(&mut self, n: usize) {
+        info!("re-indexing last {} blocks", n);
+        self.chain.drop_last_headers(n);
+        self.stats.observe_chain(&self.chain);
+    }
+
+    pub(crate) fn db_usage(&self) -> BTreeMap<&'static str, Usage> {
+        self.store.usage(self.chain.height())
+    }
+
+    /// Persist the chain's headers, to be bulk-loaded on restart (instead of re-linking all
+    /// the header rows).
+    pub(crate) fn persist_headers(&self) {
+        let snapshot = self.chain.snapshot();
+        info!(
+            "persisting {} headers ({} bytes)",
+            self.chain.height(),
+            snapshot.len()
+        );
+        self.store.set_headers_snapshot(&snapshot);
+    }
+
+    pub(crate) fn store(&self) -> &DBStore {
+        &self.store
+    }
+
+    /// Start a full compaction in the background (it may take a few hours), reporting its
+    /// progress via the `index_full_compaction` gauge. Returns `false` if one is already running.
+    pub(crate) fn start_compaction(&self) -> bool {
+        if self.compacting.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        let store = Arc::clone(&self.store);
+        let compacting = Arc::clone(&self.compacting);
+        let full_compaction = self.stats.full_compaction.clone();
+        spawn("compaction", move || {
+            store.compact(|done, total| {
+                full_compaction.set("done", done as f64);
+                full_compaction.set("total", total as f64);
+            });
+            compacting.store(false, Ordering::SeqCst);
+            Ok(())
+        });
+        true
+    }
+
     pub(crate) fn chain(&self) -> &Chain {
         &self.chain
     }
 
     pub(crate) fn limit_result<T>(&self, entries: impl Iterator<Item = T>) -> Result<Vec<T>> {
         let mut entries = entries.fuse();
-        let result: Vec<T> = match self.lookup_limit {
-            Some(lookup_limit) => entries.by_ref().take(lookup_limit).collect(),
-            None => entries.by_ref().collect(),
+        let result: Vec<T> = match self.lookup_limit.load(Ordering::Relaxed) {
+            0 => entries.by_ref().collect(),
+            lookup_limit => entries.by_ref().take(lookup_limit).collect(),
         };
+        Deadline::check()?;
         if entries.next().is_some() {
-            bail!(">{} index entries, query may take too long", result.len())
+            let message = format!(">{} index entries, query may take too long", result.len());
+            return Err(CodedError::new(ErrorCode::LookupLimitExceeded, message));
         }
         Ok(result)
     }
 
-    pub(crate) fn filter_by_txid(&self, txid: Txid) -> impl Iterator<Item = BlockHash> + '_ {
-        self.store
-            .iter_txid(TxidRow::scan_prefix(txid))
-            .map(|row| HashPrefixRow::from_db_row(&row).height())
+    /// Keep the newest `lookup_limit` heights (instead of failing, like `limit_result`).
+    /// If some heights were dropped, the oldest kept one is returned as a continuation cursor.
+    pub(crate) fn limit_newest(&self, heights: BTreeSet<usize>) -> (Vec<usize>, Option<usize>) {
+        let lookup_limit = self.lookup_limit.load(Ordering::Relaxed);
+        if lookup_limit == 0 || heights.len() <= lookup_limit {
+            return (heights.into_iter().collect(), None);
+        }
+        let mut newest: Vec<usize> = heights.into_iter().rev().take(lookup_limit).collect();
+        newest.reverse();
+        let cursor = newest.first().copied();
+        (newest, cursor)
+    }
+
+    /// Count the blocks fetched for resolving a lookup (e.g. "funding"), and how many of them
+    /// turned out to be false positives - to guide the choice of block fetching method.
+    pub(crate) fn observe_lookup(&self, lookup: &str, candidates: usize, false_positives: usize) {
+        let stats = &self.stats.lookup_blocks;
+        stats.inc_by(&format!("{}_candidates", lookup), candidates as u64);
+        stats.inc_by(
+            &format!("{}_false_positives", lookup),
+            false_positives as u64,
+        );
+    }
+
+    /// Total number of index rows scanned by lookups (used for query cost accounting).
+    pub(crate) fn rows_scanned(&self) -> u64 {
+        self.rows_scanned.load(Ordering::Relaxed)
+    }
+
+    fn count_rows<'a, T: 'a>(
+        &'a self,
+        rows: impl Iterator<Item = T> + 'a,
+    ) -> impl Iterator<Item = T> + 'a {
+        rows.inspect(move |_| {
+            self.rows_scanned.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+
+    pub(crate) fn script_type_stats(&self) -> &ScriptTypeStats {
+        &self.script_stats
+    }
+
+    /// Heights of the rows starting with `prefix` (cached until the chain tip changes).
+    fn scan_heights<'a, I: Iterator<Item = Row> + 'a>(
+        &'a self,
+        kind: ScanKind,
+        prefix: Row,
+        iter: impl FnOnce(&'a DBStore, Row) -> I,
+    ) -> impl Iterator<Item = BlockHash> + 'a {
+        self.scan_cache
+            .scan(self.chain.tip(), kind, prefix, |prefix| {
+                self.count_rows(iter(&*self.store, prefix))
+                    .map(|row| HashPrefixRow::from_db_row(&row).height())
+            })
+            .take_while(|_| !Deadline::is_expired()) // a partial scan is not cached
             .filter_map(move |height| self.chain.get_block_hash(height))
     }
 
+    pub(crate) fn filter_by_txid(&self, txid: Txid) -> impl Iterator<Item = BlockHash> + '_ {
+        self.scan_heights(
+            ScanKind::Txid,
+            TxidRow::scan_prefix(txid),
+            DBStore::iter_txid,
+        )
+    }
+
     pub(crate) fn filter_by_funding(
         &self,
         scripthash: ScriptHash,
     ) -> impl Iterator<Item = BlockHash> + '_ {
-        self.store
-            .iter_funding(ScriptHashRow::scan_prefix(scripthash))
-            .map(|row| HashPrefixRow::from_db_row(&row).height())
-            .filter_map(move |height| self.chain.get_block_hash(height))
+        self.scan_heights(
+            ScanKind::Funding,
+            ScriptHashRow::scan_prefix(scripthash),
+            DBStore::iter_funding,
+        )
     }
 
     pub(crate) fn filter_by_spending(
         &self,
         outpoint: OutPoint,
     ) -> impl Iterator<Item = BlockHash> + '_ {
-        self.store
-            .iter_spending(SpendingPrefixRow::scan_prefix(outpoint))
+        self.scan_heights(
+            ScanKind::Spending,
+            SpendingPrefixRow::scan_prefix(outpoint),
+            DBStore::iter_spending,
+        )
+    }
+
+    /// Heights of the blocks having outputs whose script pubkey may start with `prefix`
+    /// (each height may be returned more than once).
+    pub(crate) fn filter_by_script_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> impl Iterator<Item = usize> + '_ {
+        let rows = self
+            .store
+            .iter_script_prefix(ScriptPrefixRow::scan_prefix(prefix));
+        self.count_rows(rows)
+            .take_while(|_| !Deadline::is_expired())
             .map(|row| HashPrefixRow::from_db_row(&row).height())
-            .filter_map(move |height| self.chain.get_block_hash(height))
+    }
+
+    /// Coinbase summaries of the current chain's blocks, starting from `start_height`.
+    pub(crate) fn coinbase_rows(
+        &self,
+        start_height: usize,
+    ) -> impl Iterator<Item = CoinbaseRow> + '_ {
+        let rows = self
+            .store
+            .iter_coinbase(CoinbaseRow::scan_start(start_height));
+        self.count_rows(rows)
+            .map(|row| CoinbaseRow::from_db_row(&row))
+            .filter(move |row| {
+                // skip stale rows (of blocks which were reorged out)
+                self.chain
+                    .get_block_hash(row.height())
+                    .map_or(false, |blockhash| row.is_in_block(&blockhash))
+            })
+    }
+
+    /// Feerate percentiles of the current chain's blocks, starting from `start_height`.
+    pub(crate) fn feerates_rows(
+        &self,
+        start_height: usize,
+    ) -> impl Iterator<Item = FeeratesRow> + '_ {
+        let rows = self
+            .store
+            .iter_feerates(FeeratesRow::scan_start(start_height));
+        self.count_rows(rows)
+            .map(|row| FeeratesRow::from_db_row(&row))
+            .filter(move |row| {
+                // skip stale rows (of blocks which were reorged out)
+                self.chain
+                    .get_block_hash(row.height())
+                    .map_or(false, |blockhash| row.is_in_block(&blockhash))
+            })
     }
 
     // Return `Ok(true)` when the chain is fully synced and the index is compacted.
-    pub(crate) fn sync(&mut self, daemon: &Daemon, exit_flag: &ExitFlag) -> Result<bool> {
+    pub(crate) fn sync(&mut self, daemon: &impl BlockSource, exit_flag: &ExitFlag) -> Result<bool> {
         let new_headers = self
             .stats
             .observe_duration("headers", || daemon.get_new_headers(&self.chain))?;
         match (new_headers.first(), new_headers.last()) {
             (Some(first), Some(last)) => {
+                self.set_target_height(last.height());
+                self.sync_start
+                    .get_or_insert((Instant::now(), self.chain.height()));
                 let count = new_headers.len();
                 info!(
                     "indexing {} blocks: [{}..{}]",
@@ -182,73 +794,398 @@ impl Index {
             }
             _ => {
                 if self.flush_needed {
-                    self.store.flush(); // full compaction is performed on the first flush call
-                    self.flush_needed = false;
+                    self.check_free_space()?;
+                    self.flush(); // full compaction is performed on the first flush call
+                }
+                if !self.is_ready {
+                    self.log_sync_profile();
                 }
                 self.is_ready = true;
                 return Ok(true); // no more blocks to index (done for now)
             }
         }
-        for chunk in new_headers.chunks(self.batch_size) {
+        // the next chunk is parsed while the previous batch is sorted and written
+        let (batch_send, batch_recv) = bounded::<(WriteBatch, bool)>(1);
+        let store = Arc::clone(&self.store);
+        let stats = self.stats.clone();
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(move || write_batches(&store, &stats, batch_recv));
+            let result = self.sync_chunks(daemon, &new_headers, exit_flag, &batch_send);
+            // wait for the pending batches, so the chain is updated only after they are written
+            drop(batch_send);
+            writer.join().expect("batch writer thread panicked");
+            result
+        })?;
+        self.stats.observe_db(&self.store);
+        self.check_write_stall();
+        if let Some(reorg) = self.chain.update(new_headers) {
+            self.log_reorg(reorg);
+        }
+        self.stats.observe_chain(&self.chain);
+        self.flush_needed = true;
+        if self.flush_eagerly {
+            self.flush();
+        }
+        Ok(false) // sync is not done
+    }
+
+    fn log_reorg(&mut self, reorg: Reorg) {
+        let event = ReorgEvent {
+            height: reorg.height,
+            depth: reorg.depth,
+            old_tip: reorg.old_tip,
+            new_tip: self.chain.tip(),
+            new_height: self.chain.height(),
+            detected_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+        };
+        warn!("reorg detected: {:?}", event);
+        let value = serde_json::to_vec(&event).expect("failed to serialize reorg event");
+        self.store.add_reorg(self.reorgs.len() as u64, &value);
+        self.reorgs.push(event);
+    }
+
+    /// All the logged reorgs, ordered by detection.
+    pub(crate) fn reorgs(&self) -> &[ReorgEvent] {
+        &self.reorgs
+    }
+
+    // Stop syncing cleanly (with a clear error) instead of failing mid-write or mid-compaction.
+    fn check_free_space(&self) -> Result<()> {
+        let min_free_space = match self.min_free_space {
+            Some(min_free_space) => min_free_space,
+            None => return Ok(()),
+        };
+        let path = self.store.path();
+        let available = match disk::available_space(path) {
+            Ok(available) => available,
+            Err(e) => {
+                debug!("skipping free space check: {:#}", e);
+                return Ok(());
+            }
+        };
+        ensure!(
+            available >= min_free_space,
+            "low disk space: only {} MB available for DB at {} (minimum is {} MB)",
+            available >> 20,
+            path.display(),
+            min_free_space >> 20
+        );
+        Ok(())
+    }
+
+    fn check_write_stall(&mut self) {
+        let stalled = self.store.is_write_stalled();
+        if stalled && !self.write_stalled {
+            warn!("DB writes are stalled, since compactions are falling behind them");
+        } else if !stalled && self.write_stalled {
+            info!("DB writes are not stalled anymore");
+        }
+        self.write_stalled = stalled;
+    }
+
+    // The profile is logged once (after the initial sync), and then discarded.
+    fn log_sync_profile(&mut self) {
+        if let (Some(profile), Some((start, start_height))) =
+            (self.stats.profile.take(), self.sync_start)
+        {
+            let blocks = self.chain.height().saturating_sub(start_height);
+            profile.log_summary(blocks, start.elapsed());
+        }
+    }
+
+    fn flush(&mut self) {
+        let full_compaction = &self.stats.full_compaction;
+        self.stats.observe_duration("flush", || {
+            self.store.flush_observed(|done, total| {
+                full_compaction.set("done", done as f64);
+                full_compaction.set("total", total as f64);
+            })
+        });
+        self.flush_needed = false;
+        if let Some(path) = &self.manifest_path {
+            let result = self
+                .stats
+                .observe_duration("manifest", || self.write_manifest(path));
+            if let Err(e) = result {
+                warn!("failed to write manifest to {}: {:#}", path.display(), e);
+            }
+        }
+    }
+
+    fn write_manifest(&self, path: &Path) -> Result<()> {
+        let manifest = json!({
+            "height": self.chain.height(),
+            "tip": self.chain.tip(),
+            "column_families": self.store.digests(),
+        });
+        // replace the previous manifest atomically
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&manifest)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        info!(
+            "index manifest written to {} at height {}",
+            path.display(),
+            self.chain.height()
+        );
+        Ok(())
+    }
+
+    fn sync_chunks(
+        &mut self,
+        daemon: &impl BlockSource,
+        new_headers: &[NewHeader],
+        exit_flag: &ExitFlag,
+        batch_send: &Sender<(WriteBatch, bool)>,
+    ) -> Result<()> {
+        let mut rest = new_headers;
+        while !rest.is_empty() {
+            let (chunk, next) = rest.split_at(self.next_batch_size().min(rest.len()));
+            rest = next;
             exit_flag.poll().with_context(|| {
                 format!(
                     "indexing interrupted at height: {}",
                     chunk.first().unwrap().height()
                 )
             })?;
-            self.sync_blocks(daemon, chunk)?;
+            self.check_free_space()?;
+            let batch = self.sync_blocks(daemon, chunk)?;
+            // group commit: the WAL is synced only once, after the last batch is written
+            batch_send
+                .send((batch, rest.is_empty()))
+                .expect("batch writer thread failed");
         }
-        self.chain.update(new_headers);
-        self.stats.observe_chain(&self.chain);
-        self.flush_needed = true;
-        Ok(false) // sync is not done
+        Ok(())
     }
 
-    fn sync_blocks(&mut self, daemon: &Daemon, chunk: &[NewHeader]) -> Result<()> {
+    fn sync_blocks(
+        &mut self,
+        daemon: &impl BlockSource,
+        chunk: &[NewHeader],
+    ) -> Result<WriteBatch> {
         let blockhashes: Vec<BlockHash> = chunk.iter().map(|h| h.hash()).collect();
-        let mut heights = chunk.iter().map(|h| h.height());
+        // blocks may be received out of order (if some of them are being fetched concurrently)
+        let mut heights: HashMap<BlockHash, usize> =
+            chunk.iter().map(|h| (h.hash(), h.height())).collect();
 
         let mut batch = WriteBatch::default();
 
+        if self.optional.feerates {
+            // computed by bitcoind (since the spent outputs are not available here)
+            let results: Vec<(&NewHeader, Result<[u64; 5]>)> = chunk
+                .par_iter()
+                .map(|header| {
+                    let result = self.stats.observe_duration("feerates", || {
+                        daemon.get_feerate_percentiles(header.hash())
+                    });
+                    (header, result)
+                })
+                .collect();
+            for (header, result) in results {
+                match result {
+                    Ok(percentiles) => {
+                        let row = FeeratesRow::new(header.height(), header.hash(), percentiles);
+                        batch.feerates_rows.push(row.to_db_row());
+                    }
+                    // the sync is retried after bitcoind becomes reachable again
+                    Err(e) if ErrorCode::of(&e) == ErrorCode::DaemonUnreachable => return Err(e),
+                    // e.g. the block's undo data is missing (since it was pruned)
+                    Err(e) => {
+                        warn!("skipping block {} feerates: {:#}", header.hash(), e);
+                        self.stats.skipped_rows.inc("feerates");
+                    }
+                }
+            }
+        }
+
+        let fetch_start = Instant::now();
+        let mut parse_duration = Duration::ZERO;
+        let mut chunk_bytes = 0;
+        let mut touched = vec![];
+        let mut script_types = vec![];
         daemon.for_blocks(blockhashes, |blockhash, block| {
-            let height = heights.next().expect("unexpected block");
+            self.throttle.wait(); // also slows down fetching the rest of the chunk
+            let height = heights.remove(&blockhash).expect("unexpected block");
+            chunk_bytes += block.len();
+            let parse_start = Instant::now();
+            let offsets = (batch.funding_rows.len(), batch.spending_rows.len());
+            let mut block_script_types = BTreeMap::new();
             self.stats.observe_duration("block", || {
-                index_single_block(blockhash, block, height, &mut batch);
+                index_single_block(
+                    blockhash,
+                    block,
+                    height,
+                    &mut batch,
+                    &mut block_script_types,
+                    self.script_types.as_deref(),
+                    self.optional,
+                );
             });
+            script_types.push((height, block_script_types));
+            if self.is_ready {
+                let row = TouchedRow::new(
+                    height,
+                    blockhash,
+                    &batch.funding_rows[offsets.0..],
+                    &batch.spending_rows[offsets.1..],
+                );
+                touched.push((blockhash, row));
+            }
+            parse_duration += parse_start.elapsed();
             self.stats.height.set("tip", height as f64);
         })?;
-        let heights: Vec<_> = heights.collect();
+        if let Some(profile) = &self.stats.profile {
+            // time spent waiting for bitcoind (or throttling)
+            let fetch_duration = fetch_start.elapsed().saturating_sub(parse_duration);
+            profile.add("fetch", fetch_duration);
+            info!(
+                "profiled blocks [{}..{}]: fetch {:.3}s, parse {:.3}s",
+                chunk.first().unwrap().height(),
+                chunk.last().unwrap().height(),
+                fetch_duration.as_secs_f64(),
+                parse_duration.as_secs_f64(),
+            );
+        }
+        let heights: Vec<_> = heights.into_values().collect();
         assert!(
             heights.is_empty(),
             "some blocks were not indexed: {:?}",
             heights
         );
-        batch.sort();
-        self.stats.observe_batch(&batch);
-        self.stats
-            .observe_duration("write", || self.store.write(&batch));
-        self.stats.observe_db(&self.store);
-        Ok(())
+        if !touched.is_empty() {
+            let end = (chunk.last().unwrap().height() + 1).saturating_sub(MAX_TOUCHED_BLOCKS);
+            batch.touched_end = TouchedRow::scan_start(end);
+        }
+        for (blockhash, row) in touched {
+            batch.touched_rows.push(row.to_db_entry());
+            let prefixes = TouchedPrefixes::new(&row);
+            self.touched.insert(row.height(), (blockhash, prefixes));
+        }
+        while self.touched.len() > MAX_TOUCHED_BLOCKS {
+            let oldest = *self.touched.keys().next().expect("empty touched blocks");
+            self.touched.remove(&oldest);
+        }
+        let chunk_avg = chunk_bytes as f64 / chunk.len() as f64;
+        // the recent chunks are weighted more, since block sizes change gradually
+        self.avg_block_bytes = Some(
+            self.avg_block_bytes
+                .map_or(chunk_avg, |avg| (avg + chunk_avg) / 2.0),
+        );
+        // blocks may be received out of order
+        script_types.sort_unstable_by_key(|(height, _)| *height);
+        for (height, counts) in script_types {
+            self.script_stats.add(height, counts);
+        }
+        batch.script_stats_row = serde_json::to_vec(&self.script_stats)
+            .expect("failed to serialize script stats")
+            .into_boxed_slice();
+        self.stats.observe_script_types(&self.script_stats);
+        Ok(batch)
     }
 
     pub(crate) fn is_ready(&self) -> bool {
         self.is_ready
     }
+
+    /// The ETA is extrapolated from the indexing rate since the sync has started.
+    pub(crate) fn sync_progress(&self) -> SyncProgress {
+        let height = self.chain.height();
+        let target_height = self.target_height.max(height);
+        let eta = self.sync_start.and_then(|(start, start_height)| {
+            let indexed = height.checked_sub(start_height).filter(|n| *n > 0)?;
+            let left = (target_height - height) as f64;
+            Some((start.elapsed().as_secs_f64() * left / indexed as f64).round() as u64)
+        });
+        SyncProgress {
+            height,
+            target_height,
+            eta,
+        }
+    }
+}
+
+fn log_usage(usage: &BTreeMap<&'static str, Usage>) {
+    info!(
+        "{:<10} {:>12} {:>12} {:>14} {:>14}",
+        "DB CF", "disk [MB]", "memory [MB]", "keys (approx)", "bytes/block"
+    );
+    for (name, usage) in usage {
+        info!(
+            "{:<10} {:>12.1} {:>12.1} {:>14} {:>14}",
+            name,
+            usage.disk_bytes as f64 / 1e6,
+            usage.memtable_bytes as f64 / 1e6,
+            usage.keys,
+            usage.bytes_per_block
+        );
+    }
+}
+
+/// Batches are written in the order they were sent, so the tip row always points
+/// to a block whose preceding chunks are already in the DB.
+fn write_batches(store: &DBStore, stats: &Stats, batches: Receiver<(WriteBatch, bool)>) {
+    for (mut batch, commit) in batches {
+        stats.observe_duration("sort", || batch.sort());
+        stats.observe_batch(&batch);
+        stats.observe_duration("write", || {
+            if commit {
+                store.write(&batch)
+            } else {
+                store.write_unsynced(&batch)
+            }
+        });
+    }
+}
+
+fn adaptive_batch_size(batch_bytes: usize, avg_block_bytes: f64) -> usize {
+    let blocks = batch_bytes as f64 / avg_block_bytes.max(1.0);
+    (blocks as usize).clamp(1, MAX_ADAPTIVE_BATCH_SIZE)
 }
 
 fn db_rows_size(rows: &[Row]) -> usize {
     rows.iter().map(|key| key.len()).sum()
 }
 
+/// Load the recent blocks' touched prefixes (skipping the stale ones).
+fn load_touched(store: &DBStore, chain: &Chain) -> BTreeMap<usize, (BlockHash, TouchedPrefixes)> {
+    let start = (chain.height() + 1).saturating_sub(MAX_TOUCHED_BLOCKS);
+    store
+        .iter_touched(TouchedRow::scan_start(start))
+        .filter_map(|(key, value)| {
+            let row = TouchedRow::from_db_entry(&key, &value);
+            let blockhash = chain.get_block_hash(row.height())?;
+            if !row.is_in_block(&blockhash) {
+                return None; // a reorged block
+            }
+            Some((row.height(), (blockhash, TouchedPrefixes::new(&row))))
+        })
+        .collect()
+}
+
 fn index_single_block(
     block_hash: BlockHash,
     block: SerBlock,
     height: usize,
     batch: &mut WriteBatch,
+    script_types: &mut BTreeMap<ScriptType, u64>,
+    allowed_script_types: Option<&[ScriptType]>,
+    optional: OptionalIndexes,
 ) {
     struct IndexBlockVisitor<'a> {
         batch: &'a mut WriteBatch,
+        block_hash: BlockHash,
         height: usize,
+        script_types: &'a mut BTreeMap<ScriptType, u64>,
+        allowed_script_types: Option<&'a [ScriptType]>,
+        optional: OptionalIndexes,
+        tx_count: usize,
+        coinbase_script_sig: Vec<u8>,
+        coinbase_value: u64,
+        // a script may be paid many times within a block (e.g. by batched payouts), always
+        // resulting in the same rows - so they are added only once
+        funding_rows: HashSet<Row>,
+        script_prefix_rows: HashSet<Row>,
     }
 
     impl<'a> Visitor for IndexBlockVisitor<'a> {
@@ -257,25 +1194,57 @@ fn index_single_block(
             self.batch
                 .txid_rows
                 .push(TxidRow::row(txid, self.height).to_db_row());
+            if self.tx_count == 0 && self.optional.coinbase {
+                let row = CoinbaseRow::new(
+                    self.height,
+                    self.block_hash,
+                    txid,
+                    self.coinbase_value,
+                    &self.coinbase_script_sig,
+                );
+                self.batch.coinbase_rows.push(row.to_db_row());
+            }
+            self.tx_count += 1;
             ControlFlow::Continue(())
         }
 
         fn visit_tx_out(&mut self, _vout: usize, tx_out: &bsl::TxOut) -> ControlFlow<()> {
+            if self.tx_count == 0 {
+                self.coinbase_value += tx_out.value();
+            }
             let script = bitcoin::Script::from_bytes(tx_out.script_pubkey());
-            // skip indexing unspendable outputs
-            if !script.is_provably_unspendable() {
-                let row = ScriptHashRow::row(ScriptHash::new(script), self.height);
-                self.batch.funding_rows.push(row.to_db_row());
+            let script_type = ScriptType::classify(script);
+            *self.script_types.entry(script_type).or_default() += 1;
+            let allowed = self
+                .allowed_script_types
+                .map_or(true, |allowed| allowed.contains(&script_type));
+            if script.is_provably_unspendable() || self.height < self.optional.from_height {
+                return ControlFlow::Continue(()); // skip indexing unspendable (or older) outputs
+            }
+            // funding rows may be filtered out by configuration
+            if allowed {
+                let row = ScriptHashRow::row(ScriptHash::new(script), self.height).to_db_row();
+                if self.funding_rows.insert(row.clone()) {
+                    self.batch.funding_rows.push(row);
+                }
+            }
+            if self.optional.script_prefixes {
+                let row = ScriptPrefixRow::row(script, self.height).to_db_row();
+                if self.script_prefix_rows.insert(row.clone()) {
+                    self.batch.script_prefix_rows.push(row);
+                }
             }
             ControlFlow::Continue(())
         }
 
         fn visit_tx_in(&mut self, _vin: usize, tx_in: &bsl::TxIn) -> ControlFlow<()> {
             let prevout: OutPoint = tx_in.prevout().into();
-            // skip indexing coinbase transactions' input
-            if !prevout.is_null() {
+            // skip indexing coinbase transactions' input (and older blocks' inputs)
+            if !prevout.is_null() && self.height >= self.optional.from_height {
                 let row = SpendingPrefixRow::row(prevout, self.height);
                 self.batch.spending_rows.push(row.to_db_row());
+            } else if self.tx_count == 0 {
+                self.coinbase_script_sig = tx_in.script_sig().to_vec();
             }
             ControlFlow::Continue(())
         }
@@ -290,7 +1259,195 @@ fn index_single_block(
         }
     }
 
-    let mut index_block = IndexBlockVisitor { batch, height };
+    let mut index_block = IndexBlockVisitor {
+        batch,
+        block_hash,
+        height,
+        script_types,
+        allowed_script_types,
+        optional,
+        tx_count: 0,
+        coinbase_script_sig: vec![],
+        coinbase_value: 0,
+        funding_rows: HashSet::new(),
+        script_prefix_rows: HashSet::new(),
+    };
     bsl::Block::visit(&block, &mut index_block).expect("core returned invalid block");
     batch.tip_row = serialize(&block_hash).into_boxed_slice();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        adaptive_batch_size, index_single_block, ScriptTypeStats, TouchedPrefixes,
+        MAX_ADAPTIVE_BATCH_SIZE,
+    };
+    use crate::{
+        db::{OptionalIndexes, WriteBatch},
+        types::{ScriptHash, ScriptType, TouchedRow},
+    };
+    use bitcoin::{
+        absolute::LockTime, block, consensus::serialize, hashes::Hash, transaction, Amount, Block,
+        BlockHash, CompactTarget, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn,
+        TxMerkleNode, TxOut, Witness,
+    };
+    use std::collections::BTreeMap;
+
+    // the coinbase pays the same script twice, and it is paid again by the second transaction
+    fn test_block() -> Block {
+        let input = |previous_output| TxIn {
+            previous_output,
+            script_sig: ScriptBuf::from_bytes(vec![1, 2]),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        };
+        let output = |script: &[u8]| TxOut {
+            value: Amount::from_sat(1000),
+            script_pubkey: ScriptBuf::from_bytes(script.to_vec()),
+        };
+        let coinbase = Transaction {
+            version: transaction::Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec![input(OutPoint::null())],
+            output: vec![output(&[0x51]), output(&[0x52]), output(&[0x51])],
+        };
+        let spend = Transaction {
+            input: vec![input(OutPoint::new(coinbase.txid(), 1))],
+            output: vec![output(&[0x51])],
+            ..coinbase.clone()
+        };
+        Block {
+            header: block::Header {
+                version: block::Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![coinbase, spend],
+        }
+    }
+
+    fn index_block(height: usize, optional: OptionalIndexes) -> WriteBatch {
+        let block = test_block();
+        let mut batch = WriteBatch::default();
+        index_single_block(
+            block.block_hash(),
+            serialize(&block),
+            height,
+            &mut batch,
+            &mut BTreeMap::new(),
+            None,
+            optional,
+        );
+        batch
+    }
+
+    #[test]
+    fn test_dedup_funding_rows() {
+        let output = |script: &[u8]| TxOut {
+            value: Amount::from_sat(1000),
+            script_pubkey: ScriptBuf::from_bytes(script.to_vec()),
+        };
+        let coinbase = Transaction {
+            version: transaction::Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::from_bytes(vec![1, 2]),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![output(&[0x51]), output(&[0x52]), output(&[0x51])],
+        };
+        let block = Block {
+            header: block::Header {
+                version: block::Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![coinbase.clone(), coinbase],
+        };
+        let optional = OptionalIndexes {
+            script_prefixes: true,
+            ..Default::default()
+        };
+        let mut batch = WriteBatch::default();
+        index_single_block(
+            block.block_hash(),
+            serialize(&block),
+            1,
+            &mut batch,
+            &mut BTreeMap::new(),
+            None,
+            optional,
+        );
+        assert_eq!(batch.funding_rows.len(), 2); // one per distinct script
+        assert_eq!(batch.script_prefix_rows.len(), 2);
+        assert_eq!(batch.txid_rows.len(), 2);
+    }
+
+    #[test]
+    fn test_touched_prefixes() {
+        let block = test_block();
+        let batch = index_block(1, OptionalIndexes::default());
+        let row = TouchedRow::new(
+            1,
+            block.block_hash(),
+            &batch.funding_rows,
+            &batch.spending_rows,
+        );
+        let touched = TouchedPrefixes::new(&row);
+        let script = |bytes: &[u8]| ScriptHash::new(Script::from_bytes(bytes));
+        assert!(touched.funds(script(&[0x51])));
+        assert!(touched.funds(script(&[0x52])));
+        assert!(!touched.funds(script(&[0x53])));
+        let coinbase_txid = block.txdata[0].txid();
+        assert!(touched.spends(OutPoint::new(coinbase_txid, 1)));
+        assert!(!touched.spends(OutPoint::new(coinbase_txid, 0)));
+    }
+
+    #[test]
+    fn test_script_type_stats_reorg() {
+        let counts =
+            |p2wpkh, p2tr| BTreeMap::from([(ScriptType::P2wpkh, p2wpkh), (ScriptType::P2tr, p2tr)]);
+        let mut stats = ScriptTypeStats::default();
+        stats.add(10, counts(3, 1));
+        stats.add(11, counts(2, 0));
+        assert_eq!(stats.counts, counts(5, 1));
+        // the block at height 11 is reorged out, and replaced by another one
+        stats.add(11, counts(0, 4));
+        assert_eq!(stats.counts, counts(3, 5));
+        assert_eq!(stats.since_height, Some(10));
+    }
+
+    #[test]
+    fn test_adaptive_batch_size() {
+        let budget = 200 << 20;
+        assert_eq!(adaptive_batch_size(budget, 2e6), 104); // recent blocks
+        assert_eq!(adaptive_batch_size(budget, 250.0), MAX_ADAPTIVE_BATCH_SIZE); // early blocks
+        assert_eq!(adaptive_batch_size(1 << 20, 4e6), 1); // at least one block
+        assert_eq!(adaptive_batch_size(budget, 0.0), MAX_ADAPTIVE_BATCH_SIZE);
+    }
+
+    #[test]
+    fn test_index_from_height() {
+        let optional = OptionalIndexes {
+            from_height: 10,
+            ..Default::default()
+        };
+        let batch = index_block(9, optional);
+        assert!(batch.funding_rows.is_empty());
+        assert!(batch.spending_rows.is_empty());
+        assert_eq!(batch.txid_rows.len(), 2); // transactions can still be looked up
+        assert_eq!(batch.header_rows.len(), 1);
+
+        let batch = index_block(10, optional);
+        assert_eq!(batch.funding_rows.len(), 2);
+        assert_eq!(batch.spending_rows.len(), 1);
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use bitcoin::hashes::hex::{DisplayHex, FromHex};
+
+use std::collections::BTreeMap;
+
+const MAX_KEY_LEN: usize = 64;
+
+/// Opaque blobs (e.g. wallet labels), stored on behalf of an authentication identity.
+/// They should be encrypted by the client, so their contents are never visible to the server.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct LabelStore {
+    entries: BTreeMap<String, String>, // hex-encoded values
+}
+
+impl LabelStore {
+    pub(crate) fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Total size of the stored keys and (decoded) values.
+    pub(crate) fn size(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(key, value)| key.len() + value.len() / 2)
+            .sum()
+    }
+
+    /// Store a hex-encoded value (or remove it, if `None`), as long as the total size
+    /// doesn't exceed `max_bytes`.
+    pub(crate) fn set(&mut self, key: &str, value: Option<&str>, max_bytes: usize) -> Result<()> {
+        ensure!(
+            !key.is_empty() && key.len() <= MAX_KEY_LEN,
+            "label key must have 1-{} bytes",
+            MAX_KEY_LEN
+        );
+        let value = match value {
+            Some(value) => value,
+            None => {
+                self.entries.remove(key);
+                return Ok(());
+            }
+        };
+        let value = Vec::<u8>::from_hex(value)
+            .map_err(|e| anyhow!("non-hex label value: {}", e))?
+            .to_lower_hex_string();
+        let old_size = self.get(key).map_or(0, |old| key.len() + old.len() / 2);
+        let new_size = self.size() - old_size + key.len() + value.len() / 2;
+        ensure!(
+            new_size <= max_bytes,
+            "labels' size would exceed the limit: {} > {} bytes",
+            new_size,
+            max_bytes
+        );
+        self.entries.insert(key.to_owned(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LabelStore;
+
+    #[test]
+    fn test_size_limit() {
+        let mut store = LabelStore::default();
+        store.set("a", Some("0011"), 10).unwrap();
+        store.set("b", Some("AABBCC"), 10).unwrap();
+        assert_eq!(store.size(), 7);
+        assert_eq!(store.get("b"), Some("aabbcc"));
+
+        assert!(store.set("c", Some("00112233"), 10).is_err());
+        store.set("a", Some("00112233"), 10).unwrap(); // replaces the old value
+        assert_eq!(store.size(), 9);
+        assert!(store.set("a", Some("zz"), 10).is_err());
+        assert!(store.set("", Some("00"), 10).is_err());
+
+        store.set("a", None, 10).unwrap();
+        store.set("b", None, 10).unwrap();
+        assert!(store.is_empty());
+        assert_eq!(store.keys().count(), 0);
+    }
+}
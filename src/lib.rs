@@ -9,22 +9,48 @@ extern crate serde_derive;
 
 extern crate configure_me;
 
+mod admin;
+mod auxpow;
+mod broadcasts;
 mod cache;
 mod chain;
 mod config;
+mod cost;
 mod daemon;
 mod db;
+mod descriptor;
+mod disk;
 mod electrum;
+mod errors;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod http;
 mod index;
+mod labels;
+mod memory;
 mod mempool;
 mod merkle;
 mod metrics;
 mod p2p;
+mod preview;
+mod proxy;
+mod rest;
+mod scan_cache;
 mod server;
 mod signals;
+mod singleflight;
 mod status;
+mod status_page;
+mod systemd;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod thread;
+mod throttle;
+mod trace;
 mod tracker;
 mod types;
+mod warmup;
+mod watchtower;
+mod webhook;
 
 pub use server::run;
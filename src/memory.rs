@@ -0,0 +1,66 @@
+use bitcoin::Transaction;
+
+// Relative shares of the memory limit, used by the caches below
+const TX_CACHE_WEIGHT: usize = 4;
+const MEMPOOL_WEIGHT: usize = 4;
+const DB_CACHE_WEIGHT: usize = 2;
+const TOTAL_WEIGHT: usize = TX_CACHE_WEIGHT + MEMPOOL_WEIGHT + DB_CACHE_WEIGHT;
+
+// A deserialized transaction (with its allocations) takes more memory than its serialization
+const TX_MEMORY_FACTOR: usize = 3;
+
+/// A memory limit (in bytes), split between the transaction cache, the tracked mempool and
+/// RocksDB block cache according to their weights. Each of them evicts its own entries
+/// when it exceeds its share.
+///
+/// Memory used by RocksDB memtables (mostly during initial sync) and by connected clients
+/// is not accounted for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self { limit }
+    }
+
+    fn share(&self, weight: usize) -> Option<usize> {
+        self.limit.map(|limit| limit / TOTAL_WEIGHT * weight)
+    }
+
+    pub(crate) fn tx_cache(&self) -> Option<usize> {
+        self.share(TX_CACHE_WEIGHT)
+    }
+
+    pub(crate) fn mempool(&self) -> Option<usize> {
+        self.share(MEMPOOL_WEIGHT)
+    }
+
+    pub(crate) fn db_cache(&self) -> Option<usize> {
+        self.share(DB_CACHE_WEIGHT)
+    }
+}
+
+/// Estimated memory used by a deserialized transaction.
+pub(crate) fn tx_memory(tx: &Transaction) -> usize {
+    tx.total_size() * TX_MEMORY_FACTOR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryBudget;
+
+    #[test]
+    fn test_shares() {
+        let budget = MemoryBudget::new(Some(1000 << 20));
+        assert_eq!(budget.tx_cache(), Some(400 << 20));
+        assert_eq!(budget.mempool(), Some(400 << 20));
+        assert_eq!(budget.db_cache(), Some(200 << 20));
+
+        let unlimited = MemoryBudget::default();
+        assert_eq!(unlimited.tx_cache(), None);
+        assert_eq!(unlimited.mempool(), None);
+        assert_eq!(unlimited.db_cache(), None);
+    }
+}
@@ -1,16 +1,18 @@
 use anyhow::{Context, Result};
 
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::iter::FromIterator;
 use std::ops::Bound;
 
+use bitcoin::consensus::encode::{Decodable, Encodable, VarInt};
 use bitcoin::hashes::Hash;
 use bitcoin::{Amount, OutPoint, Transaction, Txid};
 use serde::ser::{Serialize, SerializeSeq, Serializer};
 
 use crate::{
-    daemon::Daemon,
+    daemon::TxSource,
+    memory::tx_memory,
     metrics::{Gauge, Metrics},
     signals::ExitFlag,
     types::ScriptHash,
@@ -24,12 +26,43 @@ pub(crate) struct Entry {
     pub has_unconfirmed_inputs: bool,
 }
 
+impl Encodable for Entry {
+    fn consensus_encode<W: std::io::Write + ?Sized>(&self, w: &mut W) -> std::io::Result<usize> {
+        let mut len = self.txid.consensus_encode(w)?;
+        len += self.tx.consensus_encode(w)?;
+        len += self.fee.to_sat().consensus_encode(w)?;
+        len += self.vsize.consensus_encode(w)?;
+        len += self.has_unconfirmed_inputs.consensus_encode(w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for Entry {
+    fn consensus_decode<R: std::io::Read + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, bitcoin::consensus::encode::Error> {
+        Ok(Entry {
+            txid: Decodable::consensus_decode(r)?,
+            tx: Decodable::consensus_decode(r)?,
+            fee: Amount::from_sat(Decodable::consensus_decode(r)?),
+            vsize: Decodable::consensus_decode(r)?,
+            has_unconfirmed_inputs: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
 /// Mempool current state
 pub(crate) struct Mempool {
     entries: HashMap<Txid, Entry>,
     by_funding: BTreeSet<(ScriptHash, Txid)>,
     by_spending: BTreeSet<(OutPoint, Txid)>,
     fees: FeeHistogram,
+    skipped: HashSet<Txid>, // evicted or too large transactions (not tracked)
+    max_txs: Option<usize>,
+    max_tx_vsize: Option<u64>,
+    max_bytes: Option<usize>,
+    bytes: usize,    // estimated memory usage of the tracked transactions
+    generation: u64, // incremented on every change of the tracked transactions
     // stats
     vsize: Gauge,
     count: Gauge,
@@ -41,14 +74,17 @@ pub(crate) struct Mempool {
 pub(crate) struct MempoolSyncUpdate {
     new_entries: Vec<Entry>,
     removed_entries: HashSet<Txid>,
+    skipped_entries: Vec<Txid>,
 }
 
 impl MempoolSyncUpdate {
     /// Poll the bitcoin node and compute a [`MempoolSyncUpdate`] based on the given set of
-    /// `old_txids` which are already cached.
-    pub fn poll(
-        daemon: &Daemon,
+    /// `old_txids` which are already cached (or skipped).
+    /// Transactions larger than `max_tx_vsize` are skipped without fetching them.
+    pub(crate) fn poll(
+        daemon: &impl TxSource,
         old_txids: HashSet<Txid>,
+        max_tx_vsize: Option<u64>,
         exit_flag: &ExitFlag,
     ) -> Result<MempoolSyncUpdate> {
         let txids = daemon.get_mempool_txids()?;
@@ -61,6 +97,7 @@ impl MempoolSyncUpdate {
 
         let to_add: Vec<Txid> = to_add.into_iter().collect();
         let mut new_entries = Vec::with_capacity(to_add.len());
+        let mut skipped_entries = Vec::new();
 
         for txids_chunk in to_add.chunks(1000) {
             exit_flag.poll().context("mempool update interrupted")?;
@@ -71,21 +108,36 @@ impl MempoolSyncUpdate {
                 entries.len(),
                 txids_chunk.len()
             );
-            let txs = daemon.get_mempool_transactions(txids_chunk)?;
+            let mut fetched = Vec::with_capacity(txids_chunk.len());
+            for (txid, entry) in txids_chunk.iter().zip(entries.into_iter()) {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(_) => continue, // may have been removed from mempool
+                };
+                if max_tx_vsize.map_or(false, |max_vsize| entry.vsize > max_vsize) {
+                    skipped_entries.push(*txid); // don't fetch too large transactions
+                    continue;
+                }
+                fetched.push((*txid, entry));
+            }
+            if fetched.is_empty() {
+                continue;
+            }
+            let txids: Vec<Txid> = fetched.iter().map(|(txid, _entry)| *txid).collect();
+            let txs = daemon.get_mempool_transactions(&txids)?;
             ensure!(
-                txids_chunk.len() == txs.len(),
+                txids.len() == txs.len(),
                 "got {} mempools transactions, expected {}",
                 txs.len(),
-                txids_chunk.len()
+                txids.len()
             );
-            let chunk_entries: Vec<Entry> = txids_chunk
-                .iter()
-                .zip(entries.into_iter().zip(txs.into_iter()))
-                .filter_map(|(txid, (entry, tx))| {
+            let chunk_entries: Vec<Entry> = fetched
+                .into_iter()
+                .zip(txs.into_iter())
+                .filter_map(|((txid, entry), tx)| {
                     let tx = tx.ok()?;
-                    let entry = entry.ok()?;
                     Some(Entry {
-                        txid: *txid,
+                        txid,
                         tx,
                         vsize: entry.vsize,
                         fee: entry.fees.base,
@@ -100,11 +152,45 @@ impl MempoolSyncUpdate {
         let update = MempoolSyncUpdate {
             new_entries,
             removed_entries: to_remove,
+            skipped_entries,
         };
         Ok(update)
     }
 }
 
+// Bumped when the persisted entries' encoding changes (see `Mempool::persist`)
+const PERSISTED_FORMAT: u8 = 0;
+
+fn encode_entries<'a>(entries: impl ExactSizeIterator<Item = &'a Entry>) -> Vec<u8> {
+    let mut data = vec![PERSISTED_FORMAT];
+    VarInt(entries.len() as u64)
+        .consensus_encode(&mut data)
+        .expect("in-memory writers don't error");
+    for entry in entries {
+        entry
+            .consensus_encode(&mut data)
+            .expect("in-memory writers don't error");
+    }
+    data
+}
+
+fn decode_entries(data: &[u8]) -> Result<Vec<Entry>> {
+    let (format, mut reader) = data.split_first().context("empty mempool data")?;
+    ensure!(
+        *format == PERSISTED_FORMAT,
+        "unsupported mempool format {} != {}",
+        format,
+        PERSISTED_FORMAT
+    );
+    let count = VarInt::consensus_decode(&mut reader)?.0;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        entries.push(Entry::consensus_decode(&mut reader)?);
+    }
+    ensure!(reader.is_empty(), "{} trailing bytes", reader.len());
+    Ok(entries)
+}
+
 // Smallest possible txid
 fn txid_min() -> Txid {
     Txid::all_zeros()
@@ -116,12 +202,23 @@ fn txid_max() -> Txid {
 }
 
 impl Mempool {
-    pub fn new(metrics: &Metrics) -> Self {
+    pub fn new(
+        metrics: &Metrics,
+        max_txs: Option<usize>,
+        max_tx_vsize: Option<u64>,
+        max_bytes: Option<usize>,
+    ) -> Self {
         Self {
             entries: Default::default(),
             by_funding: Default::default(),
             by_spending: Default::default(),
             fees: FeeHistogram::default(),
+            skipped: Default::default(),
+            max_txs,
+            max_tx_vsize,
+            max_bytes,
+            bytes: 0,
+            generation: 0,
             vsize: metrics.gauge(
                 "mempool_txs_vsize",
                 "Total vsize of mempool transactions (in bytes)",
@@ -139,10 +236,39 @@ impl Mempool {
         &self.fees
     }
 
+    /// Changes whenever transactions are added or removed (for detecting mempool changes).
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
     pub(crate) fn get(&self, txid: &Txid) -> Option<&Entry> {
         self.entries.get(txid)
     }
 
+    /// Unconfirmed ancestors of a mempool transaction (closest ones first),
+    /// or `None` if it is not tracked. Untracked (e.g. evicted) ancestors are omitted.
+    pub(crate) fn ancestors(&self, txid: &Txid) -> Option<Vec<&Entry>> {
+        let mut queue = VecDeque::from([self.get(txid)?]);
+        let mut visited = HashSet::from([*txid]);
+        let mut ancestors = vec![];
+        while let Some(entry) = queue.pop_front() {
+            if !entry.has_unconfirmed_inputs {
+                continue;
+            }
+            for txi in &entry.tx.input {
+                let parent_txid = txi.previous_output.txid;
+                if !visited.insert(parent_txid) {
+                    continue; // multiple inputs may spend the same ancestor
+                }
+                if let Some(parent) = self.get(&parent_txid) {
+                    ancestors.push(parent);
+                    queue.push_back(parent);
+                }
+            }
+        }
+        Some(ancestors)
+    }
+
     pub(crate) fn filter_by_funding(&self, scripthash: &ScriptHash) -> Vec<&Entry> {
         let range = (
             Bound::Included((*scripthash, txid_min())),
@@ -165,29 +291,86 @@ impl Mempool {
             .collect()
     }
 
+    /// Serialize the current entries, so they can be restored after a restart.
+    pub(crate) fn persist(&self) -> Vec<u8> {
+        encode_entries(self.entries.values())
+    }
+
+    /// Restore the entries persisted before a restart. They are reconciled with bitcoind's
+    /// mempool on the next sync, so only the transactions that changed meanwhile are fetched.
+    pub(crate) fn restore(&mut self, data: &[u8]) -> Result<()> {
+        let entries = decode_entries(data).context("failed to decode persisted mempool")?;
+        info!("restoring {} mempool transactions", entries.len());
+        for entry in entries {
+            self.add_entry(entry);
+        }
+        self.evict();
+        self.update_metrics();
+        Ok(())
+    }
+
     /// Apply a [`MempoolSyncUpdate`] to the mempool state.
     pub fn apply_sync_update(&mut self, update: MempoolSyncUpdate) {
         let removed = update.removed_entries.len();
         let added = update.new_entries.len();
 
         for txid_to_remove in update.removed_entries {
-            self.remove_entry(txid_to_remove);
+            if !self.skipped.remove(&txid_to_remove) {
+                self.remove_entry(txid_to_remove);
+            }
         }
 
         for entry in update.new_entries {
             self.add_entry(entry);
         }
+        self.skipped.extend(update.skipped_entries);
+        let evicted = self.evict();
 
         self.update_metrics();
 
         debug!(
-            "{} mempool txs: {} added, {} removed",
+            "{} mempool txs: {} added, {} removed, {} evicted ({} skipped)",
             self.entries.len(),
             added,
             removed,
+            evicted,
+            self.skipped.len(),
         );
     }
 
+    /// Evict the transactions with the lowest fee rate, to keep at most `max_txs` entries
+    /// and `max_bytes` of estimated memory usage.
+    /// Evicted transactions are skipped until they are removed from bitcoind's mempool.
+    fn evict(&mut self) -> usize {
+        if !self.is_over_limits() {
+            return 0;
+        }
+        let mut by_fee_rate: Vec<(u64, Txid)> = self
+            .entries
+            .values()
+            .map(|entry| (fee_rate(entry.fee, entry.vsize), entry.txid))
+            .collect();
+        by_fee_rate.sort_unstable();
+        let mut evicted = 0;
+        for (_fee_rate, txid) in by_fee_rate {
+            if !self.is_over_limits() {
+                break;
+            }
+            self.remove_entry(txid);
+            self.skipped.insert(txid);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    fn is_over_limits(&self) -> bool {
+        self.max_txs
+            .map_or(false, |max_txs| self.entries.len() > max_txs)
+            || self
+                .max_bytes
+                .map_or(false, |max_bytes| self.bytes > max_bytes)
+    }
+
     fn update_metrics(&mut self) {
         for i in 0..FeeHistogram::BINS {
             let bin_index = FeeHistogram::BINS - i - 1; // from 63 to 0
@@ -198,10 +381,11 @@ impl Mempool {
         }
     }
 
-    pub fn sync(&mut self, daemon: &Daemon, exit_flag: &ExitFlag) {
-        let old_txids = HashSet::<Txid>::from_iter(self.entries.keys().copied());
+    pub(crate) fn sync(&mut self, daemon: &impl TxSource, exit_flag: &ExitFlag) {
+        let old_txids =
+            HashSet::<Txid>::from_iter(self.entries.keys().chain(self.skipped.iter()).copied());
 
-        let poll_result = MempoolSyncUpdate::poll(daemon, old_txids, exit_flag);
+        let poll_result = MempoolSyncUpdate::poll(daemon, old_txids, self.max_tx_vsize, exit_flag);
 
         let sync_update = match poll_result {
             Ok(sync_update) => sync_update,
@@ -225,6 +409,8 @@ impl Mempool {
         }
 
         self.modify_fee_histogram(entry.fee, entry.vsize as i64);
+        self.bytes += tx_memory(&entry.tx);
+        self.generation += 1;
 
         assert!(
             self.entries.insert(entry.txid, entry).is_none(),
@@ -235,6 +421,8 @@ impl Mempool {
     /// Remove a transaction entry from the mempool and update the fee histogram.
     fn remove_entry(&mut self, txid: Txid) {
         let entry = self.entries.remove(&txid).expect("missing tx from mempool");
+        self.bytes -= tx_memory(&entry.tx);
+        self.generation += 1;
         for txi in entry.tx.input {
             self.by_spending.remove(&(txi.previous_output, txid));
         }
@@ -261,6 +449,11 @@ impl Mempool {
     }
 }
 
+// In sat/kvB, for more precise eviction ordering
+fn fee_rate(fee: Amount, vsize: u64) -> u64 {
+    fee.to_sat().saturating_mul(1000) / vsize.max(1)
+}
+
 pub(crate) struct FeeHistogram {
     /// bins[64-i] contains transactions' statistics inside the fee band of [2**(i-1), 2**i).
     /// bins[64] = [0, 1)
@@ -343,10 +536,67 @@ impl Serialize for FeeHistogram {
 
 #[cfg(test)]
 mod tests {
-    use super::FeeHistogram;
-    use bitcoin::Amount;
+    use super::{decode_entries, encode_entries, Entry, FeeHistogram, Mempool};
+    use crate::{memory::tx_memory, metrics::Metrics};
+    use bitcoin::{absolute::LockTime, blockdata::constants::genesis_block, Amount, Network};
     use serde_json::json;
 
+    #[test]
+    fn test_persisted_entries() {
+        let tx = genesis_block(Network::Bitcoin).txdata.remove(0);
+        let entry = Entry {
+            txid: tx.txid(),
+            tx,
+            fee: Amount::from_sat(1000),
+            vsize: 204,
+            has_unconfirmed_inputs: true,
+        };
+        let data = encode_entries([&entry].into_iter());
+        let entries = decode_entries(&data).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].txid, entry.txid);
+        assert_eq!(entries[0].tx, entry.tx);
+        assert_eq!(entries[0].fee, entry.fee);
+        assert_eq!(entries[0].vsize, entry.vsize);
+        assert!(entries[0].has_unconfirmed_inputs);
+
+        assert!(decode_entries(&data[..data.len() - 1]).is_err()); // truncated
+        assert!(decode_entries(&[data.as_slice(), &[0]].concat()).is_err()); // trailing data
+        let mut data = data;
+        data[0] += 1; // unsupported format
+        assert!(decode_entries(&data).is_err());
+
+        let data = encode_entries(std::iter::empty());
+        assert!(decode_entries(&data).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_evict_by_memory() {
+        let entries: Vec<Entry> = [300, 100, 200]
+            .iter()
+            .enumerate()
+            .map(|(i, &fee)| {
+                let mut tx = genesis_block(Network::Bitcoin).txdata.remove(0);
+                tx.lock_time = LockTime::from_consensus(i as u32);
+                Entry {
+                    txid: tx.txid(),
+                    tx,
+                    fee: Amount::from_sat(fee),
+                    vsize: 204,
+                    has_unconfirmed_inputs: false,
+                }
+            })
+            .collect();
+        let max_bytes = 2 * tx_memory(&entries[0].tx);
+        let mut mempool = Mempool::new(&Metrics::unserved(), None, None, Some(max_bytes));
+        mempool.restore(&encode_entries(entries.iter())).unwrap();
+        // the transaction with the lowest fee rate is evicted
+        assert!(mempool.get(&entries[0].txid).is_some());
+        assert!(mempool.get(&entries[1].txid).is_none());
+        assert!(mempool.get(&entries[2].txid).is_some());
+        assert!(mempool.skipped.contains(&entries[1].txid));
+    }
+
     #[test]
     fn test_histogram() {
         let items = vec![
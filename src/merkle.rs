@@ -7,27 +7,24 @@ pub(crate) struct Proof {
 
 impl Proof {
     pub(crate) fn create(txids: &[Txid], position: usize) -> Self {
-        assert!(position < txids.len());
-        let mut offset = position;
+        Self::create_many(txids, &[position]).pop().unwrap()
+    }
+
+    /// Proofs for several positions in the same block, building its merkle tree only once.
+    pub(crate) fn create_many(txids: &[Txid], positions: &[usize]) -> Vec<Self> {
         let mut hashes: Vec<TxMerkleNode> = txids
             .iter()
             .map(|txid| TxMerkleNode::from_raw_hash(txid.to_raw_hash()))
             .collect();
 
-        let mut proof = vec![];
+        // each level is padded to an even length (by duplicating its last node)
+        let mut levels = vec![];
         while hashes.len() > 1 {
             if hashes.len() % 2 != 0 {
                 let last = *hashes.last().unwrap();
                 hashes.push(last);
             }
-            offset = if offset % 2 == 0 {
-                offset + 1
-            } else {
-                offset - 1
-            };
-            proof.push(hashes[offset]);
-            offset /= 2;
-            hashes = hashes
+            let next = hashes
                 .chunks(2)
                 .map(|pair| {
                     let left = pair[0];
@@ -35,9 +32,27 @@ impl Proof {
                     let input = [&left[..], &right[..]].concat();
                     TxMerkleNode::hash(&input)
                 })
-                .collect()
+                .collect();
+            levels.push(hashes);
+            hashes = next;
         }
-        Self { proof, position }
+
+        positions
+            .iter()
+            .map(|&position| {
+                assert!(position < txids.len());
+                let mut offset = position;
+                let proof = levels
+                    .iter()
+                    .map(|level| {
+                        let sibling = level[offset ^ 1];
+                        offset /= 2;
+                        sibling
+                    })
+                    .collect();
+                Self { proof, position }
+            })
+            .collect()
     }
 
     pub(crate) fn to_hex(&self) -> Vec<String> {
@@ -54,7 +69,10 @@ impl Proof {
 
 #[cfg(test)]
 mod tests {
-    use bitcoin::{consensus::encode::deserialize, Block, Txid};
+    use bitcoin::{
+        consensus::encode::deserialize, hash_types::TxMerkleNode, hashes::Hash, merkle_tree, Block,
+        Txid,
+    };
     use std::path::Path;
 
     use super::Proof;
@@ -101,6 +119,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_merkle_many() {
+        let txids =
+            load_block_txids("00000000000000001203c1ea455e38612bdf36e9967fdead11935c8e22283ecc");
+        let leaves = txids
+            .iter()
+            .map(|txid| TxMerkleNode::from_raw_hash(txid.to_raw_hash()));
+        let root = merkle_tree::calculate_root(leaves).unwrap();
+
+        let positions = [157, 0, txids.len() - 1, 157];
+        let proofs = Proof::create_many(&txids, &positions);
+        assert_eq!(proofs.len(), positions.len());
+        for (proof, position) in proofs.iter().zip(positions) {
+            assert_eq!(proof.position(), position);
+            let mut offset = position;
+            let mut node = TxMerkleNode::from_raw_hash(txids[position].to_raw_hash());
+            for sibling in &proof.proof {
+                let (left, right) = if offset % 2 == 0 {
+                    (node, *sibling)
+                } else {
+                    (*sibling, node)
+                };
+                node = TxMerkleNode::hash(&[&left[..], &right[..]].concat());
+                offset /= 2;
+            }
+            assert_eq!(node, root);
+        }
+        assert!(Proof::create(&txids[..1], 0).to_hex().is_empty());
+        assert!(Proof::create_many(&txids, &[]).is_empty());
+    }
+
     fn load_block_txids(block_hash_hex: &str) -> Vec<Txid> {
         let path = Path::new("src")
             .join("tests")
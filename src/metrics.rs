@@ -5,50 +5,144 @@ mod metrics_impl {
     #[cfg(feature = "metrics_process")]
     use prometheus::process_collector::ProcessCollector;
 
+    use crossbeam_channel::{never, unbounded, Receiver, Sender};
+    use prometheus::proto::{MetricFamily, MetricType};
     use prometheus::{self, Encoder, HistogramOpts, HistogramVec, Registry};
-    use tiny_http::{Response, Server};
+    use serde_json::{json, Value};
+    use tiny_http::{Header, Method, Request, Response, Server};
 
+    use std::fmt::Write as _;
+    use std::io::Read;
     use std::net::SocketAddr;
-
-    use crate::thread::spawn;
+    use std::time::Duration;
+
+    use crate::{
+        admin::{AdminAccess, AdminRequest},
+        http::HttpTarget,
+        status_page,
+        thread::spawn,
+    };
+
+    const OPENMETRICS_CONTENT_TYPE: &str =
+        "application/openmetrics-text; version=1.0.0; charset=utf-8";
+    const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+    const ADMIN_PREFIX: &str = "/admin/";
+    const ADMIN_TIMEOUT: Duration = Duration::from_secs(60);
+    const STATUS_PAGE: &str = "/status";
+    const HTML_CONTENT_TYPE: &str = "text/html; charset=utf-8";
 
     pub struct Metrics {
         reg: Registry,
+        prefix: String,
+        admin_rx: Receiver<AdminRequest>,
     }
 
     impl Metrics {
-        pub fn new(addr: SocketAddr) -> Result<Self> {
+        /// The admin API is served only if `admin` access is configured.
+        pub fn new(addr: SocketAddr, admin: Option<AdminAccess>) -> Result<Self> {
             let reg = Registry::new();
 
             #[cfg(feature = "metrics_process")]
             reg.register(Box::new(ProcessCollector::for_self()))
                 .expect("failed to register ProcessCollector");
 
-            let result = Self { reg };
+            let (admin_tx, admin_rx) = unbounded();
+            let result = Self {
+                reg,
+                prefix: String::from("electrs_"),
+                admin_rx,
+            };
             let reg = result.reg.clone();
 
             let server = match Server::http(addr) {
                 Ok(server) => server,
                 Err(err) => bail!("failed to start HTTP server on {}: {}", addr, err),
             };
+            if admin.is_some() {
+                info!("serving admin API on {}{}", addr, ADMIN_PREFIX);
+            }
 
             spawn("metrics", move || {
                 for request in server.incoming_requests() {
-                    let mut buffer = vec![];
-                    prometheus::TextEncoder::new()
-                        .encode(&reg.gather(), &mut buffer)
-                        .context("failed to encode metrics")?;
+                    if request.url().starts_with(ADMIN_PREFIX) {
+                        serve_admin(request, admin.as_ref(), &admin_tx);
+                        continue;
+                    }
+                    let families = reg.gather();
+                    if request.url() == STATUS_PAGE {
+                        let header =
+                            Header::from_bytes(&b"Content-Type"[..], HTML_CONTENT_TYPE).unwrap();
+                        let page = status_page::render(&families);
+                        request
+                            .respond(Response::from_string(page).with_header(header))
+                            .context("failed to send HTTP response")?;
+                        continue;
+                    }
+                    let (buffer, content_type) = if accepts_openmetrics(&request) {
+                        (
+                            encode_openmetrics(&families).into_bytes(),
+                            OPENMETRICS_CONTENT_TYPE,
+                        )
+                    } else {
+                        (encode_text(&families)?, prometheus::TEXT_FORMAT)
+                    };
+                    let header = Header::from_bytes(&b"Content-Type"[..], content_type).unwrap();
                     request
-                        .respond(Response::from_data(buffer))
+                        .respond(Response::from_data(buffer).with_header(header))
                         .context("failed to send HTTP response")?;
                 }
                 Ok(())
             });
 
             info!("serving Prometheus metrics on {}", addr);
+            info!("serving status page on {}{}", addr, STATUS_PAGE);
             Ok(result)
         }
 
+        /// Collect metrics without serving them (used by the in-process test harness).
+        #[cfg(any(test, feature = "testing"))]
+        pub(crate) fn unserved() -> Self {
+            Self {
+                reg: Registry::new(),
+                prefix: String::from("electrs_"),
+                admin_rx: never(),
+            }
+        }
+
+        /// Register an additional chain's metrics (prefixed by its name) in the same registry,
+        /// so they are served and pushed together. Admin commands are handled by the main chain.
+        pub fn for_chain(&self, chain: &str) -> Self {
+            Self {
+                reg: self.reg.clone(),
+                prefix: format!("{}{}_", self.prefix, chain),
+                admin_rx: never(),
+            }
+        }
+
+        /// Commands received via the admin API.
+        pub(crate) fn admin_requests(&self) -> &Receiver<AdminRequest> {
+            &self.admin_rx
+        }
+
+        /// Periodically push all metrics to a Prometheus Pushgateway (for firewalled nodes),
+        /// e.g. 'http://pushgateway:9091/metrics/job/electrs'.
+        pub fn start_push(&self, url: &str, interval: Duration) -> Result<()> {
+            let target = HttpTarget::parse(url).context("invalid metrics push URL")?;
+            let reg = self.reg.clone();
+            spawn("metrics_push", move || loop {
+                std::thread::sleep(interval);
+                // failures are retried on the next interval
+                let result = encode_text(&reg.gather()).and_then(|body| {
+                    target.send("PUT", prometheus::TEXT_FORMAT, &body, PUSH_TIMEOUT)
+                });
+                if let Err(e) = result {
+                    warn!("failed to push metrics to {}: {:#}", target, e);
+                }
+            });
+            info!("pushing Prometheus metrics to {} every {:?}", url, interval);
+            Ok(())
+        }
+
         pub fn histogram_vec(
             &self,
             name: &str,
@@ -56,7 +150,7 @@ mod metrics_impl {
             label: &str,
             buckets: Vec<f64>,
         ) -> Histogram {
-            let name = String::from("electrs_") + name;
+            let name = self.prefix.clone() + name;
             let opts = HistogramOpts::new(name, desc).buckets(buckets);
             let hist = HistogramVec::new(opts, &[label]).unwrap();
             self.reg
@@ -66,7 +160,7 @@ mod metrics_impl {
         }
 
         pub fn gauge(&self, name: &str, desc: &str, label: &str) -> Gauge {
-            let name = String::from("electrs_") + name;
+            let name = self.prefix.clone() + name;
             let opts = prometheus::Opts::new(name, desc);
             let gauge = prometheus::GaugeVec::new(opts, &[label]).unwrap();
             self.reg
@@ -74,6 +168,207 @@ mod metrics_impl {
                 .expect("failed to register Gauge");
             Gauge { gauge }
         }
+
+        pub fn counter(&self, name: &str, desc: &str, label: &str) -> Counter {
+            let name = self.prefix.clone() + name;
+            let opts = prometheus::Opts::new(name, desc);
+            let counter = prometheus::IntCounterVec::new(opts, &[label]).unwrap();
+            self.reg
+                .register(Box::new(counter.clone()))
+                .expect("failed to register Counter");
+            Counter { counter }
+        }
+    }
+
+    fn serve_admin(
+        request: Request,
+        access: Option<&AdminAccess>,
+        admin_tx: &Sender<AdminRequest>,
+    ) {
+        let mut request = request;
+        let (status, body) = match handle_admin(&mut request, access, admin_tx) {
+            Ok(result) => (200, json!({ "result": result })),
+            Err((status, error)) => (status, json!({ "error": error })),
+        };
+        let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(header);
+        if let Err(e) = request.respond(response) {
+            warn!("failed to send admin API response: {}", e);
+        }
+    }
+
+    /// Forward the command to the server loop, and wait for its result.
+    fn handle_admin(
+        request: &mut Request,
+        access: Option<&AdminAccess>,
+        admin_tx: &Sender<AdminRequest>,
+    ) -> std::result::Result<Value, (u16, String)> {
+        let access = access.ok_or_else(|| (404, "admin API is disabled".to_owned()))?;
+        let authorization = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Authorization"))
+            .map(|header| header.value.as_str());
+        if !access.is_allowed(request.remote_addr(), authorization) {
+            return Err((403, "admin API access denied".to_owned()));
+        }
+        if *request.method() != Method::Post {
+            return Err((405, "admin commands must use POST".to_owned()));
+        }
+        let command = request.url()[ADMIN_PREFIX.len()..].to_owned();
+        let mut body = String::new();
+        request
+            .as_reader()
+            .read_to_string(&mut body)
+            .map_err(|e| (400, format!("failed to read request: {}", e)))?;
+        let params = match body.trim() {
+            "" => Value::Null,
+            body => {
+                serde_json::from_str(body).map_err(|e| (400, format!("invalid JSON: {}", e)))?
+            }
+        };
+        let (admin_request, reply_rx) = AdminRequest::new(command, params);
+        admin_tx
+            .send(admin_request)
+            .map_err(|_| (503, "server is not running".to_owned()))?;
+        match reply_rx.recv_timeout(ADMIN_TIMEOUT) {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(e)) => Err((400, e)),
+            Err(_) => Err((504, "timed out waiting for the server".to_owned())),
+        }
+    }
+
+    fn accepts_openmetrics(request: &Request) -> bool {
+        request.headers().iter().any(|header| {
+            header.field.equiv("Accept")
+                && header
+                    .value
+                    .as_str()
+                    .contains("application/openmetrics-text")
+        })
+    }
+
+    fn encode_text(families: &[MetricFamily]) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        prometheus::TextEncoder::new()
+            .encode(families, &mut buffer)
+            .context("failed to encode metrics")?;
+        Ok(buffer)
+    }
+
+    /// Encode metrics using OpenMetrics text format (https://openmetrics.io/).
+    fn encode_openmetrics(families: &[MetricFamily]) -> String {
+        let mut out = String::new();
+        for family in families {
+            let name = family.get_name();
+            let (name, kind) = match family.get_field_type() {
+                MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+                MetricType::GAUGE => (name, "gauge"),
+                MetricType::HISTOGRAM => (name, "histogram"),
+                MetricType::SUMMARY => (name, "summary"),
+                MetricType::UNTYPED => (name, "unknown"),
+            };
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(out, "# HELP {} {}", name, escape(family.get_help())).unwrap();
+            for metric in family.get_metric() {
+                let labels: Vec<(&str, &str)> = metric
+                    .get_label()
+                    .iter()
+                    .map(|label| (label.get_name(), label.get_value()))
+                    .collect();
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        let value = format_float(metric.get_counter().get_value());
+                        write_sample(&mut out, name, "_total", &labels, None, &value);
+                    }
+                    MetricType::GAUGE => {
+                        let value = format_float(metric.get_gauge().get_value());
+                        write_sample(&mut out, name, "", &labels, None, &value);
+                    }
+                    MetricType::HISTOGRAM => {
+                        let hist = metric.get_histogram();
+                        let mut has_inf = false;
+                        for bucket in hist.get_bucket() {
+                            let upper_bound = bucket.get_upper_bound();
+                            has_inf |= upper_bound == f64::INFINITY;
+                            let le = ("le", format_float(upper_bound));
+                            let count = bucket.get_cumulative_count().to_string();
+                            write_sample(&mut out, name, "_bucket", &labels, Some(le), &count);
+                        }
+                        let count = hist.get_sample_count().to_string();
+                        if !has_inf {
+                            let le = ("le", format_float(f64::INFINITY));
+                            write_sample(&mut out, name, "_bucket", &labels, Some(le), &count);
+                        }
+                        write_sample(&mut out, name, "_count", &labels, None, &count);
+                        let sum = format_float(hist.get_sample_sum());
+                        write_sample(&mut out, name, "_sum", &labels, None, &sum);
+                    }
+                    MetricType::SUMMARY => {
+                        let summary = metric.get_summary();
+                        for quantile in summary.get_quantile() {
+                            let q = ("quantile", format_float(quantile.get_quantile()));
+                            let value = format_float(quantile.get_value());
+                            write_sample(&mut out, name, "", &labels, Some(q), &value);
+                        }
+                        let count = summary.get_sample_count().to_string();
+                        write_sample(&mut out, name, "_count", &labels, None, &count);
+                        let sum = format_float(summary.get_sample_sum());
+                        write_sample(&mut out, name, "_sum", &labels, None, &sum);
+                    }
+                    MetricType::UNTYPED => {
+                        let value = format_float(metric.get_untyped().get_value());
+                        write_sample(&mut out, name, "", &labels, None, &value);
+                    }
+                }
+            }
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+
+    fn write_sample(
+        out: &mut String,
+        name: &str,
+        suffix: &str,
+        labels: &[(&str, &str)],
+        extra_label: Option<(&str, String)>,
+        value: &str,
+    ) {
+        let extra_label = extra_label.as_ref().map(|(k, v)| (*k, v.as_str()));
+        let labels: Vec<String> = labels
+            .iter()
+            .copied()
+            .chain(extra_label)
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect();
+        if labels.is_empty() {
+            writeln!(out, "{}{} {}", name, suffix, value).unwrap();
+        } else {
+            let labels = labels.join(",");
+            writeln!(out, "{}{}{{{}}} {}", name, suffix, labels, value).unwrap();
+        }
+    }
+
+    fn escape(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+
+    fn format_float(value: f64) -> String {
+        if value == f64::INFINITY {
+            "+Inf".to_owned()
+        } else if value == f64::NEG_INFINITY {
+            "-Inf".to_owned()
+        } else if value.is_nan() {
+            "NaN".to_owned()
+        } else {
+            format!("{:?}", value)
+        }
     }
 
     #[derive(Clone)]
@@ -87,6 +382,21 @@ mod metrics_impl {
         }
     }
 
+    #[derive(Clone)]
+    pub struct Counter {
+        counter: prometheus::IntCounterVec,
+    }
+
+    impl Counter {
+        pub fn inc(&self, label: &str) {
+            self.counter.with_label_values(&[label]).inc()
+        }
+
+        pub fn inc_by(&self, label: &str, value: u64) {
+            self.counter.with_label_values(&[label]).inc_by(value)
+        }
+    }
+
     #[derive(Clone)]
     pub struct Histogram {
         hist: HistogramVec,
@@ -106,23 +416,83 @@ mod metrics_impl {
                 .observe_closure_duration(func)
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::encode_openmetrics;
+        use prometheus::{Counter, GaugeVec, HistogramOpts, HistogramVec, Opts, Registry};
+
+        #[test]
+        fn test_openmetrics() {
+            let reg = Registry::new();
+            let counter = Counter::new("a_requests_total", "Total requests").unwrap();
+            reg.register(Box::new(counter.clone())).unwrap();
+            let gauge = GaugeVec::new(Opts::new("b_peers", "Peers \"count\""), &["type"]).unwrap();
+            reg.register(Box::new(gauge.clone())).unwrap();
+            let opts = HistogramOpts::new("c_duration", "Duration").buckets(vec![1.0, 2.0]);
+            let hist = HistogramVec::new(opts, &["step"]).unwrap();
+            reg.register(Box::new(hist.clone())).unwrap();
+
+            counter.inc_by(3.0);
+            gauge.with_label_values(&["tcp"]).set(2.0);
+            hist.with_label_values(&["sync"]).observe(1.5);
+
+            assert_eq!(
+                encode_openmetrics(&reg.gather()),
+                r#"# TYPE a_requests counter
+# HELP a_requests Total requests
+a_requests_total 3.0
+# TYPE b_peers gauge
+# HELP b_peers Peers \"count\"
+b_peers{type="tcp"} 2.0
+# TYPE c_duration histogram
+# HELP c_duration Duration
+c_duration_bucket{step="sync",le="1.0"} 0
+c_duration_bucket{step="sync",le="2.0"} 1
+c_duration_bucket{step="sync",le="+Inf"} 1
+c_duration_count{step="sync"} 1
+c_duration_sum{step="sync"} 1.5
+# EOF
+"#
+            );
+        }
+    }
 }
 
 #[cfg(feature = "metrics")]
-pub use metrics_impl::{Gauge, Histogram, Metrics};
+pub use metrics_impl::{Counter, Gauge, Histogram, Metrics};
 
 #[cfg(not(feature = "metrics"))]
 mod metrics_fake {
     use anyhow::Result;
+    use crossbeam_channel::{never, Receiver};
 
     use std::net::SocketAddr;
+    use std::time::Duration;
 
-    pub struct Metrics {}
+    use crate::admin::{AdminAccess, AdminRequest};
+
+    pub struct Metrics {
+        admin_rx: Receiver<AdminRequest>,
+    }
 
     impl Metrics {
-        pub fn new(_addr: SocketAddr) -> Result<Self> {
+        pub fn new(_addr: SocketAddr, _admin: Option<AdminAccess>) -> Result<Self> {
             debug!("metrics collection is disabled");
-            Ok(Self {})
+            Ok(Self { admin_rx: never() })
+        }
+
+        #[cfg(any(test, feature = "testing"))]
+        pub(crate) fn unserved() -> Self {
+            Self { admin_rx: never() }
+        }
+
+        pub fn for_chain(&self, _chain: &str) -> Self {
+            Self { admin_rx: never() }
+        }
+
+        pub(crate) fn admin_requests(&self) -> &Receiver<AdminRequest> {
+            &self.admin_rx
         }
 
         pub fn histogram_vec(
@@ -138,6 +508,14 @@ mod metrics_fake {
         pub fn gauge(&self, _name: &str, _desc: &str, _label: &str) -> Gauge {
             Gauge {}
         }
+
+        pub fn counter(&self, _name: &str, _desc: &str, _label: &str) -> Counter {
+            Counter {}
+        }
+
+        pub fn start_push(&self, _url: &str, _interval: Duration) -> Result<()> {
+            Ok(())
+        }
     }
 
     #[derive(Clone)]
@@ -147,6 +525,15 @@ mod metrics_fake {
         pub fn set(&self, _label: &str, _value: f64) {}
     }
 
+    #[derive(Clone)]
+    pub struct Counter {}
+
+    impl Counter {
+        pub fn inc(&self, _label: &str) {}
+
+        pub fn inc_by(&self, _label: &str, _value: u64) {}
+    }
+
     #[derive(Clone)]
     pub struct Histogram {}
 
@@ -163,7 +550,7 @@ mod metrics_fake {
 }
 
 #[cfg(not(feature = "metrics"))]
-pub use metrics_fake::{Gauge, Histogram, Metrics};
+pub use metrics_fake::{Counter, Gauge, Histogram, Metrics};
 
 pub(crate) fn default_duration_buckets() -> Vec<f64> {
     vec![
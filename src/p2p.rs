@@ -14,7 +14,7 @@ use bitcoin::{
         message_network, Magic,
     },
     secp256k1::{self, rand::Rng},
-    Block, BlockHash, Network,
+    BlockHash, Network,
 };
 use bitcoin_slices::{bsl, Parse};
 use crossbeam_channel::{bounded, select, Receiver, Sender};
@@ -26,11 +26,15 @@ use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::types::SerBlock;
 use crate::{
+    auxpow,
     chain::{Chain, NewHeader},
     config::ELECTRS_VERSION,
-    metrics::{default_duration_buckets, default_size_buckets, Histogram, Metrics},
+    metrics::{default_duration_buckets, default_size_buckets, Counter, Histogram, Metrics},
 };
 
+// Blocks taking longer to be received are logged, to help diagnosing slow syncs
+const SLOW_BLOCK: Duration = Duration::from_secs(10);
+
 enum Request {
     GetNewHeaders(GetHeadersMessage),
     GetBlocks(Vec<Inventory>),
@@ -58,9 +62,13 @@ pub(crate) struct Connection {
     req_send: Sender<Request>,
     blocks_recv: Receiver<SerBlock>,
     headers_recv: Receiver<Vec<BlockHeader>>,
+    announced_recv: Receiver<Vec<BlockHeader>>,
     new_block_recv: Receiver<()>,
 
+    peer: SocketAddr,
     blocks_duration: Histogram,
+    blocks_received: Counter, // blocks and bytes received from the peer
+    failures: Counter,
 }
 
 impl Connection {
@@ -68,26 +76,43 @@ impl Connection {
     /// https://en.bitcoin.it/wiki/Protocol_documentation#getheaders
     /// Defined as `&mut self` to prevent concurrent invocations (https://github.com/romanz/electrs/pull/526#issuecomment-934685515).
     pub(crate) fn get_new_headers(&mut self, chain: &Chain) -> Result<Vec<NewHeader>> {
-        self.req_send.send(Request::get_new_headers(chain))?;
-        let headers = self
-            .headers_recv
-            .recv()
-            .context("failed to get new headers")?;
+        if let Some(announced) = self.announced_headers(chain) {
+            return Ok(announced);
+        }
+        // an announcement may be received (and returned) instead of the requested headers
+        for _ in 0..2 {
+            self.req_send.send(Request::get_new_headers(chain))?;
+            let headers = self.headers_recv.recv().map_err(|e| {
+                self.failures.inc("headers");
+                anyhow!(e).context("failed to get new headers")
+            })?;
 
-        debug!("got {} new headers", headers.len());
-        let prev_blockhash = match headers.first() {
-            None => return Ok(vec![]),
-            Some(first) => first.prev_blockhash,
-        };
-        let new_heights = match chain.get_block_height(&prev_blockhash) {
-            Some(last_height) => (last_height + 1)..,
-            None => bail!("missing prev_blockhash: {}", prev_blockhash),
-        };
-        Ok(headers
-            .into_iter()
-            .zip(new_heights)
-            .map(NewHeader::from)
-            .collect())
+            debug!("got {} new headers", headers.len());
+            let prev_blockhash = match headers.first() {
+                None => return Ok(vec![]),
+                Some(first) => first.prev_blockhash,
+            };
+            match chain.get_block_height(&prev_blockhash) {
+                Some(last_height) => return Ok(new_headers(headers, last_height + 1)),
+                None => debug!("missing prev_blockhash: {}", prev_blockhash),
+            }
+        }
+        self.failures.inc("headers");
+        bail!("failed to get new headers connecting to the chain")
+    }
+
+    /// Use the headers announced since the last call (BIP-130) if they extend the current tip,
+    /// saving a `getheaders` round-trip. Otherwise (e.g. on reorgs), the headers are requested.
+    /// Blocks are still validated against their headers, and the next sync will request the
+    /// headers from the daemon (so missing or invalid announcements are handled).
+    fn announced_headers(&self, chain: &Chain) -> Option<Vec<NewHeader>> {
+        let mut headers = self.announced_recv.try_iter().last()?;
+        headers.retain(|header| chain.get_block_height(&header.block_hash()).is_none());
+        if headers.first()?.prev_blockhash != chain.tip() {
+            return None;
+        }
+        debug!("using {} announced headers", headers.len());
+        Some(new_headers(headers, chain.height() + 1))
     }
 
     /// Request and process the specified blocks (in the specified order).
@@ -109,20 +134,26 @@ impl Connection {
             })?;
 
             for hash in blockhashes {
-                let block = self.blocks_duration.observe_duration("response", || {
-                    let block = self
-                        .blocks_recv
-                        .recv()
-                        .with_context(|| format!("failed to get block {}", hash))?;
-                    let header = bsl::BlockHeader::parse(&block[..])
-                        .expect("core returned invalid blockheader")
-                        .parsed_owned();
-                    ensure!(
-                        &header.block_hash_sha2()[..] == hash.as_byte_array(),
-                        "got unexpected block"
+                let start = Instant::now();
+                let block = self
+                    .blocks_duration
+                    .observe_duration("response", || self.recv_block(hash))
+                    .map_err(|e| {
+                        self.failures.inc("blocks");
+                        e
+                    })?;
+                let elapsed = start.elapsed();
+                if elapsed >= SLOW_BLOCK {
+                    warn!(
+                        "p2p peer {} is slow: block {} ({} bytes) took {:?}",
+                        self.peer,
+                        hash,
+                        block.len(),
+                        elapsed
                     );
-                    Ok(block)
-                })?;
+                }
+                self.blocks_received.inc("blocks");
+                self.blocks_received.inc_by("bytes", block.len() as u64);
                 self.blocks_duration
                     .observe_duration("process", || func(hash, block));
             }
@@ -130,6 +161,21 @@ impl Connection {
         })
     }
 
+    fn recv_block(&self, hash: BlockHash) -> Result<SerBlock> {
+        let block = self
+            .blocks_recv
+            .recv()
+            .with_context(|| format!("failed to get block {}", hash))?;
+        let header = bsl::BlockHeader::parse(&block[..])
+            .expect("core returned invalid blockheader")
+            .parsed_owned();
+        ensure!(
+            &header.block_hash_sha2()[..] == hash.as_byte_array(),
+            "got unexpected block"
+        );
+        Ok(block)
+    }
+
     /// Note: only a single receiver will get the notification (https://github.com/romanz/electrs/pull/526#issuecomment-934687415).
     pub(crate) fn new_block_notification(&self) -> Receiver<()> {
         self.new_block_recv.clone()
@@ -179,6 +225,12 @@ impl Connection {
             "step",
             default_duration_buckets(),
         );
+        let blocks_received = metrics.counter(
+            "p2p_blocks_received",
+            "# of blocks (and their bytes) received via p2p protocol",
+            "type",
+        );
+        let failures = metrics.counter("p2p_failures", "# of failed p2p requests", "type");
 
         let stream = Arc::clone(&conn);
         let mut buffer = vec![];
@@ -245,6 +297,9 @@ impl Connection {
         let (req_send, req_recv) = bounded::<Request>(1);
         let (blocks_send, blocks_recv) = bounded::<SerBlock>(10);
         let (headers_send, headers_recv) = bounded::<Vec<BlockHeader>>(1);
+        let (announced_send, announced_recv) = bounded::<Vec<BlockHeader>>(1);
+        let stale_announced = announced_recv.clone();
+        let mut headers_requested = false;
         let (new_block_send, new_block_recv) = bounded::<()>(0);
         let (init_send, init_recv) = bounded::<()>(0);
 
@@ -284,10 +339,22 @@ impl Connection {
                             tx_send.send(NetworkMessage::Pong(nonce))?; // connection keep-alive
                         }
                         ParsedNetworkMessage::Verack => {
+                            // announce new blocks using `headers` messages (BIP-130)
+                            tx_send.send(NetworkMessage::SendHeaders)?;
                             init_send.send(())?; // peer acknowledged our version
                         }
                         ParsedNetworkMessage::Block(block) => blocks_send.send(block)?,
-                        ParsedNetworkMessage::Headers(headers) => headers_send.send(headers)?,
+                        ParsedNetworkMessage::Headers(headers) => {
+                            if headers_requested {
+                                headers_requested = false;
+                                headers_send.send(headers)?;
+                            } else if !headers.is_empty() {
+                                debug!("peer announced {} headers", headers.len());
+                                let _ = stale_announced.try_recv(); // keep the latest announcement
+                                let _ = announced_send.try_send(headers);
+                                let _ = new_block_send.try_send(()); // best-effort notification
+                            }
+                        }
                         ParsedNetworkMessage::Ignored => (),
                     }
                 }
@@ -300,7 +367,10 @@ impl Connection {
                         }
                     };
                     let msg = match req {
-                        Request::GetNewHeaders(msg) => NetworkMessage::GetHeaders(msg),
+                        Request::GetNewHeaders(msg) => {
+                            headers_requested = true;
+                            NetworkMessage::GetHeaders(msg)
+                        }
                         Request::GetBlocks(inv) => NetworkMessage::GetData(inv),
                     };
                     tx_send.send(msg)?;
@@ -314,12 +384,24 @@ impl Connection {
             req_send,
             blocks_recv,
             headers_recv,
+            announced_recv,
             new_block_recv,
+            peer: address,
             blocks_duration,
+            blocks_received,
+            failures,
         })
     }
 }
 
+fn new_headers(headers: Vec<BlockHeader>, first_height: usize) -> Vec<NewHeader> {
+    headers
+        .into_iter()
+        .zip(first_height..)
+        .map(NewHeader::from)
+        .collect()
+}
+
 fn build_version_message() -> NetworkMessage {
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
     let timestamp = SystemTime::now()
@@ -355,12 +437,13 @@ impl RawNetworkMessage {
             "version" => ParsedNetworkMessage::Version(Decodable::consensus_decode(&mut raw)?),
             "verack" => ParsedNetworkMessage::Verack,
             "inv" => ParsedNetworkMessage::Inv(Decodable::consensus_decode(&mut raw)?),
-            "block" => ParsedNetworkMessage::Block(self.raw),
+            "block" => ParsedNetworkMessage::Block(auxpow::strip_block(self.raw)?),
             "headers" => {
                 let len = VarInt::consensus_decode(&mut raw)?.0;
                 let mut headers = Vec::with_capacity(len as usize);
                 for _ in 0..len {
-                    headers.push(Block::consensus_decode(&mut raw)?.header);
+                    headers.push(auxpow::decode_header(&mut raw)?);
+                    let _txs = VarInt::consensus_decode(&mut raw)?; // always 0
                 }
                 ParsedNetworkMessage::Headers(headers)
             }
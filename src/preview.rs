@@ -0,0 +1,177 @@
+use anyhow::{Context, Result};
+use bitcoin::{
+    consensus::deserialize,
+    hashes::hex::{DisplayHex, FromHex},
+    psbt::Psbt,
+    OutPoint, Transaction, TxOut,
+};
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+
+use crate::{
+    electrum::{Rpc, MAX_PREVOUT_FETCHES},
+    errors::ErrorCode,
+    types::ScriptType,
+};
+
+const MAX_PREVIEW_INPUTS: usize = 500;
+
+/// A transaction to be previewed (with the spent outputs provided by the PSBT, if any).
+pub(crate) struct Candidate {
+    tx: Transaction,
+    psbt_prevouts: HashMap<OutPoint, TxOut>,
+}
+
+impl Candidate {
+    /// `hex` may contain a finalized PSBT, or a raw transaction.
+    pub(crate) fn parse(hex: &str) -> Result<Self> {
+        let bytes = Vec::from_hex(hex).context("non-hex transaction")?;
+        if !bytes.starts_with(b"psbt\xff") {
+            let tx = deserialize(&bytes).context("invalid transaction")?;
+            return Ok(Self {
+                tx,
+                psbt_prevouts: HashMap::new(),
+            });
+        }
+        let psbt = Psbt::deserialize(&bytes).context("invalid PSBT")?;
+        let finalized = psbt
+            .inputs
+            .iter()
+            .all(|input| input.final_script_sig.is_some() || input.final_script_witness.is_some());
+        ensure!(finalized, "PSBT is not finalized");
+        let mut psbt_prevouts = HashMap::new();
+        for (txin, input) in psbt.unsigned_tx.input.iter().zip(&psbt.inputs) {
+            let outpoint = txin.previous_output;
+            let prevout = input.witness_utxo.clone().or_else(|| {
+                let prev_tx = input.non_witness_utxo.as_ref()?;
+                prev_tx.output.get(outpoint.vout as usize).cloned()
+            });
+            if let Some(prevout) = prevout {
+                psbt_prevouts.insert(outpoint, prevout);
+            }
+        }
+        Ok(Self {
+            tx: psbt.extract_tx(),
+            psbt_prevouts,
+        })
+    }
+
+    pub(crate) fn tx(&self) -> &Transaction {
+        &self.tx
+    }
+}
+
+/// Resolve the spent outputs (preferring the indexed ones over the PSBT's, and looking up at most
+/// `MAX_PREVOUT_FETCHES` of them), and summarize the transaction's fee, RBF signaling and dust
+/// outputs (without broadcasting it).
+pub(crate) fn preview(rpc: &Rpc, candidate: &Candidate) -> Result<Value> {
+    let tx = &candidate.tx;
+    ensure!(
+        !tx.is_coin_base(),
+        "coinbase transactions can't be broadcasted"
+    );
+    ensure!(
+        tx.input.len() <= MAX_PREVIEW_INPUTS,
+        "too many inputs: {} > {}",
+        tx.input.len(),
+        MAX_PREVIEW_INPUTS
+    );
+    let mut input_value = 0;
+    let mut inputs = Vec::with_capacity(tx.input.len());
+    let mut fetches = MAX_PREVOUT_FETCHES;
+    for txin in &tx.input {
+        let outpoint = txin.previous_output;
+        let indexed = match rpc.find_prevout(outpoint, &mut fetches) {
+            Ok(prevout) => prevout,
+            // after too many lookups, the PSBT's prevouts are still used
+            Err(e)
+                if ErrorCode::of(&e) == ErrorCode::LookupLimitExceeded
+                    && candidate.psbt_prevouts.contains_key(&outpoint) =>
+            {
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        let indexed = indexed.map(|prevout| {
+            let in_mempool = rpc.in_mempool(&outpoint.txid);
+            (prevout, if in_mempool { "mempool" } else { "chain" })
+        });
+        let (prevout, source) = match indexed {
+            Some(found) => found,
+            None => match candidate.psbt_prevouts.get(&outpoint) {
+                Some(prevout) => (prevout.clone(), "psbt"),
+                None => bail!("missing spent output {}", outpoint),
+            },
+        };
+        input_value += prevout.value.to_sat();
+        inputs.push(json!({
+            "txid": outpoint.txid,
+            "vout": outpoint.vout,
+            "sequence": txin.sequence.to_consensus_u32(),
+            "value": prevout.value.to_sat(),
+            "scriptpubkey": prevout.script_pubkey.as_bytes().to_lower_hex_string(),
+            "script_type": ScriptType::classify(&prevout.script_pubkey).as_str(),
+            "source": source, // "chain", "mempool" or "psbt"
+        }));
+    }
+    let mut dust_outputs = vec![];
+    let outputs: Vec<Value> = tx
+        .output
+        .iter()
+        .enumerate()
+        .map(|(vout, txout)| {
+            let script = &txout.script_pubkey;
+            let is_dust = !script.is_op_return() && txout.value < script.dust_value();
+            if is_dust {
+                dust_outputs.push(vout);
+            }
+            json!({
+                "value": txout.value.to_sat(),
+                "scriptpubkey": script.as_bytes().to_lower_hex_string(),
+                "script_type": ScriptType::classify(script).as_str(),
+                "dust": is_dust,
+            })
+        })
+        .collect();
+    let output_value: u64 = tx.output.iter().map(|txout| txout.value.to_sat()).sum();
+    ensure!(
+        output_value <= input_value,
+        "outputs' value exceeds inputs' value: {} > {}",
+        output_value,
+        input_value
+    );
+    let fee = input_value - output_value;
+    let vsize = tx.vsize();
+    let txid = tx.txid();
+    Ok(json!({
+        "txid": txid,
+        "size": tx.total_size(),
+        "vsize": vsize,
+        "weight": tx.weight().to_wu(),
+        "inputs": inputs,
+        "outputs": outputs,
+        "fee": fee,
+        "feerate": fee as f64 / vsize as f64, // in sat/vB
+        "rbf": tx.is_explicitly_rbf(),
+        "dust_outputs": dust_outputs,
+        "in_mempool": rpc.in_mempool(&txid),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Candidate;
+
+    #[test]
+    fn test_parse() {
+        // a legacy transaction, spending a single P2PKH output
+        let tx_hex = "0100000001a15d57094aa7a21a28cb20b59aab8fc7d1149a3bdbcddba9c622e4f5f6a99ece010000006c493046022100f93bb0e7d8db7bd46e40132d1f8242026e045f03a0efe71bbb8e3f475e970d790221009337cd7f1f929f00cc6ff01f03729b069a7c21b59b1736ddfee5db5946c5da8c0121033b9b137ee87d5a812d6f506efdd37f0affa7ffc310711c06c7f3e097c9447c52ffffffff0100e1f505000000001976a9140389035a9225b3839e2bbf32d826a1e222031fd888ac00000000";
+        let candidate = Candidate::parse(tx_hex).unwrap();
+        assert_eq!(candidate.tx().input.len(), 1);
+        assert!(candidate.psbt_prevouts.is_empty());
+
+        assert!(Candidate::parse("zz").is_err());
+        assert!(Candidate::parse("70736274ff00").is_err()); // truncated PSBT
+    }
+}
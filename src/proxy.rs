@@ -0,0 +1,168 @@
+use anyhow::{Context, Result};
+
+use std::convert::TryFrom;
+use std::io::BufRead;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+// See https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt for details.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107; // including the CRLF
+
+/// Read PROXY protocol header (v1 or v2) from the beginning of a connection.
+/// Returns the original client address, or `None` if it is not provided by the proxy
+/// (e.g. health checks using `LOCAL` or `UNKNOWN` connections).
+pub(crate) fn read_header<R: BufRead>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 12]; // the shortest v1 header is longer
+    reader
+        .read_exact(&mut prefix)
+        .context("failed to read PROXY header")?;
+    if prefix == V2_SIGNATURE {
+        return read_v2(reader);
+    }
+    if !prefix.starts_with(V1_PREFIX) {
+        bail!("missing PROXY header");
+    }
+    let mut line = prefix.to_vec();
+    reader
+        .take((V1_MAX_LENGTH - prefix.len()) as u64)
+        .read_until(b'\n', &mut line)
+        .context("failed to read PROXY header")?;
+    parse_v1(&line)
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).context("non-ASCII PROXY header")?;
+    let line = match line.strip_suffix("\r\n") {
+        Some(line) => line,
+        None => bail!("too long PROXY header"),
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src_addr, _dst_addr, src_port, _dst_port] => {
+            let ip = IpAddr::from_str(src_addr)
+                .with_context(|| format!("invalid PROXY source address: {}", src_addr))?;
+            ensure!(
+                ip.is_ipv4() == (family == "TCP4"),
+                "PROXY source address {} doesn't match {}",
+                ip,
+                family
+            );
+            let port = u16::from_str(src_port)
+                .with_context(|| format!("invalid PROXY source port: {}", src_port))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("invalid PROXY header: {:?}", line),
+    }
+}
+
+fn read_v2<R: BufRead>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4];
+    reader
+        .read_exact(&mut header)
+        .context("failed to read PROXY header")?;
+    let [version_command, family, len_hi, len_lo] = header;
+    ensure!(
+        version_command >> 4 == 2,
+        "unsupported PROXY version: {}",
+        version_command >> 4
+    );
+    let mut addresses = vec![0u8; usize::from(u16::from_be_bytes([len_hi, len_lo]))];
+    reader
+        .read_exact(&mut addresses)
+        .context("failed to read PROXY addresses")?;
+    match version_command & 0x0F {
+        0x0 => return Ok(None), // LOCAL command (e.g. health check)
+        0x1 => (),              // PROXY command
+        command => bail!("unsupported PROXY command: {}", command),
+    }
+    let addr = match family >> 4 {
+        0x1 => {
+            ensure!(addresses.len() >= 12, "too short PROXY IPv4 addresses");
+            let ip = <[u8; 4]>::try_from(&addresses[0..4]).unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port)
+        }
+        0x2 => {
+            ensure!(addresses.len() >= 36, "too short PROXY IPv6 addresses");
+            let ip = <[u8; 16]>::try_from(&addresses[0..16]).unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)
+        }
+        _ => return Ok(None), // AF_UNSPEC or AF_UNIX
+    };
+    Ok(Some(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_header, V2_SIGNATURE};
+    use std::io::{BufRead, BufReader};
+    use std::net::SocketAddr;
+
+    fn parse(data: &[u8]) -> (Option<SocketAddr>, String) {
+        let mut reader = BufReader::new(data);
+        let addr = read_header(&mut reader).unwrap();
+        let rest = reader.lines().next().unwrap().unwrap();
+        (addr, rest)
+    }
+
+    #[test]
+    fn test_v1() {
+        let data = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 50001\r\n{\"id\": 1}\n";
+        assert_eq!(
+            parse(data),
+            (
+                Some("192.168.0.1:56324".parse().unwrap()),
+                "{\"id\": 1}".into()
+            )
+        );
+
+        let data = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 50001\r\n{}\n";
+        assert_eq!(
+            parse(data),
+            (Some("[2001:db8::1]:56324".parse().unwrap()), "{}".into())
+        );
+
+        let data = b"PROXY UNKNOWN\r\n{}\n";
+        assert_eq!(parse(data), (None, "{}".into()));
+    }
+
+    #[test]
+    fn test_v1_invalid() {
+        let invalid: &[&[u8]] = &[
+            b"{\"id\": 1, \"method\": \"server.ping\"}\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n",
+            b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 50001\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 99999 50001\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 50001\n",
+            b"PROXY UNKNOWN ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff\r\n",
+        ];
+        for data in invalid {
+            assert!(read_header(&mut BufReader::new(*data)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([0x21, 0x11, 0x00, 0x0C]); // PROXY command, TCP over IPv4
+        data.extend([10, 0, 0, 1, 10, 0, 0, 2, 0xDC, 0x04, 0xC3, 0x51]);
+        data.extend(b"{}\n");
+        assert_eq!(
+            parse(&data),
+            (Some("10.0.0.1:56324".parse().unwrap()), "{}".into())
+        );
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([0x20, 0x00, 0x00, 0x00]); // LOCAL command
+        data.extend(b"{}\n");
+        assert_eq!(parse(&data), (None, "{}".into()));
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([0x31, 0x11, 0x00, 0x00]); // unsupported version
+        assert!(read_header(&mut BufReader::new(&data[..])).is_err());
+    }
+}
@@ -0,0 +1,685 @@
+//! A subset of the Esplora HTTP API (see https://github.com/Blockstream/esplora/blob/master/API.md),
+//! so that Esplora-based wallets and tools can use this server directly.
+//! Header-only clients (e.g. SPV verifiers) may also fetch batches of headers, and wait for
+//! new blocks (using `/headers/:start_height[/:count]` and `/blocks/tip/wait/:hash`).
+
+use anyhow::{Context, Result};
+use bitcoin::{
+    consensus::encode::serialize_hex, hashes::hex::DisplayHex, Address, BlockHash, Network,
+    Transaction, TxOut, Txid,
+};
+use crossbeam_channel::{Receiver, Sender};
+use parking_lot::{Condvar, Mutex};
+use serde_json::{json, Value};
+
+use crate::{
+    chain::Chain,
+    electrum::{Rpc, MAX_PREVOUT_FETCHES},
+    errors::ErrorCode,
+    types::{ScriptHash, ScriptType},
+};
+
+// Same page sizes as Esplora
+const MAX_MEMPOOL_TXS: usize = 50;
+const CHAIN_TXS_PER_PAGE: usize = 25;
+
+// Same as Electrum's `blockchain.block.headers`
+const MAX_HEADERS: usize = 2016;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Route {
+    TipHeight,
+    TipHash,
+    TipWait(BlockHash), // reply when the tip is different from this one
+    BlockHeight(usize),
+    BlockHeader(BlockHash),
+    BlockStatus(BlockHash),
+    Headers {
+        start_height: usize,
+        count: usize,
+    },
+    Tx(Txid),
+    TxHex(Txid),
+    TxStatus(Txid),
+    Txs {
+        scripthash: ScriptHash,
+        script_type: Option<ScriptType>, // unknown for scripthash queries
+        mempool: bool,                   // include mempool transactions
+        last_seen: Option<Txid>,         // return confirmed transactions after this one
+    },
+    Utxo(ScriptHash, Option<ScriptType>),
+}
+
+pub(crate) enum Reply {
+    Json(Value),
+    Text(String),
+}
+
+/// Errors are replied with their code, and `None` is replied for missing resources.
+type RestReply = Result<Option<Reply>, (ErrorCode, String)>;
+
+/// A REST API request, handled by the server loop.
+pub(crate) struct RestRequest {
+    route: Route,
+    reply: Sender<RestReply>,
+}
+
+impl RestRequest {
+    #[cfg(feature = "rest")]
+    fn new(route: Route) -> (Self, Receiver<RestReply>) {
+        let (reply, reply_rx) = crossbeam_channel::bounded(1);
+        (Self { route, reply }, reply_rx)
+    }
+}
+
+/// The current chain tip (updated by the server loop), so clients can wait for new blocks
+/// without blocking the server loop.
+pub(crate) struct TipWatch {
+    tip: Mutex<(usize, BlockHash)>,
+    changed: Condvar,
+}
+
+impl TipWatch {
+    pub(crate) fn new(chain: &Chain) -> Self {
+        Self {
+            tip: Mutex::new((chain.height(), chain.tip())),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn update(&self, chain: &Chain) {
+        let new_tip = (chain.height(), chain.tip());
+        let mut tip = self.tip.lock();
+        if *tip != new_tip {
+            *tip = new_tip;
+            self.changed.notify_all();
+        }
+    }
+}
+
+fn tip_value((height, hash): (usize, BlockHash)) -> Value {
+    json!({"height": height, "hash": hash})
+}
+
+impl Route {
+    /// Returns `None` for unknown routes (e.g. "/tx/<txid>/outspends").
+    #[cfg(feature = "rest")]
+    fn parse(url: &str, network: Network) -> Result<Option<Self>> {
+        let path = url.split('?').next().unwrap_or_default();
+        let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+        Ok(Some(match parts.as_slice() {
+            ["blocks", "tip", "height"] => Route::TipHeight,
+            ["blocks", "tip", "hash"] => Route::TipHash,
+            ["blocks", "tip", "wait", hash] => Route::TipWait(parse_blockhash(hash)?),
+            ["block-height", height] => Route::BlockHeight(parse_height(height)?),
+            ["block", hash, "header"] => Route::BlockHeader(parse_blockhash(hash)?),
+            ["block", hash, "status"] => Route::BlockStatus(parse_blockhash(hash)?),
+            ["headers", start_height] => Route::Headers {
+                start_height: parse_height(start_height)?,
+                count: MAX_HEADERS,
+            },
+            ["headers", start_height, count] => Route::Headers {
+                start_height: parse_height(start_height)?,
+                count: count
+                    .parse()
+                    .with_context(|| format!("invalid count: {}", count))?,
+            },
+            ["tx", txid] => Route::Tx(parse_txid(txid)?),
+            ["tx", txid, "hex"] => Route::TxHex(parse_txid(txid)?),
+            ["tx", txid, "status"] => Route::TxStatus(parse_txid(txid)?),
+            [kind @ ("address" | "scripthash"), value, rest @ ..] => {
+                let (scripthash, script_type) = if *kind == "address" {
+                    let address = value
+                        .parse::<Address<bitcoin::address::NetworkUnchecked>>()
+                        .with_context(|| format!("invalid address: {}", value))?
+                        .require_network(network)
+                        .with_context(|| format!("address is not valid for {}", network))?;
+                    let script = address.script_pubkey();
+                    (
+                        ScriptHash::new(&script),
+                        Some(ScriptType::classify(&script)),
+                    )
+                } else {
+                    // unlike Electrum, Esplora doesn't reverse the hash
+                    use bitcoin::hashes::{sha256, Hash};
+                    let hash: sha256::Hash = value
+                        .parse()
+                        .with_context(|| format!("invalid scripthash: {}", value))?;
+                    (ScriptHash::from_raw_hash(hash), None)
+                };
+                match rest {
+                    ["txs"] => Route::Txs {
+                        scripthash,
+                        script_type,
+                        mempool: true,
+                        last_seen: None,
+                    },
+                    ["txs", "chain"] => Route::Txs {
+                        scripthash,
+                        script_type,
+                        mempool: false,
+                        last_seen: None,
+                    },
+                    ["txs", "chain", last_seen] => Route::Txs {
+                        scripthash,
+                        script_type,
+                        mempool: false,
+                        last_seen: Some(parse_txid(last_seen)?),
+                    },
+                    ["utxo"] => Route::Utxo(scripthash, script_type),
+                    _ => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        }))
+    }
+
+    /// Like Electrum, only the chain's routes are available while the index is not ready.
+    fn uses_index(&self) -> bool {
+        match self {
+            Route::TipHeight
+            | Route::TipHash
+            | Route::TipWait(_)
+            | Route::BlockHeight(_)
+            | Route::BlockHeader(_)
+            | Route::BlockStatus(_)
+            | Route::Headers { .. } => false,
+            Route::Tx(_)
+            | Route::TxHex(_)
+            | Route::TxStatus(_)
+            | Route::Txs { .. }
+            | Route::Utxo(..) => true,
+        }
+    }
+
+    fn reply(&self, rpc: &Rpc, network: Network) -> Result<Option<Reply>> {
+        let chain = rpc.chain();
+        let mut fetches = MAX_PREVOUT_FETCHES; // shared by all the replied transactions
+        Ok(Some(match self {
+            Route::TipHeight => Reply::Text(chain.height().to_string()),
+            Route::TipHash => Reply::Text(chain.tip().to_string()),
+            // waiting is done by the HTTP server (so the current tip is replied here)
+            Route::TipWait(_) => Reply::Json(tip_value((chain.height(), chain.tip()))),
+            Route::BlockHeight(height) => match chain.get_block_hash(*height) {
+                Some(blockhash) => Reply::Text(blockhash.to_string()),
+                None => return Ok(None),
+            },
+            Route::BlockHeader(blockhash) => match chain.get_block_height(blockhash) {
+                Some(height) => {
+                    let header = chain.get_block_header(height).expect("missing header");
+                    Reply::Text(serialize_hex(header))
+                }
+                None => return Ok(None),
+            },
+            // stale (and unknown) blocks are reported as not in the best chain
+            Route::BlockStatus(blockhash) => Reply::Json(match chain.get_block_height(blockhash) {
+                Some(height) => json!({
+                    "in_best_chain": true,
+                    "height": height,
+                    "next_best": chain.get_block_hash(height + 1),
+                }),
+                None => json!({ "in_best_chain": false }),
+            }),
+            Route::Headers {
+                start_height,
+                count,
+            } => {
+                let end_height = std::cmp::min(
+                    chain.height() + 1,
+                    start_height.saturating_add(std::cmp::min(*count, MAX_HEADERS)),
+                );
+                let headers: Vec<String> = (*start_height..end_height)
+                    .map(|height| {
+                        serialize_hex(chain.get_block_header(height).expect("missing header"))
+                    })
+                    .collect();
+                Reply::Json(json!(headers))
+            }
+            Route::Tx(txid) => match rpc.find_transaction(*txid)? {
+                Some((tx, blockhash)) => {
+                    Reply::Json(tx_value(rpc, network, &tx, blockhash, &mut fetches)?)
+                }
+                None => return Ok(None),
+            },
+            Route::TxHex(txid) => match rpc.find_transaction(*txid)? {
+                Some((tx, _blockhash)) => Reply::Text(serialize_hex(&tx)),
+                None => return Ok(None),
+            },
+            Route::TxStatus(txid) => match rpc.find_transaction(*txid)? {
+                Some((_tx, blockhash)) => Reply::Json(block_status(chain, blockhash)),
+                None => return Ok(None),
+            },
+            Route::Txs {
+                scripthash,
+                script_type,
+                mempool,
+                last_seen,
+            } => {
+                if let Some(script_type) = script_type {
+                    rpc.check_script_type(*script_type)?;
+                }
+                let status = rpc.scripthash_status(*scripthash)?;
+                let history = status.get_history();
+                // newest transactions first
+                let unconfirmed = history
+                    .iter()
+                    .rev()
+                    .filter(|entry| entry.confirmed_height().is_none())
+                    .take(if *mempool { MAX_MEMPOOL_TXS } else { 0 });
+                let confirmed = history
+                    .iter()
+                    .rev()
+                    .filter(|entry| entry.confirmed_height().is_some())
+                    .skip_while(|entry| last_seen.map_or(false, |txid| entry.txid() != txid))
+                    .skip(usize::from(last_seen.is_some()))
+                    .take(CHAIN_TXS_PER_PAGE);
+                let mut txs = vec![];
+                for entry in unconfirmed.chain(confirmed) {
+                    // the status sync has cached most of them
+                    let found = match rpc.known_transaction(&entry.txid()) {
+                        Some(tx) => {
+                            let height = entry.confirmed_height();
+                            Some((tx, height.and_then(|height| chain.get_block_hash(height))))
+                        }
+                        None => rpc.find_transaction(entry.txid())?,
+                    };
+                    // mempool transactions may be evicted meanwhile
+                    if let Some((tx, blockhash)) = found {
+                        txs.push(tx_value(rpc, network, &tx, blockhash, &mut fetches)?);
+                    }
+                }
+                Reply::Json(json!(txs))
+            }
+            Route::Utxo(scripthash, script_type) => {
+                if let Some(script_type) = script_type {
+                    rpc.check_script_type(*script_type)?;
+                }
+                let status = rpc.scripthash_status(*scripthash)?;
+                let utxos: Vec<Value> = rpc
+                    .unspent(&status)
+                    .iter()
+                    .map(|entry| {
+                        let outpoint = entry.outpoint();
+                        let blockhash = entry
+                            .confirmed_height()
+                            .and_then(|height| chain.get_block_hash(height));
+                        json!({
+                            "txid": outpoint.txid,
+                            "vout": outpoint.vout,
+                            "value": entry.value().to_sat(),
+                            "status": block_status(chain, blockhash),
+                        })
+                    })
+                    .collect();
+                Reply::Json(json!(utxos))
+            }
+        }))
+    }
+}
+
+#[cfg(feature = "rest")]
+fn parse_txid(value: &str) -> Result<Txid> {
+    value
+        .parse()
+        .with_context(|| format!("invalid txid: {}", value))
+}
+
+#[cfg(feature = "rest")]
+fn parse_blockhash(value: &str) -> Result<BlockHash> {
+    value
+        .parse()
+        .with_context(|| format!("invalid block hash: {}", value))
+}
+
+#[cfg(feature = "rest")]
+fn parse_height(value: &str) -> Result<usize> {
+    value
+        .parse()
+        .with_context(|| format!("invalid height: {}", value))
+}
+
+pub(crate) fn handle(rpc: &Rpc, network: Network, request: RestRequest) {
+    let result = match rpc.unavailable_index() {
+        Some(progress) if request.route.uses_index() => {
+            let error = format!("server busy, syncing ({})", progress);
+            Err((ErrorCode::IndexSyncing, error))
+        }
+        _ => request
+            .route
+            .reply(rpc, network)
+            .map_err(|e| (ErrorCode::of(&e), format!("{:#}", e))),
+    };
+    // the HTTP handler may have timed out meanwhile
+    let _ = request.reply.send(result);
+}
+
+fn block_status(chain: &Chain, blockhash: Option<BlockHash>) -> Value {
+    // stale blocks' transactions are reported as unconfirmed
+    let confirmed =
+        blockhash.and_then(|blockhash| Some((blockhash, chain.get_block_height(&blockhash)?)));
+    match confirmed {
+        Some((blockhash, height)) => json!({
+            "confirmed": true,
+            "block_height": height,
+            "block_hash": blockhash,
+            "block_time": chain.get_block_header(height).expect("missing header").time,
+        }),
+        None => json!({ "confirmed": false }),
+    }
+}
+
+fn txout_value(txout: &TxOut, network: Network) -> Value {
+    let mut value = json!({
+        "scriptpubkey": txout.script_pubkey.as_bytes().to_lower_hex_string(),
+        "value": txout.value.to_sat(),
+    });
+    if let Ok(address) = Address::from_script(&txout.script_pubkey, network) {
+        value["scriptpubkey_address"] = json!(address.to_string());
+    }
+    value
+}
+
+/// The spent outputs are looked up (bounded by `fetches`, see `Rpc::tx_prevouts`), in order
+/// to compute the fee.
+fn tx_value(
+    rpc: &Rpc,
+    network: Network,
+    tx: &Transaction,
+    blockhash: Option<BlockHash>,
+    fetches: &mut usize,
+) -> Result<Value> {
+    let prevouts = rpc.tx_prevouts(tx, blockhash, fetches)?;
+    for (txin, prevout) in tx.input.iter().zip(&prevouts) {
+        let outpoint = txin.previous_output;
+        ensure!(
+            prevout.is_some() || outpoint.is_null(), // coinbase input
+            "missing spent output {}",
+            outpoint
+        );
+    }
+    let is_coinbase = prevouts.iter().all(Option::is_none);
+    let fee = if is_coinbase {
+        0
+    } else {
+        let input_value: u64 = prevouts.iter().flatten().map(|o| o.value.to_sat()).sum();
+        let output_value: u64 = tx.output.iter().map(|o| o.value.to_sat()).sum();
+        input_value.saturating_sub(output_value)
+    };
+    let vin: Vec<Value> = tx
+        .input
+        .iter()
+        .zip(prevouts)
+        .map(|(txin, prevout)| {
+            json!({
+                "txid": txin.previous_output.txid,
+                "vout": txin.previous_output.vout,
+                "prevout": prevout.map(|txout| txout_value(&txout, network)),
+                "scriptsig": txin.script_sig.as_bytes().to_lower_hex_string(),
+                "witness": txin
+                    .witness
+                    .iter()
+                    .map(|item| item.to_lower_hex_string())
+                    .collect::<Vec<String>>(),
+                "is_coinbase": is_coinbase,
+                "sequence": txin.sequence.0,
+            })
+        })
+        .collect();
+    Ok(json!({
+        "txid": tx.txid(),
+        "version": tx.version.0,
+        "locktime": tx.lock_time.to_consensus_u32(),
+        "vin": vin,
+        "vout": tx
+            .output
+            .iter()
+            .map(|txout| txout_value(txout, network))
+            .collect::<Vec<Value>>(),
+        "size": tx.total_size(),
+        "weight": tx.weight().to_wu(),
+        "fee": fee,
+        "status": block_status(rpc.chain(), blockhash),
+    }))
+}
+
+#[cfg(feature = "rest")]
+mod http {
+    use crossbeam_channel::{never, unbounded, Receiver, Sender};
+    use tiny_http::{Header, Method, Request, Response, Server};
+
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::{tip_value, Reply, RestRequest, Route, TipWatch};
+    use crate::{errors::ErrorCode, thread::spawn};
+    use anyhow::Result;
+    use bitcoin::{BlockHash, Network};
+
+    const REST_TIMEOUT: Duration = Duration::from_secs(60);
+
+    // Waiting clients are replied with the current tip after this timeout, so they can retry
+    const TIP_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    // Each waiting client uses its own thread
+    const MAX_TIP_WAITERS: usize = 100;
+
+    impl TipWatch {
+        /// Wait until the tip is different from `known` (or until `timeout` expires),
+        /// and return the current tip's height and hash.
+        fn wait(&self, known: BlockHash, timeout: Duration) -> (usize, BlockHash) {
+            let deadline = Instant::now() + timeout;
+            let mut tip = self.tip.lock();
+            while tip.1 == known {
+                if self.changed.wait_until(&mut tip, deadline).timed_out() {
+                    break;
+                }
+            }
+            *tip
+        }
+    }
+
+    pub(crate) fn start(
+        addr: Option<SocketAddr>,
+        network: Network,
+        tips: Arc<TipWatch>,
+    ) -> Result<Receiver<RestRequest>> {
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return Ok(never()),
+        };
+        let server = match Server::http(addr) {
+            Ok(server) => server,
+            Err(err) => bail!("failed to start REST API server on {}: {}", addr, err),
+        };
+        let (rest_tx, rest_rx) = unbounded();
+        spawn("rest", move || {
+            let waiters = Arc::new(AtomicUsize::new(0));
+            for request in server.incoming_requests() {
+                match parse(&request, network) {
+                    Ok(Route::TipWait(known)) => wait(request, known, &tips, &waiters),
+                    route => {
+                        let result = route.and_then(|route| forward(route, &rest_tx));
+                        respond(request, result);
+                    }
+                }
+            }
+            Ok(())
+        });
+        info!("serving Esplora REST API on {}", addr);
+        Ok(rest_rx)
+    }
+
+    /// Reply on a separate thread, so other requests are not blocked meanwhile.
+    fn wait(request: Request, known: BlockHash, tips: &Arc<TipWatch>, waiters: &Arc<AtomicUsize>) {
+        if waiters.fetch_add(1, Ordering::SeqCst) >= MAX_TIP_WAITERS {
+            waiters.fetch_sub(1, Ordering::SeqCst);
+            let error = "too many clients are waiting".to_owned();
+            respond(request, Err((ErrorCode::Unavailable, error)));
+            return;
+        }
+        let (tips, waiters) = (Arc::clone(tips), Arc::clone(waiters));
+        spawn("rest_wait", move || {
+            let tip = tips.wait(known, TIP_WAIT_TIMEOUT);
+            waiters.fetch_sub(1, Ordering::SeqCst);
+            respond(request, Ok(Reply::Json(tip_value(tip))));
+            Ok(())
+        });
+    }
+
+    fn respond(request: Request, result: std::result::Result<Reply, (ErrorCode, String)>) {
+        let (content_type, body, code) = match result {
+            Ok(Reply::Json(value)) => ("application/json", value.to_string(), None),
+            Ok(Reply::Text(text)) => ("text/plain", text, None),
+            Err((code, error)) => ("text/plain", error, Some(code)),
+        };
+        let header = Header::from_bytes(&b"Content-Type"[..], content_type).unwrap();
+        let mut response = Response::from_string(body)
+            .with_status_code(code.map_or(200, |code| code.http_status()))
+            .with_header(header);
+        if let Some(code) = code {
+            let header = Header::from_bytes(&b"X-Error-Code"[..], code.as_str()).unwrap();
+            response.add_header(header);
+        }
+        if let Err(e) = request.respond(response) {
+            warn!("failed to send REST API response: {}", e);
+        }
+    }
+
+    fn parse(
+        request: &Request,
+        network: Network,
+    ) -> std::result::Result<Route, (ErrorCode, String)> {
+        if *request.method() != Method::Get {
+            let error = "only GET requests are supported".to_owned();
+            return Err((ErrorCode::InvalidRequest, error));
+        }
+        Route::parse(request.url(), network)
+            .map_err(|e| (ErrorCode::InvalidParams, format!("{:#}", e)))?
+            .ok_or_else(|| (ErrorCode::MethodNotFound, "not found".to_owned()))
+    }
+
+    /// Forward the request to the server loop, and wait for its reply.
+    fn forward(
+        route: Route,
+        rest_tx: &Sender<RestRequest>,
+    ) -> std::result::Result<Reply, (ErrorCode, String)> {
+        let (rest_request, reply_rx) = RestRequest::new(route);
+        rest_tx
+            .send(rest_request)
+            .map_err(|_| (ErrorCode::Unavailable, "server is not running".to_owned()))?;
+        match reply_rx.recv_timeout(REST_TIMEOUT) {
+            Ok(Ok(Some(reply))) => Ok(reply),
+            Ok(Ok(None)) => Err((ErrorCode::NotFound, "not found".to_owned())),
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                let error = "timed out waiting for the server".to_owned();
+                Err((ErrorCode::Timeout, error))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rest")]
+pub(crate) use http::start;
+
+/// Without the "rest" feature, the REST API can't be configured.
+#[cfg(not(feature = "rest"))]
+pub(crate) fn start(
+    _addr: Option<std::net::SocketAddr>,
+    _network: Network,
+    _tips: std::sync::Arc<TipWatch>,
+) -> Result<Receiver<RestRequest>> {
+    Ok(crossbeam_channel::never())
+}
+
+#[cfg(all(test, feature = "rest"))]
+mod tests {
+    use super::{Route, MAX_HEADERS};
+    use crate::types::{ScriptHash, ScriptType};
+    use bitcoin::{hashes::Hash, Address, BlockHash, Network, Txid};
+
+    use std::str::FromStr;
+
+    #[test]
+    fn test_parse_routes() {
+        let network = Network::Regtest;
+        let parse = |url: &str| Route::parse(url, network).unwrap();
+        let txid =
+            Txid::from_str("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+                .unwrap();
+        assert_eq!(parse("/blocks/tip/height"), Some(Route::TipHeight));
+        assert_eq!(parse("/blocks/tip/hash?x=1"), Some(Route::TipHash));
+        assert_eq!(parse(&format!("/tx/{}", txid)), Some(Route::Tx(txid)));
+        assert_eq!(
+            parse(&format!("/tx/{}/status", txid)),
+            Some(Route::TxStatus(txid))
+        );
+        assert_eq!(parse(&format!("/tx/{}/outspends", txid)), None);
+        assert!(parse(&format!("/tx/{}", txid)).unwrap().uses_index());
+        assert!(!parse("/blocks/tip/height").unwrap().uses_index());
+        assert_eq!(parse("/mempool"), None);
+        assert!(Route::parse("/tx/1234", network).is_err());
+
+        let blockhash = BlockHash::from_raw_hash(txid.to_raw_hash());
+        assert_eq!(parse("/block-height/7"), Some(Route::BlockHeight(7)));
+        assert_eq!(
+            parse(&format!("/block/{}/header", blockhash)),
+            Some(Route::BlockHeader(blockhash))
+        );
+        assert_eq!(
+            parse(&format!("/blocks/tip/wait/{}", blockhash)),
+            Some(Route::TipWait(blockhash))
+        );
+        assert_eq!(
+            parse("/headers/100"),
+            Some(Route::Headers {
+                start_height: 100,
+                count: MAX_HEADERS
+            })
+        );
+        assert_eq!(
+            parse("/headers/100/10"),
+            Some(Route::Headers {
+                start_height: 100,
+                count: 10
+            })
+        );
+        assert!(Route::parse("/block-height/-1", network).is_err());
+
+        let addr = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+        let scripthash = ScriptHash::new(
+            &Address::from_str(addr)
+                .unwrap()
+                .assume_checked()
+                .script_pubkey(),
+        );
+        assert_eq!(
+            parse(&format!("/address/{}/utxo", addr)),
+            Some(Route::Utxo(scripthash, Some(ScriptType::P2wpkh)))
+        );
+        assert_eq!(
+            parse(&format!("/address/{}/txs/chain/{}", addr, txid)),
+            Some(Route::Txs {
+                scripthash,
+                script_type: Some(ScriptType::P2wpkh),
+                mempool: false,
+                last_seen: Some(txid),
+            })
+        );
+        assert!(Route::parse(&format!("/address/{}/txs", addr), Network::Bitcoin).is_err());
+
+        // Esplora's scripthashes are not reversed
+        let hash = format!("{:x}", scripthash.to_raw_hash());
+        assert_eq!(
+            parse(&format!("/scripthash/{}/txs", hash)),
+            Some(Route::Txs {
+                scripthash,
+                script_type: None,
+                mempool: true,
+                last_seen: None,
+            })
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use bitcoin::BlockHash;
+use parking_lot::Mutex;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use crate::{
+    db::Row,
+    metrics::{Counter, Metrics},
+};
+
+// Estimated memory used by each entry, in addition to its prefix and heights
+const ENTRY_OVERHEAD: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum ScanKind {
+    Funding,
+    Spending,
+    Txid,
+}
+
+type Key = (ScanKind, Row);
+
+fn entry_bytes(prefix: &[u8], heights: usize) -> usize {
+    ENTRY_OVERHEAD + prefix.len() + heights * std::mem::size_of::<usize>()
+}
+
+#[derive(Default)]
+struct Entries {
+    tip: Option<BlockHash>, // the cached heights were scanned at this chain tip
+    heights: HashMap<Key, Arc<[usize]>>,
+    order: VecDeque<Key>, // by insertion, for eviction
+    bytes: usize,         // estimated memory usage
+}
+
+/// Caches the heights found by the index prefix scans, so wallets re-polling the same
+/// scripthashes don't repeat the same DB scans. The cached heights are valid only at the tip
+/// they were scanned at, so the cache is cleared when the chain changes (mempool queries
+/// don't scan the DB, so they are not cached).
+pub(crate) struct ScanCache {
+    max_bytes: usize, // 0 - disable the cache
+    entries: Mutex<Entries>,
+    lookups: Counter,
+}
+
+impl ScanCache {
+    pub(crate) fn new(metrics: &Metrics, max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            entries: Default::default(),
+            lookups: metrics.counter(
+                "index_scan_cache_lookups",
+                "# of index prefix scans looked up in the cache",
+                "result",
+            ),
+        }
+    }
+
+    pub(crate) fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+        *self.entries.get_mut() = Entries::default();
+    }
+
+    /// Return the cached heights of `prefix` (at `tip`), or scan them using `scan`.
+    /// A scan is cached only if it is fully consumed (e.g. not stopped by a lookup limit).
+    pub(crate) fn scan<'a, I: Iterator<Item = usize>>(
+        &'a self,
+        tip: BlockHash,
+        kind: ScanKind,
+        prefix: Row,
+        scan: impl FnOnce(Row) -> I,
+    ) -> CachedScan<'a, I> {
+        if self.max_bytes == 0 {
+            return CachedScan::Scanning {
+                rows: scan(prefix),
+                heights: vec![],
+                pending: None,
+                cache: self,
+            };
+        }
+        if let Some(heights) = self.get(tip, kind, &prefix) {
+            return CachedScan::Cached { heights, next: 0 };
+        }
+        CachedScan::Scanning {
+            rows: scan(prefix.clone()),
+            heights: vec![],
+            pending: Some((tip, kind, prefix)),
+            cache: self,
+        }
+    }
+
+    fn get(&self, tip: BlockHash, kind: ScanKind, prefix: &Row) -> Option<Arc<[usize]>> {
+        let mut entries = self.entries.lock();
+        if entries.tip != Some(tip) {
+            *entries = Entries {
+                tip: Some(tip),
+                ..Default::default()
+            };
+        }
+        let result = entries.heights.get(&(kind, prefix.clone())).cloned();
+        self.lookups
+            .inc(if result.is_some() { "hit" } else { "miss" });
+        result
+    }
+
+    fn insert(&self, tip: BlockHash, kind: ScanKind, prefix: Row, heights: Vec<usize>) {
+        let bytes = entry_bytes(&prefix, heights.len());
+        let mut entries = self.entries.lock();
+        if entries.tip != Some(tip) || bytes > self.max_bytes {
+            return; // scanned at a stale tip, or too large to be cached
+        }
+        let key = (kind, prefix);
+        if entries.heights.contains_key(&key) {
+            return; // scanned concurrently
+        }
+        entries.heights.insert(key.clone(), heights.into());
+        entries.order.push_back(key);
+        entries.bytes += bytes;
+        while entries.bytes > self.max_bytes {
+            let oldest = match entries.order.pop_front() {
+                Some(key) => key,
+                None => break,
+            };
+            let heights = entries
+                .heights
+                .remove(&oldest)
+                .expect("missing cached heights");
+            entries.bytes -= entry_bytes(&oldest.1, heights.len());
+        }
+    }
+}
+
+/// Heights found by a (possibly cached) index prefix scan.
+pub(crate) enum CachedScan<'a, I> {
+    Cached {
+        heights: Arc<[usize]>,
+        next: usize,
+    },
+    Scanning {
+        rows: I,
+        heights: Vec<usize>, // recorded, to be cached when the scan is over
+        pending: Option<(BlockHash, ScanKind, Row)>,
+        cache: &'a ScanCache,
+    },
+}
+
+impl<'a, I: Iterator<Item = usize>> Iterator for CachedScan<'a, I> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        match self {
+            CachedScan::Cached { heights, next } => {
+                let height = heights.get(*next).copied();
+                *next += 1;
+                height
+            }
+            CachedScan::Scanning {
+                rows,
+                heights,
+                pending,
+                cache,
+            } => match rows.next() {
+                Some(height) => {
+                    if pending.is_some() {
+                        heights.push(height);
+                        if entry_bytes(&[], heights.len()) > cache.max_bytes {
+                            // too large to be cached
+                            *pending = None;
+                            *heights = vec![];
+                        }
+                    }
+                    Some(height)
+                }
+                None => {
+                    if let Some((tip, kind, prefix)) = pending.take() {
+                        cache.insert(tip, kind, prefix, std::mem::take(heights));
+                    }
+                    None
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entry_bytes, ScanCache, ScanKind};
+    use crate::{db::Row, metrics::Metrics};
+    use bitcoin::{hashes::Hash, BlockHash};
+
+    use std::cell::Cell;
+
+    #[test]
+    fn test_scan_cache() {
+        let prefix = |i: u8| vec![i; 8].into_boxed_slice();
+        let tip = BlockHash::all_zeros();
+        let scans = &Cell::new(0);
+        let scan = |heights: Vec<usize>| {
+            move |_: Row| {
+                scans.set(scans.get() + 1);
+                heights.into_iter()
+            }
+        };
+
+        let cache = ScanCache::new(&Metrics::unserved(), 2 * entry_bytes(&prefix(0), 3));
+        let heights: Vec<usize> = cache
+            .scan(tip, ScanKind::Funding, prefix(1), scan(vec![1, 2, 3]))
+            .collect();
+        assert_eq!(heights, [1, 2, 3]);
+        let heights: Vec<usize> = cache
+            .scan(tip, ScanKind::Funding, prefix(1), scan(vec![]))
+            .collect();
+        assert_eq!(heights, [1, 2, 3]); // cached
+        assert_eq!(scans.get(), 1);
+
+        // different kind of scan, partially consumed (so not cached)
+        let first = cache
+            .scan(tip, ScanKind::Spending, prefix(1), scan(vec![5, 6]))
+            .next();
+        assert_eq!(first, Some(5));
+        let heights: Vec<usize> = cache
+            .scan(tip, ScanKind::Spending, prefix(1), scan(vec![5, 6]))
+            .collect();
+        assert_eq!(heights, [5, 6]);
+        assert_eq!(scans.get(), 3);
+
+        // the oldest entry is evicted first
+        let _ = cache
+            .scan(tip, ScanKind::Txid, prefix(2), scan(vec![7]))
+            .count();
+        let _ = cache
+            .scan(tip, ScanKind::Funding, prefix(1), scan(vec![1, 2, 3]))
+            .count();
+        assert_eq!(scans.get(), 5);
+
+        // a new tip invalidates the cache
+        let new_tip = BlockHash::from_byte_array([1; 32]);
+        let heights: Vec<usize> = cache
+            .scan(new_tip, ScanKind::Txid, prefix(2), scan(vec![7, 8]))
+            .collect();
+        assert_eq!(heights, [7, 8]);
+        assert_eq!(scans.get(), 6);
+    }
+}
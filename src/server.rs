@@ -1,74 +1,303 @@
 use anyhow::{Context, Result};
-use crossbeam_channel::{select, unbounded, Sender};
+use bitcoin::{
+    secp256k1::rand::{self, Rng},
+    BlockHash, OutPoint,
+};
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use parking_lot::RwLock;
 use rayon::prelude::*;
+use serde_json::{json, Value};
 
 use std::{
-    collections::hash_map::HashMap,
-    io::{BufRead, BufReader, Write},
+    collections::{hash_map::HashMap, BTreeSet, HashSet, VecDeque},
+    io::{self, BufRead, BufReader, Write},
     iter::once,
-    net::{Shutdown, TcpListener, TcpStream},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
-    config::Config,
+    admin::AdminRequest,
+    broadcasts::BroadcastQuery,
+    config::{Config, ListenerConfig},
     electrum::{Client, Rpc},
-    metrics::{self, Metrics},
-    signals::ExitError,
+    metrics::{self, Counter, Gauge, Histogram, Metrics},
+    proxy,
+    rest::{self, RestRequest, TipWatch},
+    signals::{ExitError, ExitFlag},
+    systemd::{self, Watchdog},
     thread::spawn,
+    types::ScriptHash,
+    watchtower::Watchtower,
+    webhook::Webhooks,
 };
 
+type Bans = Arc<RwLock<HashSet<IpAddr>>>;
+
+/// Large enough for broadcasting any standard transaction (hex-encoded).
+pub(crate) const MAX_LINE_SIZE: usize = 2 << 20;
+
+/// Deprioritized clients' request lines may be pending for a while, so clients that keep
+/// pipelining more requests (beyond this total size) are disconnected.
+pub(crate) const MAX_PENDING_SIZE: usize = 8 * MAX_LINE_SIZE;
+
+/// Pending request lines are handled in slices of (at most) this duration, so that syncing,
+/// notifications and the other APIs are not starved by steady client traffic.
+const HANDLE_TIME_SLICE: Duration = Duration::from_millis(100);
+
+/// Admin API requests and state, handled by the server loop.
+struct Admin {
+    requests: Receiver<AdminRequest>,
+    bans: Bans, // clients' addresses, rejected until unbanned
+}
+
+/// REST API requests, and the tip watched by its waiting clients.
+struct Rest {
+    requests: Receiver<RestRequest>,
+    tips: Arc<TipWatch>,
+}
+
 struct Peer {
     id: usize,
     client: Client,
     stream: TcpStream,
+    addr: SocketAddr,
+    pending: VecDeque<String>, // request lines, waiting to be handled
+    pending_size: usize,       // total length of the pending lines
+    padding: Option<usize>,
+    delayed: Option<Sender<(Instant, String)>>, // responses to be sent after a random delay
+    done: bool,                                 // no more requests will be received
+
+    // stats
+    connected: Instant,
+    last_request: Instant,
+    bytes_in: u64,
+    bytes_out: u64,
+    traffic: Counter,
 }
 
 impl Peer {
-    fn new(id: usize, stream: TcpStream) -> Self {
-        let client = Client::default();
-        Self { id, client, stream }
+    fn new(
+        id: usize,
+        stream: TcpStream,
+        addr: SocketAddr,
+        listener: &ListenerConfig,
+        traffic: Counter,
+    ) -> Self {
+        let client = Client::new(listener, addr);
+        let delayed = listener.delay.and_then(|delay| match stream.try_clone() {
+            Ok(stream) => Some(spawn_delayed_sender(id, stream, delay)),
+            Err(e) => {
+                warn!("{}: failed to delay responses: {}", id, e);
+                None
+            }
+        });
+        let now = Instant::now();
+        Self {
+            id,
+            client,
+            stream,
+            padding: listener.padding,
+            delayed,
+            addr,
+            pending: VecDeque::new(),
+            pending_size: 0,
+            done: false,
+            connected: now,
+            last_request: now,
+            bytes_in: 0,
+            bytes_out: 0,
+            traffic,
+        }
     }
 
     fn send(&mut self, values: Vec<String>) -> Result<()> {
         for mut value in values {
             debug!("{}: send {}", self.id, value);
+            if let Some(min_size) = self.padding {
+                pad(&mut value, min_size);
+            }
             value += "\n";
-            self.stream
-                .write_all(value.as_bytes())
-                .with_context(|| format!("failed to send response: {:?}", value))?;
+            match &self.delayed {
+                Some(delayed) => delayed
+                    .send((Instant::now(), value.clone()))
+                    .context("delayed sender is closed")?,
+                None => self
+                    .stream
+                    .write_all(value.as_bytes())
+                    .with_context(|| format!("failed to send response: {:?}", value))?,
+            }
+            self.bytes_out += value.len() as u64;
+            self.traffic.inc_by("out", value.len() as u64);
         }
         Ok(())
     }
 
+    fn info(&self, now: Instant) -> Value {
+        json!({
+            "id": self.id,
+            "addr": self.addr,
+            "user_agent": self.client.user_agent(),
+            "scripthashes": self.client.scripthash_subscriptions(),
+            "headers": self.client.headers_subscribed(),
+            "pending": self.pending.len(),
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "connected_secs": now.duration_since(self.connected).as_secs(),
+            "idle_secs": now.duration_since(self.last_request).as_secs(),
+        })
+    }
+
     fn disconnect(self) {
         if let Err(e) = self.stream.shutdown(Shutdown::Both) {
-            warn!("{}: failed to shutdown TCP connection {}", self.id, e)
+            warn!(
+                "{}: failed to shutdown TCP connection to {}: {}",
+                self.id, self.addr, e
+            )
         }
     }
 }
 
+/// Pad the JSON response with trailing whitespace, up to a power-of-two size (including its
+/// newline), so its size reveals less about its content.
+fn pad(value: &mut String, min_size: usize) {
+    let size = std::cmp::max((value.len() + 1).next_power_of_two(), min_size);
+    let padding = size.saturating_sub(value.len() + 1);
+    value.extend(std::iter::repeat(' ').take(padding));
+}
+
+/// Send the peer's responses (in order), each one after a random delay in the `delay` range.
+fn spawn_delayed_sender(
+    peer_id: usize,
+    mut stream: TcpStream,
+    (min, max): (Duration, Duration),
+) -> Sender<(Instant, String)> {
+    let (tx, rx) = unbounded::<(Instant, String)>();
+    spawn("delayed_send", move || {
+        let mut rng = rand::thread_rng();
+        for (queued, value) in rx {
+            let deadline = queued + rng.gen_range(min..=max);
+            if let Some(delay) = deadline.checked_duration_since(Instant::now()) {
+                std::thread::sleep(delay);
+            }
+            if let Err(e) = stream.write_all(value.as_bytes()) {
+                warn!("{}: failed to send delayed response: {}", peer_id, e);
+                // the peer is disconnected, after its receiving loop is done
+                let _ = stream.shutdown(Shutdown::Both);
+                break;
+            }
+        }
+        Ok(())
+    });
+    tx
+}
+
 pub fn run() -> Result<()> {
     let result = serve();
     if let Err(e) = &result {
-        for cause in e.chain() {
-            if cause.downcast_ref::<ExitError>().is_some() {
-                info!("electrs stopped: {:?}", e);
-                return Ok(());
-            }
+        if is_exit(e) {
+            info!("electrs stopped: {:?}", e);
+            return Ok(());
         }
     }
     result.context("electrs failed")
 }
 
+fn is_exit(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| cause.downcast_ref::<ExitError>().is_some())
+}
+
 fn serve() -> Result<()> {
-    let config = Config::from_args();
-    let metrics = Metrics::new(config.monitoring_addr)?;
+    let mut config = Config::from_args();
+    let metrics = Metrics::new(config.monitoring_addr, config.admin_access.clone())?;
+    if let Some(url) = &config.metrics_push_url {
+        metrics.start_push(url, config.metrics_push_interval)?;
+    }
+    // shared by all chains, so they exit together (e.g. after a signal)
+    let exit_flag = ExitFlag::new();
+    // additional chains are served independently (a failing chain doesn't stop the others)
+    let chains: Vec<_> = std::mem::take(&mut config.chains)
+        .into_iter()
+        .map(|chain_config| {
+            let chain_metrics = metrics.for_chain(&chain_config.network.to_string());
+            let exit_flag = exit_flag.clone();
+            spawn("chain", move || {
+                let network = chain_config.network;
+                match serve_chain(&chain_config, chain_metrics, false, exit_flag) {
+                    Err(e) if is_exit(&e) => Ok(()),
+                    result => result.with_context(|| format!("serving {} failed", network)),
+                }
+            })
+        })
+        .collect();
+    let result = serve_chain(&config, metrics, true, exit_flag.clone());
+    // the other chains are stopped also after the primary chain's failure
+    exit_flag.set();
+    for chain in chains {
+        let _ = chain.join(); // their failures are logged by their threads
+    }
+    result
+}
+
+/// Index and serve a single chain. Only the primary chain uses systemd's activated sockets
+/// and notifications.
+fn serve_chain(
+    config: &Config,
+    metrics: Metrics,
+    primary: bool,
+    exit_flag: ExitFlag,
+) -> Result<()> {
+    let admin = Admin {
+        requests: metrics.admin_requests().clone(),
+        bans: Default::default(),
+    };
 
     let (server_tx, server_rx) = unbounded();
     if !config.disable_electrum_rpc {
-        let listener = TcpListener::bind(config.electrum_rpc_addr)?;
-        info!("serving Electrum RPC on {}", listener.local_addr()?);
-        spawn("accept_loop", || accept_loop(listener, server_tx)); // detach accepting thread
+        let next_peer_id = Arc::new(AtomicUsize::new(0)); // shared by all listeners
+        let clients = metrics.gauge(
+            "electrum_clients",
+            "# of connected Electrum clients",
+            "listener",
+        );
+        let traffic = metrics.counter(
+            "electrum_traffic_bytes",
+            "# of bytes received from and sent to Electrum clients",
+            "direction",
+        );
+        let mut activated = if primary {
+            systemd::activated_listeners()?
+        } else {
+            vec![]
+        };
+        for listener_config in &config.electrum_listeners {
+            let listener = match take_listener(&mut activated, listener_config.addr) {
+                Some(listener) => listener,
+                None => TcpListener::bind(listener_config.addr)?,
+            };
+            info!("serving Electrum RPC on {}", listener.local_addr()?);
+            let acceptor = Acceptor {
+                config: Arc::new(listener_config.clone()),
+                next_peer_id: Arc::clone(&next_peer_id),
+                clients: clients.clone(),
+                traffic: traffic.clone(),
+                server_tx: server_tx.clone(),
+                bans: Arc::clone(&admin.bans),
+            };
+            spawn("accept_loop", move || acceptor.run(listener)); // detach accepting thread
+        }
+        drop(server_tx); // keep only accepting threads' senders
+        for listener in activated {
+            warn!(
+                "ignoring passed socket {:?}: no such listener",
+                listener.local_addr()
+            );
+        }
     };
 
     let server_batch_size = metrics.histogram_vec(
@@ -83,23 +312,175 @@ fn serve() -> Result<()> {
         "step",
         metrics::default_duration_buckets(),
     );
-    let mut rpc = Rpc::new(&config, metrics)?;
+    let mut rpc = Rpc::new(&config, metrics, exit_flag)?;
+    {
+        // connections accepted meanwhile are checked again when their peers are registered
+        let mut bans = admin.bans.write();
+        bans.extend(rpc.load_bans());
+        if !bans.is_empty() {
+            info!("loaded {} banned addresses", bans.len());
+        }
+    }
+    if primary {
+        systemd::notify("READY=1\nSTATUS=indexing");
+    }
+    let tips = Arc::new(TipWatch::new(rpc.chain()));
+    let rest = Rest {
+        requests: rest::start(config.http_addr, config.network, Arc::clone(&tips))?,
+        tips,
+    };
+    let mut webhooks = Webhooks::new(rpc.load_webhooks());
+    let mut watchtower = Watchtower::new(rpc.load_watches());
+    let result = serve_loop(
+        &config,
+        &mut rpc,
+        &server_rx,
+        &admin,
+        &mut webhooks,
+        &mut watchtower,
+        &rest,
+        &server_batch_size,
+        &duration,
+        primary,
+    );
+    if primary {
+        systemd::notify("STOPPING=1");
+    }
+    rpc.shutdown(); // also after failures, since the persisted state is reconciled on restart
+    result
+}
+
+/// Use a socket passed by systemd, if it is bound to the listener's address.
+fn take_listener(activated: &mut Vec<TcpListener>, addr: SocketAddr) -> Option<TcpListener> {
+    let position = activated
+        .iter()
+        .position(|listener| listener.local_addr().map_or(false, |a| a == addr))?;
+    info!("using socket passed by systemd for {}", addr);
+    Some(activated.swap_remove(position))
+}
+
+/// Defers the peers' notifications after a new tip (for up to `window`), so a burst of new blocks
+/// results in a single notification of each changed header and status (with its final state).
+struct Coalescing {
+    window: Duration, // zero - notify immediately
+    notified_tip: BlockHash,
+    deadline: Option<Instant>, // the deferred notifications are sent when it expires
+}
+
+impl Coalescing {
+    fn new(window: Duration, tip: BlockHash) -> Self {
+        Self {
+            window,
+            notified_tip: tip,
+            deadline: None,
+        }
+    }
+
+    /// Returns whether the peers should be notified now (about the current `tip`).
+    fn is_due(&mut self, tip: BlockHash, now: Instant) -> bool {
+        if self.window.is_zero() {
+            return true;
+        }
+        if self.deadline.is_none() && tip != self.notified_tip {
+            self.deadline = Some(now + self.window);
+        }
+        match self.deadline {
+            Some(deadline) if now < deadline => false,
+            _ => {
+                self.deadline = None;
+                self.notified_tip = tip;
+                true
+            }
+        }
+    }
+
+    /// Wait for up to `wait`, but not after the deferred notifications are due.
+    fn timeout(&self, wait: Duration, now: Instant) -> Duration {
+        self.deadline.map_or(wait, |deadline| {
+            wait.min(deadline.saturating_duration_since(now))
+        })
+    }
+}
 
+#[allow(clippy::too_many_arguments)]
+fn serve_loop(
+    config: &Config,
+    rpc: &mut Rpc,
+    server_rx: &Receiver<Event>,
+    admin: &Admin,
+    webhooks: &mut Webhooks,
+    watchtower: &mut Watchtower,
+    rest: &Rest,
+    server_batch_size: &Histogram,
+    duration: &Histogram,
+    primary: bool,
+) -> Result<()> {
     let new_block_rx = rpc.new_block_notification();
     let mut peers = HashMap::<usize, Peer>::new();
+    let notify = |state: &str| {
+        if primary {
+            systemd::notify(state)
+        }
+    };
+    let mut watchdog = if primary {
+        Watchdog::new()
+    } else {
+        Watchdog::disabled()
+    };
+    if watchdog
+        .interval()
+        .map_or(false, |interval| interval < config.wait_duration)
+    {
+        warn!("systemd watchdog timeout should be longer than twice the wait duration");
+    }
+    let mut coalescing = Coalescing::new(config.notify_coalescing, rpc.chain().tip());
+    let mut synced = false;
+    let mut yielded = false; // pending requests were left, to sync and update meanwhile
     loop {
         // initial sync and compaction may take a few hours
-        while server_rx.is_empty() {
+        while std::mem::take(&mut yielded)
+            || (server_rx.is_empty() && admin.requests.is_empty() && rest.requests.is_empty())
+        {
+            // may be set without a signal (e.g. when another chain's server has failed)
+            rpc.signal()
+                .exit_flag()
+                .poll()
+                .context("RPC server interrupted")?;
             let done = duration.observe_duration("sync", || rpc.sync().context("sync failed"))?; // sync a batch of blocks
-            peers = duration.observe_duration("notify", || notify_peers(&rpc, peers)); // peers are disconnected on error
+            if coalescing.is_due(rpc.chain().tip(), Instant::now()) {
+                peers = duration.observe_duration("notify", || notify_peers(rpc, peers));
+                // peers are disconnected on error
+            }
+            rest.tips.update(rpc.chain());
+            if let Err(e) = duration.observe_duration("webhooks", || webhooks.poll(rpc)) {
+                warn!("failed to poll webhooks: {:#}", e);
+            }
+            if duration.observe_duration("watchtower", || watchtower.poll(rpc)) {
+                rpc.persist_watches(watchtower.watches());
+            }
+            watchdog.ping();
+            if rpc.signal().drain_flag().is_set() {
+                break; // stop syncing
+            }
             if !done {
                 continue; // more blocks to sync
             }
+            if !synced {
+                notify("STATUS=index is synced");
+                synced = true;
+                if let Err(e) = duration.observe_duration("warmup", || rpc.warm_up()) {
+                    warn!("failed to warm up: {:#}", e);
+                }
+            }
             if config.sync_once {
                 return Ok(()); // exit after initial sync is done
             }
             break;
         }
+        if rpc.signal().drain_flag().is_set() {
+            notify("STATUS=draining");
+            return drain_peers(rpc, peers, server_rx, &admin.bans, config.drain_timeout);
+        }
         duration.observe_duration("select", || -> Result<()> {
             select! {
                 // Handle signals for graceful shutdown
@@ -121,122 +502,522 @@ fn serve() -> Result<()> {
                     let rest = server_rx.iter().take(server_rx.len());
                     let events: Vec<Event> = first.chain(rest).collect();
                     server_batch_size.observe("recv", events.len() as f64);
-                    duration.observe_duration("handle", || handle_events(&rpc, &mut peers, events));
+                    yielded = duration.observe_duration("handle", || {
+                        handle_events(rpc, &mut peers, events, server_rx, &admin.bans)
+                    });
+                },
+                // Handle admin API commands
+                recv(admin.requests) -> request => {
+                    let request = request.context("admin API disconnected")?;
+                    duration.observe_duration("admin", || handle_admin(rpc, &mut peers, admin, webhooks, watchtower, request));
+                },
+                // Handle Esplora REST API requests
+                recv(rest.requests) -> request => {
+                    let request = request.context("REST API disconnected")?;
+                    duration.observe_duration("rest", || rest::handle(rpc, config.network, request));
+                },
+                default(if has_pending(&peers) {
+                    Duration::ZERO // continue handling the pending requests
+                } else {
+                    coalescing.timeout(config.wait_duration, Instant::now())
+                }) => {
+                    if has_pending(&peers) {
+                        yielded = duration.observe_duration("handle", || {
+                            handle_events(rpc, &mut peers, vec![], server_rx, &admin.bans)
+                        });
+                    } // otherwise, sync and update
                 },
-                default(config.wait_duration) => (), // sync and update
             };
             Ok(())
         })?;
+        watchdog.ping();
+    }
+}
+
+/// Notify the connected peers that the server is going down, and keep serving them
+/// until they disconnect (or `timeout` expires). New connections are rejected.
+fn drain_peers(
+    rpc: &Rpc,
+    mut peers: HashMap<usize, Peer>,
+    server_rx: &Receiver<Event>,
+    bans: &Bans,
+    timeout: Duration,
+) -> Result<()> {
+    info!("draining {} peers (for up to {:?})", peers.len(), timeout);
+    let notification = rpc.draining_notification();
+    peers.retain(|_, peer| match peer.send(vec![notification.clone()]) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("{}: failed to notify about draining: {}", peer.id, e);
+            false
+        }
+    });
+    let deadline = Instant::now() + timeout;
+    while !peers.is_empty() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let expired = select! {
+            recv(rpc.signal().receiver()) -> result => {
+                result.context("signal channel disconnected")?;
+                rpc.signal().exit_flag().poll().context("draining interrupted")?;
+                false
+            },
+            recv(server_rx) -> event => {
+                let first = once(event.context("server disconnected")?);
+                let rest = server_rx.iter().take(server_rx.len());
+                let events = first
+                    .chain(rest)
+                    .filter_map(|Event { peer_id, msg }| match msg {
+                        Message::New(stream, addr, _listener, _traffic) => {
+                            debug!("{}: rejecting {}, server is draining", peer_id, addr);
+                            let _ = stream.shutdown(Shutdown::Both);
+                            None
+                        }
+                        msg => Some(Event { peer_id, msg }),
+                    })
+                    .collect();
+                handle_events(rpc, &mut peers, events, server_rx, bans);
+                false
+            },
+            default(if has_pending(&peers) { Duration::ZERO } else { remaining }) => {
+                if has_pending(&peers) && !remaining.is_zero() {
+                    handle_events(rpc, &mut peers, vec![], server_rx, bans);
+                    false
+                } else {
+                    true
+                }
+            },
+        };
+        if expired {
+            break;
+        }
+    }
+    info!("disconnecting {} remaining peers", peers.len());
+    for peer in peers.into_values() {
+        peer.disconnect();
+    }
+    Ok(())
+}
+
+fn handle_admin(
+    rpc: &Rpc,
+    peers: &mut HashMap<usize, Peer>,
+    admin: &Admin,
+    webhooks: &mut Webhooks,
+    watchtower: &mut Watchtower,
+    request: AdminRequest,
+) {
+    info!("admin command: {} {}", request.command, request.params);
+    let params = request.params.clone();
+    let result = admin_command(
+        rpc,
+        peers,
+        &admin.bans,
+        webhooks,
+        watchtower,
+        &request.command,
+        params,
+    );
+    request.reply(result);
+}
+
+#[derive(Deserialize)]
+struct AddrParams {
+    ip: IpAddr,
+}
+
+#[derive(Deserialize)]
+struct WatchParams {
+    outpoint: OutPoint,
+    #[serde(default)]
+    reaction: String, // hex-encoded transaction (required by `add_watch`)
+}
+
+#[derive(Deserialize)]
+struct WebhookParams {
+    url: String,
+    #[serde(default)]
+    scripthashes: Vec<ScriptHash>,
+}
+
+fn admin_command(
+    rpc: &Rpc,
+    peers: &mut HashMap<usize, Peer>,
+    bans: &Bans,
+    webhooks: &mut Webhooks,
+    watchtower: &mut Watchtower,
+    command: &str,
+    params: Value,
+) -> Result<Value> {
+    let ip = || -> Result<IpAddr> {
+        let params: AddrParams = serde_json::from_value(params.clone())
+            .with_context(|| format!("invalid {} params (expected {{\"ip\": ...}})", command))?;
+        Ok(params.ip)
+    };
+    let webhook = || -> Result<WebhookParams> {
+        serde_json::from_value(params.clone()).with_context(|| {
+            format!(
+                "invalid {} params (expected {{\"url\": ..., \"scripthashes\": [...]}})",
+                command
+            )
+        })
+    };
+    let watch = || -> Result<WatchParams> {
+        serde_json::from_value(params.clone()).with_context(|| {
+            format!(
+                "invalid {} params (expected {{\"outpoint\": \"<txid>:<vout>\", \"reaction\": <hex>}})",
+                command
+            )
+        })
+    };
+    Ok(match command {
+        "clients" => {
+            let now = Instant::now();
+            let mut clients: Vec<&Peer> = peers.values().collect();
+            clients.sort_by_key(|peer| peer.id);
+            json!(clients
+                .into_iter()
+                .map(|peer| peer.info(now))
+                .collect::<Vec<Value>>())
+        }
+        "ban" => {
+            let mut bans = bans.write();
+            bans.insert(ip()?);
+            rpc.persist_bans(&bans);
+            json!({ "disconnected": disconnect_banned(peers, &bans) })
+        }
+        "unban" => {
+            let mut bans = bans.write();
+            let removed = bans.remove(&ip()?);
+            rpc.persist_bans(&bans);
+            json!(removed)
+        }
+        // exported bans can be imported by other servers (e.g. via a shared feed)
+        "bans" => json!(bans.read().iter().collect::<BTreeSet<_>>()),
+        "import_bans" => {
+            let imported: Vec<IpAddr> = serde_json::from_value(params.clone())
+                .context("invalid import_bans params (expected a list of addresses)")?;
+            let mut bans = bans.write();
+            let before = bans.len();
+            bans.extend(imported);
+            rpc.persist_bans(&bans);
+            json!({
+                "added": bans.len() - before,
+                "disconnected": disconnect_banned(peers, &bans),
+            })
+        }
+        "webhooks" => json!(webhooks.registrations()),
+        "add_webhook" => {
+            let WebhookParams { url, scripthashes } = webhook()?;
+            webhooks.add(url, scripthashes)?;
+            rpc.persist_webhooks(webhooks.registrations());
+            json!(true)
+        }
+        "remove_webhook" => {
+            let removed = webhooks.remove(&webhook()?.url);
+            rpc.persist_webhooks(webhooks.registrations());
+            json!(removed)
+        }
+        "watches" => json!(watchtower.watches()),
+        "add_watch" => {
+            let WatchParams { outpoint, reaction } = watch()?;
+            watchtower.add(outpoint, reaction)?;
+            rpc.persist_watches(watchtower.watches());
+            json!(true)
+        }
+        "remove_watch" => {
+            let removed = watchtower.remove(watch()?.outpoint);
+            rpc.persist_watches(watchtower.watches());
+            json!(removed)
+        }
+        "broadcasts" => {
+            let query: BroadcastQuery = match params {
+                Value::Null => BroadcastQuery::default(),
+                params => serde_json::from_value(params).context(
+                    "invalid broadcasts params (expected {\"txid\": ..., \"peer\": ..., \"limit\": ...})",
+                )?,
+            };
+            json!(rpc.broadcasts(&query))
+        }
+        "drop_caches" => {
+            rpc.drop_caches();
+            json!(true)
+        }
+        "compact" => json!({ "started": rpc.compact_db()? }), // false if already running
+        _ => bail!("unknown admin command: {}", command),
+    })
+}
+
+/// Returns the number of disconnected peers.
+fn disconnect_banned(peers: &mut HashMap<usize, Peer>, bans: &HashSet<IpAddr>) -> usize {
+    let banned: Vec<usize> = peers
+        .values()
+        .filter(|peer| bans.contains(&peer.addr.ip()))
+        .map(|peer| peer.id)
+        .collect();
+    for peer_id in &banned {
+        let peer = peers.remove(peer_id).unwrap();
+        info!("{}: disconnecting, {} is banned", peer_id, peer.addr.ip());
+        peer.disconnect();
     }
+    banned.len()
 }
 
 fn notify_peers(rpc: &Rpc, peers: HashMap<usize, Peer>) -> HashMap<usize, Peer> {
+    let mut peers: Vec<Peer> = peers.into_values().collect();
+    let notifications = {
+        let mut clients: Vec<&mut Client> = peers.iter_mut().map(|peer| &mut peer.client).collect();
+        rpc.update_clients(&mut clients)
+    };
     peers
         .into_par_iter()
-        .filter_map(|(_, mut peer)| match notify_peer(rpc, &mut peer) {
-            Ok(()) => Some((peer.id, peer)),
-            Err(e) => {
-                error!("failed to notify peer {}: {}", peer.id, e);
-                peer.disconnect();
-                None
+        .zip(notifications)
+        .filter_map(|(mut peer, notifications)| {
+            let result = notifications
+                .context("failed to generate notifications")
+                .and_then(|notifications| {
+                    peer.send(notifications)
+                        .context("failed to send notifications")
+                });
+            match result {
+                Ok(()) => Some((peer.id, peer)),
+                Err(e) => {
+                    error!("failed to notify peer {}: {}", peer.id, e);
+                    peer.disconnect();
+                    None
+                }
             }
         })
         .collect()
 }
 
-fn notify_peer(rpc: &Rpc, peer: &mut Peer) -> Result<()> {
-    let notifications = rpc
-        .update_client(&mut peer.client)
-        .context("failed to generate notifications")?;
-    peer.send(notifications)
-        .context("failed to send notifications")
-}
-
 struct Event {
     peer_id: usize,
     msg: Message,
 }
 
 enum Message {
-    New(TcpStream),
+    New(TcpStream, SocketAddr, Arc<ListenerConfig>, Counter),
     Request(String),
     Done,
 }
 
-fn handle_events(rpc: &Rpc, peers: &mut HashMap<usize, Peer>, events: Vec<Event>) {
-    let mut events_by_peer = HashMap::<usize, Vec<Message>>::new();
-    events
-        .into_iter()
-        .for_each(|e| events_by_peer.entry(e.peer_id).or_default().push(e.msg));
-    for (peer_id, messages) in events_by_peer {
-        handle_peer_events(rpc, peers, peer_id, messages);
+/// Handle the received events, and the peers' pending request lines (for up to
+/// `HANDLE_TIME_SLICE`). Returns whether request lines are still pending, so the server loop
+/// can sync and update meanwhile before handling them.
+fn handle_events(
+    rpc: &Rpc,
+    peers: &mut HashMap<usize, Peer>,
+    events: Vec<Event>,
+    server_rx: &Receiver<Event>,
+    bans: &Bans,
+) -> bool {
+    enqueue_events(peers, events, bans);
+    let deadline = Instant::now() + HANDLE_TIME_SLICE;
+    // Serve one request line at a time, always from the peer with the lowest recent usage,
+    // so a client doing heavy queries can't starve interactive queries from other clients.
+    while Instant::now() < deadline {
+        let now = Instant::now();
+        let next_peer_id = peers
+            .values()
+            .filter(|peer| !peer.pending.is_empty())
+            .min_by(|a, b| {
+                let (a, b) = (a.client.usage().level(now), b.client.usage().level(now));
+                a.total_cmp(&b)
+            })
+            .map(|peer| peer.id);
+        match next_peer_id {
+            Some(peer_id) => handle_peer_request(rpc, peers, peer_id),
+            None => break,
+        }
+        // handle requests that have arrived meanwhile according to their peers' usage
+        let events = server_rx.try_iter().take(server_rx.len()).collect();
+        enqueue_events(peers, events, bans);
     }
+    // already disconnected, just remove from peers' map (after their pending requests are handled)
+    peers.retain(|_, peer| !peer.done || !peer.pending.is_empty());
+    has_pending(peers)
 }
 
-fn handle_peer_events(
-    rpc: &Rpc,
-    peers: &mut HashMap<usize, Peer>,
-    peer_id: usize,
-    messages: Vec<Message>,
-) {
-    let mut lines = vec![];
-    let mut done = false;
-    for msg in messages {
+fn has_pending(peers: &HashMap<usize, Peer>) -> bool {
+    peers.values().any(|peer| !peer.pending.is_empty())
+}
+
+fn enqueue_events(peers: &mut HashMap<usize, Peer>, events: Vec<Event>, bans: &Bans) {
+    for Event { peer_id, msg } in events {
         match msg {
-            Message::New(stream) => {
-                debug!("{}: connected", peer_id);
-                peers.insert(peer_id, Peer::new(peer_id, stream));
+            Message::New(stream, addr, _listener, _traffic) if bans.read().contains(&addr.ip()) => {
+                // accepted before the persisted bans were loaded
+                warn!("{}: rejecting {}, banned via admin API", peer_id, addr);
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            Message::New(stream, addr, listener, traffic) => {
+                debug!("{}: connected from {}", peer_id, addr);
+                let peer = Peer::new(peer_id, stream, addr, &listener, traffic);
+                peers.insert(peer_id, peer);
+            }
+            Message::Request(line) => {
+                if let Some(peer) = peers.get_mut(&peer_id) {
+                    peer.bytes_in += line.len() as u64 + 1; // including the newline
+                    peer.last_request = Instant::now();
+                    peer.pending_size += line.len();
+                    peer.pending.push_back(line); // ignore unknown peers
+                    if peer.pending_size > MAX_PENDING_SIZE {
+                        error!(
+                            "{}: disconnecting, too many pending requests ({} bytes)",
+                            peer_id, peer.pending_size
+                        );
+                        peers.remove(&peer_id).unwrap().disconnect();
+                    }
+                }
             }
-            Message::Request(line) => lines.push(line),
             Message::Done => {
-                done = true;
-                break;
+                if let Some(peer) = peers.get_mut(&peer_id) {
+                    peer.done = true; // remove after its pending requests are handled
+                }
             }
         }
     }
-    let result = match peers.get_mut(&peer_id) {
-        Some(peer) => {
-            let responses = rpc.handle_requests(&mut peer.client, &lines);
-            peer.send(responses)
-        }
-        None => return, // unknown peer
-    };
-    if let Err(e) = result {
+}
+
+fn handle_peer_request(rpc: &Rpc, peers: &mut HashMap<usize, Peer>, peer_id: usize) {
+    let peer = peers.get_mut(&peer_id).unwrap();
+    let line = peer.pending.pop_front().unwrap();
+    peer.pending_size -= line.len();
+    let was_over_budget = peer.client.usage().is_over_budget(Instant::now());
+    let responses = rpc.handle_requests(&mut peer.client, std::slice::from_ref(&line));
+    if !was_over_budget && peer.client.usage().is_over_budget(Instant::now()) {
+        info!("{}: exceeded its query budget, deprioritizing", peer_id);
+    }
+    if let Err(e) = peer.send(responses) {
         error!("{}: disconnecting due to {}", peer_id, e);
         peers.remove(&peer_id).unwrap().disconnect();
-    } else if done {
-        peers.remove(&peer_id); // already disconnected, just remove from peers' map
     }
 }
 
-fn accept_loop(listener: TcpListener, server_tx: Sender<Event>) -> Result<()> {
-    for (peer_id, conn) in listener.incoming().enumerate() {
-        let stream = conn.context("failed to accept")?;
-        let tx = server_tx.clone();
-        spawn("recv_loop", move || {
-            let result = recv_loop(peer_id, &stream, tx);
-            if let Err(e) = stream.shutdown(Shutdown::Read) {
-                warn!("{}: failed to shutdown TCP receiving {}", peer_id, e)
+struct Acceptor {
+    config: Arc<ListenerConfig>,
+    next_peer_id: Arc<AtomicUsize>,
+    clients: Gauge,
+    traffic: Counter,
+    server_tx: Sender<Event>,
+    bans: Bans,
+}
+
+impl Acceptor {
+    fn run(self, listener: TcpListener) -> Result<()> {
+        let label = self.config.addr.to_string();
+        let active = Arc::new(AtomicUsize::new(0));
+        for conn in listener.incoming() {
+            let stream = conn.context("failed to accept")?;
+            if let Some(max_clients) = self.config.max_clients {
+                if active.load(Ordering::SeqCst) >= max_clients {
+                    warn!(
+                        "{}: rejecting {:?}, too many clients ({})",
+                        label,
+                        stream.peer_addr(),
+                        max_clients
+                    );
+                    let _ = stream.shutdown(Shutdown::Both);
+                    continue;
+                }
             }
-            result
-        });
+            let peer_id = self.next_peer_id.fetch_add(1, Ordering::Relaxed);
+            let count = active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.clients.set(&label, count as f64);
+
+            let tx = self.server_tx.clone();
+            let active = Arc::clone(&active);
+            let clients = self.clients.clone();
+            let label = label.clone();
+            let config = Arc::clone(&self.config);
+            let bans = Arc::clone(&self.bans);
+            let traffic = self.traffic.clone();
+            spawn("recv_loop", move || {
+                let result = recv_loop(peer_id, &stream, tx, &config, &bans, traffic);
+                if let Err(e) = stream.shutdown(Shutdown::Read) {
+                    warn!("{}: failed to shutdown TCP receiving {}", peer_id, e)
+                }
+                let count = active.fetch_sub(1, Ordering::SeqCst) - 1;
+                clients.set(&label, count as f64);
+                result
+            });
+        }
+        Ok(())
     }
-    Ok(())
 }
 
-fn recv_loop(peer_id: usize, stream: &TcpStream, server_tx: Sender<Event>) -> Result<()> {
-    let msg = Message::New(stream.try_clone()?);
+/// Read a single line (without its line ending), failing if it exceeds `max_size` bytes
+/// (so a client can't make the server allocate unbounded memory), or is not valid UTF-8.
+pub(crate) fn read_line<R: BufRead>(reader: &mut R, max_size: usize) -> io::Result<Option<String>> {
+    let mut line = vec![];
+    let limit = u64::try_from(max_size)
+        .unwrap_or(u64::MAX)
+        .saturating_add(1);
+    if reader.take(limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None); // EOF
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+    if line.len() > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line is longer than {} bytes", max_size),
+        ));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn recv_loop(
+    peer_id: usize,
+    stream: &TcpStream,
+    server_tx: Sender<Event>,
+    config: &Arc<ListenerConfig>,
+    bans: &Bans,
+    traffic: Counter,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut addr = stream.peer_addr()?;
+    if config.proxy_protocol {
+        // the header must be consumed before any Electrum request is parsed
+        let header = proxy::read_header(&mut reader)
+            .with_context(|| format!("{}: invalid PROXY header from {}", peer_id, addr))?;
+        if let Some(client_addr) = header {
+            debug!("{}: {} is proxied from {}", peer_id, client_addr, addr);
+            addr = client_addr;
+        }
+    }
+    if !config.is_allowed(addr.ip()) {
+        warn!("{}: rejecting {}, not allowed by ACL", peer_id, addr);
+        return Ok(());
+    }
+    if bans.read().contains(&addr.ip()) {
+        warn!("{}: rejecting {}, banned via admin API", peer_id, addr);
+        return Ok(());
+    }
+    let msg = Message::New(
+        stream.try_clone()?,
+        addr,
+        Arc::clone(config),
+        traffic.clone(),
+    );
     server_tx.send(Event { peer_id, msg })?;
 
     let mut first_line = true;
-    for line in BufReader::new(stream).lines() {
+    while let Some(line) = read_line(&mut reader, MAX_LINE_SIZE).transpose() {
         if let Err(e) = &line {
             if first_line && e.kind() == std::io::ErrorKind::InvalidData {
                 warn!("InvalidData on first line may indicate client attempted to connect using SSL when server expects unencrypted communication.")
             }
         }
         let line = line.with_context(|| format!("{}: recv failed", peer_id))?;
-        debug!("{}: recv {}", peer_id, line);
+        debug!("{}: recv {} bytes", peer_id, line.len());
+        traffic.inc_by("in", line.len() as u64 + 1);
         let msg = Message::Request(line);
         server_tx.send(Event { peer_id, msg })?;
         first_line = false;
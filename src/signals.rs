@@ -9,7 +9,7 @@ use std::sync::{
 };
 use std::{error, fmt};
 
-use crate::thread::spawn;
+use crate::{thread::spawn, throttle::Throttle};
 
 #[derive(Debug)]
 pub struct ExitError;
@@ -28,7 +28,7 @@ pub(crate) struct ExitFlag {
 }
 
 impl ExitFlag {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         ExitFlag {
             flag: Arc::new(AtomicBool::new(false)),
         }
@@ -42,7 +42,24 @@ impl ExitFlag {
         }
     }
 
-    fn set(&self) {
+    pub(crate) fn set(&self) {
+        self.flag.store(true, Ordering::Relaxed)
+    }
+}
+
+/// Set when the server should stop accepting new connections, and exit after serving
+/// the connected clients for a while (see `--drain-timeout-secs`).
+#[derive(Clone, Default)]
+pub(crate) struct DrainFlag {
+    flag: Arc<AtomicBool>,
+}
+
+impl DrainFlag {
+    pub fn is_set(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self) {
         self.flag.store(true, Ordering::Relaxed)
     }
 }
@@ -50,27 +67,35 @@ impl ExitFlag {
 pub(crate) struct Signal {
     rx: Receiver<()>,
     exit: ExitFlag,
+    drain: DrainFlag,
 }
 
 impl Signal {
-    pub fn new() -> Signal {
+    /// `exit` may be shared by multiple servers (e.g. of different chains).
+    pub fn new(throttle: Throttle, exit: ExitFlag) -> Signal {
         let ids = vec![
             SIGINT, SIGTERM,
             SIGUSR1, // allow external triggering (e.g. via bitcoind `blocknotify`)
+            SIGUSR2, // toggle index throttling
+            SIGQUIT, // graceful draining
         ];
         let (tx, rx) = unbounded();
         let result = Signal {
             rx,
-            exit: ExitFlag::new(),
+            exit,
+            drain: DrainFlag::default(),
         };
 
         let exit_flag = result.exit.clone();
+        let drain_flag = result.drain.clone();
         let mut signals = Signals::new(ids).expect("failed to register signal hook");
         spawn("signal", move || {
             for id in &mut signals {
                 info!("notified via SIG{}", id);
                 match id {
                     SIGUSR1 => (),
+                    SIGUSR2 => throttle.toggle(),
+                    SIGQUIT => drain_flag.set(),
                     _ => exit_flag.set(),
                 };
                 tx.send(()).context("failed to send signal")?;
@@ -87,4 +112,8 @@ impl Signal {
     pub fn exit_flag(&self) -> &ExitFlag {
         &self.exit
     }
+
+    pub fn drain_flag(&self) -> &DrainFlag {
+        &self.drain
+    }
 }
@@ -0,0 +1,157 @@
+use anyhow::Result;
+use parking_lot::{Condvar, Mutex};
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+
+/// Coalesce concurrent identical requests, so that only the first one ("leader") is executed,
+/// while the rest ("followers") wait for its result.
+pub(crate) struct SingleFlight<K, V> {
+    flights: Mutex<HashMap<K, Arc<Flight<V>>>>,
+}
+
+struct Flight<V> {
+    result: Mutex<Option<Result<V, String>>>,
+    done: Condvar,
+}
+
+pub(crate) enum Join<'a, K: Eq + Hash, V: Clone> {
+    Leader(Leader<'a, K, V>),
+    Follower(Follower<V>),
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> SingleFlight<K, V> {
+    pub(crate) fn new() -> Self {
+        Self {
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The leader must execute the request and `complete()` it, the followers `wait()` for it.
+    pub(crate) fn join(&self, key: K) -> Join<'_, K, V> {
+        let mut flights = self.flights.lock();
+        if let Some(flight) = flights.get(&key) {
+            return Join::Follower(Follower {
+                flight: Arc::clone(flight),
+            });
+        }
+        let flight = Arc::new(Flight {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        });
+        flights.insert(key.clone(), Arc::clone(&flight));
+        Join::Leader(Leader {
+            group: self,
+            key,
+            flight,
+            completed: false,
+        })
+    }
+}
+
+pub(crate) struct Leader<'a, K: Eq + Hash, V: Clone> {
+    group: &'a SingleFlight<K, V>,
+    key: K,
+    flight: Arc<Flight<V>>,
+    completed: bool,
+}
+
+impl<'a, K: Eq + Hash, V: Clone> Leader<'a, K, V> {
+    pub(crate) fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Share the result with the followers (if there are any).
+    pub(crate) fn complete(mut self, result: &Result<V>) {
+        self.completed = true;
+        self.finish(|| match result {
+            Ok(value) => Ok(value.clone()),
+            Err(e) => Err(format!("{:#}", e)),
+        });
+    }
+
+    fn finish(&self, make_result: impl FnOnce() -> Result<V, String>) {
+        // No new followers can join after the flight is removed
+        self.group.flights.lock().remove(&self.key);
+        if Arc::strong_count(&self.flight) > 1 {
+            *self.flight.result.lock() = Some(make_result());
+            self.flight.done.notify_all();
+        }
+    }
+}
+
+impl<'a, K: Eq + Hash, V: Clone> Drop for Leader<'a, K, V> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.finish(|| Err("in-flight request was cancelled".to_owned()));
+        }
+    }
+}
+
+pub(crate) struct Follower<V> {
+    flight: Arc<Flight<V>>,
+}
+
+impl<V: Clone> Follower<V> {
+    pub(crate) fn wait(self) -> Result<V> {
+        let mut result = self.flight.result.lock();
+        while result.is_none() {
+            self.flight.done.wait(&mut result);
+        }
+        match result.as_ref().unwrap() {
+            Ok(value) => Ok(value.clone()),
+            Err(e) => bail!("{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Join, SingleFlight};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_single_flight() {
+        let group = Arc::new(SingleFlight::<u32, String>::new());
+        let leader = match group.join(1) {
+            Join::Leader(leader) => leader,
+            Join::Follower(_) => panic!("first request must lead"),
+        };
+        let followers: Vec<_> = (0..3)
+            .map(|_| match group.join(1) {
+                Join::Leader(_) => panic!("concurrent request must follow"),
+                Join::Follower(follower) => std::thread::spawn(move || follower.wait().unwrap()),
+            })
+            .collect();
+        assert!(matches!(group.join(2), Join::Leader(_))); // different key
+
+        leader.complete(&Ok("result".to_owned()));
+        for follower in followers {
+            assert_eq!(follower.join().unwrap(), "result");
+        }
+        assert!(matches!(group.join(1), Join::Leader(_))); // flight is done
+    }
+
+    #[test]
+    fn test_single_flight_failure() {
+        let group = SingleFlight::<u32, String>::new();
+        let leader = group.join(1);
+        let follower = match group.join(1) {
+            Join::Follower(follower) => follower,
+            Join::Leader(_) => panic!("concurrent request must follow"),
+        };
+        drop(leader);
+        let err = follower.wait().unwrap_err();
+        assert_eq!(err.to_string(), "in-flight request was cancelled");
+
+        let leader = group.join(1);
+        let follower = group.join(1);
+        if let Join::Leader(leader) = leader {
+            leader.complete(&Err(anyhow!("failed")));
+        }
+        if let Join::Follower(follower) = follower {
+            assert_eq!(follower.wait().unwrap_err().to_string(), "failed");
+        }
+    }
+}
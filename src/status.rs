@@ -10,16 +10,17 @@ use serde::ser::{Serialize, Serializer};
 
 use std::convert::TryFrom;
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
-    ops::ControlFlow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ops::{ControlFlow, RangeInclusive},
 };
 
 use crate::{
     cache::Cache,
     chain::Chain,
-    daemon::Daemon,
+    daemon::BlockSource,
     index::Index,
-    mempool::Mempool,
+    mempool::{self, Mempool},
+    trace::Deadline,
     types::{bsl_txid, ScriptHash, SerBlock, StatusHash},
 };
 
@@ -103,6 +104,18 @@ pub(crate) struct HistoryEntry {
 }
 
 impl HistoryEntry {
+    pub(crate) fn txid(&self) -> Txid {
+        self.txid
+    }
+
+    /// `None` for mempool entries.
+    pub(crate) fn confirmed_height(&self) -> Option<usize> {
+        match self.height {
+            Height::Confirmed { height } => Some(height),
+            Height::Unconfirmed { .. } => None,
+        }
+    }
+
     fn hash(&self, engine: &mut sha256::HashEngine) {
         let s = format!("{}:{}:", self.txid, self.height);
         engine.input(s.as_bytes());
@@ -127,6 +140,15 @@ impl HistoryEntry {
     }
 }
 
+/// The newest part of a scripthash confirmed history, with a cursor for fetching older
+/// entries (by passing it as the next page's `before_height`)
+#[derive(Serialize)]
+pub(crate) struct HistoryPage {
+    history: Vec<HistoryEntry>,
+    truncated: bool,
+    cursor: Option<usize>, // set only if truncated
+}
+
 /// ScriptHash subscription status
 pub struct ScriptHashStatus {
     scripthash: ScriptHash, // specific scripthash to be queried
@@ -135,6 +157,32 @@ pub struct ScriptHashStatus {
     mempool: Vec<TxEntry>,                       // unconfirmed entries
     history: Vec<HistoryEntry>,                  // computed from confirmed and mempool entries
     statushash: Option<StatusHash>,              // computed from history
+    mempool_fingerprint: Option<sha256::Hash>,   // of the relevant mempool entries (when synced)
+    hashed_prefix: Option<HashedPrefix>,         // for hashing only the new confirmed entries
+}
+
+/// The status hash's state after hashing the confirmed history, up to (and including) a
+/// specific block. If the chain still contains this block, the entries up to it are the same
+/// (their blocks are synced once), so only the entries after them need to be hashed.
+#[derive(Clone)]
+struct HashedPrefix {
+    height: usize,
+    blockhash: BlockHash,
+    entries: usize, // # of hashed history entries
+    engine: sha256::HashEngine,
+}
+
+impl HashedPrefix {
+    /// `confirmed` is the number of confirmed entries (preceding the mempool ones).
+    fn is_valid(&self, history: &[HistoryEntry], confirmed: usize, chain: &Chain) -> bool {
+        self.entries > 0
+            && self.entries <= confirmed
+            && chain.get_block_hash(self.height) == Some(self.blockhash)
+            && history[self.entries - 1].confirmed_height() == Some(self.height)
+            && history[self.entries..confirmed]
+                .first()
+                .map_or(true, |next| next.confirmed_height() > Some(self.height))
+    }
 }
 
 /// Specific scripthash balance
@@ -157,6 +205,21 @@ pub(crate) struct UnspentEntry {
     value: Amount,
 }
 
+impl UnspentEntry {
+    pub(crate) fn outpoint(&self) -> OutPoint {
+        OutPoint::new(self.tx_hash, self.tx_pos)
+    }
+
+    pub(crate) fn value(&self) -> Amount {
+        self.value
+    }
+
+    /// `None` for mempool entries.
+    pub(crate) fn confirmed_height(&self) -> Option<usize> {
+        Some(self.height).filter(|height| *height > 0)
+    }
+}
+
 #[derive(Default)]
 struct Unspent {
     // mapping an outpoint to its value & confirmation height
@@ -232,6 +295,8 @@ impl ScriptHashStatus {
             mempool: Vec::new(),
             history: Vec::new(),
             statushash: None,
+            mempool_fingerprint: None,
+            hashed_prefix: None,
         }
     }
 
@@ -307,72 +372,126 @@ impl ScriptHashStatus {
             .collect()
     }
 
-    /// Apply func only on the new blocks (fetched from daemon).
-    fn for_new_blocks<B, F>(&self, blockhashes: B, daemon: &Daemon, func: F) -> Result<()>
-    where
-        B: IntoIterator<Item = BlockHash>,
-        F: FnMut(BlockHash, SerBlock),
-    {
-        daemon.for_blocks(
-            blockhashes
-                .into_iter()
-                .filter(|blockhash| !self.confirmed.contains_key(blockhash)),
-            func,
+    /// Get funding and spending entries from new blocks, for multiple statuses at once
+    /// (each new block is fetched only once, even if it is relevant to several statuses).
+    /// Also cache relevant transactions.
+    fn sync_confirmed(
+        statuses: &[&ScriptHashStatus],
+        index: &Index,
+        daemon: &impl BlockSource,
+        cache: &Cache,
+        outpoints: &mut [HashSet<OutPoint>],
+    ) -> Result<Vec<HashMap<BlockHash, Vec<TxEntry>>>> {
+        let funding_blockhashes = statuses
+            .iter()
+            .map(|status| index.limit_result(index.filter_by_funding(status.scripthash)))
+            .collect::<Result<Vec<_>>>()?;
+        Self::sync_confirmed_blocks(
+            statuses,
+            funding_blockhashes,
+            index,
+            daemon,
+            cache,
+            outpoints,
+            |_blockhash| true,
         )
     }
 
-    /// Get funding and spending entries from new blocks.
-    /// Also cache relevant transactions and their merkle proofs.
-    fn sync_confirmed(
-        &self,
+    /// Same as `sync_confirmed`, but only the specified funding blocks are fetched for each status
+    /// (and only the spending blocks accepted by `spending_filter`).
+    fn sync_confirmed_blocks(
+        statuses: &[&ScriptHashStatus],
+        funding_blockhashes: Vec<Vec<BlockHash>>,
         index: &Index,
-        daemon: &Daemon,
+        daemon: &impl BlockSource,
         cache: &Cache,
-        outpoints: &mut HashSet<OutPoint>,
-    ) -> Result<HashMap<BlockHash, Vec<TxEntry>>> {
-        let scripthash = self.scripthash;
-        let mut result = HashMap::<BlockHash, HashMap<usize, TxEntry>>::new();
-
-        let funding_blockhashes = index.limit_result(index.filter_by_funding(scripthash))?;
-        self.for_new_blocks(funding_blockhashes, daemon, |blockhash, block| {
-            let block_entries = result.entry(blockhash).or_default();
-            for filtered_outputs in filter_block_txs_outputs(block, scripthash) {
-                cache.add_tx(filtered_outputs.txid, move || filtered_outputs.tx);
-                outpoints.extend(make_outpoints(
-                    filtered_outputs.txid,
-                    &filtered_outputs.result,
-                ));
-                block_entries
-                    .entry(filtered_outputs.pos)
-                    .or_insert_with(|| TxEntry::new(filtered_outputs.txid))
-                    .outputs = filtered_outputs.result;
-            }
+        outpoints: &mut [HashSet<OutPoint>],
+        spending_filter: impl Fn(&BlockHash) -> bool + Sync,
+    ) -> Result<Vec<HashMap<BlockHash, Vec<TxEntry>>>> {
+        let mut results: Vec<HashMap<BlockHash, HashMap<usize, TxEntry>>> =
+            statuses.iter().map(|_| HashMap::new()).collect();
+
+        let mut funding_blocks = NewBlocks::default();
+        for (i, (status, blockhashes)) in statuses.iter().zip(funding_blockhashes).enumerate() {
+            funding_blocks.add(status, i, blockhashes);
+        }
+        // blocks are prefetched and filtered in parallel, while the results are merged sequentially
+        let funding = daemon.map_blocks(funding_blocks.blockhashes(), |blockhash, block| {
+            let filtered: Vec<(usize, Vec<FilteredTx<TxOutput>>)> = funding_blocks
+                .statuses(&blockhash)
+                .iter()
+                .map(|&i| (i, filter_block_txs_outputs(&block, statuses[i].scripthash)))
+                .collect();
+            (blockhash, filtered)
         })?;
-        let spending_blockhashes: HashSet<BlockHash> = outpoints
-            .par_iter()
-            .flat_map_iter(|outpoint| index.filter_by_spending(*outpoint))
-            .collect();
-        self.for_new_blocks(spending_blockhashes, daemon, |blockhash, block| {
-            let block_entries = result.entry(blockhash).or_default();
-            for filtered_inputs in filter_block_txs_inputs(&block, outpoints) {
-                cache.add_tx(filtered_inputs.txid, move || filtered_inputs.tx);
-                block_entries
-                    .entry(filtered_inputs.pos)
-                    .or_insert_with(|| TxEntry::new(filtered_inputs.txid))
-                    .spent = filtered_inputs.result;
+        observe_lookup(index, "funding", &funding);
+        for (blockhash, filtered) in funding {
+            for (i, filtered_txs) in filtered {
+                let block_entries = results[i].entry(blockhash).or_default();
+                for filtered_outputs in filtered_txs {
+                    cache.add_tx(filtered_outputs.txid, move || filtered_outputs.tx);
+                    outpoints[i].extend(make_outpoints(
+                        filtered_outputs.txid,
+                        &filtered_outputs.result,
+                    ));
+                    block_entries
+                        .entry(filtered_outputs.pos)
+                        .or_insert_with(|| TxEntry::new(filtered_outputs.txid))
+                        .outputs = filtered_outputs.result;
+                }
             }
-        })?;
+        }
+
+        let mut spending_blocks = NewBlocks::default();
+        for (i, status) in statuses.iter().enumerate() {
+            let blockhashes: HashSet<BlockHash> = outpoints[i]
+                .par_iter()
+                .flat_map_iter(|outpoint| index.filter_by_spending(*outpoint))
+                .filter(|blockhash| spending_filter(blockhash))
+                .collect();
+            spending_blocks.add(status, i, blockhashes);
+        }
+        let spending = {
+            let outpoints = &*outpoints;
+            daemon.map_blocks(spending_blocks.blockhashes(), |blockhash, block| {
+                let filtered: Vec<(usize, Vec<FilteredTx<OutPoint>>)> = spending_blocks
+                    .statuses(&blockhash)
+                    .iter()
+                    .map(|&i| (i, filter_block_txs_inputs(&block, &outpoints[i])))
+                    .collect();
+                (blockhash, filtered)
+            })?
+        };
+        observe_lookup(index, "spending", &spending);
+        Deadline::check()?; // the scans may have been stopped early
+        for (blockhash, filtered) in spending {
+            for (i, filtered_txs) in filtered {
+                let block_entries = results[i].entry(blockhash).or_default();
+                for filtered_inputs in filtered_txs {
+                    cache.add_tx(filtered_inputs.txid, move || filtered_inputs.tx);
+                    block_entries
+                        .entry(filtered_inputs.pos)
+                        .or_insert_with(|| TxEntry::new(filtered_inputs.txid))
+                        .spent = filtered_inputs.result;
+                }
+            }
+        }
 
-        Ok(result
+        Ok(results
             .into_iter()
-            .map(|(blockhash, entries_map)| {
-                // sort transactions by their position in a block
-                let sorted_entries = entries_map
+            .map(|result| {
+                result
                     .into_iter()
-                    .collect::<BTreeMap<usize, TxEntry>>()
-                    .into_values()
-                    .collect();
-                (blockhash, sorted_entries)
+                    .map(|(blockhash, entries_map)| {
+                        // sort transactions by their position in a block
+                        let sorted_entries = entries_map
+                            .into_iter()
+                            .collect::<BTreeMap<usize, TxEntry>>()
+                            .into_values()
+                            .collect();
+                        (blockhash, sorted_entries)
+                    })
+                    .collect()
             })
             .collect())
     }
@@ -417,17 +536,133 @@ impl ScriptHashStatus {
         &mut self,
         index: &Index,
         mempool: &Mempool,
-        daemon: &Daemon,
+        daemon: &impl BlockSource,
+        cache: &Cache,
+    ) -> Result<()> {
+        Self::sync_many(std::slice::from_mut(self), index, mempool, daemon, cache)
+    }
+
+    /// Sync multiple statuses together, so that blocks relevant to several of them are fetched once.
+    /// After a successful sync, all scripthash statuses are updated.
+    pub(crate) fn sync_many(
+        statuses: &mut [ScriptHashStatus],
+        index: &Index,
+        mempool: &Mempool,
+        daemon: &impl BlockSource,
         cache: &Cache,
     ) -> Result<()> {
-        let mut outpoints: HashSet<OutPoint> = self.confirmed_outpoints(index.chain());
+        let chain = index.chain();
+        let mut outpoints: Vec<HashSet<OutPoint>> = statuses
+            .iter()
+            .map(|status| status.confirmed_outpoints(chain))
+            .collect();
+
+        let new_tip = chain.tip();
+        let mut outdated: Vec<usize> = vec![];
+        for (i, status) in statuses.iter_mut().enumerate() {
+            if status.tip != new_tip && !status.skip_untouched(index, &outpoints[i]) {
+                outdated.push(i);
+            }
+        }
+        let mut resynced = vec![false; statuses.len()];
+        for &i in &outdated {
+            resynced[i] = true;
+        }
+        if !outdated.is_empty() {
+            let mut outdated_outpoints: Vec<HashSet<OutPoint>> = outdated
+                .iter()
+                .map(|&i| std::mem::take(&mut outpoints[i]))
+                .collect();
+            let updates = {
+                let outdated_statuses: Vec<&ScriptHashStatus> =
+                    outdated.iter().map(|&i| &statuses[i]).collect();
+                Self::sync_confirmed(
+                    &outdated_statuses,
+                    index,
+                    daemon,
+                    cache,
+                    &mut outdated_outpoints,
+                )?
+            };
+            for ((i, update), status_outpoints) in
+                outdated.into_iter().zip(updates).zip(outdated_outpoints)
+            {
+                statuses[i].confirmed.extend(update);
+                statuses[i].tip = new_tip;
+                outpoints[i] = status_outpoints;
+            }
+        }
+        for ((status, mut status_outpoints), resynced) in
+            statuses.iter_mut().zip(outpoints).zip(resynced)
+        {
+            let fingerprint = status.mempool_fingerprint(mempool, &status_outpoints);
+            if resynced || status.mempool_fingerprint != Some(fingerprint) {
+                status.sync_unconfirmed(index, mempool, cache, &mut status_outpoints);
+                status.mempool_fingerprint = Some(fingerprint);
+            }
+        }
+        Ok(())
+    }
+
+    /// Advance the status to the current tip without re-syncing it, if the new blocks don't
+    /// fund its scripthash or spend its outpoints (so its confirmed entries are the same).
+    fn skip_untouched(&mut self, index: &Index, outpoints: &HashSet<OutPoint>) -> bool {
+        let touched = match index.touched_since(&self.tip) {
+            Some(touched) => touched,
+            None => return false, // unknown (or reorged) blocks must be synced
+        };
+        let is_touched = touched.iter().any(|block| {
+            block.funds(self.scripthash) || outpoints.iter().any(|outpoint| block.spends(*outpoint))
+        });
+        if is_touched {
+            return false;
+        }
+        self.tip = index.chain().tip();
+        true
+    }
 
-        let new_tip = index.chain().tip();
-        if self.tip != new_tip {
-            let update = self.sync_confirmed(index, daemon, cache, &mut outpoints)?;
-            self.confirmed.extend(update);
-            self.tip = new_tip;
+    /// Identifies the mempool entries which may be relevant to the status (funding its
+    /// scripthash, or spending its outpoints), so its unconfirmed entries are re-synced only
+    /// when they change.
+    fn mempool_fingerprint(
+        &self,
+        mempool: &Mempool,
+        outpoints: &HashSet<OutPoint>,
+    ) -> sha256::Hash {
+        let funding = mempool.filter_by_funding(&self.scripthash);
+        let funded_outpoints: Vec<OutPoint> = funding
+            .iter()
+            .flat_map(|entry| {
+                (0..entry.tx.output.len()).map(move |vout| OutPoint::new(entry.txid, vout as u32))
+            })
+            .collect();
+        let spending = outpoints
+            .iter()
+            .chain(&funded_outpoints)
+            .flat_map(|outpoint| mempool.filter_by_spending(outpoint));
+        let entries: BTreeMap<Txid, &mempool::Entry> = funding
+            .iter()
+            .copied()
+            .chain(spending)
+            .map(|entry| (entry.txid, entry))
+            .collect();
+        let mut engine = sha256::Hash::engine();
+        for entry in entries.values() {
+            engine.input(&entry.txid[..]);
+            engine.input(&entry.fee.to_sat().to_be_bytes());
+            engine.input(&[u8::from(entry.has_unconfirmed_inputs)]);
         }
+        sha256::Hash::from_engine(engine)
+    }
+
+    /// Sync mempool entries and recompute history (confirmed entries should be already synced).
+    fn sync_unconfirmed(
+        &mut self,
+        index: &Index,
+        mempool: &Mempool,
+        cache: &Cache,
+        outpoints: &mut HashSet<OutPoint>,
+    ) {
         if !self.confirmed.is_empty() {
             debug!(
                 "{} transactions from {} blocks",
@@ -435,17 +670,114 @@ impl ScriptHashStatus {
                 self.confirmed.len()
             );
         }
-        self.mempool = self.sync_mempool(mempool, cache, &mut outpoints);
+        self.mempool = self.sync_mempool(mempool, cache, outpoints);
         if !self.mempool.is_empty() {
             debug!("{} mempool transactions", self.mempool.len());
         }
+        let chain = index.chain();
         self.history.clear();
-        self.history
-            .extend(self.get_confirmed_history(index.chain()));
+        self.history.extend(self.get_confirmed_history(chain));
+        let confirmed = self.history.len();
         self.history.extend(self.get_mempool_history(mempool));
 
-        self.statushash = compute_status_hash(&self.history);
-        Ok(())
+        self.statushash =
+            compute_status_hash(&self.history, confirmed, chain, &mut self.hashed_prefix);
+    }
+
+    /// Fetch the newest confirmed history entries below `before_height` (if specified),
+    /// fetching at most `lookup_limit` funding blocks (instead of failing, like `sync` does).
+    /// Mempool entries are not included, and spending entries are returned only if their
+    /// funding entries are - so the page of an output's funding also contains its spending
+    /// (even above `before_height`, since the newer pages don't look it up).
+    pub(crate) fn recent_history(
+        scripthash: ScriptHash,
+        before_height: Option<usize>,
+        index: &Index,
+        daemon: &impl BlockSource,
+        cache: &Cache,
+    ) -> Result<HistoryPage> {
+        let chain = index.chain();
+        let before_height = before_height.unwrap_or(usize::MAX);
+        let heights: BTreeSet<usize> = index
+            .filter_by_funding(scripthash)
+            .filter_map(|blockhash| chain.get_block_height(&blockhash))
+            .filter(|height| *height < before_height)
+            .collect();
+        Deadline::check()?; // the scan may have been stopped early
+        let (heights, cursor) = index.limit_newest(heights);
+        let blockhashes = heights
+            .into_iter()
+            .filter_map(|height| chain.get_block_hash(height))
+            .collect();
+
+        let mut status = ScriptHashStatus::new(scripthash);
+        let mut outpoints = [HashSet::new()];
+        let update = Self::sync_confirmed_blocks(
+            &[&status],
+            vec![blockhashes],
+            index,
+            daemon,
+            cache,
+            &mut outpoints,
+            |_blockhash| true,
+        )?;
+        status.confirmed = update.into_iter().next().expect("missing update");
+        Ok(HistoryPage {
+            history: status.get_confirmed_history(chain),
+            truncated: cursor.is_some(),
+            cursor,
+        })
+    }
+
+    /// Fetch the confirmed history entries within `heights` (without syncing the whole status).
+    /// The funding blocks above its end are not fetched, and the spending blocks are fetched only
+    /// within it (older funding blocks are still needed, for finding the spent outpoints).
+    pub(crate) fn history_range(
+        scripthash: ScriptHash,
+        heights: RangeInclusive<usize>,
+        index: &Index,
+        daemon: &impl BlockSource,
+        cache: &Cache,
+    ) -> Result<Vec<HistoryEntry>> {
+        let chain = index.chain();
+        let in_range = |blockhash: &BlockHash| {
+            chain
+                .get_block_height(blockhash)
+                .map_or(false, |height| heights.contains(&height))
+        };
+        let funding = index.filter_by_funding(scripthash).filter(|blockhash| {
+            chain
+                .get_block_height(blockhash)
+                .map_or(false, |height| height <= *heights.end())
+        });
+        let blockhashes = index.limit_result(funding)?;
+
+        let mut status = ScriptHashStatus::new(scripthash);
+        let mut outpoints = [HashSet::new()];
+        let update = Self::sync_confirmed_blocks(
+            &[&status],
+            vec![blockhashes],
+            index,
+            daemon,
+            cache,
+            &mut outpoints,
+            in_range,
+        )?;
+        status.confirmed = update.into_iter().next().expect("missing update");
+        Ok(status
+            .get_confirmed_history(chain)
+            .into_iter()
+            .filter(|entry| {
+                entry
+                    .confirmed_height()
+                    .map_or(false, |height| heights.contains(&height))
+            })
+            .collect())
+    }
+
+    /// Get the queried script hash.
+    pub fn scripthash(&self) -> ScriptHash {
+        self.scripthash
     }
 
     /// Get current status hash.
@@ -454,6 +786,15 @@ impl ScriptHashStatus {
     }
 }
 
+/// Each (block, status) pair is a single candidate.
+fn observe_lookup<T>(index: &Index, lookup: &str, blocks: &[(BlockHash, Vec<(usize, Vec<T>)>)]) {
+    let candidates = blocks.iter().flat_map(|(_, filtered)| filtered);
+    let (total, false_positives) = candidates.fold((0, 0), |(total, fp), (_, txs)| {
+        (total + 1, fp + usize::from(txs.is_empty()))
+    });
+    index.observe_lookup(lookup, total, false_positives);
+}
+
 fn make_outpoints(txid: Txid, outputs: &[TxOutput]) -> impl Iterator<Item = OutPoint> + '_ {
     outputs
         .iter()
@@ -489,17 +830,78 @@ fn filter_inputs(tx: &Transaction, outpoints: &HashSet<OutPoint>) -> Vec<OutPoin
         .collect()
 }
 
-fn compute_status_hash(history: &[HistoryEntry]) -> Option<StatusHash> {
+/// Hash the history (whose first `confirmed` entries are confirmed), resuming from the cached
+/// `prefix` if it is still valid - and cache the state after hashing its confirmed entries.
+fn compute_status_hash(
+    history: &[HistoryEntry],
+    confirmed: usize,
+    chain: &Chain,
+    prefix: &mut Option<HashedPrefix>,
+) -> Option<StatusHash> {
     if history.is_empty() {
+        *prefix = None;
         return None;
     }
-    let mut engine = StatusHash::engine();
-    for entry in history {
+    let (mut engine, start) = match prefix
+        .take()
+        .filter(|prefix| prefix.is_valid(history, confirmed, chain))
+    {
+        Some(prefix) => (prefix.engine, prefix.entries),
+        None => (StatusHash::engine(), 0),
+    };
+    for entry in &history[start..confirmed] {
+        entry.hash(&mut engine);
+    }
+    if let Some(height) = confirmed
+        .checked_sub(1)
+        .and_then(|last| history[last].confirmed_height())
+    {
+        *prefix = chain.get_block_hash(height).map(|blockhash| HashedPrefix {
+            height,
+            blockhash,
+            entries: confirmed,
+            engine: engine.clone(),
+        });
+    }
+    for entry in &history[confirmed..] {
         entry.hash(&mut engine);
     }
     Some(StatusHash::from_engine(engine))
 }
 
+/// New (not synced yet) blocks, mapped to the positions of the statuses requiring them.
+#[derive(Default)]
+struct NewBlocks {
+    map: HashMap<BlockHash, Vec<usize>>,
+}
+
+impl NewBlocks {
+    fn add(
+        &mut self,
+        status: &ScriptHashStatus,
+        position: usize,
+        blockhashes: impl IntoIterator<Item = BlockHash>,
+    ) {
+        for blockhash in blockhashes {
+            if status.confirmed.contains_key(&blockhash) {
+                continue; // already synced
+            }
+            let positions = self.map.entry(blockhash).or_default();
+            if positions.last() != Some(&position) {
+                positions.push(position);
+            }
+        }
+    }
+
+    fn blockhashes(&self) -> Vec<BlockHash> {
+        self.map.keys().copied().collect()
+    }
+
+    fn statuses(&self, blockhash: &BlockHash) -> &[usize] {
+        self.map.get(blockhash).map(Vec::as_slice).unwrap_or(&[])
+    }
+}
+
 struct FilteredTx<T> {
     tx: Transaction,
     txid: Txid,
@@ -507,7 +909,7 @@ struct FilteredTx<T> {
     result: Vec<T>,
 }
 
-fn filter_block_txs_outputs(block: SerBlock, scripthash: ScriptHash) -> Vec<FilteredTx<TxOutput>> {
+fn filter_block_txs_outputs(block: &SerBlock, scripthash: ScriptHash) -> Vec<FilteredTx<TxOutput>> {
     struct FindOutputs {
         scripthash: ScriptHash,
         result: Vec<FilteredTx<TxOutput>>,
@@ -549,7 +951,7 @@ fn filter_block_txs_outputs(block: SerBlock, scripthash: ScriptHash) -> Vec<Filt
         pos: 0,
     };
 
-    bsl::Block::visit(&block, &mut find_outputs).expect("core returned invalid block");
+    bsl::Block::visit(block, &mut find_outputs).expect("core returned invalid block");
 
     find_outputs.result
 }
@@ -609,8 +1011,9 @@ mod tests {
 
     use crate::types::ScriptHash;
 
-    use super::HistoryEntry;
-    use bitcoin::{Address, Amount};
+    use super::{compute_status_hash, HistoryEntry};
+    use crate::chain::Chain;
+    use bitcoin::{hashes::Hash, Address, Amount, Network, Txid};
     use bitcoin_test_data::blocks::mainnet_702861;
     use serde_json::json;
 
@@ -637,6 +1040,43 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_incremental_status_hash() {
+        let chain = Chain::new(Network::Regtest);
+        let txid = |i: u8| Txid::from_byte_array([i; 32]);
+        let full_hash = |history: &[HistoryEntry], confirmed| {
+            compute_status_hash(history, confirmed, &chain, &mut None)
+        };
+        let mut prefix = None;
+        assert_eq!(compute_status_hash(&[], 0, &chain, &mut prefix), None);
+
+        let mut history = vec![
+            HistoryEntry::confirmed(txid(1), 0),
+            HistoryEntry::confirmed(txid(2), 0),
+        ];
+        let hash = compute_status_hash(&history, 2, &chain, &mut prefix);
+        assert_eq!(hash, full_hash(&history, 2));
+        assert_eq!(prefix.as_ref().map(|p| p.entries), Some(2));
+
+        // new mempool entries are hashed after the cached confirmed ones
+        history.push(HistoryEntry::unconfirmed(txid(3), false, Amount::ZERO));
+        let hash = compute_status_hash(&history, 2, &chain, &mut prefix);
+        assert_eq!(hash, full_hash(&history, 2));
+        assert_ne!(hash, full_hash(&history[..2], 2));
+        assert_eq!(prefix.as_ref().map(|p| p.entries), Some(2));
+
+        // a new entry at the cached height invalidates the cached prefix
+        history.insert(1, HistoryEntry::confirmed(txid(4), 0));
+        let hash = compute_status_hash(&history, 3, &chain, &mut prefix);
+        assert_eq!(hash, full_hash(&history, 3));
+        assert_eq!(prefix.as_ref().map(|p| p.entries), Some(3));
+
+        // only mempool entries
+        let mempool = [HistoryEntry::unconfirmed(txid(5), true, Amount::ZERO)];
+        let hash = compute_status_hash(&mempool, 0, &chain, &mut prefix);
+        assert_eq!(hash, full_hash(&mempool, 0));
+    }
+
     #[test]
     fn test_find_outputs() {
         let block = mainnet_702861().to_vec();
@@ -646,7 +1086,7 @@ mod tests {
             .assume_checked();
         let scripthash = ScriptHash::new(&addr.script_pubkey());
 
-        let result = &super::filter_block_txs_outputs(block, scripthash)[0];
+        let result = &super::filter_block_txs_outputs(&block, scripthash)[0];
         assert_eq!(
             result.txid.to_string(),
             "7bcdcb44422da5a99daad47d6ba1c3d6f2e48f961a75e42c4fa75029d4b0ef49"
@@ -0,0 +1,199 @@
+use log::{Level, Log, Metadata, Record};
+use parking_lot::{const_mutex, Mutex};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAX_RECENT_ERRORS: usize = 20;
+
+// (UNIX timestamp, message) of the most recently logged errors (oldest first)
+static RECENT_ERRORS: Mutex<Vec<(u64, String)>> = const_mutex(Vec::new());
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Logs via `inner`, also keeping the recently logged errors (to be shown by the status page).
+pub(crate) struct ErrorLog<L> {
+    inner: L,
+}
+
+impl<L: Log> ErrorLog<L> {
+    pub(crate) fn new(inner: L) -> Self {
+        Self { inner }
+    }
+}
+
+impl<L: Log> Log for ErrorLog<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Error && self.inner.enabled(record.metadata()) {
+            let message = format!("{}: {}", record.target(), record.args());
+            let mut errors = RECENT_ERRORS.lock();
+            if errors.len() >= MAX_RECENT_ERRORS {
+                errors.remove(0);
+            }
+            errors.push((unix_time(), message));
+        }
+        self.inner.log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) use render::render;
+
+/// A minimal HTML page, summarizing the chains' state from their metrics (for operators
+/// without a Prometheus setup).
+#[cfg(feature = "metrics")]
+mod render {
+    use prometheus::proto::{MetricFamily, MetricType};
+
+    use std::fmt::Write as _;
+
+    use super::{unix_time, RECENT_ERRORS};
+
+    const SIZE_PROPERTY: &str = "rocksdb.total-sst-files-size:";
+
+    fn escape(value: &str) -> String {
+        value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+
+    /// Gauges' values (by their label's value) of the family named `name`.
+    fn gauges<'a>(families: &'a [MetricFamily], name: &str) -> Vec<(&'a str, f64)> {
+        families
+            .iter()
+            .filter(|family| family.get_name() == name)
+            .filter(|family| family.get_field_type() == MetricType::GAUGE)
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                let label = metric.get_label().first().map_or("", |l| l.get_value());
+                (label, metric.get_gauge().get_value())
+            })
+            .collect()
+    }
+
+    fn write_table(out: &mut String, title: &str, rows: &[(String, String)]) {
+        writeln!(out, "<h3>{}</h3>\n<table>", title).unwrap();
+        for (name, value) in rows {
+            writeln!(
+                out,
+                "<tr><td>{}</td><td>{}</td></tr>",
+                escape(name),
+                escape(value)
+            )
+            .unwrap();
+        }
+        out.push_str("</table>\n");
+    }
+
+    fn write_chain(out: &mut String, families: &[MetricFamily], prefix: &str) {
+        let gauge_sum = |name: &str| -> f64 {
+            gauges(families, &(prefix.to_owned() + name))
+                .iter()
+                .map(|(_, v)| v)
+                .sum()
+        };
+        let tip = gauges(families, &(prefix.to_owned() + "index_height"))
+            .into_iter()
+            .find(|(label, _)| *label == "tip")
+            .map_or("-".to_owned(), |(_, height)| height.to_string());
+        let summary = [
+            ("Tip height".to_owned(), tip),
+            (
+                "Connected clients".to_owned(),
+                gauge_sum("electrum_clients").to_string(),
+            ),
+            (
+                "Mempool transactions".to_owned(),
+                gauge_sum("mempool_txs_count").to_string(),
+            ),
+        ];
+        write_table(out, &escape(prefix.trim_end_matches('_')), &summary);
+
+        let db_sizes: Vec<(String, String)> =
+            gauges(families, &(prefix.to_owned() + "index_db_properties"))
+                .into_iter()
+                .filter_map(|(label, size)| {
+                    let cf = label.strip_prefix(SIZE_PROPERTY)?;
+                    Some((cf.to_owned(), format!("{:.1} MB", size / 1e6)))
+                })
+                .collect();
+        write_table(out, "DB size", &db_sizes);
+    }
+
+    pub(crate) fn render(families: &[MetricFamily]) -> String {
+        // each chain's metrics are prefixed by its name (e.g. "electrs_" or "electrs_signet_")
+        let prefixes: Vec<&str> = families
+            .iter()
+            .filter_map(|family| family.get_name().strip_suffix("index_height"))
+            .collect();
+        let mut out = String::from(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>electrs status</title></head>\n<body>\n<h2>electrs status</h2>\n",
+        );
+        for prefix in prefixes {
+            write_chain(&mut out, families, prefix);
+        }
+
+        let now = unix_time();
+        let errors: Vec<(String, String)> = RECENT_ERRORS
+            .lock()
+            .iter()
+            .rev() // newest first
+            .map(|(time, message)| {
+                let age = format!("{}s ago", now.saturating_sub(*time));
+                (age, message.clone())
+            })
+            .collect();
+        write_table(&mut out, "Recent errors", &errors);
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::render;
+        use prometheus::{GaugeVec, Opts, Registry};
+
+        #[test]
+        fn test_render() {
+            let reg = Registry::new();
+            let gauge = |name: &str, label: &str| {
+                let gauge = GaugeVec::new(Opts::new(name, "test"), &[label]).unwrap();
+                reg.register(Box::new(gauge.clone())).unwrap();
+                gauge
+            };
+            gauge("electrs_index_height", "type")
+                .with_label_values(&["tip"])
+                .set(800000.0);
+            let clients = gauge("electrs_electrum_clients", "listener");
+            clients.with_label_values(&["tcp"]).set(2.0);
+            clients.with_label_values(&["ssl"]).set(3.0);
+            gauge("electrs_index_db_properties", "name")
+                .with_label_values(&["rocksdb.total-sst-files-size:funding"])
+                .set(12.5e6);
+            gauge("electrs_signet_index_height", "type")
+                .with_label_values(&["tip"])
+                .set(200000.0);
+
+            let page = render(&reg.gather());
+            assert!(page.contains("<h3>electrs</h3>"));
+            assert!(page.contains("<tr><td>Tip height</td><td>800000</td></tr>"));
+            assert!(page.contains("<tr><td>Connected clients</td><td>5</td></tr>"));
+            assert!(page.contains("<tr><td>funding</td><td>12.5 MB</td></tr>"));
+            assert!(page.contains("<h3>electrs_signet</h3>"));
+            assert!(page.contains("<tr><td>Tip height</td><td>200000</td></tr>"));
+        }
+    }
+}
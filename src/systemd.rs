@@ -0,0 +1,130 @@
+//! Integration with systemd: socket activation (see `sd_listen_fds(3)`),
+//! readiness and watchdog notifications (see `sd_notify(3)`).
+//! When not running under systemd, all of these are no-ops.
+
+use anyhow::{Context, Result};
+
+use std::env;
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+
+// The first passed file descriptor (`SD_LISTEN_FDS_START`)
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Take the listening sockets passed by systemd (if any).
+/// The environment variables are cleared, so they are not inherited by child processes.
+#[cfg(unix)]
+pub(crate) fn activated_listeners() -> Result<Vec<TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let fds = match (pid, fds) {
+        (Some(pid), Some(fds)) if pid == std::process::id().to_string() => fds,
+        _ => return Ok(vec![]), // not activated (or meant for another process)
+    };
+    let count: i32 = fds
+        .parse()
+        .with_context(|| format!("invalid LISTEN_FDS: {:?}", fds))?;
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // the passed descriptors are owned by this process
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            listener
+                .local_addr()
+                .with_context(|| format!("passed fd {} is not a TCP listener", fd))?;
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+                warn!("failed to set FD_CLOEXEC on passed fd {}", fd);
+            }
+            Ok(listener)
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn activated_listeners() -> Result<Vec<TcpListener>> {
+    Ok(vec![])
+}
+
+/// Send a notification to the service manager (e.g. "READY=1"), if it is expected.
+pub(crate) fn notify(state: &str) {
+    if let Err(e) = send(state) {
+        warn!("failed to notify systemd ({:?}): {:#}", state, e);
+    }
+}
+
+#[cfg(unix)]
+fn send(state: &str) -> Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    ensure!(
+        !path.to_string_lossy().starts_with('@'),
+        "abstract NOTIFY_SOCKET is not supported"
+    );
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .send_to(state.as_bytes(), &path)
+        .with_context(|| format!("failed to send to {:?}", path))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_state: &str) -> Result<()> {
+    Ok(())
+}
+
+/// Keeps systemd's watchdog from restarting the service, as long as `ping()` is called
+/// more frequently than `WatchdogSec=`. It is pinged between sync batches, so during the
+/// initial sync the timeout should be longer than indexing a batch of headers takes.
+pub(crate) struct Watchdog {
+    interval: Option<Duration>, // half of the watchdog timeout, as recommended by systemd
+    last: Instant,
+}
+
+impl Watchdog {
+    pub(crate) fn new() -> Self {
+        let pid_matches =
+            env::var("WATCHDOG_PID").map_or(true, |pid| pid == std::process::id().to_string());
+        let interval = env::var("WATCHDOG_USEC")
+            .ok()
+            .filter(|_| pid_matches)
+            .and_then(|usec| usec.parse().ok())
+            .map(|usec: u64| Duration::from_micros(usec) / 2);
+        if let Some(interval) = interval {
+            info!("notifying systemd watchdog every {:?}", interval);
+        }
+        Self {
+            interval,
+            last: Instant::now(),
+        }
+    }
+
+    /// Never notifies systemd (e.g. for additional chains, served by the same process).
+    pub(crate) fn disabled() -> Self {
+        Self {
+            interval: None,
+            last: Instant::now(),
+        }
+    }
+
+    pub(crate) fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    pub(crate) fn ping(&mut self) {
+        if let Some(interval) = self.interval {
+            if self.last.elapsed() >= interval {
+                notify("WATCHDOG=1");
+                self.last = Instant::now();
+            }
+        }
+    }
+}
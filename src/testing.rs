@@ -0,0 +1,617 @@
+//! In-process test harness (enabled by the `testing` feature), allowing downstream crates to run
+//! integration tests against the indexer without a live bitcoind.
+//!
+//! ```ignore
+//! let mut daemon = MockDaemon::new(Network::Regtest);
+//! daemon.mine(script.clone(), vec![]);
+//! let mut index = TestIndex::open(dir.path(), Network::Regtest)?;
+//! index.sync(&daemon)?;
+//! assert_eq!(index.funding_heights(&script), [1]);
+//! ```
+
+use anyhow::{Context, Result};
+use bitcoin::{
+    absolute::LockTime,
+    block::{self, Header},
+    blockdata::constants::genesis_block,
+    consensus::serialize,
+    ecdsa,
+    hashes::{sha256, Hash, HashEngine},
+    key::TweakedPublicKey,
+    script::Builder,
+    secp256k1::{Message, PublicKey, Scalar, Secp256k1, SecretKey, Signing, Verification},
+    transaction, Amount, Block, BlockHash, Network, OutPoint, Script, ScriptBuf, Sequence,
+    Transaction, TxIn, TxOut, Txid, Witness,
+};
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::{
+    chain::{Chain, NewHeader},
+    daemon::BlockSource,
+    db::{DBStore, DBTuning, OptionalIndexes},
+    index::Index,
+    metrics::Metrics,
+    signals::ExitFlag,
+    types::{ScriptHash, SerBlock},
+};
+
+const BLOCK_INTERVAL_SECS: u32 = 600;
+const COINBASE_VALUE: Amount = Amount::from_sat(50 * 100_000_000);
+const INDEX_BATCH_SIZE: usize = 10;
+
+/// A predefined chain of blocks, served instead of bitcoind.
+pub struct MockDaemon {
+    blocks: Vec<Block>, // starting from the genesis block
+}
+
+impl MockDaemon {
+    pub fn new(network: Network) -> Self {
+        Self {
+            blocks: vec![genesis_block(network)],
+        }
+    }
+
+    /// Append a new block, whose coinbase pays to `coinbase_script`.
+    pub fn mine(&mut self, coinbase_script: ScriptBuf, txs: Vec<Transaction>) -> &Block {
+        let prev = self.tip().header;
+        let height = self.height() + 1;
+        let coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                // BIP-34 height (also makes sure coinbase txids are unique)
+                script_sig: Builder::new()
+                    .push_int(height as i64)
+                    .push_int(0)
+                    .into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: COINBASE_VALUE,
+                script_pubkey: coinbase_script,
+            }],
+        };
+        let mut block = Block {
+            header: Header {
+                version: block::Version::ONE,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: prev.merkle_root, // replaced below
+                time: prev.time + BLOCK_INTERVAL_SECS,
+                bits: prev.bits,
+                nonce: 0, // proof-of-work is not validated
+            },
+            txdata: std::iter::once(coinbase).chain(txs).collect(),
+        };
+        block.header.merkle_root = block.compute_merkle_root().expect("empty block");
+        self.blocks.push(block);
+        self.tip()
+    }
+
+    /// Drop the last `n` blocks (e.g. for simulating a reorg, by mining different blocks instead).
+    pub fn invalidate(&mut self, n: usize) {
+        let height = self.height().saturating_sub(n);
+        self.blocks.truncate(height + 1);
+    }
+
+    pub fn tip(&self) -> &Block {
+        self.blocks.last().expect("empty chain")
+    }
+
+    pub fn height(&self) -> usize {
+        self.blocks.len() - 1
+    }
+}
+
+impl BlockSource for MockDaemon {
+    fn get_new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>> {
+        // find the last common block (the genesis block is always shared)
+        let fork_height = (0..=chain.height().min(self.height()))
+            .rev()
+            .find(|&height| chain.get_block_hash(height) == Some(self.blocks[height].block_hash()))
+            .context("genesis block mismatch")?;
+        Ok(self.blocks[fork_height + 1..]
+            .iter()
+            .zip(fork_height + 1..)
+            .map(|(block, height)| NewHeader::from((block.header, height)))
+            .collect())
+    }
+
+    fn for_blocks<B, F>(&self, blockhashes: B, mut func: F) -> Result<()>
+    where
+        B: IntoIterator<Item = BlockHash>,
+        F: FnMut(BlockHash, SerBlock),
+    {
+        for blockhash in blockhashes {
+            let block = self
+                .blocks
+                .iter()
+                .find(|block| block.block_hash() == blockhash)
+                .with_context(|| format!("unknown block {}", blockhash))?;
+            func(blockhash, serialize(block));
+        }
+        Ok(())
+    }
+}
+
+/// An index DB, synced in-process from a `MockDaemon`.
+pub struct TestIndex {
+    index: Index,
+    exit_flag: ExitFlag,
+}
+
+impl TestIndex {
+    pub fn open(path: &Path, network: Network) -> Result<Self> {
+        let network_id = format!("{}/{}", network, network.magic());
+        let store = DBStore::open(
+            path,
+            None,
+            false,
+            None,
+            OptionalIndexes::default(),
+            &network_id,
+            DBTuning::default(),
+        )?;
+        let metrics = Metrics::unserved();
+        let index = Index::load(
+            store,
+            Chain::new(network),
+            &metrics,
+            INDEX_BATCH_SIZE,
+            None,
+            0,
+            None,
+        )?;
+        Ok(Self {
+            index,
+            exit_flag: ExitFlag::new(),
+        })
+    }
+
+    /// Index all the new blocks (possibly after a reorg).
+    pub fn sync(&mut self, daemon: &MockDaemon) -> Result<()> {
+        while !self.index.sync(daemon, &self.exit_flag)? {}
+        Ok(())
+    }
+
+    pub fn height(&self) -> usize {
+        self.index.chain().height()
+    }
+
+    pub fn tip(&self) -> BlockHash {
+        self.index.chain().tip()
+    }
+
+    /// Heights of the blocks funding `script` (may contain false positives, like the real index).
+    pub fn funding_heights(&self, script: &Script) -> Vec<usize> {
+        let scripthash = ScriptHash::new(script);
+        self.heights(self.index.filter_by_funding(scripthash))
+    }
+
+    /// Heights of the blocks spending `outpoint` (may contain false positives).
+    pub fn spending_heights(&self, outpoint: OutPoint) -> Vec<usize> {
+        self.heights(self.index.filter_by_spending(outpoint))
+    }
+
+    /// Heights of the blocks confirming `txid` (may contain false positives).
+    pub fn txid_heights(&self, txid: Txid) -> Vec<usize> {
+        self.heights(self.index.filter_by_txid(txid))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn index(&self) -> &Index {
+        &self.index
+    }
+
+    fn heights(&self, blockhashes: impl Iterator<Item = BlockHash>) -> Vec<usize> {
+        let chain = self.index.chain();
+        let mut heights: Vec<usize> = blockhashes
+            .filter_map(|blockhash| chain.get_block_height(&blockhash))
+            .collect();
+        heights.sort_unstable();
+        heights.dedup();
+        heights
+    }
+}
+
+enum Output {
+    Script(TxOut),
+    SilentPayment {
+        scan: PublicKey,
+        spend: PublicKey,
+        value: Amount,
+    },
+}
+
+/// Build (unsigned) transactions, including BIP-352 silent payments outputs.
+#[derive(Default)]
+pub struct TxBuilder {
+    inputs: Vec<(OutPoint, Option<SecretKey>)>,
+    outputs: Vec<Output>,
+}
+
+impl TxBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spend an input without a key (it doesn't contribute to silent payments outputs).
+    pub fn spend(mut self, outpoint: OutPoint) -> Self {
+        self.inputs.push((outpoint, None));
+        self
+    }
+
+    /// Spend a P2WPKH input, owned by `key` (its witness signature is a placeholder).
+    pub fn spend_p2wpkh(mut self, outpoint: OutPoint, key: SecretKey) -> Self {
+        self.inputs.push((outpoint, Some(key)));
+        self
+    }
+
+    pub fn pay(mut self, script_pubkey: ScriptBuf, value: Amount) -> Self {
+        self.outputs.push(Output::Script(TxOut {
+            value,
+            script_pubkey,
+        }));
+        self
+    }
+
+    /// Pay to a silent payments address, given its scan and spend public keys.
+    pub fn pay_silent_payment(mut self, scan: PublicKey, spend: PublicKey, value: Amount) -> Self {
+        self.outputs
+            .push(Output::SilentPayment { scan, spend, value });
+        self
+    }
+
+    pub fn build(self) -> Result<Transaction> {
+        let secp = Secp256k1::new();
+        let has_silent_payments = self
+            .outputs
+            .iter()
+            .any(|out| matches!(out, Output::SilentPayment { .. }));
+        let sender_key = if has_silent_payments {
+            Some(self.sender_key(&secp)?)
+        } else {
+            None
+        };
+        let mut counters = HashMap::<PublicKey, u32>::new(); // `k` per scan key
+        let mut output = Vec::with_capacity(self.outputs.len());
+        for out in self.outputs.iter() {
+            output.push(match out {
+                Output::Script(txout) => txout.clone(),
+                Output::SilentPayment { scan, spend, value } => {
+                    let tweak = Scalar::from(sender_key.expect("missing sender key"));
+                    let shared_secret = scan.mul_tweak(&secp, &tweak)?;
+                    let k = counters.entry(*scan).or_default();
+                    let script_pubkey = silent_payment_script(&secp, &shared_secret, spend, *k)?;
+                    *k += 1;
+                    TxOut {
+                        value: *value,
+                        script_pubkey,
+                    }
+                }
+            });
+        }
+        let input = self
+            .inputs
+            .iter()
+            .map(|(outpoint, key)| TxIn {
+                previous_output: *outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: key.map_or_else(Witness::new, |key| placeholder_witness(&secp, &key)),
+            })
+            .collect();
+        Ok(Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input,
+            output,
+        })
+    }
+
+    // input_hash·a, where `a` is the sum of the inputs' keys
+    fn sender_key<C: Signing>(&self, secp: &Secp256k1<C>) -> Result<SecretKey> {
+        let mut keys = self.inputs.iter().filter_map(|(_, key)| *key);
+        let first = keys
+            .next()
+            .context("silent payments require an input with a key")?;
+        let sum = keys.try_fold(first, |sum, key| sum.add_tweak(&Scalar::from(key)))?;
+        let smallest_outpoint = self
+            .inputs
+            .iter()
+            .map(|(outpoint, _)| serialize(outpoint))
+            .min()
+            .expect("no inputs");
+        let input_hash = input_hash(&smallest_outpoint, &PublicKey::from_secret_key(secp, &sum))?;
+        Ok(sum.mul_tweak(&input_hash)?)
+    }
+}
+
+fn placeholder_witness<C: Signing>(secp: &Secp256k1<C>, key: &SecretKey) -> Witness {
+    let msg = Message::from_digest([1; 32]); // transactions are not validated by the indexer
+    let sig = ecdsa::Signature::sighash_all(secp.sign_ecdsa(&msg, key));
+    Witness::p2wpkh(&sig, &PublicKey::from_secret_key(secp, key))
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    for data in data {
+        engine.input(data);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+fn input_hash(smallest_outpoint: &[u8], sum: &PublicKey) -> Result<Scalar> {
+    let hash = tagged_hash("BIP0352/Inputs", &[smallest_outpoint, &sum.serialize()]);
+    Ok(Scalar::from_be_bytes(hash)?)
+}
+
+// P2TR output of `spend`, tweaked by the `k`-th shared secret hash (as defined by BIP-352)
+fn silent_payment_script<C: Verification>(
+    secp: &Secp256k1<C>,
+    shared_secret: &PublicKey,
+    spend: &PublicKey,
+    k: u32,
+) -> Result<ScriptBuf> {
+    let hash = tagged_hash(
+        "BIP0352/SharedSecret",
+        &[&shared_secret.serialize(), &k.to_be_bytes()],
+    );
+    let output_key = spend.add_exp_tweak(secp, &Scalar::from_be_bytes(hash)?)?;
+    let output_key = TweakedPublicKey::dangerous_assume_tweaked(output_key.x_only_public_key().0);
+    Ok(ScriptBuf::new_p2tr_tweaked(output_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{input_hash, silent_payment_script, MockDaemon, TestIndex, TxBuilder};
+    use crate::{
+        cache::Cache, errors::ErrorCode, metrics::Metrics, status::ScriptHashStatus,
+        trace::Deadline, types::ScriptHash,
+    };
+    use bitcoin::{
+        consensus::serialize,
+        key::TweakedPublicKey,
+        secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey, XOnlyPublicKey},
+        Amount, Network, OutPoint, ScriptBuf,
+    };
+    use serde_json::json;
+    use std::{str::FromStr, time::Instant};
+
+    fn key(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    fn p2wpkh(byte: u8) -> ScriptBuf {
+        let secp = Secp256k1::new();
+        let pubkey = bitcoin::PublicKey::new(PublicKey::from_secret_key(&secp, &key(byte)));
+        ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash().unwrap())
+    }
+
+    #[test]
+    fn test_index_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let secp = Secp256k1::new();
+        let script = |byte| {
+            let pubkey = bitcoin::PublicKey::new(PublicKey::from_secret_key(&secp, &key(byte)));
+            ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash().unwrap())
+        };
+        let mut daemon = MockDaemon::new(Network::Regtest);
+        let coinbase = daemon.mine(script(1), vec![]).txdata[0].txid();
+        let tx = TxBuilder::new()
+            .spend_p2wpkh(OutPoint::new(coinbase, 0), key(1))
+            .pay(script(2), Amount::from_sat(1000))
+            .build()
+            .unwrap();
+        daemon.mine(script(3), vec![tx.clone()]);
+
+        let mut index = TestIndex::open(dir.path(), Network::Regtest).unwrap();
+        index.sync(&daemon).unwrap();
+        assert_eq!(index.height(), 2);
+        let progress = index.index.sync_progress().to_string();
+        assert_eq!(progress, "height 2 of 2, ETA 0s");
+        assert_eq!(index.funding_heights(&script(1)), [1]);
+        assert_eq!(index.funding_heights(&script(2)), [2]);
+        assert_eq!(index.spending_heights(OutPoint::new(coinbase, 0)), [2]);
+        assert_eq!(index.txid_heights(tx.txid()), [2]);
+
+        // reorg the spending transaction into a later block
+        daemon.invalidate(1);
+        daemon.mine(script(3), vec![]);
+        daemon.mine(script(3), vec![tx.clone()]);
+        index.sync(&daemon).unwrap();
+        assert_eq!(index.tip(), daemon.tip().block_hash());
+        // stale blocks' rows are not removed (they are filtered out when fetching the blocks)
+        assert_eq!(index.funding_heights(&script(2)), [2, 3]);
+        assert_eq!(index.txid_heights(tx.txid()), [2, 3]);
+    }
+
+    #[test]
+    fn test_index_tuning() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let index = TestIndex::open(dir.path(), Network::Regtest).unwrap();
+            let update = serde_json::from_value(json!({"batch_size": 3})).unwrap();
+            let tuned = index.index.tune(update).unwrap();
+            assert_eq!(json!(tuned), json!({"batch_size": 3, "lookup_limit": 0}));
+            let invalid = serde_json::from_value(json!({"batch_size": 0})).unwrap();
+            assert!(index.index.tune(invalid).is_err());
+        }
+        // tuned parameters are persisted, and the other ones are not overridden
+        let index = TestIndex::open(dir.path(), Network::Regtest).unwrap();
+        assert_eq!(
+            json!(index.index.tuning()),
+            json!({"batch_size": 3, "lookup_limit": 0})
+        );
+    }
+
+    #[test]
+    fn test_limit_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = TestIndex::open(dir.path(), Network::Regtest).unwrap();
+        let heights = || [5, 1, 3, 2].into_iter().collect();
+        assert_eq!(
+            index.index.limit_newest(heights()),
+            (vec![1, 2, 3, 5], None)
+        );
+
+        let update = serde_json::from_value(json!({"lookup_limit": 2})).unwrap();
+        index.index.tune(update).unwrap();
+        assert_eq!(index.index.limit_newest(heights()), (vec![3, 5], Some(3)));
+        assert!(index.index.limit_result(heights().into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_recent_history_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut daemon = MockDaemon::new(Network::Regtest);
+        daemon.mine(p2wpkh(1), vec![]);
+        let mut index = TestIndex::open(dir.path(), Network::Regtest).unwrap();
+        index.sync(&daemon).unwrap();
+        let cache = Cache::new(&Metrics::unserved(), None);
+        let scripthash = ScriptHash::new(&p2wpkh(1));
+        let recent_history =
+            || ScriptHashStatus::recent_history(scripthash, None, &index.index, &daemon, &cache);
+        assert!(recent_history().is_ok());
+        // an expired deadline stops the index scan early, so its partial result is not used
+        let err = Deadline::scope(Some(Instant::now()), recent_history).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::Timeout);
+    }
+
+    #[test]
+    fn test_recent_history_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut daemon = MockDaemon::new(Network::Regtest);
+        let funding = daemon.mine(p2wpkh(1), vec![]).txdata[0].txid();
+        let spending = TxBuilder::new()
+            .spend_p2wpkh(OutPoint::new(funding, 0), key(1))
+            .pay(p2wpkh(2), Amount::from_sat(1000))
+            .build()
+            .unwrap();
+        let coinbase = daemon.mine(p2wpkh(1), vec![spending.clone()]).txdata[0].txid();
+        let mut index = TestIndex::open(dir.path(), Network::Regtest).unwrap();
+        index.sync(&daemon).unwrap();
+        let update = serde_json::from_value(json!({"lookup_limit": 1})).unwrap();
+        index.index.tune(update).unwrap();
+
+        let cache = Cache::new(&Metrics::unserved(), None);
+        let scripthash = ScriptHash::new(&p2wpkh(1));
+        let page = |before_height| {
+            let page = ScriptHashStatus::recent_history(
+                scripthash,
+                before_height,
+                &index.index,
+                &daemon,
+                &cache,
+            );
+            json!(page.unwrap())
+        };
+        assert_eq!(
+            page(None),
+            json!({"history": [{"tx_hash": coinbase, "height": 2}], "truncated": true, "cursor": 2})
+        );
+        // the output funded on the older page is spent on the newer one
+        assert_eq!(
+            page(Some(2)),
+            json!({
+                "history": [{"tx_hash": funding, "height": 1}, {"tx_hash": spending.txid(), "height": 2}],
+                "truncated": false,
+                "cursor": null,
+            })
+        );
+    }
+
+    #[test]
+    fn test_silent_payment_outputs() {
+        let secp = Secp256k1::new();
+        let (scan, spend) = (key(10), key(11));
+        let scan_pubkey = PublicKey::from_secret_key(&secp, &scan);
+        let spend_pubkey = PublicKey::from_secret_key(&secp, &spend);
+        let outpoints = [
+            OutPoint::new(
+                "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16"
+                    .parse()
+                    .unwrap(),
+                1,
+            ),
+            OutPoint::new(
+                "a1075db55d416d3ca199f55b6084e2115b9345e16c5cf302fc80e9d5fbf5d48d"
+                    .parse()
+                    .unwrap(),
+                0,
+            ),
+        ];
+        let tx = TxBuilder::new()
+            .spend_p2wpkh(outpoints[0], key(1))
+            .spend_p2wpkh(outpoints[1], key(2))
+            .pay_silent_payment(scan_pubkey, spend_pubkey, Amount::from_sat(1000))
+            .pay(ScriptBuf::new(), Amount::from_sat(500))
+            .pay_silent_payment(scan_pubkey, spend_pubkey, Amount::from_sat(2000))
+            .build()
+            .unwrap();
+
+        // the receiver derives the shared secret from the inputs' public keys and its scan key
+        let pubkeys: Vec<PublicKey> = tx
+            .input
+            .iter()
+            .map(|txin| PublicKey::from_slice(txin.witness.last().unwrap()).unwrap())
+            .collect();
+        let sum = PublicKey::combine_keys(&pubkeys.iter().collect::<Vec<_>>()).unwrap();
+        let smallest_outpoint = outpoints.iter().map(serialize).min().unwrap();
+        let input_hash = input_hash(&smallest_outpoint, &sum).unwrap();
+        let tweak = Scalar::from(scan.mul_tweak(&input_hash).unwrap());
+        let shared_secret = sum.mul_tweak(&secp, &tweak).unwrap();
+        for (vout, k) in [(0, 0), (2, 1)] {
+            let expected = silent_payment_script(&secp, &shared_secret, &spend_pubkey, k).unwrap();
+            assert_eq!(tx.output[vout].script_pubkey, expected);
+        }
+        assert!(tx.output[0].script_pubkey.is_p2tr());
+        assert!(TxBuilder::new()
+            .spend(outpoints[0])
+            .pay_silent_payment(scan_pubkey, spend_pubkey, Amount::from_sat(1000))
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_silent_payment_vector() {
+        // BIP-352 sending test vector "Simple send: two inputs"
+        let secret = |hex: &str| SecretKey::from_str(hex).unwrap();
+        let pubkey = |hex: &str| PublicKey::from_str(hex).unwrap();
+        let scan_pubkey =
+            pubkey("0220bcfac5b99e04ad1a06ddfb016ee13582609d60b6291e98d01a9bc9a16c96d4");
+        let spend_pubkey =
+            pubkey("025cc9856d6f8375350e123978daac200c260cb5b5ae83106cab90484dcd8fcf36");
+        let tx = TxBuilder::new()
+            .spend_p2wpkh(
+                OutPoint::from_str(
+                    "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16:0",
+                )
+                .unwrap(),
+                secret("eadc78165ff1f8ea94ad7cfdc54990738a4c53f6e0507b42154201b8e5dff3b1"),
+            )
+            .spend_p2wpkh(
+                OutPoint::from_str(
+                    "a1075db55d416d3ca199f55b6084e2115b9345e16c5cf302fc80e9d5fbf5d48d:0",
+                )
+                .unwrap(),
+                secret("93f5ed907ad5b2bdbbdcb5d9116ebc0a4e1f92f910d5260237fa45a9408aad16"),
+            )
+            .pay_silent_payment(scan_pubkey, spend_pubkey, Amount::from_sat(1000))
+            .build()
+            .unwrap();
+        let output_key = XOnlyPublicKey::from_str(
+            "3e9fce73d4e77a4809908e3c3a2e54ee147b9312dc5044a193d1fc85de46e3c1",
+        )
+        .unwrap();
+        let expected =
+            ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(output_key));
+        assert_eq!(tx.output[0].script_pubkey, expected);
+    }
+}
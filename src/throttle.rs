@@ -0,0 +1,110 @@
+use parking_lot::Mutex;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Limits the indexing rate (in blocks per second), so that the initial sync doesn't starve
+/// bitcoind's block validation when both share a low-power device.
+/// The limit can be adjusted at runtime (via RPC or `SIGUSR2`), and is shared by all clones.
+#[derive(Clone)]
+pub(crate) struct Throttle {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    limit: Option<u32>,      // current limit (`None` means unlimited)
+    configured: Option<u32>, // the last non-empty limit, restored by `toggle()`
+    next: Instant,           // when the next block may be indexed
+}
+
+impl Throttle {
+    pub(crate) fn new(limit: Option<u32>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                limit,
+                configured: limit,
+                next: Instant::now(),
+            })),
+        }
+    }
+
+    pub(crate) fn limit(&self) -> Option<u32> {
+        self.state.lock().limit
+    }
+
+    pub(crate) fn set_limit(&self, limit: Option<u32>) {
+        let mut state = self.state.lock();
+        state.limit = limit;
+        if limit.is_some() {
+            state.configured = limit;
+        }
+        info!("index throttle set to {}", describe(limit));
+    }
+
+    /// Switch between the last configured limit and no limit.
+    pub(crate) fn toggle(&self) {
+        let limit = {
+            let state = self.state.lock();
+            match state.limit {
+                Some(_) => None,
+                None => state.configured,
+            }
+        };
+        self.set_limit(limit);
+    }
+
+    /// Wait (if needed) before indexing the next block.
+    pub(crate) fn wait(&self) {
+        let delay = {
+            let mut state = self.state.lock();
+            let limit = match state.limit {
+                Some(limit) => limit,
+                None => return,
+            };
+            let now = Instant::now();
+            let start = std::cmp::max(state.next, now);
+            state.next = start + Duration::from_secs_f64(1.0 / f64::from(limit));
+            start - now
+        };
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+fn describe(limit: Option<u32>) -> String {
+    match limit {
+        Some(limit) => format!("{} blocks/second", limit),
+        None => "unlimited".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Throttle;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(Some(100));
+        let start = Instant::now();
+        for _ in 0..6 {
+            throttle.wait();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        throttle.toggle();
+        assert_eq!(throttle.limit(), None);
+        let start = Instant::now();
+        for _ in 0..1000 {
+            throttle.wait();
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        throttle.toggle();
+        assert_eq!(throttle.limit(), Some(100));
+        throttle.set_limit(None);
+        throttle.toggle();
+        assert_eq!(throttle.limit(), Some(100));
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::Result;
+
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::errors::{CodedError, ErrorCode};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT: Cell<Option<RequestId>> = Cell::new(None);
+    static DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+}
+
+/// Identifies a client request in the logs, so it can be correlated with the daemon calls
+/// it caused. It is kept per thread, so it doesn't need to be passed through every call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RequestId(u64);
+
+impl RequestId {
+    pub(crate) fn next() -> Self {
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The request handled by the current thread (if any).
+    pub(crate) fn current() -> Option<Self> {
+        CURRENT.with(Cell::get)
+    }
+
+    /// Run `func` on behalf of `request` (restoring the previous one afterwards).
+    pub(crate) fn scope<T>(request: Option<Self>, func: impl FnOnce() -> T) -> T {
+        let previous = CURRENT.with(|current| current.replace(request));
+        let result = func();
+        CURRENT.with(|current| current.set(previous));
+        result
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "request #{}", self.0)
+    }
+}
+
+/// The time by which the current thread's request should be done. Afterwards, index scans
+/// are stopped early and the request fails (instead of finishing work nobody will read).
+/// Like `RequestId`, it is kept per thread.
+pub(crate) struct Deadline;
+
+impl Deadline {
+    /// The deadline of the request handled by the current thread (if any).
+    pub(crate) fn current() -> Option<Instant> {
+        DEADLINE.with(Cell::get)
+    }
+
+    /// Run `func` until `deadline` (restoring the previous one afterwards).
+    pub(crate) fn scope<T>(deadline: Option<Instant>, func: impl FnOnce() -> T) -> T {
+        let previous = DEADLINE.with(|current| current.replace(deadline));
+        let result = func();
+        DEADLINE.with(|current| current.set(previous));
+        result
+    }
+
+    pub(crate) fn is_expired() -> bool {
+        Self::current().map_or(false, |deadline| Instant::now() >= deadline)
+    }
+
+    /// Fail if the deadline has expired - so partial results (e.g. of the scans stopped
+    /// due to the deadline) are not used.
+    pub(crate) fn check() -> Result<()> {
+        if Self::is_expired() {
+            let message = "request timed out".to_owned();
+            return Err(CodedError::new(ErrorCode::Timeout, message));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Deadline, RequestId};
+    use crate::errors::ErrorCode;
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_scope() {
+        assert_eq!(RequestId::current(), None);
+        let (outer, inner) = (RequestId::next(), RequestId::next());
+        assert_ne!(outer, inner);
+        RequestId::scope(Some(outer), || {
+            assert_eq!(RequestId::current(), Some(outer));
+            RequestId::scope(Some(inner), || {
+                assert_eq!(RequestId::current(), Some(inner))
+            });
+            assert_eq!(RequestId::current(), Some(outer));
+            // e.g. a background thread, fetching blocks on behalf of the request
+            let request = RequestId::current();
+            std::thread::spawn(move || {
+                assert_eq!(RequestId::current(), None);
+                RequestId::scope(request, || assert_eq!(RequestId::current(), Some(outer)));
+            })
+            .join()
+            .unwrap();
+        });
+        assert_eq!(RequestId::current(), None);
+    }
+
+    #[test]
+    fn test_deadline() {
+        assert!(Deadline::check().is_ok()); // no deadline
+        let expired = Instant::now();
+        Deadline::scope(Some(expired), || {
+            assert!(Deadline::is_expired());
+            let err = Deadline::check().unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::Timeout);
+            let later = Instant::now() + Duration::from_secs(60);
+            Deadline::scope(Some(later), || assert!(Deadline::check().is_ok()));
+            assert!(Deadline::is_expired());
+        });
+        assert!(!Deadline::is_expired());
+    }
+}
@@ -1,57 +1,200 @@
 use anyhow::{Context, Result};
-use bitcoin::{BlockHash, Transaction, Txid};
+use bitcoin::{
+    hashes::{hex::DisplayHex, sha256, Hash},
+    BlockHash, OutPoint, Transaction, Txid,
+};
 use bitcoin_slices::{
     bsl::{self, FindTransaction},
     Error::VisitBreak,
-    Visit,
+    Visit, Visitor,
 };
+use rayon::prelude::*;
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::ops::{ControlFlow, RangeInclusive};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use crate::{
+    broadcasts::BroadcastRecord,
     cache::Cache,
     chain::Chain,
     config::Config,
-    daemon::Daemon,
-    db::DBStore,
-    index::Index,
+    daemon::{BlockSource, TxSource},
+    db::{DBStore, DBTuning, OptionalIndexes, Usage},
+    index::{Index, ReorgEvent, ScriptTypeStats, SyncProgress, TouchedPrefixes, Tuning},
+    labels::LabelStore,
     mempool::{FeeHistogram, Mempool},
     metrics::Metrics,
     signals::ExitFlag,
-    status::{Balance, ScriptHashStatus, UnspentEntry},
+    status::{Balance, HistoryEntry, HistoryPage, ScriptHashStatus, UnspentEntry},
+    throttle::Throttle,
+    trace::{Deadline, RequestId},
+    types::{bsl_txid, ScriptHash, ScriptPrefixRow},
+    watchtower::Watch,
+    webhook::Registrations,
 };
 
+// Maximal number of statuses to be synced together (see `Tracker::sync_statuses`)
+const STATUS_SYNC_CHUNK_SIZE: usize = 50;
+
+// Maximal number of candidate blocks to be fetched together (see `Tracker::search_script_prefix`)
+const SCRIPT_PREFIX_CHUNK_SIZE: usize = 10;
+
 /// Electrum protocol subscriptions' tracker
 pub struct Tracker {
     index: Index,
     mempool: Mempool,
     metrics: Metrics,
     ignore_mempool: bool,
+    mempool_sync_interval: Duration,
+    last_mempool_sync: Option<Instant>,
+    reindex_request: AtomicUsize, // # of last blocks to re-index on next sync
 }
 
 pub(crate) enum Error {
     NotReady,
 }
 
+/// The transaction input spending a specific outpoint
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Spender {
+    tx_hash: Txid,
+    height: usize, // 0 = mempool entry
+    vin: u32,
+}
+
+impl Spender {
+    pub(crate) fn tx_hash(&self) -> Txid {
+        self.tx_hash
+    }
+
+    pub(crate) fn is_confirmed(&self) -> bool {
+        self.height > 0
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        self.height
+    }
+}
+
+/// A confirmed output, whose script pubkey starts with the searched prefix
+#[derive(Serialize)]
+pub(crate) struct PrefixMatch {
+    height: usize,
+    tx_hash: Txid,
+    tx_pos: u32,
+    value: u64,
+    script_pubkey: String, // hex-encoded
+}
+
+/// A confirmed transaction, funding some of the rescanned scripthashes
+#[derive(Serialize)]
+pub(crate) struct FundingMatch {
+    height: usize,
+    tx_hash: Txid,
+    scripthashes: Vec<ScriptHash>, // funded by this transaction
+}
+
+/// A block whose coinbase miner tag matched the searched pattern
+#[derive(Serialize)]
+pub(crate) struct CoinbaseMatch {
+    height: usize,
+    block_hash: BlockHash,
+    coinbase_txid: Txid,
+    value: u64,        // total coinbase outputs' value (subsidy + fees), in satoshis
+    tag: String,       // hex-encoded
+    tag_ascii: String, // printable characters only
+}
+
+/// A block's feerate percentiles (see `FEERATE_PERCENTILES`)
+#[derive(Serialize)]
+pub(crate) struct BlockFeerates {
+    height: usize,
+    block_hash: BlockHash,
+    feerate_percentiles: [u64; 5], // in sat/vB
+}
+
+/// An unconfirmed ancestor of a mempool transaction
+#[derive(Serialize)]
+pub(crate) struct MempoolAncestor {
+    tx_hash: Txid,
+    fee: u64, // in satoshis
+    vsize: u64,
+}
+
 impl Tracker {
     pub fn new(config: &Config, metrics: Metrics) -> Result<Self> {
-        let store = DBStore::open(
+        let script_types = config.index_script_types.as_ref().map(|types| {
+            types
+                .iter()
+                .map(|script_type| script_type.as_str().to_owned())
+                .collect()
+        });
+        let optional = OptionalIndexes {
+            script_prefixes: config.index_script_prefixes,
+            coinbase: config.index_coinbase,
+            feerates: config.index_feerates,
+            from_height: config.index_from_height,
+        };
+        let mut store = DBStore::open(
             &config.db_path,
             config.db_log_dir.as_deref(),
             config.auto_reindex,
+            script_types,
+            optional,
+            &db_network(config),
+            DBTuning {
+                block_cache_size: config.memory_budget.db_cache(),
+                bloom_bits_per_key: config.db_bloom_bits_per_key,
+            },
         )?;
-        let chain = Chain::new(config.network);
+        store.set_ingest_sst_files(config.ingest_sst_files)?;
+        let chain = match config.custom_genesis {
+            Some(genesis) => Chain::with_genesis(config.network, genesis),
+            None => Chain::new(config.network),
+        };
+        let mut mempool = Mempool::new(
+            &metrics,
+            config.mempool_max_txs,
+            config.mempool_max_tx_vsize,
+            config.memory_budget.mempool(),
+        );
+        if !config.ignore_mempool {
+            if let Some(data) = store.get_mempool() {
+                if let Err(e) = mempool.restore(&data) {
+                    warn!("ignoring persisted mempool: {:#}", e);
+                }
+            }
+        }
+        let mut index = Index::load(
+            store,
+            chain,
+            &metrics,
+            config.index_batch_size,
+            config.index_lookup_limit,
+            config.reindex_last_blocks,
+            config.index_script_types.clone(),
+        )
+        .context("failed to open index")?;
+        index.set_flush_eagerly(config.regtest_testing);
+        index.set_manifest_path(config.index_manifest_path.clone());
+        index.set_min_free_space(config.db_min_free_space);
+        index.set_optional_indexes(optional);
+        index.set_throttle(Throttle::new(config.index_throttle));
+        index.set_profile_sync(config.profile_sync);
+        index.set_batch_bytes(config.index_batch_bytes);
+        index.set_scan_cache_size(config.scan_cache_bytes);
         Ok(Self {
-            index: Index::load(
-                store,
-                chain,
-                &metrics,
-                config.index_batch_size,
-                config.index_lookup_limit,
-                config.reindex_last_blocks,
-            )
-            .context("failed to open index")?,
-            mempool: Mempool::new(&metrics),
+            index,
+            mempool,
             metrics,
             ignore_mempool: config.ignore_mempool,
+            mempool_sync_interval: config.mempool_sync_interval,
+            last_mempool_sync: None,
+            reindex_request: AtomicUsize::new(0),
         })
     }
 
@@ -67,19 +210,164 @@ impl Tracker {
         &self.metrics
     }
 
+    pub(crate) fn throttle(&self) -> &Throttle {
+        self.index.throttle()
+    }
+
     pub(crate) fn get_unspent(&self, status: &ScriptHashStatus) -> Vec<UnspentEntry> {
         status.get_unspent(self.index.chain())
     }
 
-    pub(crate) fn sync(&mut self, daemon: &Daemon, exit_flag: &ExitFlag) -> Result<bool> {
+    /// Request re-indexing the last `n` blocks (applied on the next sync).
+    pub(crate) fn request_reindex(&self, n: usize) {
+        self.reindex_request.fetch_max(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sync(
+        &mut self,
+        daemon: &(impl BlockSource + TxSource),
+        exit_flag: &ExitFlag,
+    ) -> Result<bool> {
+        let reindex_last_blocks = self.reindex_request.swap(0, Ordering::Relaxed);
+        if reindex_last_blocks > 0 {
+            self.index.reindex_last_blocks(reindex_last_blocks);
+        }
+        let tip = self.index.chain().tip();
         let done = self.index.sync(daemon, exit_flag)?;
         if done && !self.ignore_mempool {
-            self.mempool.sync(daemon, exit_flag);
+            // new blocks confirm (and remove) mempool transactions, so don't wait for the interval
+            let new_tip = self.index.chain().tip() != tip;
+            let due = self
+                .last_mempool_sync
+                .map_or(true, |last| last.elapsed() >= self.mempool_sync_interval);
+            if new_tip || due {
+                self.mempool.sync(daemon, exit_flag);
+                self.last_mempool_sync = Some(Instant::now());
+            }
             // TODO: double check tip - and retry on diff
         }
         Ok(done)
     }
 
+    /// Persist the mempool, to be restored (and reconciled) after restart.
+    pub(crate) fn persist_mempool(&self) {
+        if self.ignore_mempool {
+            return;
+        }
+        let data = self.mempool.persist();
+        info!("persisting mempool ({} bytes)", data.len());
+        self.index.store().set_mempool(&data);
+    }
+
+    pub(crate) fn load_bans(&self) -> HashSet<IpAddr> {
+        match self.index.store().get_bans() {
+            Some(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("ignoring persisted bans: {}", e);
+                HashSet::new()
+            }),
+            None => HashSet::new(),
+        }
+    }
+
+    pub(crate) fn persist_bans(&self, bans: &HashSet<IpAddr>) {
+        let data = serde_json::to_vec(bans).expect("failed to serialize bans");
+        self.index.store().set_bans(&data);
+    }
+
+    pub(crate) fn load_webhooks(&self) -> Registrations {
+        match self.index.store().get_webhooks() {
+            Some(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("ignoring persisted webhooks: {}", e);
+                Registrations::new()
+            }),
+            None => Registrations::new(),
+        }
+    }
+
+    pub(crate) fn persist_webhooks(&self, registrations: &Registrations) {
+        let data = serde_json::to_vec(registrations).expect("failed to serialize webhooks");
+        self.index.store().set_webhooks(&data);
+    }
+
+    pub(crate) fn load_watches(&self) -> Vec<Watch> {
+        match self.index.store().get_watches() {
+            Some(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("ignoring persisted watches: {}", e);
+                vec![]
+            }),
+            None => vec![],
+        }
+    }
+
+    pub(crate) fn persist_watches(&self, watches: &[Watch]) {
+        let data = serde_json::to_vec(watches).expect("failed to serialize watches");
+        self.index.store().set_watches(&data);
+    }
+
+    /// The logged broadcasts (oldest first).
+    pub(crate) fn load_broadcasts(&self) -> Vec<BroadcastRecord> {
+        self.index
+            .store()
+            .read_broadcasts()
+            .into_iter()
+            .filter_map(|(seq, value)| match serde_json::from_slice(&value) {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    warn!("ignoring logged broadcast #{}: {}", seq, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn last_broadcast_seq(&self) -> Option<u64> {
+        self.index.store().last_broadcast_seq()
+    }
+
+    pub(crate) fn log_broadcast(&self, seq: u64, first_kept: u64, record: &BroadcastRecord) {
+        let value = serde_json::to_vec(record).expect("failed to serialize broadcast");
+        self.index.store().add_broadcast(seq, &value, first_kept);
+    }
+
+    /// Scripthashes queried before the last shutdown (newest first).
+    pub(crate) fn load_warmup_hints(&self) -> Vec<ScriptHash> {
+        match self.index.store().get_warmup() {
+            Some(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("ignoring persisted warm-up hints: {}", e);
+                vec![]
+            }),
+            None => vec![],
+        }
+    }
+
+    pub(crate) fn persist_headers(&self) {
+        self.index.persist_headers();
+    }
+
+    pub(crate) fn persist_warmup_hints(&self, scripthashes: &[ScriptHash]) {
+        let data = serde_json::to_vec(scripthashes).expect("failed to serialize warm-up hints");
+        info!("persisting {} warm-up hints", scripthashes.len());
+        self.index.store().set_warmup(&data);
+    }
+
+    pub(crate) fn load_labels(&self, identity: &sha256::Hash) -> LabelStore {
+        match self.index.store().get_labels(identity.as_byte_array()) {
+            Some(data) => serde_json::from_slice(&data).expect("invalid persisted labels"),
+            None => LabelStore::default(),
+        }
+    }
+
+    pub(crate) fn persist_labels(&self, identity: &sha256::Hash, labels: &LabelStore) {
+        let data = if labels.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_vec(labels).expect("failed to serialize labels"))
+        };
+        self.index
+            .store()
+            .set_labels(identity.as_byte_array(), data.as_deref());
+    }
+
     pub(crate) fn status(&self) -> Result<(), Error> {
         if self.index.is_ready() {
             return Ok(());
@@ -87,10 +375,18 @@ impl Tracker {
         Err(Error::NotReady)
     }
 
+    pub(crate) fn sync_progress(&self) -> SyncProgress {
+        self.index.sync_progress()
+    }
+
+    pub(crate) fn set_sync_target(&mut self, target_height: usize) {
+        self.index.set_target_height(target_height)
+    }
+
     pub(crate) fn update_scripthash_status(
         &self,
         status: &mut ScriptHashStatus,
-        daemon: &Daemon,
+        daemon: &impl BlockSource,
         cache: &Cache,
     ) -> Result<bool> {
         let prev_statushash = status.statushash();
@@ -98,18 +394,139 @@ impl Tracker {
         Ok(prev_statushash != status.statushash())
     }
 
+    pub(crate) fn recent_history(
+        &self,
+        scripthash: ScriptHash,
+        before_height: Option<usize>,
+        daemon: &impl BlockSource,
+        cache: &Cache,
+    ) -> Result<HistoryPage> {
+        if let Some(before_height) = before_height {
+            self.index.check_indexed(before_height.saturating_sub(1))?;
+        }
+        ScriptHashStatus::recent_history(scripthash, before_height, &self.index, daemon, cache)
+    }
+
+    pub(crate) fn history_range(
+        &self,
+        scripthash: ScriptHash,
+        heights: RangeInclusive<usize>,
+        daemon: &impl BlockSource,
+        cache: &Cache,
+    ) -> Result<Vec<HistoryEntry>> {
+        self.index.check_indexed(*heights.start())?;
+        ScriptHashStatus::history_range(scripthash, heights, &self.index, daemon, cache)
+    }
+
+    /// Sync multiple statuses, sharing block fetches between statuses of the same chunk
+    /// (while chunks are synced in parallel).
+    pub(crate) fn sync_statuses(
+        &self,
+        statuses: &mut [ScriptHashStatus],
+        daemon: &impl BlockSource,
+        cache: &Cache,
+    ) -> Result<()> {
+        // the chunks are synced on behalf of the current request
+        let request = RequestId::current();
+        let deadline = Deadline::current();
+        statuses
+            .par_chunks_mut(STATUS_SYNC_CHUNK_SIZE)
+            .try_for_each(|chunk| {
+                RequestId::scope(request, || {
+                    Deadline::scope(deadline, || {
+                        ScriptHashStatus::sync_many(
+                            chunk,
+                            &self.index,
+                            &self.mempool,
+                            daemon,
+                            cache,
+                        )
+                    })
+                })
+            })
+    }
+
+    pub(crate) fn script_type_stats(&self) -> &ScriptTypeStats {
+        self.index.script_type_stats()
+    }
+
+    pub(crate) fn preflight_disk_space(&self, target_height: usize) {
+        self.index.preflight_disk_space(target_height)
+    }
+
+    pub(crate) fn db_usage(&self) -> BTreeMap<&'static str, Usage> {
+        self.index.db_usage()
+    }
+
+    /// Returns `false` if a compaction is already running.
+    pub(crate) fn compact_db(&self) -> Result<bool> {
+        ensure!(
+            !self.index.store().is_bulk_import(),
+            "initial sync is not over yet"
+        );
+        Ok(self.index.start_compaction())
+    }
+
+    pub(crate) fn tune_index(&self, update: Tuning) -> Result<Tuning> {
+        self.index.tune(update)
+    }
+
+    pub(crate) fn reorgs(&self) -> &[ReorgEvent] {
+        self.index.reorgs()
+    }
+
+    pub(crate) fn rows_scanned(&self) -> u64 {
+        self.index.rows_scanned()
+    }
+
+    /// See `Index::touched_since`.
+    pub(crate) fn touched_since(&self, blockhash: &BlockHash) -> Option<Vec<&TouchedPrefixes>> {
+        self.index.touched_since(blockhash)
+    }
+
+    pub(crate) fn mempool_generation(&self) -> u64 {
+        self.mempool.generation()
+    }
+
+    pub(crate) fn mempool_transaction(&self, txid: &Txid) -> Option<&Transaction> {
+        self.mempool.get(txid).map(|entry| &entry.tx)
+    }
+
+    pub(crate) fn mempool_ancestors(&self, txid: &Txid) -> Option<Vec<MempoolAncestor>> {
+        let ancestors = self.mempool.ancestors(txid)?;
+        Some(
+            ancestors
+                .into_iter()
+                .map(|entry| MempoolAncestor {
+                    tx_hash: entry.txid,
+                    fee: entry.fee.to_sat(),
+                    vsize: entry.vsize,
+                })
+                .collect(),
+        )
+    }
+
     pub(crate) fn get_balance(&self, status: &ScriptHashStatus) -> Balance {
         status.get_balance(self.chain())
     }
 
     pub(crate) fn lookup_transaction(
         &self,
-        daemon: &Daemon,
+        daemon: &impl BlockSource,
         txid: Txid,
     ) -> Result<Option<(BlockHash, Transaction)>> {
         // Note: there are two blocks with coinbase transactions having same txid (see BIP-30)
-        let blockhashes = self.index.filter_by_txid(txid);
+        self.find_transaction_in(daemon, txid, self.index.filter_by_txid(txid))
+    }
+
+    fn find_transaction_in(
+        &self,
+        daemon: &impl BlockSource,
+        txid: Txid,
+        blockhashes: impl IntoIterator<Item = BlockHash>,
+    ) -> Result<Option<(BlockHash, Transaction)>> {
         let mut result = None;
+        let (mut candidates, mut false_positives) = (0, 0);
         daemon.for_blocks(blockhashes, |blockhash, block| {
             if result.is_some() {
                 return; // keep first matching transaction
@@ -119,7 +536,404 @@ impl Tracker {
                 Ok(_) | Err(VisitBreak) => visitor.tx_found().map(|tx| (blockhash, tx)),
                 Err(e) => panic!("core returned invalid block: {:?}", e),
             };
+            candidates += 1;
+            false_positives += usize::from(result.is_none());
         })?;
+        self.index
+            .observe_lookup("txid", candidates, false_positives);
         Ok(result)
     }
+
+    /// Find (up to `limit`) confirmed outputs whose script pubkey starts with `prefix`,
+    /// ordered by their position in the chain.
+    pub(crate) fn search_script_prefix(
+        &self,
+        daemon: &impl BlockSource,
+        prefix: &[u8],
+        limit: usize,
+    ) -> Result<Vec<PrefixMatch>> {
+        ensure!(
+            self.index.optional_indexes().script_prefixes,
+            "script prefix index is disabled"
+        );
+        ensure!(!prefix.is_empty(), "empty script prefix");
+        let heights: Vec<usize> = self
+            .index
+            .limit_result(self.index.filter_by_script_prefix(prefix))?
+            .into_iter()
+            .collect::<BTreeSet<usize>>()
+            .into_iter()
+            .collect();
+        let mut result = vec![];
+        let (mut candidates, mut false_positives) = (0, 0);
+        // fetch the candidates in chain order, stopping as soon as `limit` matches are found
+        for chunk in heights.chunks(SCRIPT_PREFIX_CHUNK_SIZE) {
+            if result.len() >= limit {
+                break;
+            }
+            let blockhashes = chunk
+                .iter()
+                .filter_map(|height| self.chain().get_block_hash(*height));
+            daemon.for_blocks(blockhashes, |blockhash, block| {
+                let height = self
+                    .chain()
+                    .get_block_height(&blockhash)
+                    .expect("missing block");
+                let mut visitor = FindScriptPrefix::new(prefix);
+                bsl::Block::visit(&block, &mut visitor).expect("core returned invalid block");
+                candidates += 1;
+                false_positives += usize::from(visitor.found.is_empty());
+                result.extend(visitor.found.into_iter().enumerate().map(
+                    |(i, (txid, vout, value, script))| {
+                        let m = PrefixMatch {
+                            height,
+                            tx_hash: txid,
+                            tx_pos: vout,
+                            value,
+                            script_pubkey: script.to_lower_hex_string(),
+                        };
+                        ((height, i), m)
+                    },
+                ));
+            })?;
+        }
+        // longer prefixes (up to the indexed length) should have less false positives
+        let scanned_len = ScriptPrefixRow::scan_prefix(prefix).len();
+        let lookup = format!("script_prefix_{}", scanned_len);
+        self.index
+            .observe_lookup(&lookup, candidates, false_positives);
+        // blocks may be fetched out of order (within a chunk)
+        result.sort_unstable_by_key(|(key, _)| *key);
+        Ok(result.into_iter().take(limit).map(|(_, m)| m).collect())
+    }
+
+    /// Find the transactions funding `scripthashes` in [start_height, end_height], sorted by
+    /// their confirmation height and position within the block.
+    /// The candidate blocks are fetched, in order to drop the funding index false positives.
+    pub(crate) fn find_funding(
+        &self,
+        daemon: &impl BlockSource,
+        scripthashes: &[ScriptHash],
+        start_height: usize,
+        end_height: usize,
+    ) -> Result<Vec<FundingMatch>> {
+        ensure!(
+            start_height <= end_height,
+            "invalid height range: {}-{}",
+            start_height,
+            end_height
+        );
+        self.index.check_indexed(start_height)?;
+        let mut heights = BTreeSet::new();
+        for scripthash in scripthashes {
+            let blockhashes = self
+                .index
+                .limit_result(self.index.filter_by_funding(*scripthash))?;
+            heights.extend(
+                blockhashes
+                    .iter()
+                    .filter_map(|blockhash| self.chain().get_block_height(blockhash))
+                    .filter(|height| (start_height..=end_height).contains(height)),
+            );
+        }
+        let blockhashes = heights
+            .into_iter()
+            .filter_map(|height| self.chain().get_block_hash(height));
+        let scripthashes: HashSet<ScriptHash> = scripthashes.iter().copied().collect();
+        let mut result = vec![];
+        daemon.for_blocks(blockhashes, |blockhash, block| {
+            let height = self
+                .chain()
+                .get_block_height(&blockhash)
+                .expect("missing block");
+            let mut visitor = FindFunding::new(&scripthashes);
+            bsl::Block::visit(&block, &mut visitor).expect("core returned invalid block");
+            result.extend(
+                visitor
+                    .found
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, (txid, funded))| {
+                        let m = FundingMatch {
+                            height,
+                            tx_hash: txid,
+                            scripthashes: funded,
+                        };
+                        ((height, i), m)
+                    }),
+            );
+        })?;
+        // blocks may be fetched out of order
+        result.sort_unstable_by_key(|(key, _)| *key);
+        Ok(result.into_iter().map(|(_, m)| m).collect())
+    }
+
+    /// Find the blocks in [start_height, start_height + count) whose miner tag contains
+    /// `pattern` (case-insensitive, an empty pattern matches all blocks).
+    pub(crate) fn search_coinbase(
+        &self,
+        pattern: &str,
+        start_height: usize,
+        count: usize,
+    ) -> Result<Vec<CoinbaseMatch>> {
+        ensure!(
+            self.index.optional_indexes().coinbase,
+            "coinbase index is disabled"
+        );
+        let pattern = pattern.to_ascii_lowercase();
+        let end_height = start_height.saturating_add(count);
+        Ok(self
+            .index
+            .coinbase_rows(start_height)
+            .take_while(|row| row.height() < end_height)
+            .filter_map(|row| {
+                let tag_ascii: String = row
+                    .tag
+                    .iter()
+                    .filter(|b| b.is_ascii_graphic() || **b == b' ')
+                    .map(|b| char::from(*b))
+                    .collect();
+                if !tag_ascii.to_ascii_lowercase().contains(&pattern) {
+                    return None;
+                }
+                Some(CoinbaseMatch {
+                    height: row.height(),
+                    block_hash: self.chain().get_block_hash(row.height())?,
+                    coinbase_txid: row.txid,
+                    value: row.value,
+                    tag: row.tag.to_lower_hex_string(),
+                    tag_ascii,
+                })
+            })
+            .collect())
+    }
+
+    /// Feerate percentiles of the blocks in [start_height, start_height + count).
+    pub(crate) fn block_feerates(
+        &self,
+        start_height: usize,
+        count: usize,
+    ) -> Result<Vec<BlockFeerates>> {
+        ensure!(
+            self.index.optional_indexes().feerates,
+            "feerates index is disabled"
+        );
+        let end_height = start_height.saturating_add(count);
+        Ok(self
+            .index
+            .feerates_rows(start_height)
+            .take_while(|row| row.height() < end_height)
+            .filter_map(|row| {
+                Some(BlockFeerates {
+                    height: row.height(),
+                    block_hash: self.chain().get_block_hash(row.height())?,
+                    feerate_percentiles: row.percentiles,
+                })
+            })
+            .collect())
+    }
+
+    /// Find the transaction spending `outpoint` (confirmed spenders are preferred).
+    /// Candidate blocks are fetched and scanned, in order to skip false-positive index rows.
+    pub(crate) fn lookup_spender(
+        &self,
+        daemon: &impl BlockSource,
+        outpoint: OutPoint,
+    ) -> Result<Option<Spender>> {
+        let blockhashes: Vec<BlockHash> = self.index.filter_by_spending(outpoint).collect();
+        // there is usually a single candidate, so they are fetched one at a time
+        // (to stop as soon as the spender is found)
+        for blockhash in blockhashes {
+            let mut result = None;
+            daemon.for_blocks([blockhash], |blockhash, block| {
+                let mut visitor = FindSpender::new(outpoint);
+                result = match bsl::Block::visit(&block, &mut visitor) {
+                    Ok(_) | Err(VisitBreak) => visitor.spender.map(|(txid, vin)| Spender {
+                        tx_hash: txid,
+                        height: self
+                            .chain()
+                            .get_block_height(&blockhash)
+                            .expect("missing spending block"),
+                        vin,
+                    }),
+                    Err(e) => panic!("core returned invalid block: {:?}", e),
+                };
+            })?;
+            if result.is_some() {
+                return Ok(result);
+            }
+        }
+        if let Some(spender) = self.mempool_spender(outpoint) {
+            return Ok(Some(spender));
+        }
+        let from_height = self.index.optional_indexes().from_height;
+        if from_height > 0 {
+            // an older output's spender may be missing from the index, so only the funding
+            // transaction's candidate blocks below `from_height` need to be fetched
+            let older: Vec<BlockHash> = self
+                .index
+                .filter_by_txid(outpoint.txid)
+                .filter(|blockhash| {
+                    self.chain()
+                        .get_block_height(blockhash)
+                        .map_or(false, |height| height < from_height)
+                })
+                .collect();
+            if let Some((blockhash, _tx)) =
+                self.find_transaction_in(daemon, outpoint.txid, older)?
+            {
+                let height = self
+                    .chain()
+                    .get_block_height(&blockhash)
+                    .expect("missing funding block");
+                self.index.check_indexed(height)?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the mempool transaction spending `outpoint` (without looking up the index).
+    pub(crate) fn mempool_spender(&self, outpoint: OutPoint) -> Option<Spender> {
+        self.mempool
+            .filter_by_spending(&outpoint)
+            .into_iter()
+            .find_map(|entry| {
+                let vin = entry
+                    .tx
+                    .input
+                    .iter()
+                    .position(|txi| txi.previous_output == outpoint)?;
+                Some(Spender {
+                    tx_hash: entry.txid,
+                    height: 0,
+                    vin: u32::try_from(vin).expect("too many inputs"),
+                })
+            })
+    }
+}
+
+/// Identifies the indexed chain, so a DB is never used for a different one.
+/// Custom signets share the same genesis block (so their magic is recorded), and custom
+/// chains may share the same magic (so their genesis block hash is recorded).
+fn db_network(config: &Config) -> String {
+    let network = format!("{}/{}", config.network, config.signet_magic);
+    match &config.custom_genesis {
+        Some(genesis) => format!("{}/{}", network, genesis.block_hash()),
+        None => network,
+    }
+}
+
+struct FindScriptPrefix<'a> {
+    prefix: &'a [u8],
+    outputs: Vec<(u32, u64, Vec<u8>)>, // matching outputs of the current transaction
+    found: Vec<(Txid, u32, u64, Vec<u8>)>,
+}
+
+impl<'a> FindScriptPrefix<'a> {
+    fn new(prefix: &'a [u8]) -> Self {
+        Self {
+            prefix,
+            outputs: vec![],
+            found: vec![],
+        }
+    }
+}
+
+impl<'a> Visitor for FindScriptPrefix<'a> {
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        if !self.outputs.is_empty() {
+            let txid = bsl_txid(tx);
+            self.found.extend(
+                self.outputs
+                    .drain(..)
+                    .map(|(vout, value, script)| (txid, vout, value, script)),
+            );
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn visit_tx_out(&mut self, vout: usize, tx_out: &bsl::TxOut) -> ControlFlow<()> {
+        let script = tx_out.script_pubkey();
+        // unspendable outputs are not indexed
+        if script.starts_with(self.prefix)
+            && !bitcoin::Script::from_bytes(script).is_provably_unspendable()
+        {
+            let vout = u32::try_from(vout).expect("too many outputs");
+            self.outputs.push((vout, tx_out.value(), script.to_vec()));
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+struct FindFunding<'a> {
+    scripthashes: &'a HashSet<ScriptHash>,
+    funded: Vec<ScriptHash>, // by the current transaction
+    found: Vec<(Txid, Vec<ScriptHash>)>,
+}
+
+impl<'a> FindFunding<'a> {
+    fn new(scripthashes: &'a HashSet<ScriptHash>) -> Self {
+        Self {
+            scripthashes,
+            funded: vec![],
+            found: vec![],
+        }
+    }
+}
+
+impl<'a> Visitor for FindFunding<'a> {
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        if !self.funded.is_empty() {
+            let mut funded = std::mem::take(&mut self.funded);
+            funded.sort_unstable();
+            funded.dedup();
+            self.found.push((bsl_txid(tx), funded));
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn visit_tx_out(&mut self, _vout: usize, tx_out: &bsl::TxOut) -> ControlFlow<()> {
+        let script = bitcoin::Script::from_bytes(tx_out.script_pubkey());
+        let scripthash = ScriptHash::new(script);
+        if self.scripthashes.contains(&scripthash) {
+            self.funded.push(scripthash);
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+struct FindSpender {
+    outpoint: OutPoint,
+    vin: Option<u32>,
+    spender: Option<(Txid, u32)>,
+}
+
+impl FindSpender {
+    fn new(outpoint: OutPoint) -> Self {
+        Self {
+            outpoint,
+            vin: None,
+            spender: None,
+        }
+    }
+}
+
+impl Visitor for FindSpender {
+    fn visit_transaction(&mut self, tx: &bsl::Transaction) -> ControlFlow<()> {
+        match self.vin {
+            Some(vin) => {
+                self.spender = Some((bsl_txid(tx), vin));
+                ControlFlow::Break(())
+            }
+            None => ControlFlow::Continue(()),
+        }
+    }
+
+    fn visit_tx_in(&mut self, vin: usize, tx_in: &bsl::TxIn) -> ControlFlow<()> {
+        let prevout: OutPoint = tx_in.prevout().into();
+        if prevout == self.outpoint {
+            self.vin = Some(u32::try_from(vin).expect("too many inputs"));
+        }
+        ControlFlow::Continue(())
+    }
 }
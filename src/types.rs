@@ -6,7 +6,7 @@ use bitcoin::blockdata::block::Header as BlockHeader;
 use bitcoin::{
     consensus::encode::{deserialize, Decodable, Encodable},
     hashes::{hash_newtype, sha256, Hash},
-    OutPoint, Script, Txid,
+    BlockHash, OutPoint, Script, Txid,
 };
 use bitcoin_slices::bsl;
 
@@ -108,6 +108,30 @@ impl ScriptHashRow {
     }
 }
 
+/// Indexes outputs by the first bytes of their script pubkey (zero-padded if shorter),
+/// so they can be searched by witness version, script template or vanity prefix.
+pub(crate) struct ScriptPrefixRow;
+
+impl ScriptPrefixRow {
+    /// Longer prefixes are truncated, so matching rows must be verified by the caller.
+    pub(crate) fn scan_prefix(prefix: &[u8]) -> Box<[u8]> {
+        prefix[..prefix.len().min(HASH_PREFIX_LEN)]
+            .to_vec()
+            .into_boxed_slice()
+    }
+
+    pub(crate) fn row(script: &Script, height: usize) -> HashPrefixRow {
+        let bytes = script.as_bytes();
+        let mut prefix = HashPrefix::default();
+        let len = bytes.len().min(HASH_PREFIX_LEN);
+        prefix[..len].copy_from_slice(&bytes[..len]);
+        HashPrefixRow {
+            prefix,
+            height: Height::try_from(height).expect("invalid height"),
+        }
+    }
+}
+
 // ***************************************************************************
 
 hash_newtype! {
@@ -192,14 +216,392 @@ impl HeaderRow {
     }
 }
 
+// ***************************************************************************
+
+fn block_prefix(blockhash: &BlockHash) -> HashPrefix {
+    let mut prefix = HashPrefix::default();
+    prefix.copy_from_slice(&blockhash[..HASH_PREFIX_LEN]);
+    prefix
+}
+
+// per-block rows start with a big-endian height, so they are ordered by height
+fn height_scan_start(height: usize) -> Box<[u8]> {
+    let height = Height::try_from(height).expect("invalid height");
+    Box::new(height.to_be_bytes())
+}
+
+const MINER_TAG_LEN: usize = 64;
+const COINBASE_ROW_MIN_SIZE: usize = HEIGHT_SIZE + HASH_PREFIX_LEN + 32 + 8;
+
+/// Per-block coinbase summary (stored only if the coinbase index is enabled).
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct CoinbaseRow {
+    height: Height,           // big-endian in DB rows, so they are ordered by height
+    block_prefix: HashPrefix, // used for skipping stale rows (left by reorgs)
+    pub(crate) txid: Txid,
+    pub(crate) value: u64, // total coinbase outputs' value (subsidy + fees), in satoshis
+    pub(crate) tag: Vec<u8>, // first bytes of the coinbase scriptSig (containing the miner tag)
+}
+
+impl CoinbaseRow {
+    pub(crate) fn new(
+        height: usize,
+        blockhash: BlockHash,
+        txid: Txid,
+        value: u64,
+        script_sig: &[u8],
+    ) -> Self {
+        Self {
+            height: Height::try_from(height).expect("invalid height"),
+            block_prefix: block_prefix(&blockhash),
+            txid,
+            value,
+            tag: script_sig[..script_sig.len().min(MINER_TAG_LEN)].to_vec(),
+        }
+    }
+
+    pub(crate) fn scan_start(height: usize) -> Box<[u8]> {
+        height_scan_start(height)
+    }
+
+    pub(crate) fn to_db_row(&self) -> db::Row {
+        let mut vec = Vec::with_capacity(COINBASE_ROW_MIN_SIZE + self.tag.len());
+        vec.extend_from_slice(&self.height.to_be_bytes());
+        vec.extend_from_slice(&self.block_prefix);
+        vec.extend_from_slice(self.txid.as_byte_array());
+        vec.extend_from_slice(&self.value.to_le_bytes());
+        vec.extend_from_slice(&self.tag);
+        vec.into_boxed_slice()
+    }
+
+    pub(crate) fn from_db_row(row: &[u8]) -> Self {
+        assert!(row.len() >= COINBASE_ROW_MIN_SIZE, "bad CoinbaseRow");
+        let (height, row) = row.split_at(HEIGHT_SIZE);
+        let (block_prefix, row) = row.split_at(HASH_PREFIX_LEN);
+        let (txid, row) = row.split_at(32);
+        let (value, tag) = row.split_at(8);
+        Self {
+            height: Height::from_be_bytes(height.try_into().unwrap()),
+            block_prefix: block_prefix.try_into().unwrap(),
+            txid: Txid::from_slice(txid).unwrap(),
+            value: u64::from_le_bytes(value.try_into().unwrap()),
+            tag: tag.to_vec(),
+        }
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        usize::try_from(self.height).expect("invalid height")
+    }
+
+    pub(crate) fn is_in_block(&self, blockhash: &BlockHash) -> bool {
+        block_prefix(blockhash) == self.block_prefix
+    }
+}
+
+/// The percentiles of `FeeratesRow::percentiles`.
+pub(crate) const FEERATE_PERCENTILES: [u8; 5] = [10, 25, 50, 75, 90];
+const FEERATES_ROW_SIZE: usize = HEIGHT_SIZE + HASH_PREFIX_LEN + 8 * FEERATE_PERCENTILES.len();
+
+/// Per-block feerate percentiles (stored only if the feerates index is enabled).
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct FeeratesRow {
+    height: Height,
+    block_prefix: HashPrefix, // used for skipping stale rows (left by reorgs)
+    pub(crate) percentiles: [u64; 5], // weighted by transactions' vsize, in sat/vB
+}
+
+impl FeeratesRow {
+    pub(crate) fn new(height: usize, blockhash: BlockHash, percentiles: [u64; 5]) -> Self {
+        Self {
+            height: Height::try_from(height).expect("invalid height"),
+            block_prefix: block_prefix(&blockhash),
+            percentiles,
+        }
+    }
+
+    pub(crate) fn scan_start(height: usize) -> Box<[u8]> {
+        height_scan_start(height)
+    }
+
+    pub(crate) fn to_db_row(&self) -> db::Row {
+        let mut vec = Vec::with_capacity(FEERATES_ROW_SIZE);
+        vec.extend_from_slice(&self.height.to_be_bytes());
+        vec.extend_from_slice(&self.block_prefix);
+        for value in self.percentiles {
+            vec.extend_from_slice(&value.to_le_bytes());
+        }
+        vec.into_boxed_slice()
+    }
+
+    pub(crate) fn from_db_row(row: &[u8]) -> Self {
+        assert_eq!(row.len(), FEERATES_ROW_SIZE, "bad FeeratesRow");
+        let (height, row) = row.split_at(HEIGHT_SIZE);
+        let (prefix, row) = row.split_at(HASH_PREFIX_LEN);
+        let mut percentiles = [0u64; 5];
+        for (value, bytes) in percentiles.iter_mut().zip(row.chunks_exact(8)) {
+            *value = u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+        Self {
+            height: Height::from_be_bytes(height.try_into().unwrap()),
+            block_prefix: prefix.try_into().unwrap(),
+            percentiles,
+        }
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        usize::try_from(self.height).expect("invalid height")
+    }
+
+    pub(crate) fn is_in_block(&self, blockhash: &BlockHash) -> bool {
+        block_prefix(blockhash) == self.block_prefix
+    }
+}
+
+// ***************************************************************************
+
+const TOUCHED_KEY_SIZE: usize = HEIGHT_SIZE + HASH_PREFIX_LEN;
+
+/// The funded scripthashes' and spent outpoints' prefixes of a block (stored only for the
+/// recent blocks, after the initial sync). Unlike the other rows, the prefixes are stored
+/// in the value (since there may be thousands of them).
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct TouchedRow {
+    height: Height,           // big-endian in DB keys, so they are ordered by height
+    block_prefix: HashPrefix, // used for skipping stale rows (left by reorgs)
+    funding: Vec<HashPrefix>,
+    spending: Vec<HashPrefix>,
+}
+
+impl TouchedRow {
+    /// Collect the prefixes of a block's funding and spending rows.
+    pub(crate) fn new(
+        height: usize,
+        blockhash: BlockHash,
+        funding_rows: &[db::Row],
+        spending_rows: &[db::Row],
+    ) -> Self {
+        let prefixes = |rows: &[db::Row]| {
+            rows.iter()
+                .map(|row| HashPrefixRow::from_db_row(row).prefix)
+                .collect()
+        };
+        Self {
+            height: Height::try_from(height).expect("invalid height"),
+            block_prefix: block_prefix(&blockhash),
+            funding: prefixes(funding_rows),
+            spending: prefixes(spending_rows),
+        }
+    }
+
+    pub(crate) fn scan_start(height: usize) -> Box<[u8]> {
+        height_scan_start(height)
+    }
+
+    pub(crate) fn to_db_entry(&self) -> (db::Row, db::Row) {
+        let mut key = Vec::with_capacity(TOUCHED_KEY_SIZE);
+        key.extend_from_slice(&self.height.to_be_bytes());
+        key.extend_from_slice(&self.block_prefix);
+        let count = u32::try_from(self.funding.len()).expect("too many funding prefixes");
+        let mut value =
+            Vec::with_capacity(4 + HASH_PREFIX_LEN * (self.funding.len() + self.spending.len()));
+        value.extend_from_slice(&count.to_le_bytes());
+        for prefix in self.funding.iter().chain(&self.spending) {
+            value.extend_from_slice(prefix);
+        }
+        (key.into_boxed_slice(), value.into_boxed_slice())
+    }
+
+    pub(crate) fn from_db_entry(key: &[u8], value: &[u8]) -> Self {
+        assert_eq!(key.len(), TOUCHED_KEY_SIZE, "bad TouchedRow key");
+        assert!(value.len() >= 4, "bad TouchedRow value");
+        let (height, block_prefix) = key.split_at(HEIGHT_SIZE);
+        let (count, prefixes) = value.split_at(4);
+        let count = usize::try_from(u32::from_le_bytes(count.try_into().unwrap())).unwrap();
+        let mut prefixes = prefixes
+            .chunks_exact(HASH_PREFIX_LEN)
+            .map(|prefix| HashPrefix::try_from(prefix).unwrap());
+        Self {
+            height: Height::from_be_bytes(height.try_into().unwrap()),
+            block_prefix: block_prefix.try_into().unwrap(),
+            funding: prefixes.by_ref().take(count).collect(),
+            spending: prefixes.collect(),
+        }
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        usize::try_from(self.height).expect("invalid height")
+    }
+
+    pub(crate) fn is_in_block(&self, blockhash: &BlockHash) -> bool {
+        block_prefix(blockhash) == self.block_prefix
+    }
+
+    /// Matching the scan prefixes of `ScriptHashRow`.
+    pub(crate) fn funding(&self) -> impl Iterator<Item = &[u8]> {
+        self.funding.iter().map(|prefix| &prefix[..])
+    }
+
+    /// Matching the scan prefixes of `SpendingPrefixRow`.
+    pub(crate) fn spending(&self) -> impl Iterator<Item = &[u8]> {
+        self.spending.iter().map(|prefix| &prefix[..])
+    }
+}
+
+// ***************************************************************************
+
+/// Output script types, as tracked by the index statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    OpReturn,
+    Nonstandard,
+}
+
+impl ScriptType {
+    pub(crate) const ALL: [ScriptType; 7] = [
+        ScriptType::P2pkh,
+        ScriptType::P2sh,
+        ScriptType::P2wpkh,
+        ScriptType::P2wsh,
+        ScriptType::P2tr,
+        ScriptType::OpReturn,
+        ScriptType::Nonstandard,
+    ];
+
+    pub(crate) fn classify(script: &Script) -> Self {
+        if script.is_p2pkh() {
+            ScriptType::P2pkh
+        } else if script.is_p2sh() {
+            ScriptType::P2sh
+        } else if script.is_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script.is_p2wsh() {
+            ScriptType::P2wsh
+        } else if script.is_p2tr() {
+            ScriptType::P2tr
+        } else if script.is_op_return() {
+            ScriptType::OpReturn
+        } else {
+            ScriptType::Nonstandard
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2sh => "p2sh",
+            ScriptType::P2wpkh => "p2wpkh",
+            ScriptType::P2wsh => "p2wsh",
+            ScriptType::P2tr => "p2tr",
+            ScriptType::OpReturn => "op_return",
+            ScriptType::Nonstandard => "nonstandard",
+        }
+    }
+}
+
+impl std::str::FromStr for ScriptType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        ScriptType::ALL
+            .iter()
+            .copied()
+            .find(|script_type| script_type.as_str() == s)
+            .ok_or_else(|| format!("unknown script type {:?}", s))
+    }
+}
+
+/// A (BOLT 7) short channel ID: the funding output's block height, transaction position and
+/// output index - packed into 64 bits, or formatted as "<height>x<tx_pos>x<vout>".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ShortChannelId {
+    height: u32, // 3 bytes
+    tx_pos: u32, // 3 bytes
+    vout: u16,
+}
+
+impl ShortChannelId {
+    const MAX_POSITION: usize = (1 << 24) - 1;
+
+    pub(crate) fn new(height: usize, tx_pos: usize, vout: u32) -> Result<Self> {
+        ensure!(
+            height <= Self::MAX_POSITION && tx_pos <= Self::MAX_POSITION,
+            "position {}x{} doesn't fit a short channel ID",
+            height,
+            tx_pos
+        );
+        let vout = u16::try_from(vout).map_err(|_| anyhow!("vout {} is too large", vout))?;
+        Ok(Self {
+            height: height as u32,
+            tx_pos: tx_pos as u32,
+            vout,
+        })
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        self.height as usize
+    }
+
+    pub(crate) fn tx_pos(&self) -> usize {
+        self.tx_pos as usize
+    }
+
+    pub(crate) fn vout(&self) -> u32 {
+        u32::from(self.vout)
+    }
+
+    pub(crate) fn to_u64(self) -> u64 {
+        (u64::from(self.height) << 40) | (u64::from(self.tx_pos) << 16) | u64::from(self.vout)
+    }
+}
+
+impl From<u64> for ShortChannelId {
+    fn from(value: u64) -> Self {
+        Self {
+            height: (value >> 40) as u32,
+            tx_pos: ((value >> 16) & 0xff_ffff) as u32,
+            vout: value as u16,
+        }
+    }
+}
+
+impl std::str::FromStr for ShortChannelId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split('x').collect();
+        match parts[..] {
+            [height, tx_pos, vout] => Self::new(height.parse()?, tx_pos.parse()?, vout.parse()?),
+            _ => bail!(
+                "invalid short channel ID {:?} (expected <height>x<tx_pos>x<vout>)",
+                s
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for ShortChannelId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}x{}x{}", self.height, self.tx_pos, self.vout)
+    }
+}
+
 pub(crate) fn bsl_txid(tx: &bsl::Transaction) -> Txid {
     bitcoin::Txid::from_slice(tx.txid_sha2().as_slice()).expect("invalid txid")
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::types::{spending_prefix, HashPrefixRow, ScriptHash, ScriptHashRow, TxidRow};
-    use bitcoin::{Address, OutPoint, Txid};
+    use crate::types::{
+        spending_prefix, CoinbaseRow, FeeratesRow, HashPrefixRow, ScriptHash, ScriptHashRow,
+        ScriptPrefixRow, ScriptType, ShortChannelId, SpendingPrefixRow, TouchedRow, TxidRow,
+    };
+    use bitcoin::{hashes::Hash, Address, BlockHash, OutPoint, ScriptBuf, Txid};
     use hex_lit::hex;
     use serde_json::{from_str, json};
 
@@ -213,6 +615,23 @@ mod tests {
         assert_eq!(json!(scripthash).to_string(), hex);
     }
 
+    #[test]
+    fn test_short_channel_id() {
+        let scid: ShortChannelId = "539268x845x1".parse().unwrap();
+        assert_eq!(
+            (scid.height(), scid.tx_pos(), scid.vout()),
+            (539268, 845, 1)
+        );
+        assert_eq!(scid.to_u64(), 592931436542885889);
+        assert_eq!(ShortChannelId::from(scid.to_u64()), scid);
+        assert_eq!(scid.to_string(), "539268x845x1");
+
+        assert!("539268x845".parse::<ShortChannelId>().is_err());
+        assert!("539268x845x1x2".parse::<ShortChannelId>().is_err());
+        assert!("16777216x0x0".parse::<ShortChannelId>().is_err());
+        assert!(ShortChannelId::new(1, 2, 65536).is_err());
+    }
+
     #[test]
     fn test_scripthash_row() {
         let hex = "\"4b3d912c1523ece4615e91bf0d27381ca72169dbf6b1c2ffcc9f92381d4984a3\"";
@@ -224,6 +643,76 @@ mod tests {
         assert_eq!(row1, row2);
     }
 
+    #[test]
+    fn test_script_prefix_row() {
+        let script =
+            ScriptBuf::from_bytes(hex!("0014e8df018c7e326cc253faac7e46cdc51e68542c42").to_vec());
+        let row = ScriptPrefixRow::row(&script, 123456).to_db_row();
+        assert_eq!(&*row, &hex!("0014e8df018c7e3240e20100"));
+        let row = ScriptPrefixRow::row(&ScriptBuf::from_bytes(vec![0x51]), 1).to_db_row();
+        assert_eq!(&*row, &hex!("510000000000000001000000"));
+
+        assert_eq!(&*ScriptPrefixRow::scan_prefix(&hex!("0014")), &hex!("0014"));
+        assert_eq!(
+            &*ScriptPrefixRow::scan_prefix(script.as_bytes()),
+            &hex!("0014e8df018c7e32")
+        );
+    }
+
+    #[test]
+    fn test_coinbase_row() {
+        let blockhash: BlockHash =
+            "000000000000000000016b0ee4c5d4ab8fd5a2b3a3d53fb98a4c7105c6c48bb4"
+                .parse()
+                .unwrap();
+        let txid: Txid = "3178396f5e9fd4862a5f67c3e9a9ba6a4bc9d1fba1314207ce606ba28360e52a"
+            .parse()
+            .unwrap();
+        let row1 = CoinbaseRow::new(800000, blockhash, txid, 637_000_000, &[0xab; 100]);
+        assert_eq!(row1.tag.len(), 64);
+        let db_row = row1.to_db_row();
+        assert_eq!(&db_row[..4], &*CoinbaseRow::scan_start(800000));
+        let row2 = CoinbaseRow::from_db_row(&db_row);
+        assert_eq!(row1, row2);
+        assert_eq!(row2.height(), 800000);
+        assert!(row2.is_in_block(&blockhash));
+        assert!(!row2.is_in_block(&BlockHash::all_zeros()));
+    }
+
+    #[test]
+    fn test_feerates_row() {
+        let row1 = FeeratesRow::new(123, BlockHash::all_zeros(), [1, 2, 5, 10, 100]);
+        let db_row = row1.to_db_row();
+        assert_eq!(&db_row[..4], &*FeeratesRow::scan_start(123));
+        assert_eq!(FeeratesRow::from_db_row(&db_row), row1);
+    }
+
+    #[test]
+    fn test_touched_row() {
+        let scripthash = ScriptHash::from_byte_array([1; 32]);
+        let outpoint = OutPoint::new(Txid::from_byte_array([2; 32]), 3);
+        let funding_rows = [ScriptHashRow::row(scripthash, 123).to_db_row()];
+        let spending_rows = [
+            SpendingPrefixRow::row(outpoint, 123).to_db_row(),
+            SpendingPrefixRow::row(OutPoint::null(), 123).to_db_row(),
+        ];
+        let row1 = TouchedRow::new(123, BlockHash::all_zeros(), &funding_rows, &spending_rows);
+        let (key, value) = row1.to_db_entry();
+        assert_eq!(&key[..4], &*TouchedRow::scan_start(123));
+        assert_eq!(value.len(), 4 + 3 * 8);
+        let row2 = TouchedRow::from_db_entry(&key, &value);
+        assert_eq!(row2, row1);
+        assert!(row2.is_in_block(&BlockHash::all_zeros()));
+        assert_eq!(
+            row2.funding().collect::<Vec<_>>(),
+            [&*ScriptHashRow::scan_prefix(scripthash)]
+        );
+        assert_eq!(
+            row2.spending().next(),
+            Some(&*SpendingPrefixRow::scan_prefix(outpoint))
+        );
+    }
+
     #[test]
     fn test_scripthash() {
         let addr = Address::from_str("1KVNjD3AAnQ3gTMqoTKcWFeqSFujq9gTBT")
@@ -288,4 +777,40 @@ mod tests {
             [31, 30, 29, 28, 27, 26, 26, 24]
         );
     }
+
+    #[test]
+    fn test_script_type() {
+        let cases = [
+            ("1KVNjD3AAnQ3gTMqoTKcWFeqSFujq9gTBT", ScriptType::P2pkh),
+            ("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy", ScriptType::P2sh),
+            (
+                "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq",
+                ScriptType::P2wpkh,
+            ),
+            (
+                "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3",
+                ScriptType::P2wsh,
+            ),
+            (
+                "bc1p5d7rjq7g6rdk2yhzks9smlaqtedr4dekq08ge8ztwac72sfr9rusxg3297",
+                ScriptType::P2tr,
+            ),
+        ];
+        for (addr, script_type) in cases {
+            let addr = Address::from_str(addr).unwrap().assume_checked();
+            assert_eq!(ScriptType::classify(&addr.script_pubkey()), script_type);
+        }
+        let op_return = ScriptBuf::from_bytes(vec![0x6a, 0x03, 1, 2, 3]); // OP_RETURN <010203>
+        assert_eq!(ScriptType::classify(&op_return), ScriptType::OpReturn);
+        assert_eq!(
+            ScriptType::classify(&ScriptBuf::from_bytes(vec![0x51])),
+            ScriptType::Nonstandard
+        );
+        assert_eq!(json!(ScriptType::OpReturn), json!("op_return"));
+        for script_type in ScriptType::ALL {
+            assert_eq!(json!(script_type), json!(script_type.as_str()));
+            assert_eq!(ScriptType::from_str(script_type.as_str()), Ok(script_type));
+        }
+        assert!(ScriptType::from_str("p2wpkh ").is_err());
+    }
 }
@@ -0,0 +1,106 @@
+use parking_lot::Mutex;
+
+use std::collections::HashMap;
+
+use crate::{status::ScriptHashStatus, types::ScriptHash};
+
+#[derive(Default)]
+struct Recent {
+    counter: u64,
+    last_used: HashMap<ScriptHash, u64>, // scripthash -> counter value at its last query
+}
+
+/// Tracks the most recently queried scripthashes (to be persisted on shutdown), so their statuses
+/// can be synced after a restart - before the reconnecting clients query them again.
+pub(crate) struct Warmup {
+    max_scripthashes: usize, // 0 = disabled
+    recent: Mutex<Recent>,
+    statuses: Mutex<HashMap<ScriptHash, ScriptHashStatus>>, // pre-synced, until their first query
+}
+
+impl Warmup {
+    pub(crate) fn new(max_scripthashes: usize) -> Self {
+        Self {
+            max_scripthashes,
+            recent: Default::default(),
+            statuses: Default::default(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.max_scripthashes > 0
+    }
+
+    pub(crate) fn touch(&self, scripthash: ScriptHash) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut recent = self.recent.lock();
+        recent.counter += 1;
+        let counter = recent.counter;
+        recent.last_used.insert(scripthash, counter);
+        // prune in bulk, to amortize the cost
+        if recent.last_used.len() >= 2 * self.max_scripthashes {
+            let threshold = counter - self.max_scripthashes as u64;
+            recent
+                .last_used
+                .retain(|_, last_used| *last_used > threshold);
+        }
+    }
+
+    /// The most recently queried scripthashes (newest first).
+    pub(crate) fn hints(&self) -> Vec<ScriptHash> {
+        let recent = self.recent.lock();
+        let mut entries: Vec<(u64, ScriptHash)> = recent
+            .last_used
+            .iter()
+            .map(|(scripthash, last_used)| (*last_used, *scripthash))
+            .collect();
+        entries.sort_unstable_by(|a, b| b.cmp(a));
+        entries
+            .into_iter()
+            .take(self.max_scripthashes)
+            .map(|(_, scripthash)| scripthash)
+            .collect()
+    }
+
+    pub(crate) fn max_scripthashes(&self) -> usize {
+        self.max_scripthashes
+    }
+
+    pub(crate) fn add_statuses(&self, statuses: Vec<ScriptHashStatus>) {
+        let mut warm = self.statuses.lock();
+        warm.extend(
+            statuses
+                .into_iter()
+                .map(|status| (status.scripthash(), status)),
+        );
+    }
+
+    /// A pre-synced status is used only once (afterwards, it is kept by the client).
+    pub(crate) fn take_status(&self, scripthash: &ScriptHash) -> Option<ScriptHashStatus> {
+        self.statuses.lock().remove(scripthash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Warmup;
+    use crate::types::ScriptHash;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_hints() {
+        let scripthash = |i: u8| ScriptHash::from_byte_array([i; 32]);
+        let disabled = Warmup::new(0);
+        disabled.touch(scripthash(1));
+        assert!(disabled.hints().is_empty());
+
+        let warmup = Warmup::new(2);
+        for i in [1, 2, 3, 1, 4] {
+            warmup.touch(scripthash(i));
+        }
+        assert_eq!(warmup.hints(), [scripthash(4), scripthash(1)]);
+        assert!(warmup.recent.lock().last_used.len() < 4); // older entries are pruned
+    }
+}
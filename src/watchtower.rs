@@ -0,0 +1,208 @@
+use anyhow::{Context, Result};
+use bitcoin::{
+    consensus::deserialize, hashes::hex::FromHex, BlockHash, OutPoint, Transaction, Txid,
+};
+
+use crate::{electrum::Rpc, tracker::Spender};
+
+const MAX_WATCHES: usize = 1000;
+
+/// A watched outpoint, and the (pre-signed) transaction to broadcast when it is spent by another
+/// transaction - e.g. a Lightning penalty transaction, reacting to a revoked commitment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Watch {
+    outpoint: OutPoint,
+    reaction: String,            // hex-encoded transaction
+    spender: Option<Txid>,       // set when the spend is found
+    spent_in: Option<BlockHash>, // the spender's block (`None` while it is unconfirmed)
+    error: Option<String>,       // the daemon's rejection of the reaction (retried on new blocks)
+}
+
+impl Watch {
+    fn reaction(&self) -> Result<Transaction> {
+        let bytes = Vec::from_hex(&self.reaction).context("non-hex reaction transaction")?;
+        deserialize(&bytes).context("invalid reaction transaction")
+    }
+
+    /// Whether the known spender is still confirmed (or still in the mempool).
+    fn is_spender_present(&self, rpc: &Rpc) -> bool {
+        match self.spent_in {
+            Some(blockhash) => rpc.chain().get_block_height(&blockhash).is_some(),
+            None => rpc.find_mempool_spender(self.outpoint).map(|s| s.tx_hash()) == self.spender,
+        }
+    }
+
+    /// Returns whether the watch has changed. The index is looked up only after a new tip
+    /// (otherwise, only the mempool may have a new spender).
+    fn poll(&mut self, rpc: &Rpc, new_tip: bool) -> Result<bool> {
+        if self.spender.is_some() && self.is_spender_present(rpc) {
+            if self.error.is_none() || !new_tip {
+                return Ok(false);
+            }
+            self.react(rpc)?; // retry the rejected reaction
+            return Ok(true);
+        }
+        let found = if new_tip || self.spender.is_some() {
+            rpc.find_spender(self.outpoint)?
+        } else {
+            rpc.find_mempool_spender(self.outpoint)
+        };
+        let spender = found.as_ref().map(Spender::tx_hash);
+        let spent_in = found
+            .filter(Spender::is_confirmed)
+            .and_then(|s| rpc.chain().get_block_hash(s.height()));
+        let changed = (spender, spent_in) != (self.spender, self.spent_in);
+        if spender != self.spender {
+            if let Some(old) = self.spender {
+                warn!("watched {} is no longer spent by {}", self.outpoint, old);
+            }
+            self.spender = spender;
+            self.error = None;
+            if spender.is_some() {
+                self.react(rpc)?;
+            }
+        }
+        self.spent_in = spent_in;
+        Ok(changed)
+    }
+
+    /// Broadcast the reaction (unless it is the spender).
+    fn react(&mut self, rpc: &Rpc) -> Result<()> {
+        let spender = self.spender.expect("missing spender");
+        let reaction = self.reaction()?;
+        if reaction.txid() == spender {
+            info!(
+                "watched {} is spent by its reaction {}",
+                self.outpoint, spender
+            );
+            self.error = None;
+            return Ok(());
+        }
+        warn!(
+            "watched {} is spent by {}, broadcasting reaction {}",
+            self.outpoint,
+            spender,
+            reaction.txid()
+        );
+        self.error = match rpc.broadcast(&reaction, None) {
+            Ok(_) => None,
+            Err(e) => {
+                warn!("failed to broadcast reaction {}: {:#}", reaction.txid(), e);
+                Some(format!("{:#}", e))
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Broadcasts the registered reactions, when their outpoints are spent (in a block or in the
+/// mempool). Registered via the admin API, and polled after each sync which has changed the
+/// tip or the mempool.
+pub(crate) struct Watchtower {
+    watches: Vec<Watch>,
+    polled: Option<(BlockHash, u64)>, // the tip and mempool generation of the last poll
+}
+
+impl Watchtower {
+    pub(crate) fn new(watches: Vec<Watch>) -> Self {
+        if !watches.is_empty() {
+            info!("loaded {} watched outpoints", watches.len());
+        }
+        Self {
+            watches,
+            polled: None,
+        }
+    }
+
+    pub(crate) fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    /// Watch `outpoint` (replacing its previous reaction, if any).
+    pub(crate) fn add(&mut self, outpoint: OutPoint, reaction: String) -> Result<()> {
+        let watch = Watch {
+            outpoint,
+            reaction,
+            spender: None,
+            spent_in: None,
+            error: None,
+        };
+        let tx = watch.reaction()?;
+        ensure!(
+            !tx.is_coin_base(),
+            "coinbase transactions can't be broadcasted"
+        );
+        self.watches.retain(|watch| watch.outpoint != outpoint);
+        ensure!(
+            self.watches.len() < MAX_WATCHES,
+            "too many watched outpoints: {}",
+            self.watches.len()
+        );
+        self.watches.push(watch);
+        self.polled = None; // look up the new watch on the next poll
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, outpoint: OutPoint) -> bool {
+        let before = self.watches.len();
+        self.watches.retain(|watch| watch.outpoint != outpoint);
+        self.watches.len() < before
+    }
+
+    /// Broadcast the reactions of the newly spent outpoints (unless the reaction is the spender).
+    /// Rejected reactions are retried after each new tip, and a spender which is reorged out
+    /// (or evicted from the mempool) is looked up again. A failing watch doesn't stop the others.
+    /// Returns whether any watch has changed (so the watches should be persisted).
+    pub(crate) fn poll(&mut self, rpc: &Rpc) -> bool {
+        let state = (rpc.chain().tip(), rpc.mempool_generation());
+        if self.polled == Some(state) {
+            return false;
+        }
+        let new_tip = self.polled.map_or(true, |(tip, _)| tip != state.0);
+        self.polled = Some(state);
+        let mut changed = false;
+        for watch in &mut self.watches {
+            match watch.poll(rpc, new_tip) {
+                Ok(watch_changed) => changed |= watch_changed,
+                Err(e) => warn!("failed to poll watched {}: {:#}", watch.outpoint, e),
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Watchtower;
+    use bitcoin::{
+        absolute::LockTime, consensus::encode::serialize_hex, hashes::Hash, transaction::Version,
+        OutPoint, Transaction, TxIn, Txid,
+    };
+
+    #[test]
+    fn test_watches() {
+        let outpoint = |i: u8| OutPoint::new(Txid::from_byte_array([i; 32]), 0);
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: outpoint(9),
+                ..Default::default()
+            }],
+            output: vec![],
+        };
+        let reaction = serialize_hex(&tx);
+
+        let mut watchtower = Watchtower::new(vec![]);
+        assert!(watchtower.add(outpoint(1), "xyz".to_owned()).is_err());
+        assert!(watchtower.add(outpoint(1), "00".to_owned()).is_err());
+        watchtower.add(outpoint(1), reaction.clone()).unwrap();
+        watchtower.add(outpoint(2), reaction.clone()).unwrap();
+        watchtower.add(outpoint(1), reaction).unwrap(); // replaced
+        assert_eq!(watchtower.watches().len(), 2);
+
+        assert!(watchtower.remove(outpoint(1)));
+        assert!(!watchtower.remove(outpoint(1)));
+        assert_eq!(watchtower.watches()[0].outpoint, outpoint(2));
+    }
+}
@@ -0,0 +1,257 @@
+use anyhow::{Context, Result};
+use bitcoin::{BlockHash, Txid};
+use crossbeam_channel::{unbounded, Sender};
+use serde_json::{json, Value};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::Duration;
+
+use crate::{
+    electrum::Rpc,
+    http::HttpTarget,
+    status::ScriptHashStatus,
+    thread::spawn,
+    types::{ScriptHash, StatusHash},
+};
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Registered webhook URLs, and the scripthashes watched by each of them.
+pub(crate) type Registrations = BTreeMap<String, BTreeSet<ScriptHash>>;
+
+/// A watched scripthash's status, and its last notified state.
+struct Watched {
+    status: ScriptHashStatus,
+    notified: Notified,
+}
+
+struct Notified {
+    statushash: Option<StatusHash>,
+    history: HashSet<(Txid, Option<usize>)>,
+}
+
+struct Delivery {
+    url: String,
+    body: String,
+}
+
+/// POSTs a JSON notification to the registered URLs, when their watched scripthashes
+/// have new (confirmed or unconfirmed) activity.
+/// Notifications are delivered by a separate thread, so slow endpoints don't block the server.
+pub(crate) struct Webhooks {
+    registrations: Registrations,
+    watched: HashMap<ScriptHash, Watched>, // shared by all URLs
+    polled: Option<(BlockHash, u64)>,      // chain tip and mempool generation at the last poll
+    deliveries: Sender<Delivery>,
+}
+
+impl Webhooks {
+    pub(crate) fn new(registrations: Registrations) -> Self {
+        let (deliveries, deliveries_rx) = unbounded::<Delivery>();
+        spawn("webhooks", move || {
+            for delivery in deliveries_rx {
+                let result = HttpTarget::parse(&delivery.url).and_then(|target| {
+                    let body = delivery.body.as_bytes();
+                    target.send("POST", "application/json", body, DELIVERY_TIMEOUT)
+                });
+                if let Err(e) = result {
+                    warn!("failed to notify webhook {}: {:#}", delivery.url, e);
+                }
+            }
+            Ok(())
+        });
+        if !registrations.is_empty() {
+            info!("loaded {} webhooks", registrations.len());
+        }
+        Self {
+            registrations,
+            watched: HashMap::new(),
+            polled: None,
+            deliveries,
+        }
+    }
+
+    pub(crate) fn registrations(&self) -> &Registrations {
+        &self.registrations
+    }
+
+    /// Watch more scripthashes (the URL is registered if needed).
+    pub(crate) fn add(&mut self, url: String, scripthashes: Vec<ScriptHash>) -> Result<()> {
+        HttpTarget::parse(&url).context("invalid webhook URL")?;
+        self.registrations
+            .entry(url)
+            .or_default()
+            .extend(scripthashes);
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, url: &str) -> bool {
+        let removed = self.registrations.remove(url).is_some();
+        let watched: HashSet<&ScriptHash> = self.registrations.values().flatten().collect();
+        self.watched
+            .retain(|scripthash, _| watched.contains(scripthash));
+        removed
+    }
+
+    /// Notify about activity since the last poll. Newly watched scripthashes are synced first,
+    /// so their existing history is not notified.
+    /// Since it runs on the server's thread, the statuses are synced together (in parallel),
+    /// and only if the chain or the mempool have changed since the last poll.
+    pub(crate) fn poll(&mut self, rpc: &Rpc) -> Result<()> {
+        let polled = (rpc.chain().tip(), rpc.mempool_generation());
+        let changed = self.sync(polled, |statuses| rpc.sync_statuses(statuses))?;
+        if changed.is_empty() {
+            return Ok(());
+        }
+        let chain = rpc.chain();
+        for (url, scripthashes) in &self.registrations {
+            let changes: Vec<&Value> = scripthashes
+                .iter()
+                .filter_map(|scripthash| changed.get(scripthash))
+                .collect();
+            if changes.is_empty() {
+                continue;
+            }
+            let body = json!({"height": chain.height(), "tip": chain.tip(), "changes": changes});
+            let delivery = Delivery {
+                url: url.clone(),
+                body: body.to_string(),
+            };
+            self.deliveries
+                .send(delivery)
+                .context("webhooks thread stopped")?;
+        }
+        Ok(())
+    }
+
+    /// Sync the watched statuses, returning the activity that wasn't notified yet.
+    /// If the sync fails, some statuses may be already updated - so their activity is
+    /// computed from their last notified history (on the next successful sync).
+    fn sync(
+        &mut self,
+        polled: (BlockHash, u64),
+        sync_statuses: impl FnOnce(&mut [ScriptHashStatus]) -> Result<()>,
+    ) -> Result<HashMap<ScriptHash, Value>> {
+        let watched: BTreeSet<ScriptHash> =
+            self.registrations.values().flatten().copied().collect();
+        if self.polled == Some(polled)
+            && watched
+                .iter()
+                .all(|scripthash| self.watched.contains_key(scripthash))
+        {
+            return Ok(HashMap::new()); // no new activity
+        }
+        let mut notified = HashMap::<ScriptHash, Notified>::new(); // of the synced statuses
+        let mut statuses: Vec<ScriptHashStatus> = watched
+            .into_iter()
+            .map(|scripthash| match self.watched.remove(&scripthash) {
+                Some(watched) => {
+                    notified.insert(scripthash, watched.notified);
+                    watched.status
+                }
+                None => ScriptHashStatus::new(scripthash),
+            })
+            .collect();
+        if let Err(e) = sync_statuses(&mut statuses) {
+            // the new statuses are dropped, to be synced again on the next poll
+            for status in statuses {
+                let scripthash = status.scripthash();
+                if let Some(notified) = notified.remove(&scripthash) {
+                    self.watched
+                        .insert(scripthash, Watched { status, notified });
+                }
+            }
+            return Err(e);
+        }
+        self.polled = Some(polled);
+        let mut changed = HashMap::<ScriptHash, Value>::new();
+        for status in statuses {
+            let scripthash = status.scripthash();
+            if let Some(old) = notified.get(&scripthash) {
+                if status.statushash() != old.statushash {
+                    let new: Vec<Value> = history(&status)
+                        .filter(|entry| !old.history.contains(entry))
+                        .map(|(txid, height)| json!({"txid": txid, "height": height}))
+                        .collect();
+                    let change = json!({
+                        "scripthash": scripthash,
+                        "status": status.statushash(),
+                        "transactions": new, // height is null for mempool transactions
+                    });
+                    changed.insert(scripthash, change);
+                }
+            }
+            let notified = Notified {
+                statushash: status.statushash(),
+                history: history(&status).collect(),
+            };
+            self.watched
+                .insert(scripthash, Watched { status, notified });
+        }
+        Ok(changed)
+    }
+}
+
+fn history(status: &ScriptHashStatus) -> impl Iterator<Item = (Txid, Option<usize>)> + '_ {
+    status
+        .get_history()
+        .iter()
+        .map(|entry| (entry.txid(), entry.confirmed_height()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Webhooks;
+    use crate::{
+        cache::Cache,
+        mempool::Mempool,
+        metrics::Metrics,
+        status::ScriptHashStatus,
+        testing::{MockDaemon, TestIndex},
+        types::ScriptHash,
+    };
+    use anyhow::bail;
+    use bitcoin::{Network, ScriptBuf};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_failed_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = |byte| ScriptBuf::from_bytes(vec![0x51, byte]);
+        let mut daemon = MockDaemon::new(Network::Regtest);
+        daemon.mine(script(1), vec![]);
+        let mut index = TestIndex::open(dir.path(), Network::Regtest).unwrap();
+        index.sync(&daemon).unwrap();
+        let metrics = Metrics::unserved();
+        let mempool = Mempool::new(&metrics, None, None, None);
+        let cache = Cache::new(&metrics, None);
+        let sync_many = |statuses: &mut [ScriptHashStatus]| {
+            ScriptHashStatus::sync_many(statuses, index.index(), &mempool, &daemon, &cache)
+        };
+
+        let (paid, other) = (ScriptHash::new(&script(1)), ScriptHash::new(&script(2)));
+        let registrations = BTreeMap::from([("http://localhost".to_owned(), [paid, other].into())]);
+        let mut webhooks = Webhooks::new(registrations);
+        // the existing history is not notified
+        let changed = webhooks.sync((index.tip(), 0), sync_many).unwrap();
+        assert!(changed.is_empty());
+
+        daemon.mine(script(1), vec![]);
+        index.sync(&daemon).unwrap();
+        let sync_many = |statuses: &mut [ScriptHashStatus]| {
+            ScriptHashStatus::sync_many(statuses, index.index(), &mempool, &daemon, &cache)
+        };
+        // only the paid scripthash's status is updated (as if the other chunk has failed)
+        let result = webhooks.sync((index.tip(), 0), |statuses| {
+            let paid_status = statuses.iter_mut().find(|s| s.scripthash() == paid);
+            sync_many(std::slice::from_mut(paid_status.unwrap()))?;
+            bail!("failed to sync the other chunk")
+        });
+        assert!(result.is_err());
+        // the new activity is notified by the next successful sync
+        let changed = webhooks.sync((index.tip(), 0), sync_many).unwrap();
+        assert_eq!(changed.keys().collect::<Vec<_>>(), [&paid]);
+        assert_eq!(changed[&paid]["transactions"][0]["height"], 2);
+        assert_eq!(changed[&paid]["transactions"].as_array().unwrap().len(), 1);
+    }
+}